@@ -1,17 +1,22 @@
 use std::collections::HashMap;
+use std::env;
 use std::str::FromStr;
 
 use tokio::runtime::Runtime;
 use tokio::task;
 
-use log::info;
+use tracing::info;
 
 use libp2ep::bitcoin::hashes::hex::FromHex;
 use libp2ep::bitcoin::secp256k1::{All, Secp256k1};
 use libp2ep::bitcoin::*;
 
+use libp2ep::bip21::Bip21Uri;
 use libp2ep::client::*;
+use libp2ep::coin_selection::{base_transaction, BranchAndBound, WeightedUtxo};
 use libp2ep::demo::*;
+use libp2ep::transport::LibTor;
+use libp2ep::FeeRate;
 
 fn main() {
     env_logger::init();
@@ -23,13 +28,19 @@ fn main() {
 }
 
 async fn run() {
-    let send_to = Address::from_str("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080").unwrap();
-    let send_to_amount = 3_000_000;
+    // The URI printed by the server example
+    let uri = env::args().nth(1).expect("Usage: client <bip21-uri>");
+    let Bip21Uri {
+        address: send_to,
+        amount,
+        ..
+    } = Bip21Uri::from_str(&uri).unwrap();
+    let send_to_amount = amount.unwrap();
 
     let secp: Secp256k1<All> = Secp256k1::gen_new();
     let sk = PrivateKey::from_str("cVt4o7BGAig1UXywgGSmARhxMdzP5qvQsxKkSsc1XEkw3tDTQFpy").unwrap();
     let address = Address::p2wpkh(&sk.public_key(&secp), Network::Regtest);
-    info!("address: {}", address.to_string());
+    info!("address: {}", address);
 
     let previous_output_value = 100_000_000;
     let previous_output = OutPoint {
@@ -37,46 +48,49 @@ async fn run() {
             .unwrap(),
         vout: 0,
     };
-    let vin = TxIn {
+    let utxos = vec![WeightedUtxo::new(
         previous_output,
-        sequence: 0xFFFFFFFF,
-        ..Default::default()
-    };
-    let tx = Transaction {
-        version: 2,
-        lock_time: 0,
-        input: vec![vin],
-        output: vec![
-            TxOut {
-                script_pubkey: address.script_pubkey(),
-                value: previous_output_value - send_to_amount - 5000,
-            },
-            TxOut {
-                script_pubkey: send_to.script_pubkey(),
-                value: send_to_amount,
-            },
-        ],
+        TxOut {
+            script_pubkey: address.script_pubkey(),
+            value: previous_output_value,
+        },
+    )];
+    let payment = TxOut {
+        script_pubkey: send_to.script_pubkey(),
+        value: send_to_amount,
     };
+    let tx = base_transaction(
+        &BranchAndBound,
+        &utxos,
+        payment,
+        address.script_pubkey(),
+        FeeRate::default(),
+    )
+    .unwrap();
 
     let mut meta_map = HashMap::new();
     meta_map.insert(
-        tx.input[0].previous_output.clone(),
+        previous_output,
         (previous_output_value, address.script_pubkey()),
     );
 
     let electrum = ElectrumBlockchain::new();
     let signer = SoftwareSigner::new(sk, meta_map);
 
-    let mut client = Client::new(
-        "vzxfzi6dn6t64hvscbjn735joug4ln4wc4zfaxvifr26fqbgudflz6yd.onion:9000",
+    let mut client = Client::from_uri(
+        &LibTor::default(),
+        &uri,
+        Network::Regtest,
         electrum,
         signer,
         tx,
-        1,
     )
     .await
     .unwrap();
-    let txid = client.start().await.unwrap();
+    let result = client.start().await.unwrap();
 
-    info!("Completed with txid: {}", txid);
+    info!(
+        "Completed with txid: {} ({} sat in fees)",
+        result.txid, result.fee_paid
+    );
 }
@@ -4,13 +4,15 @@ use std::str::FromStr;
 use tokio::runtime::Runtime;
 use tokio::task;
 
-use log::info;
+use tracing::info;
 
 use libp2ep::bitcoin::hashes::hex::FromHex;
 use libp2ep::bitcoin::secp256k1::{All, Secp256k1};
 use libp2ep::bitcoin::*;
+use libp2ep::decoy::AnyDecoy;
 use libp2ep::demo::*;
 use libp2ep::server::*;
+use libp2ep::wallet::*;
 
 fn main() {
     env_logger::init();
@@ -34,23 +36,30 @@ async fn run() {
     };
 
     let mut meta_map = HashMap::new();
-    meta_map.insert(our_output.clone(), (200_000_000, address.script_pubkey()));
+    meta_map.insert(our_output, (200_000_000, address.script_pubkey()));
 
     let electrum = ElectrumBlockchain::new();
     let signer = SoftwareSigner::new(sk, meta_map);
+    let wallet = SimpleReceiverWallet::new(
+        vec![(
+            our_output,
+            TxOut {
+                value: 200_000_000,
+                script_pubkey: address.script_pubkey(),
+            },
+        )],
+        vec![address.script_pubkey()],
+    );
 
-    let mut server = Server::new(
-        "127.0.0.1:9000",
-        electrum,
-        signer,
-        our_output,
-        address.script_pubkey(),
-        3_000_000,
-    )
-    .await
-    .unwrap();
-
-    let full_addr = server.setup(Network::Regtest).unwrap();
+    // The demo blockchain only knows a single coinbase output, which no filter would accept
+    let mut server = ServerBuilder::new(electrum, signer, wallet, 3_000_000)
+        .decoy_selector(AnyDecoy)
+        .network(Network::Regtest)
+        .bind("127.0.0.1:9000")
+        .await
+        .unwrap();
+
+    let full_addr = server.setup().await.unwrap();
     info!("BIP21: {}", full_addr);
 
     server.mainloop().await.unwrap();
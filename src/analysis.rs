@@ -0,0 +1,352 @@
+//! Inspection of final transactions
+//!
+//! Given the final transaction of a session and what one of the sides knows about it, tell apart
+//! the inputs and outputs of the sender and of the receiver, and check how the transaction looks
+//! to a chain observer. The heuristics are the ones of BIP78: an output smaller than every input
+//! is likely to be the change (UIH1) and an input larger than every output would not have been
+//! picked by a regular wallet (UIH2).
+
+use tracing::warn;
+
+use bitcoin::{OutPoint, Script, Transaction, TxOut};
+
+use crate::blockchain::{Blockchain, TxSource};
+use crate::fees::FeeRate;
+use crate::{Error, ProtocolError};
+
+/// Who an input or an output belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    Sender,
+    /// Outputs paying the receiver, including the payment itself
+    Receiver,
+}
+
+/// What a side knows about the session, enough to attribute every input and output
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// Inputs contributed by the receiver
+    pub receiver_inputs: Vec<OutPoint>,
+    /// Scripts of the outputs paying the receiver. Every other output is the sender's, either its
+    /// change or the other payments of a batch
+    pub receiver_scripts: Vec<Script>,
+}
+
+impl Metadata {
+    /// From the side of the sender: every input missing from `original` was added by the receiver
+    pub fn from_original(
+        original: &Transaction,
+        transaction: &Transaction,
+        receiver_script: Script,
+    ) -> Self {
+        let receiver_inputs = transaction
+            .input
+            .iter()
+            .map(|input| input.previous_output)
+            .filter(|prev_out| {
+                !original
+                    .input
+                    .iter()
+                    .any(|input| input.previous_output == *prev_out)
+            })
+            .collect();
+
+        Metadata {
+            receiver_inputs,
+            receiver_scripts: vec![receiver_script],
+        }
+    }
+}
+
+/// Heuristics a chain observer could apply to the transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Privacy {
+    /// Both sides have inputs, breaking the common-input-ownership heuristic
+    pub mixed_inputs: bool,
+    /// An output is smaller than every input (UIH1)
+    pub optimal_change: bool,
+    /// An input is larger than every output (UIH2)
+    pub unnecessary_input: bool,
+}
+
+impl Privacy {
+    /// Heuristics on a transaction spending `inputs` to `outputs`, by value
+    pub fn of(inputs: &[u64], outputs: &[u64], mixed_inputs: bool) -> Self {
+        let min_input = inputs.iter().min();
+        let max_input = inputs.iter().max();
+        let min_output = outputs.iter().min();
+        let max_output = outputs.iter().max();
+
+        Privacy {
+            mixed_inputs,
+            optimal_change: matches!(
+                (min_output, min_input),
+                (Some(output), Some(input)) if output < input
+            ),
+            unnecessary_input: matches!(
+                (max_input, max_output),
+                (Some(input), Some(output)) if input > output
+            ),
+        }
+    }
+
+    /// Whether either of the unnecessary input heuristics applies
+    pub fn is_detectable(&self) -> bool {
+        self.optimal_change || self.unnecessary_input
+    }
+
+    /// From 0 for a regular transaction to 3 for a payjoin that no heuristic gives away
+    pub fn score(&self) -> u8 {
+        self.mixed_inputs as u8 + !self.optimal_change as u8 + !self.unnecessary_input as u8
+    }
+}
+
+/// What to do with a payjoin the unnecessary input heuristics would give away
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UihPolicy {
+    Ignore,
+    /// Log a warning and go on
+    #[default]
+    Warn,
+    /// Fail with `ProtocolError::Detectable`
+    Abort,
+}
+
+impl UihPolicy {
+    pub(crate) fn check(&self, privacy: &Privacy) -> Result<(), Error> {
+        if !privacy.is_detectable() {
+            return Ok(());
+        }
+
+        match self {
+            UihPolicy::Ignore => Ok(()),
+            UihPolicy::Warn => {
+                warn!(
+                    "The payjoin can be told apart by the unnecessary input heuristics: {:?}",
+                    privacy
+                );
+                Ok(())
+            }
+            UihPolicy::Abort => Err(ProtocolError::Detectable.into()),
+        }
+    }
+}
+
+/// Breakdown of a final transaction
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    /// Owner and value of every input, in order
+    pub inputs: Vec<(Owner, u64)>,
+    /// Owner and value of every output, in order
+    pub outputs: Vec<(Owner, u64)>,
+    /// Fees of the whole transaction, in satoshis
+    pub fee: u64,
+    pub fee_rate: FeeRate,
+    pub privacy: Privacy,
+}
+
+impl Analysis {
+    /// Whether the receiver contributed inputs, as opposed to a fallback transaction
+    pub fn is_payjoin(&self) -> bool {
+        self.privacy.mixed_inputs
+    }
+
+    /// Value of the inputs of `owner` minus the value of its outputs, negative for the receiver
+    pub fn net_spent(&self, owner: Owner) -> i64 {
+        let sum = |entries: &[(Owner, u64)]| {
+            entries
+                .iter()
+                .filter(|(entry_owner, _)| *entry_owner == owner)
+                .map(|(_, value)| *value as i64)
+                .sum::<i64>()
+        };
+
+        sum(&self.inputs) - sum(&self.outputs)
+    }
+}
+
+/// Outputs spent by `transaction`, looked up on `blockchain`
+pub fn prev_outs<B>(blockchain: &B, transaction: &Transaction) -> Result<Vec<TxOut>, Error>
+where
+    B: Blockchain,
+    Error: From<<B as TxSource>::Error>,
+{
+    let mut prev_outs = Vec::with_capacity(transaction.input.len());
+    for input in &transaction.input {
+        let prev_tx = blockchain.get_tx(&input.previous_output.txid)?;
+        prev_outs.push(
+            prev_tx
+                .output
+                .get(input.previous_output.vout as usize)
+                .ok_or(ProtocolError::InvalidUtxo)?
+                .clone(),
+        );
+    }
+
+    Ok(prev_outs)
+}
+
+/// Analyze `transaction`, spending `prev_outs` in the same order as its inputs
+pub fn analyze(
+    transaction: &Transaction,
+    prev_outs: &[TxOut],
+    metadata: &Metadata,
+) -> Result<Analysis, Error> {
+    if prev_outs.len() != transaction.input.len() {
+        return Err(ProtocolError::InvalidUtxo.into());
+    }
+
+    let inputs = transaction
+        .input
+        .iter()
+        .zip(prev_outs)
+        .map(|(input, prev_out)| {
+            let owner = if metadata.receiver_inputs.contains(&input.previous_output) {
+                Owner::Receiver
+            } else {
+                Owner::Sender
+            };
+            (owner, prev_out.value)
+        })
+        .collect::<Vec<_>>();
+    let outputs = transaction
+        .output
+        .iter()
+        .map(|output| {
+            let owner = if metadata.receiver_scripts.contains(&output.script_pubkey) {
+                Owner::Receiver
+            } else {
+                Owner::Sender
+            };
+            (owner, output.value)
+        })
+        .collect::<Vec<_>>();
+
+    let input_value = inputs.iter().map(|(_, value)| value).sum::<u64>();
+    let output_value = outputs.iter().map(|(_, value)| value).sum::<u64>();
+    let fee = input_value
+        .checked_sub(output_value)
+        .ok_or(ProtocolError::InvalidUtxo)?;
+    let vbytes = transaction.get_weight().div_ceil(4);
+
+    let privacy = Privacy::of(
+        &inputs.iter().map(|(_, value)| *value).collect::<Vec<_>>(),
+        &outputs.iter().map(|(_, value)| *value).collect::<Vec<_>>(),
+        inputs.iter().any(|(owner, _)| *owner == Owner::Sender)
+            && inputs.iter().any(|(owner, _)| *owner == Owner::Receiver),
+    );
+
+    Ok(Analysis {
+        inputs,
+        outputs,
+        fee,
+        fee_rate: FeeRate::from_sat_per_vb(fee as f32 / vbytes as f32),
+        privacy,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::TxIn;
+
+    use super::*;
+    use crate::testutil::Payjoin;
+
+    fn transaction(inputs: &[u64], outputs: &[u64]) -> (Transaction, Vec<TxOut>) {
+        let transaction = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: (0..inputs.len())
+                .map(|vout| TxIn {
+                    previous_output: OutPoint::new(Default::default(), vout as u32),
+                    ..Default::default()
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|value| TxOut {
+                    value: *value,
+                    ..Default::default()
+                })
+                .collect(),
+        };
+        let prev_outs = inputs
+            .iter()
+            .map(|value| TxOut {
+                value: *value,
+                ..Default::default()
+            })
+            .collect();
+
+        (transaction, prev_outs)
+    }
+
+    #[test]
+    fn test_heuristics() {
+        let receiver = Metadata {
+            receiver_inputs: vec![OutPoint::new(Default::default(), 1)],
+            receiver_scripts: vec![],
+        };
+
+        // Change smaller than both inputs
+        let (tx, prev_outs) = transaction(&[50_000, 40_000], &[80_000, 9_000]);
+        let analysis = analyze(&tx, &prev_outs, &receiver).unwrap();
+        assert_eq!(analysis.fee, 1_000);
+        assert!(analysis.privacy.optimal_change);
+        assert!(!analysis.privacy.unnecessary_input);
+        assert_eq!(analysis.privacy.score(), 2);
+
+        // The first input alone pays for every output
+        let (tx, prev_outs) = transaction(&[100_000, 40_000], &[70_000, 69_000]);
+        let analysis = analyze(&tx, &prev_outs, &receiver).unwrap();
+        assert!(!analysis.privacy.optimal_change);
+        assert!(analysis.privacy.unnecessary_input);
+
+        let (tx, prev_outs) = transaction(&[50_000, 40_000], &[89_000]);
+        let analysis = analyze(&tx, &prev_outs, &receiver).unwrap();
+        assert_eq!(analysis.privacy.score(), 3);
+        let analysis = analyze(&tx, &prev_outs, &Metadata::default()).unwrap();
+        assert!(!analysis.is_payjoin());
+        assert_eq!(analysis.privacy.score(), 2);
+
+        assert!(matches!(
+            UihPolicy::Abort.check(&Privacy::of(&[50_000, 40_000], &[80_000, 9_000], true)),
+            Err(Error::Protocol(ProtocolError::Detectable))
+        ));
+        assert!(UihPolicy::Warn
+            .check(&Privacy::of(&[50_000, 40_000], &[80_000, 9_000], true))
+            .is_ok());
+        assert!(UihPolicy::Abort
+            .check(&Privacy::of(&[50_000, 40_000], &[89_000], true))
+            .is_ok());
+
+        assert!(analyze(&tx, &prev_outs[..1], &receiver).is_err());
+        let (tx, prev_outs) = transaction(&[50_000], &[60_000]);
+        assert!(analyze(&tx, &prev_outs, &receiver).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_payjoin() {
+        let payjoin = Payjoin::new(3_000_000).await.unwrap();
+        let blockchain = payjoin.blockchain.clone();
+        let original = payjoin.fallback.clone();
+        let receiver_script = original.output[1].script_pubkey.clone();
+
+        let (result, _) = payjoin.run().await;
+        let result = result.unwrap();
+        let metadata = Metadata::from_original(&original, &result.transaction, receiver_script);
+        let prev_outs = prev_outs(&blockchain, &result.transaction).unwrap();
+        let analysis = analyze(&result.transaction, &prev_outs, &metadata).unwrap();
+
+        assert!(analysis.is_payjoin());
+        assert_eq!(metadata.receiver_inputs.len(), result.receiver_input_count);
+        assert_eq!(analysis.fee, result.fee_paid);
+        assert!(analysis.fee_rate >= FeeRate::default());
+        // The receiver got the payment on top of what it contributed
+        assert_eq!(analysis.net_spent(Owner::Receiver), -3_000_000);
+        assert_eq!(
+            analysis.net_spent(Owner::Sender),
+            3_000_000 + result.fee_paid as i64
+        );
+    }
+}
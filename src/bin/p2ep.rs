@@ -0,0 +1,583 @@
+//! Send and receive payments from the command line
+//!
+//! ```text
+//! p2ep --key <WIF> --utxo <txid:vout> receive --amount <sat> --address <address>
+//! p2ep --key <WIF> --utxo <txid:vout> send <bip21-uri>
+//! ```
+//!
+//! Keys are given either as WIF or as a single-key `wpkh()` or `tr()` descriptor, and the UTXOs
+//! spent or contributed with them must be listed with `--utxo`.
+
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::str::FromStr;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use rand::seq::SliceRandom;
+
+use serde_json::{json, Value};
+
+use tokio::runtime::Runtime;
+
+use tracing::info;
+
+use bitcoincore_rpc::{Auth, Client as RpcClient, RpcApi};
+
+use libp2ep::bip21::Bip21Uri;
+use libp2ep::bitcoin::consensus::{deserialize, serialize};
+use libp2ep::bitcoin::hashes::hex::{FromHex, ToHex};
+use libp2ep::bitcoin::secp256k1::Secp256k1;
+use libp2ep::bitcoin::*;
+use libp2ep::blockchain::MempoolEntry;
+use libp2ep::client::ClientConfig;
+use libp2ep::coin_selection::{base_transaction, BranchAndBound, WeightedUtxo};
+use libp2ep::decoy::AnyDecoy;
+use libp2ep::demo::{ElectrumBlockchain, SoftwareSigner};
+use libp2ep::server::ServerConfig;
+use libp2ep::transport::{Direct, Transport};
+use libp2ep::wallet::SimpleReceiverWallet;
+use libp2ep::{Broadcaster, ClientBuilder, FeeRate, PaymentResult, Server, TxSource};
+
+type CliResult<T> = Result<T, Box<dyn StdError>>;
+/// UTXOs given with `--utxo`, with the outputs they spend
+type Utxos = Vec<(OutPoint, TxOut)>;
+
+/// Backend selected with `--backend`
+enum Backend {
+    Demo(ElectrumBlockchain),
+    Rpc(RpcClient),
+}
+
+impl fmt::Debug for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backend::Demo(_) => write!(f, "Backend::Demo"),
+            Backend::Rpc(_) => write!(f, "Backend::Rpc"),
+        }
+    }
+}
+
+impl TxSource for Backend {
+    type Error = ();
+
+    fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+        match self {
+            Backend::Demo(demo) => demo.get_tx(txid),
+            Backend::Rpc(rpc) => {
+                let hex: String = rpc
+                    .call("getrawtransaction", &[json!(txid.to_hex())])
+                    .map_err(|_| ())?;
+
+                deserialize(&Vec::<u8>::from_hex(&hex).map_err(|_| ())?).map_err(|_| ())
+            }
+        }
+    }
+
+    fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error> {
+        match self {
+            Backend::Demo(demo) => demo.is_unspent(txout),
+            Backend::Rpc(rpc) => {
+                let result: Value = rpc
+                    .call(
+                        "gettxout",
+                        &[json!(txout.txid.to_hex()), json!(txout.vout), json!(true)],
+                    )
+                    .map_err(|_| ())?;
+
+                Ok(!result.is_null())
+            }
+        }
+    }
+
+    /// Decoys are taken from the node's wallet, the node has no index of the UTXO set
+    fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+        match self {
+            Backend::Demo(demo) => demo.get_random_utxo(),
+            Backend::Rpc(rpc) => {
+                let unspent: Vec<Value> = rpc.call("listunspent", &[]).map_err(|_| ())?;
+                let utxo = unspent.choose(&mut rand::thread_rng()).ok_or(())?;
+
+                Ok(OutPoint {
+                    txid: Txid::from_hex(utxo["txid"].as_str().ok_or(())?).map_err(|_| ())?,
+                    vout: utxo["vout"].as_u64().ok_or(())? as u32,
+                })
+            }
+        }
+    }
+
+    fn get_height(&self) -> Result<Option<u32>, Self::Error> {
+        match self {
+            Backend::Demo(demo) => demo.get_height(),
+            Backend::Rpc(rpc) => rpc
+                .call::<u32>("getblockcount", &[])
+                .map(Some)
+                .map_err(|_| ()),
+        }
+    }
+
+    fn in_mempool(&self, txid: &Txid) -> Result<Option<bool>, Self::Error> {
+        match self {
+            Backend::Demo(demo) => demo.in_mempool(txid),
+            // Fails for the transactions that aren't in the mempool
+            Backend::Rpc(rpc) => Ok(Some(
+                rpc.call::<Value>("getmempoolentry", &[json!(txid.to_hex())])
+                    .is_ok(),
+            )),
+        }
+    }
+
+    fn get_mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Self::Error> {
+        match self {
+            Backend::Demo(demo) => demo.get_mempool_entry(txid),
+            Backend::Rpc(rpc) => {
+                let entry: Value = match rpc.call("getmempoolentry", &[json!(txid.to_hex())]) {
+                    Ok(entry) => entry,
+                    Err(_) => return Ok(None),
+                };
+                let sats = |value: &Value| {
+                    Amount::from_btc(value.as_f64().ok_or(())?)
+                        .map(|amount| amount.as_sat())
+                        .map_err(|_| ())
+                };
+                let size = |value: &Value| value.as_u64().map(|size| size as usize).ok_or(());
+
+                Ok(Some(MempoolEntry {
+                    fee: sats(&entry["fees"]["base"])?,
+                    vsize: size(&entry["vsize"])?,
+                    ancestor_fee: sats(&entry["fees"]["ancestor"])?,
+                    ancestor_vsize: size(&entry["ancestorsize"])?,
+                    replaceable: entry["bip125-replaceable"].as_bool().ok_or(())?,
+                }))
+            }
+        }
+    }
+}
+
+impl Broadcaster for Backend {
+    type Error = ();
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        match self {
+            Backend::Demo(demo) => demo.broadcast(tx),
+            Backend::Rpc(rpc) => rpc
+                .call::<Value>("sendrawtransaction", &[json!(serialize(tx).to_hex())])
+                .map(|_| ())
+                .map_err(|_| ()),
+        }
+    }
+}
+
+fn backend(matches: &ArgMatches) -> CliResult<Backend> {
+    match matches.value_of("backend").unwrap() {
+        "demo" => Ok(Backend::Demo(ElectrumBlockchain::new())),
+        "rpc" => {
+            let url = matches
+                .value_of("rpc_url")
+                .ok_or("--rpc-url is required with the rpc backend")?;
+            let auth = match (
+                matches.value_of("rpc_cookie"),
+                matches.value_of("rpc_user"),
+                matches.value_of("rpc_pass"),
+            ) {
+                (Some(cookie), _, _) => Auth::CookieFile(cookie.into()),
+                (None, Some(user), Some(pass)) => Auth::UserPass(user.into(), pass.into()),
+                _ => return Err("--rpc-cookie or --rpc-user and --rpc-pass are required".into()),
+            };
+
+            Ok(Backend::Rpc(RpcClient::new(url.to_string(), auth)?))
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Single-key script types we can sign for
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KeyType {
+    P2wpkh,
+    P2tr,
+}
+
+/// Parse a WIF key, or a `wpkh()` or `tr()` descriptor containing one
+fn parse_key(key: &str) -> CliResult<(PrivateKey, KeyType)> {
+    let key = key.split('#').next().unwrap_or_default();
+    let (inner, key_type) = if let Some(inner) = key.strip_prefix("wpkh(") {
+        (inner.strip_suffix(')'), KeyType::P2wpkh)
+    } else if let Some(inner) = key.strip_prefix("tr(") {
+        (inner.strip_suffix(')'), KeyType::P2tr)
+    } else {
+        (Some(key), KeyType::P2wpkh)
+    };
+    let inner = inner.ok_or("Unbalanced parentheses in the descriptor")?;
+
+    Ok((PrivateKey::from_wif(inner)?, key_type))
+}
+
+fn parse_outpoint(s: &str) -> CliResult<OutPoint> {
+    let mut parts = s.splitn(2, ':');
+    let txid = Txid::from_hex(parts.next().unwrap_or_default())?;
+    let vout = parts.next().ok_or("UTXOs must be in the txid:vout form")?;
+
+    Ok(OutPoint {
+        txid,
+        vout: vout.parse()?,
+    })
+}
+
+/// Signer for `--key`, with the values of the `--utxo` outputs looked up with `blockchain`
+fn signer(
+    matches: &ArgMatches,
+    blockchain: &Backend,
+) -> CliResult<(SoftwareSigner, Script, Utxos)> {
+    let (key, key_type) = parse_key(matches.value_of("key").unwrap())?;
+
+    let mut utxos = Vec::new();
+    for utxo in matches.values_of("utxo").unwrap() {
+        let outpoint = parse_outpoint(utxo)?;
+        let txout = blockchain
+            .get_tx(&outpoint.txid)
+            .map_err(|_| format!("Unable to find the transaction of {}", outpoint))?
+            .output
+            .get(outpoint.vout as usize)
+            .cloned()
+            .ok_or_else(|| format!("{} doesn't exist", outpoint))?;
+        utxos.push((outpoint, txout));
+    }
+
+    let metadata = utxos
+        .iter()
+        .map(|(outpoint, txout)| (*outpoint, (txout.value, txout.script_pubkey.clone())))
+        .collect::<HashMap<_, _>>();
+    let signer = SoftwareSigner::new(key, metadata);
+    let script = match key_type {
+        KeyType::P2wpkh => {
+            Address::p2wpkh(&key.public_key(&Secp256k1::new()), key.network).script_pubkey()
+        }
+        KeyType::P2tr => signer.p2tr_script(),
+    };
+
+    if let Some((outpoint, _)) = utxos
+        .iter()
+        .find(|(_, txout)| txout.script_pubkey != script)
+    {
+        return Err(format!("{} isn't spendable with the key", outpoint).into());
+    }
+
+    Ok((signer, script, utxos))
+}
+
+fn network(matches: &ArgMatches) -> Network {
+    Network::from_str(matches.value_of("network").unwrap()).unwrap()
+}
+
+async fn send(matches: &ArgMatches<'_>, args: &ArgMatches<'_>) -> CliResult<PaymentResult> {
+    let blockchain = backend(matches)?;
+    let (signer, change_script, utxos) = signer(matches, &blockchain)?;
+    let uri = args.value_of("uri").unwrap();
+    let Bip21Uri {
+        address, amount, ..
+    } = Bip21Uri::from_str(uri)?;
+    let amount = match (amount, args.value_of("amount")) {
+        (Some(amount), _) => amount,
+        // Donation URIs let the sender pick the amount
+        (None, Some(amount)) => amount.parse()?,
+        (None, None) => return Err("The URI has no amount, use --amount".into()),
+    };
+    if address.network != network(matches) {
+        return Err(format!("{} is not a {} address", address, network(matches)).into());
+    }
+    let fee_rate = FeeRate::from_sat_per_vb(args.value_of("fee_rate").unwrap().parse()?);
+
+    let utxos = utxos
+        .into_iter()
+        .map(|(outpoint, txout)| WeightedUtxo::new(outpoint, txout))
+        .collect::<Vec<_>>();
+    let payment = TxOut {
+        script_pubkey: address.script_pubkey(),
+        value: amount,
+    };
+    let base_transaction =
+        base_transaction(&BranchAndBound, &utxos, payment, change_script, fee_rate)
+            .map_err(|_| "The UTXOs are not enough to pay the amount and the fees")?;
+
+    let transport: Box<dyn Transport> = if args.is_present("direct") {
+        Box::new(Direct)
+    } else {
+        tor_transport(args.value_of("socks_port").unwrap().parse()?)?
+    };
+    let mut client = ClientBuilder::new(blockchain, signer, base_transaction)
+        .config(ClientConfig {
+            fee_rate,
+            change_output_index: Some(0),
+            rbf: args.is_present("rbf"),
+            anti_fee_sniping: args.is_present("anti_fee_sniping"),
+            anyonecanpay_proof: args.is_present("anyonecanpay_proof"),
+            network: network(matches),
+            dry_run: args.is_present("dry_run"),
+            ..Default::default()
+        })
+        .connect_uri(&*transport, uri)
+        .await?;
+
+    Ok(client.start().await?)
+}
+
+#[cfg(feature = "libtor")]
+fn tor_transport(socks_port: u16) -> CliResult<Box<dyn Transport>> {
+    Ok(Box::new(libp2ep::transport::LibTor::new(socks_port)))
+}
+
+#[cfg(not(feature = "libtor"))]
+fn tor_transport(_socks_port: u16) -> CliResult<Box<dyn Transport>> {
+    Err("Built without Tor support, use --direct".into())
+}
+
+async fn receive(matches: &ArgMatches<'_>, args: &ArgMatches<'_>) -> CliResult<()> {
+    let blockchain = backend(matches)?;
+    let demo = matches!(blockchain, Backend::Demo(_));
+    let (signer, _, utxos) = signer(matches, &blockchain)?;
+    let address = Address::from_str(args.value_of("address").unwrap())?;
+    if address.network != network(matches) {
+        return Err(format!("{} is not a {} address", address, network(matches)).into());
+    }
+    let amount = args.value_of("amount").unwrap().parse()?;
+
+    let wallet = SimpleReceiverWallet::new(utxos, vec![address.script_pubkey()]);
+    let mut server = Server::new(
+        args.value_of("bind").unwrap(),
+        blockchain,
+        signer,
+        wallet,
+        amount,
+    )
+    .await?;
+    if args.is_present("direct") {
+        server.set_transport(Direct);
+    } else if args.is_present("hidden_service_dir") {
+        #[cfg(feature = "libtor")]
+        {
+            let mut tor = libp2ep::transport::LibTor::default();
+            tor.set_hidden_service_dir(args.value_of("hidden_service_dir").unwrap());
+            server.set_transport(tor);
+        }
+        #[cfg(not(feature = "libtor"))]
+        return Err("Built without Tor support, use --direct".into());
+    }
+    server.set_config(ServerConfig {
+        onion_port: args.value_of("onion_port").unwrap().parse()?,
+        require_token: args.is_present("require_token"),
+        donations: args.is_present("donations"),
+        network: network(matches),
+        dry_run: args.is_present("dry_run"),
+        invoice_expiry: match args.value_of("expiry") {
+            Some(expiry) => Some(std::time::Duration::from_secs(expiry.parse()?)),
+            None => None,
+        },
+        ..Default::default()
+    });
+    // The demo blockchain only knows a single coinbase output, which no filter would accept
+    if demo {
+        server.set_decoy_selector(AnyDecoy);
+    }
+
+    let uri = server.setup().await?;
+    server.wait_ready().await?;
+    println!("{}", uri);
+
+    server.mainloop().await?;
+    for transaction in server.dry_runs() {
+        println!("{}", serialize(transaction).to_hex());
+    }
+    if args.is_present("donations") {
+        info!("Received a donation");
+    } else {
+        info!("Received {} sat", amount);
+    }
+
+    Ok(())
+}
+
+fn main() {
+    env_logger::init();
+
+    let direct = Arg::with_name("direct")
+        .long("direct")
+        .help("Use plain TCP instead of Tor");
+    let dry_run = Arg::with_name("dry_run")
+        .long("dry-run")
+        .help("Negotiate without broadcasting, printing the final transaction instead");
+    let matches = App::new("p2ep")
+        .about("Send and receive P2EP payments")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .takes_value(true)
+                .required(true)
+                .help("WIF private key, or a wpkh() or tr() descriptor with a single WIF key"),
+        )
+        .arg(
+            Arg::with_name("utxo")
+                .long("utxo")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(true)
+                .help("UTXO spent (send) or contributed (receive) with the key, as txid:vout"),
+        )
+        .arg(
+            Arg::with_name("network")
+                .long("network")
+                .takes_value(true)
+                .possible_values(&["bitcoin", "testnet", "regtest"])
+                .default_value("bitcoin"),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .takes_value(true)
+                .possible_values(&["rpc", "demo"])
+                .default_value("rpc")
+                .help("A bitcoind node with -txindex, or the static demo transactions"),
+        )
+        .arg(
+            Arg::with_name("unsafe_logging")
+                .long("unsafe-logging")
+                .help("Log keys, signatures and transactions, only for debugging"),
+        )
+        .arg(Arg::with_name("rpc_url").long("rpc-url").takes_value(true))
+        .arg(
+            Arg::with_name("rpc_cookie")
+                .long("rpc-cookie")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rpc_user")
+                .long("rpc-user")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rpc_pass")
+                .long("rpc-pass")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("send")
+                .about("Pay a URI printed by `p2ep receive`")
+                .arg(Arg::with_name("uri").required(true))
+                .arg(
+                    Arg::with_name("amount")
+                        .long("amount")
+                        .takes_value(true)
+                        .help("Amount in satoshis, for URIs without one"),
+                )
+                .arg(
+                    Arg::with_name("fee_rate")
+                        .long("fee-rate")
+                        .takes_value(true)
+                        .default_value("1")
+                        .help("Fee rate in sat/vB"),
+                )
+                .arg(
+                    Arg::with_name("rbf")
+                        .long("rbf")
+                        .help("Signal RBF in the payjoin transaction"),
+                )
+                .arg(
+                    Arg::with_name("anti_fee_sniping")
+                        .long("anti-fee-sniping")
+                        .help("Set the locktime of the payjoin transaction to the current height"),
+                )
+                .arg(
+                    Arg::with_name("anyonecanpay_proof")
+                        .long("anyonecanpay-proof")
+                        .help("Sign the proof with SIGHASH_ALL | SIGHASH_ANYONECANPAY"),
+                )
+                .arg(
+                    Arg::with_name("socks_port")
+                        .long("socks-port")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("SOCKS port of the Tor instance, 0 for a free one"),
+                )
+                .arg(direct.clone())
+                .arg(dry_run.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("receive")
+                .about("Wait for a single payment")
+                .arg(
+                    Arg::with_name("amount")
+                        .long("amount")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Amount in satoshis"),
+                )
+                .arg(
+                    Arg::with_name("address")
+                        .long("address")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("bind")
+                        .long("bind")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:9000"),
+                )
+                .arg(
+                    Arg::with_name("onion_port")
+                        .long("onion-port")
+                        .takes_value(true)
+                        .default_value("9000")
+                        .help("Public port of the onion service, 0 for a random one"),
+                )
+                .arg(
+                    Arg::with_name("donations")
+                        .long("donations")
+                        .help("Accept any amount of at least --amount"),
+                )
+                .arg(
+                    Arg::with_name("require_token")
+                        .long("require-token")
+                        .help("Only accept the sender presenting the token in the printed URI"),
+                )
+                .arg(
+                    Arg::with_name("hidden_service_dir")
+                        .long("hidden-service-dir")
+                        .takes_value(true)
+                        .help("Keep the onion service keys here, to reuse the same address"),
+                )
+                .arg(
+                    Arg::with_name("expiry")
+                        .long("expiry")
+                        .takes_value(true)
+                        .help("Stop accepting the payment after this many seconds"),
+                )
+                .arg(direct)
+                .arg(dry_run),
+        )
+        .get_matches();
+    libp2ep::redact::set_unsafe_logging(matches.is_present("unsafe_logging"));
+
+    let mut rt = Runtime::new().unwrap();
+    let result = rt.block_on(async {
+        match matches.subcommand() {
+            ("send", Some(args)) => send(&matches, args).await.map(|result| {
+                println!("{}", result.txid);
+                if args.is_present("dry_run") {
+                    println!("{}", serialize(&result.transaction).to_hex());
+                }
+                info!("Paid {} sat in fees", result.fee_paid);
+            }),
+            ("receive", Some(args)) => receive(&matches, args).await,
+            _ => unreachable!(),
+        }
+    });
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
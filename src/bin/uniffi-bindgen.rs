@@ -0,0 +1,5 @@
+//! Generate the Kotlin and Swift sources of the bindings in `ffi`
+
+fn main() {
+    uniffi::uniffi_bindgen_main()
+}
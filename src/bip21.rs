@@ -0,0 +1,367 @@
+//! BIP21 payment URIs
+//!
+//! The receiver advertises its server with an `endpoint` parameter containing its `host:port`.
+//! BIP78 receivers use a `pj` parameter with the URL of their endpoint instead, which is kept as it
+//! is in `endpoint`. Other schemes, like the `ws://` of a WebSocket server, stay in `endpoint`, and
+//! so does the `<key>@` prefix of a Noise server.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::util::address::Error as AddressError;
+use bitcoin::util::amount::{Denomination, ParseAmountError};
+use bitcoin::{Address, Amount, Network};
+
+const SCHEME: &str = "bitcoin:";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bip21Uri {
+    pub address: Address,
+    /// Requested amount, in satoshis
+    pub amount: Option<u64>,
+    pub endpoint: Option<String>,
+    /// One-time access token, presented to the server in the VERSION message
+    pub token: Option<String>,
+    /// Payment picked among the ones the server is waiting for, also sent in the VERSION message
+    pub invoice: Option<String>,
+    /// Hex `Offer` of the receiver, to pay without contacting the endpoint (see `offer`)
+    pub offer: Option<String>,
+    /// Unix time, in seconds, after which the receiver doesn't accept the payment anymore
+    pub exp: Option<u64>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Bip21Error {
+    InvalidScheme,
+    InvalidAddress(AddressError),
+    InvalidAmount(ParseAmountError),
+    /// The `exp` parameter is not a Unix time
+    InvalidExpiry,
+    /// Malformed percent-encoding, or encoded bytes that are not UTF-8
+    InvalidEncoding,
+    /// A `req-` parameter we don't understand, which BIP21 requires to reject
+    UnknownRequiredParameter(String),
+    MissingEndpoint,
+    /// The URI has no amount, and none was given
+    MissingAmount,
+    /// The transaction doesn't pay the address and amount of the URI
+    PaymentMismatch,
+    /// The address is for another network than the one we are on
+    WrongNetwork(Network),
+    /// Our script has no address form to put in the URI
+    UnsupportedScript,
+}
+
+impl From<AddressError> for Bip21Error {
+    fn from(err: AddressError) -> Self {
+        Bip21Error::InvalidAddress(err)
+    }
+}
+
+impl From<ParseAmountError> for Bip21Error {
+    fn from(err: ParseAmountError) -> Self {
+        Bip21Error::InvalidAmount(err)
+    }
+}
+
+impl fmt::Display for Bip21Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bip21Error::InvalidScheme => write!(f, "the URI doesn't start with `{}`", SCHEME),
+            Bip21Error::InvalidAddress(_) => write!(f, "invalid address"),
+            Bip21Error::InvalidAmount(_) => write!(f, "invalid amount"),
+            Bip21Error::InvalidExpiry => write!(f, "invalid expiry"),
+            Bip21Error::InvalidEncoding => write!(f, "invalid percent-encoding"),
+            Bip21Error::UnknownRequiredParameter(name) => {
+                write!(f, "unsupported required parameter `{}`", name)
+            }
+            Bip21Error::MissingEndpoint => write!(f, "the URI has no endpoint"),
+            Bip21Error::MissingAmount => write!(f, "the URI has no amount"),
+            Bip21Error::PaymentMismatch => {
+                write!(
+                    f,
+                    "the transaction doesn't pay the address and amount of the URI"
+                )
+            }
+            Bip21Error::WrongNetwork(network) => write!(f, "the address is for {}", network),
+            Bip21Error::UnsupportedScript => write!(f, "the script has no address form"),
+        }
+    }
+}
+
+impl std::error::Error for Bip21Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Bip21Error::InvalidAddress(e) => Some(e),
+            Bip21Error::InvalidAmount(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Bip21Uri {
+    pub fn new(address: Address) -> Self {
+        Bip21Uri {
+            address,
+            amount: None,
+            endpoint: None,
+            token: None,
+            invoice: None,
+            offer: None,
+            exp: None,
+            label: None,
+            message: None,
+        }
+    }
+
+    /// `host:port` of the endpoint, without the scheme and path of a `pj` URL
+    pub fn endpoint_addr(&self) -> Option<&str> {
+        let endpoint = self.endpoint.as_deref()?;
+        let endpoint = match endpoint.find("://") {
+            Some(pos) => &endpoint[pos + 3..],
+            None => endpoint,
+        };
+
+        endpoint.split('/').next()
+    }
+
+    /// Time after which the receiver doesn't accept the payment anymore. An `exp` too far in the
+    /// future to be represented never expires
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.exp
+            .and_then(|exp| UNIX_EPOCH.checked_add(Duration::from_secs(exp)))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at()
+            .is_some_and(|expires_at| expires_at <= SystemTime::now())
+    }
+
+    /// Make sure the address of the URI is meant for `network`
+    pub fn check_network(&self, network: Network) -> Result<(), Bip21Error> {
+        if self.address.network != network {
+            return Err(Bip21Error::WrongNetwork(self.address.network));
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Bip21Uri {
+    type Err = Bip21Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The scheme is case-insensitive, so that URIs can be encoded in uppercase QR codes
+        match s.get(..SCHEME.len()) {
+            Some(scheme) if scheme.eq_ignore_ascii_case(SCHEME) => {}
+            _ => return Err(Bip21Error::InvalidScheme),
+        }
+
+        let mut parts = s[SCHEME.len()..].splitn(2, '?');
+        let mut uri = Bip21Uri::new(Address::from_str(parts.next().unwrap_or_default())?);
+
+        for param in parts.next().unwrap_or_default().split('&') {
+            let mut param = param.splitn(2, '=');
+            let name = param.next().unwrap_or_default();
+            let value = percent_decode(param.next().unwrap_or_default())?;
+
+            match name {
+                "" => {}
+                "amount" => {
+                    uri.amount = Some(Amount::from_str_in(&value, Denomination::Bitcoin)?.as_sat())
+                }
+                "label" => uri.label = Some(value),
+                "message" => uri.message = Some(value),
+                "endpoint" | "pj" => uri.endpoint = Some(value),
+                "token" => uri.token = Some(value),
+                "invoice" => uri.invoice = Some(value),
+                "offer" => uri.offer = Some(value),
+                "exp" => {
+                    let exp = value.parse().map_err(|_| Bip21Error::InvalidExpiry)?;
+                    UNIX_EPOCH
+                        .checked_add(Duration::from_secs(exp))
+                        .ok_or(Bip21Error::InvalidExpiry)?;
+                    uri.exp = Some(exp);
+                }
+                name if name.starts_with("req-") => {
+                    return Err(Bip21Error::UnknownRequiredParameter(name.to_string()))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(uri)
+    }
+}
+
+impl fmt::Display for Bip21Uri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", SCHEME, self.address)?;
+
+        let mut params = Vec::new();
+        if let Some(amount) = self.amount {
+            let amount = Amount::from_sat(amount).to_string_in(Denomination::Bitcoin);
+            // Always formatted with 8 decimals
+            let amount = amount.trim_end_matches('0').trim_end_matches('.');
+            params.push(("amount", amount.to_string()));
+        }
+        if let Some(label) = &self.label {
+            params.push(("label", percent_encode(label)));
+        }
+        if let Some(message) = &self.message {
+            params.push(("message", percent_encode(message)));
+        }
+        if let Some(endpoint) = &self.endpoint {
+            let name = if endpoint.starts_with("http://") || endpoint.starts_with("https://") {
+                "pj"
+            } else {
+                "endpoint"
+            };
+            params.push((name, percent_encode(endpoint)));
+        }
+        if let Some(token) = &self.token {
+            params.push(("token", percent_encode(token)));
+        }
+        if let Some(invoice) = &self.invoice {
+            params.push(("invoice", percent_encode(invoice)));
+        }
+        if let Some(offer) = &self.offer {
+            params.push(("offer", percent_encode(offer)));
+        }
+        if let Some(exp) = self.exp {
+            params.push(("exp", exp.to_string()));
+        }
+
+        for (i, (name, value)) in params.iter().enumerate() {
+            let separator = if i == 0 { '?' } else { '&' };
+            write!(f, "{}{}={}", separator, name, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Escape everything but the characters allowed unescaped in a query value
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Result<String, Bip21Error> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [
+                iter.next().ok_or(Bip21Error::InvalidEncoding)?,
+                iter.next().ok_or(Bip21Error::InvalidEncoding)?,
+            ];
+            let hex = std::str::from_utf8(&hex).map_err(|_| Bip21Error::InvalidEncoding)?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| Bip21Error::InvalidEncoding)?);
+        } else {
+            bytes.push(b);
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| Bip21Error::InvalidEncoding)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ADDRESS: &str = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+
+    #[test]
+    fn test_bip21_roundtrip() {
+        let uri = Bip21Uri {
+            address: Address::from_str(ADDRESS).unwrap(),
+            amount: Some(3_000_000),
+            endpoint: Some("example.onion:9000".to_string()),
+            token: Some("00ff".to_string()),
+            invoice: Some("01".to_string()),
+            offer: Some("70736274ff".to_string()),
+            exp: Some(1_700_000_000),
+            label: Some("Luke Jr".to_string()),
+            message: Some("50% off".to_string()),
+        };
+        let s = uri.to_string();
+
+        assert_eq!(
+            s,
+            format!(
+                "bitcoin:{}?amount=0.03&label=Luke%20Jr&message=50%25%20off&endpoint=example.onion:9000&token=00ff&invoice=01&offer=70736274ff&exp=1700000000",
+                ADDRESS
+            )
+        );
+        assert_eq!(Bip21Uri::from_str(&s).unwrap(), uri);
+
+        let mut uri = Bip21Uri::new(Address::from_str(ADDRESS).unwrap());
+        uri.endpoint = Some("ws://example.onion:9000".to_string());
+        assert_eq!(
+            uri.to_string(),
+            format!("bitcoin:{}?endpoint=ws://example.onion:9000", ADDRESS)
+        );
+        assert_eq!(uri.endpoint_addr(), Some("example.onion:9000"));
+    }
+
+    #[test]
+    fn test_bip21_parse() {
+        let uri = Bip21Uri::from_str(&format!(
+            "BITCOIN:{}?amount=1.5&pj=https://example.com/payjoin&foo=bar",
+            ADDRESS
+        ))
+        .unwrap();
+        assert_eq!(uri.amount, Some(150_000_000));
+        assert_eq!(uri.endpoint.as_deref(), Some("https://example.com/payjoin"));
+        assert_eq!(uri.endpoint_addr(), Some("example.com"));
+
+        let uri = Bip21Uri::from_str(&format!("bitcoin:{}", ADDRESS)).unwrap();
+        assert_eq!(uri, Bip21Uri::new(Address::from_str(ADDRESS).unwrap()));
+        assert!(!uri.is_expired());
+        let uri = Bip21Uri::from_str(&format!("bitcoin:{}?exp=1", ADDRESS)).unwrap();
+        assert!(uri.is_expired());
+
+        assert_eq!(
+            Bip21Uri::from_str(&format!("bitcoin:{}?req-foo=1", ADDRESS)),
+            Err(Bip21Error::UnknownRequiredParameter("req-foo".to_string()))
+        );
+        assert_eq!(
+            Bip21Uri::from_str(&format!("bitcoin:{}?label=%4", ADDRESS)),
+            Err(Bip21Error::InvalidEncoding)
+        );
+        assert_eq!(
+            Bip21Uri::from_str(&format!("bitcoin:{}?exp=soon", ADDRESS)),
+            Err(Bip21Error::InvalidExpiry)
+        );
+        assert_eq!(
+            Bip21Uri::from_str(&format!("bitcoin:{}?exp={}", ADDRESS, u64::MAX)),
+            Err(Bip21Error::InvalidExpiry)
+        );
+        let mut uri = Bip21Uri::new(Address::from_str(ADDRESS).unwrap());
+        uri.exp = Some(u64::MAX);
+        assert_eq!(uri.expires_at(), None);
+        assert!(!uri.is_expired());
+        assert_eq!(Bip21Uri::from_str(ADDRESS), Err(Bip21Error::InvalidScheme));
+    }
+
+    #[test]
+    fn test_bip21_network() {
+        let uri = Bip21Uri::new(Address::from_str(ADDRESS).unwrap());
+
+        assert_eq!(uri.check_network(Network::Regtest), Ok(()));
+        assert_eq!(
+            uri.check_network(Network::Bitcoin),
+            Err(Bip21Error::WrongNetwork(Network::Regtest))
+        );
+    }
+}
@@ -1,10 +1,1255 @@
-use bitcoin::{OutPoint, Transaction, Txid};
+use std::collections::{HashMap, VecDeque};
+#[cfg(not(target_arch = "wasm32"))]
+use std::future::Future;
+use std::hash::Hash;
+#[cfg(not(target_arch = "wasm32"))]
+use std::pin::Pin;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub trait Blockchain {
+#[cfg(not(target_arch = "wasm32"))]
+use async_trait::async_trait;
+
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::runtime::Handle;
+
+use tracing::warn;
+#[cfg(not(target_arch = "wasm32"))]
+use tracing::{debug_span, trace_span};
+
+use bitcoin::{OutPoint, Script, Transaction, Txid};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::run_blocking;
+use crate::Error;
+
+/// What the mempool knows about an unconfirmed transaction, like `bitcoind`'s `getmempoolentry`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MempoolEntry {
+    /// Fees of the transaction, in satoshis
+    pub fee: u64,
+    pub vsize: usize,
+    /// Fees and size of the transaction along with all its unconfirmed ancestors
+    pub ancestor_fee: u64,
+    pub ancestor_vsize: usize,
+    /// Whether the transaction can be replaced, because it or one of its unconfirmed ancestors
+    /// signals RBF
+    pub replaceable: bool,
+}
+
+/// Queries about transactions and UTXOs
+pub trait TxSource {
     type Error;
 
     fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error>;
     fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error>;
     fn get_random_utxo(&self) -> Result<OutPoint, Self::Error>;
+
+    /// Several transactions, in the same order as `txids`. Backends that can batch requests, like
+    /// with Electrum's `blockchain.transaction.get`, should override it: the default looks them up
+    /// one by one
+    fn get_txs(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Self::Error> {
+        txids.iter().map(|txid| self.get_tx(txid)).collect()
+    }
+    /// Confirmations of a transaction, or `None` if the backend doesn't track them
+    fn get_confirmations(&self, _txid: &Txid) -> Result<Option<u32>, Self::Error> {
+        Ok(None)
+    }
+    /// Height of the chain tip, or `None` if the backend doesn't know it
+    fn get_height(&self) -> Result<Option<u32>, Self::Error> {
+        Ok(None)
+    }
+    /// Whether a transaction is waiting in the mempool, or `None` if the backend doesn't track it
+    fn in_mempool(&self, _txid: &Txid) -> Result<Option<bool>, Self::Error> {
+        Ok(None)
+    }
+    /// Transaction spending `txout`, or `None` if it's unspent or the backend can't look it up
+    fn get_spending_tx(&self, _txout: &OutPoint) -> Result<Option<Transaction>, Self::Error> {
+        Ok(None)
+    }
+    /// Mempool entry of an unconfirmed transaction, or `None` if it's not in the mempool or the
+    /// backend doesn't track it
+    fn get_mempool_entry(&self, _txid: &Txid) -> Result<Option<MempoolEntry>, Self::Error> {
+        Ok(None)
+    }
+    /// Block until a transaction involving `script_pubkey` appears or confirms, like with
+    /// Electrum's `blockchain.scripthash.subscribe`, or until `timeout` elapses. Returns whether
+    /// there was an update
+    ///
+    /// The default doesn't know about updates and just sleeps, so that callers poll instead.
+    fn wait_for_update(
+        &self,
+        _script_pubkey: &Script,
+        timeout: Duration,
+    ) -> Result<bool, Self::Error> {
+        thread::sleep(timeout);
+        Ok(false)
+    }
+}
+
+/// Relays transactions to the network
+pub trait Broadcaster {
+    type Error;
+
     fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error>;
 }
+
+/// Everything `Client` and `Server` need from the chain, implemented for any `TxSource` that is
+/// also a `Broadcaster` with the same error. To query through one backend, like a private node,
+/// and broadcast through another, like a push-tx API or a random peer over Tor, pair them in a
+/// `SplitBlockchain`
+pub trait Blockchain: TxSource + Broadcaster<Error = <Self as TxSource>::Error> {}
+
+impl<T> Blockchain for T where T: TxSource + Broadcaster<Error = <T as TxSource>::Error> + ?Sized {}
+
+impl<Q: TxSource + ?Sized> TxSource for &Q {
+    type Error = Q::Error;
+
+    fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+        (**self).get_tx(txid)
+    }
+    fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error> {
+        (**self).is_unspent(txout)
+    }
+    fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+        (**self).get_random_utxo()
+    }
+    fn get_txs(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Self::Error> {
+        (**self).get_txs(txids)
+    }
+    fn get_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Self::Error> {
+        (**self).get_confirmations(txid)
+    }
+    fn get_height(&self) -> Result<Option<u32>, Self::Error> {
+        (**self).get_height()
+    }
+    fn in_mempool(&self, txid: &Txid) -> Result<Option<bool>, Self::Error> {
+        (**self).in_mempool(txid)
+    }
+    fn get_spending_tx(&self, txout: &OutPoint) -> Result<Option<Transaction>, Self::Error> {
+        (**self).get_spending_tx(txout)
+    }
+    fn get_mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Self::Error> {
+        (**self).get_mempool_entry(txid)
+    }
+    fn wait_for_update(
+        &self,
+        script_pubkey: &Script,
+        timeout: Duration,
+    ) -> Result<bool, Self::Error> {
+        (**self).wait_for_update(script_pubkey, timeout)
+    }
+}
+
+impl<Bc: Broadcaster + ?Sized> Broadcaster for &Bc {
+    type Error = Bc::Error;
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        (**self).broadcast(tx)
+    }
+}
+
+/// `Blockchain` answering queries from `source` and broadcasting through `broadcaster`, so that
+/// the backend we query doesn't learn which transactions are ours
+#[derive(Debug)]
+pub struct SplitBlockchain<Q, Bc> {
+    source: Q,
+    broadcaster: Bc,
+}
+
+impl<Q, Bc> SplitBlockchain<Q, Bc>
+where
+    Q: TxSource,
+    Bc: Broadcaster,
+{
+    pub fn new(source: Q, broadcaster: Bc) -> Self {
+        SplitBlockchain {
+            source,
+            broadcaster,
+        }
+    }
+
+    pub fn into_inner(self) -> (Q, Bc) {
+        (self.source, self.broadcaster)
+    }
+}
+
+impl<Q, Bc> TxSource for SplitBlockchain<Q, Bc>
+where
+    Q: TxSource,
+    Error: From<<Q as TxSource>::Error>,
+{
+    type Error = Error;
+
+    fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+        Ok(self.source.get_tx(txid)?)
+    }
+    fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error> {
+        Ok(self.source.is_unspent(txout)?)
+    }
+    fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+        Ok(self.source.get_random_utxo()?)
+    }
+    fn get_txs(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Self::Error> {
+        Ok(self.source.get_txs(txids)?)
+    }
+    fn get_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Self::Error> {
+        Ok(self.source.get_confirmations(txid)?)
+    }
+    fn get_height(&self) -> Result<Option<u32>, Self::Error> {
+        Ok(self.source.get_height()?)
+    }
+    fn in_mempool(&self, txid: &Txid) -> Result<Option<bool>, Self::Error> {
+        Ok(self.source.in_mempool(txid)?)
+    }
+    fn get_spending_tx(&self, txout: &OutPoint) -> Result<Option<Transaction>, Self::Error> {
+        Ok(self.source.get_spending_tx(txout)?)
+    }
+    fn get_mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Self::Error> {
+        Ok(self.source.get_mempool_entry(txid)?)
+    }
+    fn wait_for_update(
+        &self,
+        script_pubkey: &Script,
+        timeout: Duration,
+    ) -> Result<bool, Self::Error> {
+        Ok(self.source.wait_for_update(script_pubkey, timeout)?)
+    }
+}
+
+impl<Q, Bc> Broadcaster for SplitBlockchain<Q, Bc>
+where
+    Bc: Broadcaster,
+    Error: From<<Bc as Broadcaster>::Error>,
+{
+    type Error = Error;
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        Ok(self.broadcaster.broadcast(tx)?)
+    }
+}
+
+/// Runs every call to the wrapped `Blockchain` in its own tracing span
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+pub(crate) struct Instrumented<B>(pub B);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<B: TxSource> TxSource for Instrumented<B> {
+    type Error = B::Error;
+
+    fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+        let _span = trace_span!("get_tx", %txid).entered();
+        self.0.get_tx(txid)
+    }
+    fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error> {
+        let _span = trace_span!("is_unspent", %txout).entered();
+        self.0.is_unspent(txout)
+    }
+    fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+        let _span = trace_span!("get_random_utxo").entered();
+        self.0.get_random_utxo()
+    }
+    fn get_txs(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Self::Error> {
+        let _span = trace_span!("get_txs", count = txids.len()).entered();
+        self.0.get_txs(txids)
+    }
+    fn get_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Self::Error> {
+        let _span = trace_span!("get_confirmations", %txid).entered();
+        self.0.get_confirmations(txid)
+    }
+    fn get_height(&self) -> Result<Option<u32>, Self::Error> {
+        let _span = trace_span!("get_height").entered();
+        self.0.get_height()
+    }
+    fn in_mempool(&self, txid: &Txid) -> Result<Option<bool>, Self::Error> {
+        let _span = trace_span!("in_mempool", %txid).entered();
+        self.0.in_mempool(txid)
+    }
+    fn get_spending_tx(&self, txout: &OutPoint) -> Result<Option<Transaction>, Self::Error> {
+        let _span = trace_span!("get_spending_tx", %txout).entered();
+        self.0.get_spending_tx(txout)
+    }
+    fn get_mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Self::Error> {
+        let _span = trace_span!("get_mempool_entry", %txid).entered();
+        self.0.get_mempool_entry(txid)
+    }
+    fn wait_for_update(
+        &self,
+        script_pubkey: &Script,
+        timeout: Duration,
+    ) -> Result<bool, Self::Error> {
+        let _span = trace_span!("wait_for_update", %script_pubkey).entered();
+        self.0.wait_for_update(script_pubkey, timeout)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<B: Broadcaster> Broadcaster for Instrumented<B> {
+    type Error = B::Error;
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        let _span = debug_span!("broadcast", txid = %tx.txid()).entered();
+        self.0.broadcast(tx)
+    }
+}
+
+/// Longest wait for an update before checking the confirmations again, since new blocks only
+/// update a script when they confirm one of its transactions
+#[cfg(not(target_arch = "wasm32"))]
+const CONFIRMATION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Wait for `txid` to get `confirmations`. Returns `false` right away if the backend doesn't track
+/// confirmations
+///
+/// Updates are followed on the script of its first output: any script involved in a transaction
+/// is updated when it confirms.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn wait_for_confirmations<B>(
+    blockchain: Arc<B>,
+    txid: Txid,
+    confirmations: u32,
+) -> Result<bool, Error>
+where
+    B: Blockchain + Send + Sync + 'static,
+    Error: From<<B as TxSource>::Error>,
+{
+    loop {
+        let blockchain = Arc::clone(&blockchain);
+        let confirmed = run_blocking(move || -> Result<Option<bool>, Error> {
+            match blockchain.get_confirmations(&txid)? {
+                Some(current) if current >= confirmations => Ok(Some(true)),
+                Some(_) => {
+                    let tx = blockchain.get_tx(&txid)?;
+                    let script_pubkey = &tx.output.first().ok_or(Error::Other)?.script_pubkey;
+                    blockchain.wait_for_update(script_pubkey, CONFIRMATION_INTERVAL)?;
+                    Ok(None)
+                }
+                None => Ok(Some(false)),
+            }
+        })
+        .await??;
+
+        if let Some(confirmed) = confirmed {
+            return Ok(confirmed);
+        }
+    }
+}
+
+/// Wait for the next block, following updates on `script_pubkey`. Returns `false` right away if
+/// the backend doesn't know the height of the chain
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn wait_for_block<B>(
+    blockchain: Arc<B>,
+    script_pubkey: Script,
+) -> Result<bool, Error>
+where
+    B: Blockchain + Send + Sync + 'static,
+    Error: From<<B as TxSource>::Error>,
+{
+    let start = {
+        let blockchain = Arc::clone(&blockchain);
+        match run_blocking(move || -> Result<_, Error> { Ok(blockchain.get_height()?) }).await?? {
+            Some(height) => height,
+            None => return Ok(false),
+        }
+    };
+
+    loop {
+        let blockchain = Arc::clone(&blockchain);
+        let script_pubkey = script_pubkey.clone();
+        let height = run_blocking(move || -> Result<Option<u32>, Error> {
+            blockchain.wait_for_update(&script_pubkey, CONFIRMATION_INTERVAL)?;
+            Ok(blockchain.get_height()?)
+        })
+        .await??;
+
+        match height {
+            Some(height) if height > start => return Ok(true),
+            Some(_) => {}
+            None => return Ok(false),
+        }
+    }
+}
+
+/// Whether `txid` has reached the mempool or a block. Backends that track neither are asked for
+/// the transaction itself
+pub(crate) fn is_propagated<B: Blockchain>(
+    blockchain: &B,
+    txid: &Txid,
+) -> Result<bool, <B as TxSource>::Error> {
+    if blockchain.in_mempool(txid)? == Some(true) {
+        return Ok(true);
+    }
+
+    match blockchain.get_confirmations(txid)? {
+        Some(confirmations) => Ok(confirmations > 0),
+        None => Ok(blockchain.get_tx(txid).is_ok()),
+    }
+}
+
+/// Attempts of `broadcast_with_retry`, waiting twice as long after every failure
+const BROADCAST_ATTEMPTS: u32 = 4;
+const BROADCAST_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Broadcast `tx`, retrying with an exponential backoff. Sleeps between the attempts, so it must
+/// run on the blocking thread pool
+pub(crate) fn broadcast_with_retry<B: Blockchain>(
+    blockchain: &B,
+    tx: &Transaction,
+) -> Result<(), <B as TxSource>::Error> {
+    let mut delay = BROADCAST_BACKOFF;
+    for _ in 1..BROADCAST_ATTEMPTS {
+        if blockchain.broadcast(tx).is_ok() {
+            return Ok(());
+        }
+
+        warn!("Broadcast of {} failed, retrying in {:?}", tx.txid(), delay);
+        thread::sleep(delay);
+        delay *= 2;
+    }
+
+    blockchain.broadcast(tx)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Async version of the `Blockchain` trait, for backends that do their own I/O on the runtime
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+pub trait AsyncBlockchain {
+    type Error;
+
+    async fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error>;
+    async fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error>;
+    async fn get_random_utxo(&self) -> Result<OutPoint, Self::Error>;
+    async fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error>;
+
+    // Written without `async` so that the default doesn't require `Self: Sync`
+    fn get_confirmations<'a>(
+        &'a self,
+        _txid: &'a Txid,
+    ) -> BoxFuture<'a, Result<Option<u32>, Self::Error>> {
+        Box::pin(async { Ok(None) })
+    }
+    fn get_height(&self) -> BoxFuture<'_, Result<Option<u32>, Self::Error>> {
+        Box::pin(async { Ok(None) })
+    }
+    fn in_mempool<'a>(
+        &'a self,
+        _txid: &'a Txid,
+    ) -> BoxFuture<'a, Result<Option<bool>, Self::Error>> {
+        Box::pin(async { Ok(None) })
+    }
+    fn get_spending_tx<'a>(
+        &'a self,
+        _txout: &'a OutPoint,
+    ) -> BoxFuture<'a, Result<Option<Transaction>, Self::Error>> {
+        Box::pin(async { Ok(None) })
+    }
+    fn get_mempool_entry<'a>(
+        &'a self,
+        _txid: &'a Txid,
+    ) -> BoxFuture<'a, Result<Option<MempoolEntry>, Self::Error>> {
+        Box::pin(async { Ok(None) })
+    }
+    fn wait_for_update<'a>(
+        &'a self,
+        _script_pubkey: &'a Script,
+        timeout: Duration,
+    ) -> BoxFuture<'a, Result<bool, Self::Error>> {
+        Box::pin(async move {
+            tokio::time::delay_for(timeout).await;
+            Ok(false)
+        })
+    }
+}
+
+/// Use an `AsyncBlockchain` where a `Blockchain` is expected
+///
+/// Every call waits on the runtime with `Handle::block_on`, which panics on one of its worker
+/// threads: only call it from tokio's blocking thread pool, like through `run_blocking()`, which
+/// is where `Client` and `Server` run the protocol state machines.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+pub struct BlockingAdapter<A> {
+    inner: A,
+    handle: Handle,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<A: AsyncBlockchain> BlockingAdapter<A> {
+    /// Wrap `inner`. Must be called from within a tokio runtime
+    pub fn new(inner: A) -> Self {
+        BlockingAdapter {
+            inner,
+            handle: Handle::current(),
+        }
+    }
+
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<A: AsyncBlockchain> TxSource for BlockingAdapter<A> {
+    type Error = A::Error;
+
+    fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+        self.handle.block_on(self.inner.get_tx(txid))
+    }
+    fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error> {
+        self.handle.block_on(self.inner.is_unspent(txout))
+    }
+    fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+        self.handle.block_on(self.inner.get_random_utxo())
+    }
+    fn get_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Self::Error> {
+        self.handle.block_on(self.inner.get_confirmations(txid))
+    }
+    fn get_height(&self) -> Result<Option<u32>, Self::Error> {
+        self.handle.block_on(self.inner.get_height())
+    }
+    fn in_mempool(&self, txid: &Txid) -> Result<Option<bool>, Self::Error> {
+        self.handle.block_on(self.inner.in_mempool(txid))
+    }
+    fn get_spending_tx(&self, txout: &OutPoint) -> Result<Option<Transaction>, Self::Error> {
+        self.handle.block_on(self.inner.get_spending_tx(txout))
+    }
+    fn get_mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Self::Error> {
+        self.handle.block_on(self.inner.get_mempool_entry(txid))
+    }
+    fn wait_for_update(
+        &self,
+        script_pubkey: &Script,
+        timeout: Duration,
+    ) -> Result<bool, Self::Error> {
+        self.handle
+            .block_on(self.inner.wait_for_update(script_pubkey, timeout))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<A: AsyncBlockchain> Broadcaster for BlockingAdapter<A> {
+    type Error = A::Error;
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        self.handle.block_on(self.inner.broadcast(tx))
+    }
+}
+
+/// Calls made on a fresh connection after the one they were running on failed
+const RECONNECT_ATTEMPTS: usize = 2;
+
+/// Keep a `Blockchain` working across disconnections from its server, like an Electrum server
+/// being restarted under a long-running `Server`
+///
+/// Connections are opened with `connect` when needed, and up to `pool_size` idle ones are kept
+/// around, so that a long `wait_for_update()` doesn't hold up the other calls. Backends don't tell
+/// a lost connection from other failures, so every failed call drops its connection and is
+/// retried once on a new one.
+pub struct ReconnectingBlockchain<B, F> {
+    connect: F,
+    idle: Mutex<Vec<B>>,
+    pool_size: usize,
+}
+
+impl<B, F> ReconnectingBlockchain<B, F>
+where
+    B: Blockchain,
+    F: Fn() -> Result<B, <B as TxSource>::Error>,
+{
+    /// Wrap the backends returned by `connect`, keeping a single connection
+    pub fn new(connect: F) -> Self {
+        ReconnectingBlockchain {
+            connect,
+            idle: Mutex::new(Vec::new()),
+            pool_size: 1,
+        }
+    }
+
+    /// Keep up to `pool_size` idle connections
+    pub fn pool_size(mut self, pool_size: usize) -> Self {
+        self.pool_size = pool_size;
+        self
+    }
+
+    /// Connections currently idle in the pool
+    pub fn idle_connections(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    fn call<R, C>(&self, call: C) -> Result<R, <B as TxSource>::Error>
+    where
+        C: Fn(&B) -> Result<R, <B as TxSource>::Error>,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let idle = self.idle.lock().unwrap().pop();
+            let backend = match idle {
+                Some(backend) => backend,
+                None => (self.connect)()?,
+            };
+
+            match call(&backend) {
+                Ok(result) => {
+                    let mut idle = self.idle.lock().unwrap();
+                    if idle.len() < self.pool_size {
+                        idle.push(backend);
+                    }
+
+                    return Ok(result);
+                }
+                Err(e) if attempt >= RECONNECT_ATTEMPTS => return Err(e),
+                Err(_) => warn!("Backend call failed, retrying on a new connection"),
+            }
+        }
+    }
+}
+
+impl<B, F> std::fmt::Debug for ReconnectingBlockchain<B, F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ReconnectingBlockchain")
+            .field("pool_size", &self.pool_size)
+            .finish()
+    }
+}
+
+impl<B, F> TxSource for ReconnectingBlockchain<B, F>
+where
+    B: Blockchain,
+    F: Fn() -> Result<B, <B as TxSource>::Error>,
+{
+    type Error = <B as TxSource>::Error;
+
+    fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+        self.call(|backend| backend.get_tx(txid))
+    }
+    fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error> {
+        self.call(|backend| backend.is_unspent(txout))
+    }
+    fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+        self.call(|backend| backend.get_random_utxo())
+    }
+    fn get_txs(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Self::Error> {
+        self.call(|backend| backend.get_txs(txids))
+    }
+    fn get_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Self::Error> {
+        self.call(|backend| backend.get_confirmations(txid))
+    }
+    fn get_height(&self) -> Result<Option<u32>, Self::Error> {
+        self.call(|backend| backend.get_height())
+    }
+    fn in_mempool(&self, txid: &Txid) -> Result<Option<bool>, Self::Error> {
+        self.call(|backend| backend.in_mempool(txid))
+    }
+    fn get_spending_tx(&self, txout: &OutPoint) -> Result<Option<Transaction>, Self::Error> {
+        self.call(|backend| backend.get_spending_tx(txout))
+    }
+    fn get_mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Self::Error> {
+        self.call(|backend| backend.get_mempool_entry(txid))
+    }
+    fn wait_for_update(
+        &self,
+        script_pubkey: &Script,
+        timeout: Duration,
+    ) -> Result<bool, Self::Error> {
+        self.call(|backend| backend.wait_for_update(script_pubkey, timeout))
+    }
+}
+
+impl<B, F> Broadcaster for ReconnectingBlockchain<B, F>
+where
+    B: Blockchain,
+    F: Fn() -> Result<B, <B as TxSource>::Error>,
+{
+    type Error = <B as TxSource>::Error;
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        self.call(|backend| backend.broadcast(tx))
+    }
+}
+
+/// Map keeping at most `capacity` entries, evicting the least recently used one
+#[derive(Debug)]
+struct Lru<K, V> {
+    capacity: usize,
+    entries: HashMap<K, V>,
+    /// From the least to the most recently used
+    order: VecDeque<K>,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Lru<K, V> {
+    fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn touch(&mut self, key: &K) {
+        if let Some(position) = self.order.iter().position(|other| other == key) {
+            self.order.remove(position);
+        }
+        self.order.push_back(key.clone());
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        let value = self.entries.get(key).cloned()?;
+        self.touch(key);
+
+        Some(value)
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.touch(&key);
+        self.entries.insert(key, value);
+        while self.entries.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+/// Entries of a `CachedBlockchain` kept by default
+const CACHE_CAPACITY: usize = 1000;
+
+/// Memoize the lookups of a `Blockchain` that are repeated during a session: both sides fetch the
+/// same previous transactions for the proof and for the final transaction built on every candidate
+/// UTXO
+///
+/// Transactions never change, so they are kept until evicted. Whether an output is unspent does,
+/// so those answers are only reused for `max_age`, by default about as long as a session lasts.
+/// Our own broadcasts mark their inputs as spent right away. Everything else goes to the wrapped
+/// backend.
+#[derive(Debug)]
+pub struct CachedBlockchain<B> {
+    inner: B,
+    transactions: Mutex<Lru<Txid, Transaction>>,
+    unspent: Mutex<Lru<OutPoint, (bool, Instant)>>,
+    max_age: Duration,
+}
+
+impl<B: Blockchain> CachedBlockchain<B> {
+    pub fn new(inner: B) -> Self {
+        CachedBlockchain {
+            inner,
+            transactions: Mutex::new(Lru::new(CACHE_CAPACITY)),
+            unspent: Mutex::new(Lru::new(CACHE_CAPACITY)),
+            max_age: Duration::from_secs(60),
+        }
+    }
+
+    /// Keep up to `capacity` transactions, and as many answers of `is_unspent()`
+    pub fn capacity(self, capacity: usize) -> Self {
+        CachedBlockchain {
+            transactions: Mutex::new(Lru::new(capacity)),
+            unspent: Mutex::new(Lru::new(capacity)),
+            ..self
+        }
+    }
+
+    /// Reuse the answers of `is_unspent()` for up to `max_age`
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Forget everything
+    pub fn clear(&self) {
+        self.transactions.lock().unwrap().clear();
+        self.unspent.lock().unwrap().clear();
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+
+impl<B: TxSource> TxSource for CachedBlockchain<B> {
+    type Error = B::Error;
+
+    fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+        if let Some(tx) = self.transactions.lock().unwrap().get(txid) {
+            return Ok(tx);
+        }
+
+        let tx = self.inner.get_tx(txid)?;
+        self.transactions.lock().unwrap().insert(*txid, tx.clone());
+        Ok(tx)
+    }
+    fn get_txs(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Self::Error> {
+        let cached = {
+            let mut transactions = self.transactions.lock().unwrap();
+            txids
+                .iter()
+                .map(|txid| transactions.get(txid))
+                .collect::<Vec<_>>()
+        };
+        let missing = txids
+            .iter()
+            .zip(&cached)
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(txid, _)| *txid)
+            .collect::<Vec<_>>();
+        let mut fetched = match missing.len() {
+            0 => vec![],
+            _ => self.inner.get_txs(&missing)?,
+        };
+        // A backend skipping the transactions it doesn't know would shift all the following ones,
+        // look them up one by one to get its error instead
+        if fetched.len() != missing.len() {
+            fetched = missing
+                .iter()
+                .map(|txid| self.inner.get_tx(txid))
+                .collect::<Result<_, _>>()?;
+        }
+        let mut fetched = fetched.into_iter();
+
+        let mut transactions = self.transactions.lock().unwrap();
+        Ok(txids
+            .iter()
+            .zip(cached)
+            .filter_map(|(txid, tx)| {
+                tx.or_else(|| {
+                    let tx = fetched.next()?;
+                    transactions.insert(*txid, tx.clone());
+                    Some(tx)
+                })
+            })
+            .collect())
+    }
+    fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error> {
+        let cached = self.unspent.lock().unwrap().get(txout);
+        if let Some((unspent, time)) = cached {
+            if time.elapsed() < self.max_age {
+                return Ok(unspent);
+            }
+        }
+
+        let unspent = self.inner.is_unspent(txout)?;
+        self.unspent
+            .lock()
+            .unwrap()
+            .insert(*txout, (unspent, Instant::now()));
+        Ok(unspent)
+    }
+    fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+        self.inner.get_random_utxo()
+    }
+    fn get_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Self::Error> {
+        self.inner.get_confirmations(txid)
+    }
+    fn get_height(&self) -> Result<Option<u32>, Self::Error> {
+        self.inner.get_height()
+    }
+    fn in_mempool(&self, txid: &Txid) -> Result<Option<bool>, Self::Error> {
+        self.inner.in_mempool(txid)
+    }
+    fn get_spending_tx(&self, txout: &OutPoint) -> Result<Option<Transaction>, Self::Error> {
+        self.inner.get_spending_tx(txout)
+    }
+    fn get_mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Self::Error> {
+        self.inner.get_mempool_entry(txid)
+    }
+    fn wait_for_update(
+        &self,
+        script_pubkey: &Script,
+        timeout: Duration,
+    ) -> Result<bool, Self::Error> {
+        self.inner.wait_for_update(script_pubkey, timeout)
+    }
+}
+
+impl<B: Broadcaster> Broadcaster for CachedBlockchain<B> {
+    type Error = B::Error;
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        self.inner.broadcast(tx)?;
+
+        let mut unspent = self.unspent.lock().unwrap();
+        for input in &tx.input {
+            unspent.insert(input.previous_output, (false, Instant::now()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use bitcoin::TxIn;
+
+    use super::*;
+
+    /// Fails the first `failures` broadcasts
+    struct Flaky {
+        failures: usize,
+        attempts: AtomicUsize,
+    }
+
+    impl TxSource for Flaky {
+        type Error = ();
+
+        fn get_tx(&self, _txid: &Txid) -> Result<Transaction, Self::Error> {
+            Err(())
+        }
+        fn is_unspent(&self, _txout: &OutPoint) -> Result<bool, Self::Error> {
+            Err(())
+        }
+        fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+            Err(())
+        }
+    }
+
+    impl Broadcaster for Flaky {
+        type Error = ();
+
+        fn broadcast(&self, _tx: &Transaction) -> Result<(), Self::Error> {
+            if self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+                Err(())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    /// Connection that fails every call once the server behind it went away
+    struct Connection {
+        alive: Arc<AtomicBool>,
+    }
+
+    impl TxSource for Connection {
+        type Error = ();
+
+        fn get_tx(&self, _txid: &Txid) -> Result<Transaction, Self::Error> {
+            Err(())
+        }
+        fn is_unspent(&self, _txout: &OutPoint) -> Result<bool, Self::Error> {
+            Err(())
+        }
+        fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+            Err(())
+        }
+        fn get_height(&self) -> Result<Option<u32>, Self::Error> {
+            if self.alive.load(Ordering::SeqCst) {
+                Ok(Some(100))
+            } else {
+                Err(())
+            }
+        }
+    }
+
+    impl Broadcaster for Connection {
+        type Error = ();
+
+        fn broadcast(&self, _tx: &Transaction) -> Result<(), Self::Error> {
+            Err(())
+        }
+    }
+
+    /// Answers from a `MockBlockchain`, once a timer of the runtime fires
+    struct Delayed(crate::testutil::MockBlockchain);
+
+    #[async_trait]
+    impl AsyncBlockchain for Delayed {
+        type Error = ();
+
+        async fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+            self.0.get_tx(txid)
+        }
+        async fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error> {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+            self.0.is_unspent(txout)
+        }
+        async fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+            self.0.get_random_utxo()
+        }
+        async fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+            self.0.broadcast(tx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocking_adapter() {
+        let mock = crate::testutil::MockBlockchain::new();
+        let utxo = mock.fund(Script::new(), 100_000);
+        let blockchain = Arc::new(BlockingAdapter::new(Delayed(mock.clone())));
+
+        let spend = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: utxo,
+                script_sig: Script::new(),
+                sequence: 0xFFFF_FFFF,
+                witness: vec![],
+            }],
+            output: vec![],
+        };
+        let adapter = Arc::clone(&blockchain);
+        let (tx, unspent, updated) = run_blocking(move || {
+            let tx = adapter.get_tx(&utxo.txid);
+            adapter.broadcast(&spend).unwrap();
+            let updated = adapter.wait_for_update(&Script::new(), Duration::from_millis(10));
+            (tx, adapter.is_unspent(&utxo), updated)
+        })
+        .await
+        .unwrap();
+        assert_eq!(tx.unwrap().txid(), utxo.txid);
+        assert_eq!(unspent, Ok(false));
+        assert_eq!(updated, Ok(false));
+        assert_eq!(mock.broadcasts().len(), 1);
+    }
+
+    #[test]
+    fn test_reconnecting_blockchain() {
+        let reachable = AtomicBool::new(true);
+        let connections = Mutex::new(Vec::<Arc<AtomicBool>>::new());
+        let blockchain = ReconnectingBlockchain::new(|| {
+            if !reachable.load(Ordering::SeqCst) {
+                return Err(());
+            }
+
+            let alive = Arc::new(AtomicBool::new(true));
+            connections.lock().unwrap().push(Arc::clone(&alive));
+            Ok(Connection { alive })
+        });
+        let restart = || {
+            for alive in connections.lock().unwrap().iter() {
+                alive.store(false, Ordering::SeqCst);
+            }
+        };
+
+        assert_eq!(blockchain.get_height(), Ok(Some(100)));
+        assert_eq!(blockchain.get_height(), Ok(Some(100)));
+        assert_eq!(connections.lock().unwrap().len(), 1);
+
+        restart();
+        assert_eq!(blockchain.get_height(), Ok(Some(100)));
+        assert_eq!(connections.lock().unwrap().len(), 2);
+        assert_eq!(blockchain.idle_connections(), 1);
+
+        restart();
+        reachable.store(false, Ordering::SeqCst);
+        assert!(blockchain.get_height().is_err());
+        assert_eq!(blockchain.idle_connections(), 0);
+    }
+
+    /// Counts the calls that reach it
+    #[derive(Default)]
+    struct Counting {
+        calls: AtomicUsize,
+    }
+
+    fn dummy_tx(lock_time: u32) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time,
+            input: vec![],
+            output: vec![],
+        }
+    }
+
+    impl TxSource for Counting {
+        type Error = ();
+
+        fn get_tx(&self, _txid: &Txid) -> Result<Transaction, Self::Error> {
+            Ok(dummy_tx(self.calls.fetch_add(1, Ordering::SeqCst) as u32))
+        }
+        fn is_unspent(&self, _txout: &OutPoint) -> Result<bool, Self::Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(true)
+        }
+        fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+            Err(())
+        }
+    }
+
+    impl Broadcaster for Counting {
+        type Error = ();
+
+        fn broadcast(&self, _tx: &Transaction) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    /// Batches that silently leave out their last transaction, which it fails to look up alone
+    struct Lossy {
+        unknown: Txid,
+    }
+
+    impl TxSource for Lossy {
+        type Error = ();
+
+        fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+            match *txid == self.unknown {
+                true => Err(()),
+                false => Ok(dummy_tx(0)),
+            }
+        }
+        fn get_txs(&self, txids: &[Txid]) -> Result<Vec<Transaction>, Self::Error> {
+            Ok(vec![dummy_tx(0); txids.len().saturating_sub(1)])
+        }
+        fn is_unspent(&self, _txout: &OutPoint) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+        fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+            Err(())
+        }
+    }
+
+    impl Broadcaster for Lossy {
+        type Error = ();
+
+        fn broadcast(&self, _tx: &Transaction) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    /// Push-tx API, only taking transactions
+    #[derive(Default)]
+    struct Relay {
+        relayed: Mutex<Vec<Txid>>,
+    }
+
+    impl Broadcaster for Relay {
+        type Error = ();
+
+        fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+            self.relayed.lock().unwrap().push(tx.txid());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_split_blockchain() {
+        let tx = dummy_tx(100);
+
+        let blockchain = SplitBlockchain::new(Counting::default(), Relay::default());
+        assert!(blockchain.is_unspent(&OutPoint::new(tx.txid(), 0)).unwrap());
+        blockchain.broadcast(&tx).unwrap();
+
+        let (source, relay) = blockchain.into_inner();
+        assert_eq!(source.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(*relay.relayed.lock().unwrap(), vec![tx.txid()]);
+    }
+
+    #[test]
+    fn test_cached_blockchain() {
+        let first = dummy_tx(100).txid();
+        let second = dummy_tx(200).txid();
+        let outpoint = OutPoint::new(first, 0);
+
+        let blockchain = CachedBlockchain::new(Counting::default()).capacity(1);
+        let tx = blockchain.get_tx(&first).unwrap();
+        assert_eq!(blockchain.get_tx(&first), Ok(tx.clone()));
+        assert_eq!(
+            blockchain.get_txs(&[first, first]),
+            Ok(vec![tx.clone(), tx])
+        );
+        assert_eq!(blockchain.inner.calls.load(Ordering::SeqCst), 1);
+
+        // evicts `first`
+        blockchain.get_tx(&second).unwrap();
+        blockchain.get_tx(&first).unwrap();
+        assert_eq!(blockchain.inner.calls.load(Ordering::SeqCst), 3);
+
+        assert_eq!(blockchain.is_unspent(&outpoint), Ok(true));
+        assert_eq!(blockchain.is_unspent(&outpoint), Ok(true));
+        assert_eq!(blockchain.inner.calls.load(Ordering::SeqCst), 4);
+
+        let mut spend = dummy_tx(0);
+        spend.input.push(TxIn {
+            previous_output: outpoint,
+            script_sig: Script::new(),
+            sequence: 0xFFFF_FFFF,
+            witness: vec![],
+        });
+        blockchain.broadcast(&spend).unwrap();
+        assert_eq!(blockchain.is_unspent(&outpoint), Ok(false));
+
+        let blockchain = blockchain.max_age(Duration::from_secs(0));
+        assert_eq!(blockchain.is_unspent(&outpoint), Ok(true));
+        assert_eq!(blockchain.inner.calls.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn test_cached_blockchain_lossy_batch() {
+        let known = dummy_tx(100).txid();
+        let unknown = dummy_tx(200).txid();
+
+        let blockchain = CachedBlockchain::new(Lossy { unknown });
+        assert_eq!(blockchain.get_txs(&[known, unknown]), Err(()));
+        // Nothing is cached out of order either
+        assert_eq!(blockchain.get_txs(&[known]), Ok(vec![dummy_tx(0)]));
+
+        let blockchain = CachedBlockchain::new(Lossy {
+            unknown: dummy_tx(300).txid(),
+        });
+        assert_eq!(
+            blockchain.get_txs(&[known, unknown]),
+            Ok(vec![dummy_tx(0), dummy_tx(0)])
+        );
+    }
+
+    #[test]
+    fn test_broadcast_with_retry() {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        };
+
+        let flaky = Flaky {
+            failures: 2,
+            attempts: AtomicUsize::new(0),
+        };
+        assert!(broadcast_with_retry(&flaky, &tx).is_ok());
+        assert_eq!(flaky.attempts.load(Ordering::SeqCst), 3);
+
+        let broken = Flaky {
+            failures: usize::MAX,
+            attempts: AtomicUsize::new(0),
+        };
+        assert!(broadcast_with_retry(&broken, &tx).is_err());
+        assert_eq!(
+            broken.attempts.load(Ordering::SeqCst),
+            BROADCAST_ATTEMPTS as usize
+        );
+    }
+
+    #[test]
+    fn test_send_sync() {
+        // Shared across the sessions of a `Server`, each running on its own thread
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<crate::demo::ElectrumBlockchain>();
+        assert_send_sync::<crate::testutil::MockBlockchain>();
+        assert_send_sync::<CachedBlockchain<crate::demo::ElectrumBlockchain>>();
+        assert_send_sync::<SplitBlockchain<crate::demo::ElectrumBlockchain, Flaky>>();
+        assert_send_sync::<Instrumented<crate::demo::ElectrumBlockchain>>();
+    }
+}
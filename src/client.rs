@@ -1,314 +1,852 @@
-use std::convert::TryFrom;
-use std::time::Duration;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time::{delay_for, timeout};
 
-use tokio::time::timeout;
+use tracing::{debug, info, warn};
 
-use tokio_socks::tcp::Socks5Stream;
-use tokio_socks::IntoTargetAddr;
+use rand::seq::SliceRandom;
 
-use log::{debug, info, trace};
+use bitcoin::{Network, Transaction, TxOut, Txid};
 
-use bitcoin::{OutPoint, Transaction, TxIn, Txid};
-
-use libtor::{Tor, TorFlag};
-
-use crate::blockchain::Blockchain;
-use crate::common::*;
+use crate::bip21::{Bip21Error, Bip21Uri};
+use crate::blockchain::{
+    broadcast_with_retry, is_propagated, wait_for_confirmations, Blockchain, Broadcaster,
+    Instrumented, TxSource,
+};
+use crate::coin_selection::{base_transaction, BranchAndBound};
+use crate::events::{NoObserver, SessionObserver};
+use crate::fees::{FeeEstimator, FeeRate};
 use crate::jsonrpc::*;
+use crate::offer::Offer;
+use crate::shutdown::CancellationToken;
 use crate::signer::Signer;
-use crate::{Error, ProtocolError, Request, Response, WitnessWrapper, VERSION};
-
-#[derive(Debug)]
-enum StateVariant {
-    WaitingVersion,
-    ServerVersion {
-        version: String,
-    },
-    ServerUtxos {
-        version: String,
-        utxos: Vec<OutPoint>,
-        proof: ProofTransaction<Created>,
-    },
-    ServerTxid {
-        version: String,
-        txid: Txid,
-        transaction: Transaction,
-    },
-}
+use crate::transcript::Transcript;
+use crate::transport::{AsyncStream, BoxedStream, Transport};
+use crate::wallet::SenderWallet;
+use crate::{run_blocking, Capabilities, Error, ProtocolError, Request, Response, TimeoutPhase};
 
-#[derive(Debug)]
-struct ClientState<'a, B, S> {
-    base_transaction: Transaction,
-    receiver_output_index: usize,
+pub use crate::protocol::sender::{ClientConfig, PaymentResult, TxOrdering};
+use crate::protocol::sender::{ClientSession, ClientState};
 
-    state: StateVariant,
-
-    blockchain: &'a B,
-    signer: &'a S,
-}
-
-impl<'a, B, S> ClientState<'a, B, S>
-where
-    B: Blockchain + std::fmt::Debug,
-    Error: From<<B as Blockchain>::Error>,
-    S: Signer + std::fmt::Debug,
-    Error: From<<S as Signer>::Error>,
-{
-    fn new(
-        base_transaction: Transaction,
-        receiver_output_index: usize,
-        blockchain: &'a B,
-        signer: &'a S,
-    ) -> ClientState<'a, B, S> {
-        ClientState {
-            base_transaction,
-            receiver_output_index,
-            state: StateVariant::WaitingVersion,
-            blockchain,
-            signer,
-        }
-    }
+/// Confirmation target of the fee rate estimated by `Client::send()`, in blocks
+const SEND_TARGET_BLOCKS: usize = 6;
 
-    fn transition(&mut self, message: Response) -> Result<Option<Request>, Error> {
-        match &self.state {
-            StateVariant::WaitingVersion => match message {
-                Response::Version { version } if version == VERSION => {
-                    self.state = StateVariant::ServerVersion { version };
-
-                    let transaction = ProofTransaction::<Created>::try_from((
-                        self.base_transaction.clone(),
-                        self.signer,
-                    ))?;
-
-                    Ok(Some(Request::Proof {
-                        transaction: transaction.into_inner(),
-                    }))
-                }
-                Response::Version { version } => Err(ProtocolError::InvalidVersion(version).into()),
-                _ => Err(ProtocolError::Expected("VERSION".into()).into()),
-            },
-            StateVariant::ServerVersion { version } => match message {
-                Response::Utxos { utxos } => {
-                    let tx = &self.base_transaction;
-
-                    let change_script_index = if self.receiver_output_index == 0 {
-                        1
-                    } else {
-                        0
-                    };
-                    let change_script = tx.output[change_script_index].script_pubkey.clone();
-
-                    let proof_transaction = ProofTransaction::<Created>::try_from((
-                        self.base_transaction.clone(),
-                        self.signer,
-                    ))?;
-                    let fees = 5000;
-                    let receiver_txin = TxIn {
-                        sequence: 0xFFFF_FFFF,
-                        //previous_output: (),
-                        ..Default::default()
-                    };
-                    let receiver_input_index = tx.input.len(); // TODO: shuffle
-                    let receiver_txout = tx.output[self.receiver_output_index].clone();
-                    let receiver_output_index = self.receiver_output_index;
-
-                    let final_transaction_meta = FinalTransactionMeta {
-                        tx: proof_transaction.clone(),
-                        fees,
-                        sender_script: change_script.clone(),
-                        receiver_txin,
-                        receiver_input_index,
-                        receiver_txout,
-                        receiver_output_index,
-                    };
-
-                    let mut witnesses = Vec::new();
-                    for utxo in &utxos {
-                        if !self.blockchain.is_unspent(&utxo)? {
-                            trace!("Invalid prev_out (wrong type or spent)");
-                            return Err(ProtocolError::InvalidUtxo.into());
-                        }
-
-                        let mut final_transaction_meta = final_transaction_meta.clone();
-                        final_transaction_meta.receiver_txin.previous_output = *utxo;
-
-                        let final_transaction = FinalTransaction::<Unsigned>::try_from((
-                            final_transaction_meta,
-                            self.blockchain,
-                        ))?;
-                        let final_transaction = FinalTransaction::<SenderSigned>::try_from((
-                            final_transaction,
-                            self.signer,
-                        ))?;
-
-                        let inputs_to_sign = (0..final_transaction.input.len())
-                            .filter(|i| *i != receiver_input_index)
-                            .collect::<Vec<_>>();
-                        let this_utxo_witnesses = inputs_to_sign
-                            .into_iter()
-                            .map(|index| {
-                                WitnessWrapper::new(&final_transaction.input[index].witness)
-                            })
-                            .collect();
-
-                        witnesses.push(this_utxo_witnesses);
-                    }
-
-                    self.state = StateVariant::ServerUtxos {
-                        version: version.to_string(),
-                        proof: proof_transaction,
-                        utxos,
-                    };
-
-                    Ok(Some(Request::Witnesses {
-                        fees,
-                        change_script,
-                        receiver_input_position: receiver_input_index,
-                        receiver_output_position: receiver_output_index,
-                        witnesses,
-                    }))
-                }
-                _ => Err(ProtocolError::Expected("UTXOS".into()).into()),
-            },
-            StateVariant::ServerUtxos { version, .. } => match message {
-                Response::Txid { txid, transaction } => {
-                    self.state = StateVariant::ServerTxid {
-                        version: version.to_string(),
-                        transaction,
-                        txid,
-                    };
-
-                    Ok(None)
-                }
-                _ => Err(ProtocolError::Expected("TXID".into()).into()),
-            },
-            _ => Err(ProtocolError::UnexpectedMessage.into()),
-        }
-    }
-}
+/// Time given to the negotiation before broadcasting the fallback transaction
+pub const FALLBACK_TIMEOUT: Duration = Duration::from_secs(60);
 
-impl<'a, B, S> JsonRpcState for ClientState<'a, B, S>
+impl<B, S> JsonRpcState for ClientState<B, S>
 where
-    B: Blockchain + std::fmt::Debug,
-    Error: From<<B as Blockchain>::Error>,
-    S: Signer + std::fmt::Debug,
+    B: Blockchain + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<B as TxSource>::Error>,
+    S: Signer + std::fmt::Debug + Send + Sync + 'static,
     Error: From<<S as Signer>::Error>,
 {
     type OutMessage = Request;
     type InMessage = Response;
-    type Response = (Txid, Transaction);
+    type Response = PaymentResult;
     type Error = Error;
 
     fn setup(&mut self) -> Result<Option<Self::OutMessage>, Self::Error> {
-        Ok(Some(Request::Version {
-            version: VERSION.to_string(),
-        }))
+        Ok(Some(self.start()))
     }
 
     fn message(
         &mut self,
         message: Self::InMessage,
     ) -> Result<Option<Self::OutMessage>, Self::Error> {
-        Ok(self.transition(message)?)
+        self.step(message)
+    }
+
+    fn recover(&mut self, error: &ProtocolError) -> Result<Option<Self::OutMessage>, Self::Error> {
+        self.recover(error)
     }
 
     fn done(&self) -> Result<Self::Response, ()> {
-        if let StateVariant::ServerTxid {
-            txid, transaction, ..
-        } = &self.state
-        {
-            Ok((*txid, transaction.clone()))
-        } else {
-            Err(())
-        }
+        self.result().ok_or(())
     }
 }
 
-pub struct Client<B, S>
-where
-    B: Blockchain + std::fmt::Debug,
-    S: Signer + std::fmt::Debug,
-{
-    stream: Socks5Stream,
+/// Settings of a `Client`, on top of the parts it can't do without
+///
+/// ```ignore
+/// let mut client = ClientBuilder::new(blockchain, signer, base_transaction)
+///     .fee_rate(FeeRate::from_sat_per_vb(2.0))
+///     .use_psbt(true)
+///     .connect_uri(&transport, uri)
+///     .await?;
+/// ```
+pub struct ClientBuilder<B, S> {
     blockchain: B,
     signer: S,
-
     base_transaction: Transaction,
-    receiver_output_index: usize,
+    receiver_output_index: Option<usize>,
+
+    config: ClientConfig,
+    token: Option<String>,
+    invoice: Option<String>,
+    endpoint: Option<String>,
+    use_psbt: bool,
+    observer: Arc<dyn SessionObserver>,
+    shutdown: CancellationToken,
+    transcript: Option<Transcript>,
 }
 
-impl<B, S> Client<B, S>
+impl<B, S> ClientBuilder<B, S>
 where
-    B: Blockchain + std::fmt::Debug,
-    Error: From<<B as Blockchain>::Error>,
-    S: Signer + std::fmt::Debug,
+    B: Blockchain + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<B as TxSource>::Error>,
+    S: Signer + std::fmt::Debug + Send + Sync + 'static,
     Error: From<<S as Signer>::Error>,
 {
-    pub async fn new<'a, A: IntoTargetAddr<'a> + std::clone::Clone>(
-        server: A,
-        blockchain: B,
-        signer: S,
-        base_transaction: Transaction,
-        receiver_output_index: usize,
-    ) -> Result<Client<B, S>, Error> {
-        let rand_string: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
+    /// Start building a client paying the receiver with `base_transaction`
+    pub fn new(blockchain: B, signer: S, base_transaction: Transaction) -> Self {
+        ClientBuilder {
+            blockchain,
+            signer,
+            base_transaction,
+            receiver_output_index: None,
+
+            config: ClientConfig::default(),
+            token: None,
+            invoice: None,
+            endpoint: None,
+            use_psbt: false,
+            observer: Arc::new(NoObserver),
+            shutdown: CancellationToken::new(),
+            transcript: None,
+        }
+    }
+
+    /// Index of the output paying the receiver. Not needed with `connect_uri()`, which looks it
+    /// up
+    pub fn receiver_output_index(mut self, receiver_output_index: usize) -> Self {
+        self.receiver_output_index = Some(receiver_output_index);
+        self
+    }
+
+    pub fn config(mut self, config: ClientConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn fee_rate(mut self, fee_rate: FeeRate) -> Self {
+        self.config.fee_rate = fee_rate;
+        self
+    }
 
-        let mut dir = std::env::temp_dir();
-        dir.push(rand_string);
+    /// Time allowed for every attempt to connect and for every message of the server
+    pub fn message_timeout(mut self, message_timeout: Duration) -> Self {
+        self.config.message_timeout = message_timeout;
+        self
+    }
 
-        debug!("Using tempdir: {}", dir.display());
+    /// Time allowed for the whole negotiation, once connected
+    pub fn session_timeout(mut self, session_timeout: Duration) -> Self {
+        self.config.session_timeout = session_timeout;
+        self
+    }
 
-        Tor::new()
-            .flag(TorFlag::DataDirectory(dir.to_str().unwrap().into()))
-            .flag(TorFlag::SocksPort(9051))
-            .start_background();
+    /// Time allowed for the transport to get ready before connecting, like for Tor to bootstrap
+    pub fn bootstrap_timeout(mut self, bootstrap_timeout: Duration) -> Self {
+        self.config.bootstrap_timeout = bootstrap_timeout;
+        self
+    }
+
+    /// Network we are on. Payment URIs for other networks are rejected
+    pub fn network(mut self, network: Network) -> Self {
+        self.config.network = network;
+        self
+    }
+
+    /// See `Client::set_token()`
+    pub fn token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// See `Client::set_invoice()`
+    pub fn invoice(mut self, invoice: Option<String>) -> Self {
+        self.invoice = invoice;
+        self
+    }
+
+    /// See `Client::set_endpoint()`
+    pub fn endpoint(mut self, endpoint: Option<String>) -> Self {
+        self.endpoint = endpoint;
+        self
+    }
+
+    /// Negotiate without broadcasting anything, see `ClientConfig::dry_run`
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.config.dry_run = dry_run;
+        self
+    }
+
+    /// See `Client::use_psbt()`
+    pub fn use_psbt(mut self, use_psbt: bool) -> Self {
+        self.use_psbt = use_psbt;
+        self
+    }
+
+    /// See `Client::set_observer()`
+    pub fn observer<O: SessionObserver + 'static>(mut self, observer: Arc<O>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Stop the client through `token`, shared with the rest of the application. Otherwise it gets
+    /// one of its own, see `Client::cancellation_token()`
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.shutdown = token;
+        self
+    }
+
+    /// See `Client::set_transcript()`
+    pub fn transcript(mut self, transcript: Transcript) -> Self {
+        self.transcript = Some(transcript);
+        self
+    }
+
+    /// Connect to `server`, in the `host:port` form, using `transport`
+    pub async fn connect<T: Transport + ?Sized>(
+        self,
+        transport: &T,
+        server: &str,
+    ) -> Result<Client<B, S>, Error> {
+        let receiver_output_index = self
+            .receiver_output_index
+            .ok_or(ProtocolError::MissingData)?;
+        // Wait for Tor to bootstrap, the connection can still fail while the onion service of the
+        // server is being looked up
+        timeout(self.config.bootstrap_timeout, transport.wait_ready())
+            .await
+            .unwrap_or(Err(Error::Timeout(TimeoutPhase::Bootstrap)))?;
 
         let mut attempts = 0;
         let stream = loop {
             if attempts > 10 {
-                return Err(Error::Timeout);
+                return Err(Error::Timeout(TimeoutPhase::Connect));
             }
 
             debug!("Attempting to connect...");
             attempts += 1;
 
-            match timeout(
-                Duration::from_secs(10),
-                Socks5Stream::connect("127.0.0.1:9051", server.clone()),
-            )
-            .await
-            {
+            match timeout(self.config.message_timeout, transport.connect(server)).await {
                 Err(_) => continue,
-                Ok(Err(_)) => std::thread::sleep(Duration::from_secs(2)),
+                Ok(Err(_)) => delay_for(Duration::from_secs(2)).await,
                 Ok(Ok(stream)) => break stream,
             };
         };
 
-        Ok(Client {
+        let socks_port = transport.socks_port();
+        let mut client = self.build(stream, server, receiver_output_index);
+        client.socks_port = socks_port;
+
+        Ok(client)
+    }
+
+    /// Connect to the endpoint of a BIP21 `uri` using `transport`. The receiver's output is the
+    /// one of the base transaction paying the address and amount of the URI
+    pub async fn connect_uri<T: Transport + ?Sized>(
+        mut self,
+        transport: &T,
+        uri: &str,
+    ) -> Result<Client<B, S>, Error> {
+        let uri = Bip21Uri::from_str(uri)?;
+        uri.check_network(self.config.network)?;
+        if uri.is_expired() {
+            return Err(ProtocolError::InvoiceExpired.into());
+        }
+        let server = uri.endpoint_addr().ok_or(Bip21Error::MissingEndpoint)?;
+        let receiver_output_index = self
+            .base_transaction
+            .output
+            .iter()
+            .position(|output| {
+                output.script_pubkey == uri.address.script_pubkey()
+                    && uri.amount.is_none_or(|amount| output.value == amount)
+            })
+            .ok_or(Bip21Error::PaymentMismatch)?;
+
+        self.receiver_output_index = Some(receiver_output_index);
+        self.token = uri.token.clone().or(self.token);
+        self.invoice = uri.invoice.clone().or(self.invoice);
+        self.endpoint = uri.endpoint.clone();
+        self.connect(transport, server).await
+    }
+
+    /// Run the protocol over a stream that is already connected to the server, like a Unix socket
+    /// or an in-memory pipe. `server` is only used for logging and as the BIP78 `Host`
+    pub fn from_stream<T: AsyncStream + 'static>(
+        self,
+        stream: T,
+        server: &str,
+    ) -> Result<Client<B, S>, Error> {
+        let receiver_output_index = self
+            .receiver_output_index
+            .ok_or(ProtocolError::MissingData)?;
+
+        Ok(self.build(Box::new(stream), server, receiver_output_index))
+    }
+
+    fn build(
+        self,
+        stream: BoxedStream,
+        server: &str,
+        receiver_output_index: usize,
+    ) -> Client<B, S> {
+        let mut capabilities = Capabilities::TAPROOT
+            | Capabilities::MULTI_UTXO
+            | Capabilities::BATCH
+            | Capabilities::SENDER_BROADCAST
+            | Capabilities::ANYONECANPAY_PROOF
+            | Capabilities::BOUND_PROOF
+            | Capabilities::RECEIVER_CHANGE;
+        if self.use_psbt {
+            capabilities.insert(Capabilities::PSBT);
+        }
+
+        Client {
             stream,
+            server: server.to_string(),
+            blockchain: Arc::new(Instrumented(self.blockchain)),
+            signer: Arc::new(self.signer),
+
+            base_transaction: self.base_transaction,
+            receiver_output_index,
+            capabilities,
+            config: self.config,
+
+            session: Arc::new(Mutex::new(None)),
+            resume: false,
+            socks_port: None,
+            token: self.token,
+            invoice: self.invoice,
+            endpoint: self.endpoint,
+            observer: self.observer,
+            shutdown: self.shutdown,
+            transcript: self.transcript,
+        }
+    }
+}
+
+pub struct Client<B, S>
+where
+    B: Blockchain + std::fmt::Debug + Send + Sync + 'static,
+    S: Signer + std::fmt::Debug + Send + Sync + 'static,
+{
+    stream: BoxedStream,
+    server: String,
+    blockchain: Arc<Instrumented<B>>,
+    signer: Arc<S>,
+
+    base_transaction: Transaction,
+    receiver_output_index: usize,
+    capabilities: Capabilities,
+    config: ClientConfig,
+
+    session: Arc<Mutex<Option<ClientSession>>>,
+    resume: bool,
+    socks_port: Option<u16>,
+    token: Option<String>,
+    invoice: Option<String>,
+    endpoint: Option<String>,
+    observer: Arc<dyn SessionObserver>,
+    shutdown: CancellationToken,
+    transcript: Option<Transcript>,
+}
+
+impl<B, S> Client<B, S>
+where
+    B: Blockchain + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<B as TxSource>::Error>,
+    S: Signer + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<S as Signer>::Error>,
+{
+    /// Connect to `server` through a Tor instance started with `libtor`
+    #[cfg(feature = "libtor")]
+    pub async fn new(
+        server: &str,
+        blockchain: B,
+        signer: S,
+        base_transaction: Transaction,
+        receiver_output_index: usize,
+    ) -> Result<Client<B, S>, Error> {
+        use crate::transport::LibTor;
+
+        Self::with_transport(
+            &LibTor::default(),
+            server,
             blockchain,
             signer,
+            base_transaction,
+            receiver_output_index,
+        )
+        .await
+    }
 
+    /// Connect to `server`, in the `host:port` form, using `transport`
+    pub async fn with_transport<T: Transport + ?Sized>(
+        transport: &T,
+        server: &str,
+        blockchain: B,
+        signer: S,
+        base_transaction: Transaction,
+        receiver_output_index: usize,
+    ) -> Result<Client<B, S>, Error> {
+        ClientBuilder::new(blockchain, signer, base_transaction)
+            .receiver_output_index(receiver_output_index)
+            .connect(transport, server)
+            .await
+    }
+
+    /// Connect to the endpoint of a BIP21 `uri` using `transport`. The receiver's output is the
+    /// one of `base_transaction` paying the address and amount of the URI, which must be on
+    /// `network`
+    pub async fn from_uri<T: Transport + ?Sized>(
+        transport: &T,
+        uri: &str,
+        network: Network,
+        blockchain: B,
+        signer: S,
+        base_transaction: Transaction,
+    ) -> Result<Client<B, S>, Error> {
+        ClientBuilder::new(blockchain, signer, base_transaction)
+            .network(network)
+            .connect_uri(transport, uri)
+            .await
+    }
+
+    /// Pay `uri` in a single call: fund the payment with UTXOs of `wallet`, sending the change
+    /// to a fresh script of it, at the fee rate estimated by `estimator`, then connect to the
+    /// endpoint of the URI using `transport` and run the negotiation
+    ///
+    /// `amount` is only needed when the URI doesn't request one, and must match it otherwise. The
+    /// address of the URI must be on `network`.
+    ///
+    /// URIs with an offer and no endpoint are paid without any negotiation, by completing the
+    /// offer and broadcasting the transaction (see `offer`).
+    #[allow(clippy::too_many_arguments)]
+    pub async fn send<T, W, F>(
+        transport: &T,
+        uri: &Bip21Uri,
+        network: Network,
+        amount: Option<u64>,
+        wallet: &W,
+        blockchain: B,
+        signer: S,
+        estimator: &F,
+    ) -> Result<PaymentResult, Error>
+    where
+        T: Transport + ?Sized,
+        W: SenderWallet,
+        Error: From<<W as SenderWallet>::Error>,
+        F: FeeEstimator,
+        Error: From<<F as FeeEstimator>::Error>,
+    {
+        Self::send_with_observer(
+            transport,
+            uri,
+            network,
+            amount,
+            wallet,
+            blockchain,
+            signer,
+            estimator,
+            Arc::new(NoObserver),
+        )
+        .await
+    }
+
+    /// Like `send()`, reporting the progress of the negotiation to `observer`
+    #[allow(clippy::too_many_arguments)]
+    pub async fn send_with_observer<T, W, F, O>(
+        transport: &T,
+        uri: &Bip21Uri,
+        network: Network,
+        amount: Option<u64>,
+        wallet: &W,
+        blockchain: B,
+        signer: S,
+        estimator: &F,
+        observer: Arc<O>,
+    ) -> Result<PaymentResult, Error>
+    where
+        T: Transport + ?Sized,
+        W: SenderWallet,
+        Error: From<<W as SenderWallet>::Error>,
+        F: FeeEstimator,
+        Error: From<<F as FeeEstimator>::Error>,
+        O: SessionObserver + 'static,
+    {
+        uri.check_network(network)?;
+        let amount = match (uri.amount, amount) {
+            (Some(requested), Some(amount)) if requested != amount => {
+                return Err(Bip21Error::PaymentMismatch.into())
+            }
+            (Some(amount), _) | (None, Some(amount)) => amount,
+            (None, None) => return Err(Bip21Error::MissingAmount.into()),
+        };
+        // Receivers that can't stay online publish an offer instead of an endpoint
+        let offer = match (uri.endpoint_addr(), &uri.offer) {
+            (Some(_), _) => None,
+            (None, Some(offer)) => Some(Offer::from_str(offer)?),
+            (None, None) => return Err(Bip21Error::MissingEndpoint.into()),
+        };
+        let fee_rate = estimator.estimate_fee_rate(SEND_TARGET_BLOCKS)?;
+        debug!("Estimated fee rate: {:?}", fee_rate);
+
+        let payment = TxOut {
+            script_pubkey: uri.address.script_pubkey(),
+            value: amount,
+        };
+        let base_transaction = base_transaction(
+            &BranchAndBound,
+            &wallet.list_unspent()?,
+            payment,
+            wallet.change_script()?,
+            fee_rate,
+        )?;
+
+        let server = match (uri.endpoint_addr(), offer) {
+            (Some(server), _) => server,
+            (None, Some(offer)) => {
+                info!("Paying offer for {}", offer.utxo());
+                return run_blocking(move || {
+                    let transaction =
+                        offer.complete(&base_transaction, 1, 0, fee_rate, &blockchain, &signer)?;
+                    broadcast_with_retry(&blockchain, &transaction)?;
+
+                    PaymentResult::new(&blockchain, transaction, 1)
+                })
+                .await?;
+            }
+            (None, None) => unreachable!(),
+        };
+
+        let mut client = ClientBuilder::new(blockchain, signer, base_transaction)
+            .receiver_output_index(1)
+            .token(uri.token.clone())
+            .invoice(uri.invoice.clone())
+            .endpoint(uri.endpoint.clone())
+            .config(ClientConfig {
+                fee_rate,
+                change_output_index: Some(0),
+                network,
+                ..Default::default()
+            })
+            .observer(observer)
+            .connect(transport, server)
+            .await?;
+
+        client.start().await
+    }
+
+    /// Connect to `server` over plain TCP, without Tor. Meant for regtest and LAN setups
+    pub async fn new_direct<A: ToSocketAddrs>(
+        server: A,
+        blockchain: B,
+        signer: S,
+        base_transaction: Transaction,
+        receiver_output_index: usize,
+    ) -> Result<Client<B, S>, Error> {
+        let stream = TcpStream::connect(server).await?;
+        let server = stream.peer_addr()?.to_string();
+
+        Self::from_stream(
+            stream,
+            &server,
+            blockchain,
+            signer,
             base_transaction,
             receiver_output_index,
-        })
+        )
+    }
+
+    /// Run the protocol over a stream that is already connected to the server, like a Unix socket
+    /// or an in-memory pipe. `server` is only used for logging and as the BIP78 `Host`
+    pub fn from_stream<T: AsyncStream + 'static>(
+        stream: T,
+        server: &str,
+        blockchain: B,
+        signer: S,
+        base_transaction: Transaction,
+        receiver_output_index: usize,
+    ) -> Result<Client<B, S>, Error> {
+        ClientBuilder::new(blockchain, signer, base_transaction)
+            .receiver_output_index(receiver_output_index)
+            .from_stream(stream, server)
+    }
+
+    pub fn set_config(&mut self, config: ClientConfig) {
+        self.config = config;
+    }
+
+    /// Access token to present to servers that require one, normally taken from the payment URI
+    pub fn set_token(&mut self, token: Option<String>) {
+        self.token = token;
+    }
+
+    /// Invoice to pay on servers waiting for more than one payment, normally taken from the
+    /// payment URI
+    pub fn set_invoice(&mut self, invoice: Option<String>) {
+        self.invoice = invoice;
+    }
+
+    /// Endpoint of the receiver, as in its payment URI, that the proof is bound to. Without it,
+    /// the proof is the same for every receiver
+    pub fn set_endpoint(&mut self, endpoint: Option<String>) {
+        self.endpoint = endpoint;
+    }
+
+    /// Local SOCKS port of the transport used to connect, if it goes through a proxy
+    pub fn socks_port(&self) -> Option<u16> {
+        self.socks_port
+    }
+
+    /// Report the progress of the negotiation to `observer`, like the UI of a wallet
+    pub fn set_observer<O: SessionObserver + 'static>(&mut self, observer: Arc<O>) {
+        self.observer = observer;
+    }
+
+    /// Token to stop the negotiation from another task, making it fail with `Error::Cancelled`
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Record the messages exchanged with the server in `transcript`, resumed sessions included.
+    /// Keep a clone of it to read them once the negotiation is over
+    pub fn set_transcript(&mut self, transcript: Transcript) {
+        self.transcript = Some(transcript);
+    }
+
+    /// Set the fee rate to the one estimated by `estimator` for confirmation within
+    /// `target_blocks` blocks
+    pub fn estimate_fee_rate<F>(
+        &mut self,
+        estimator: &F,
+        target_blocks: usize,
+    ) -> Result<FeeRate, Error>
+    where
+        F: FeeEstimator,
+        Error: From<<F as FeeEstimator>::Error>,
+    {
+        self.config.fee_rate = estimator.estimate_fee_rate(target_blocks)?;
+        debug!("Estimated fee rate: {:?}", self.config.fee_rate);
+
+        Ok(self.config.fee_rate)
     }
 
-    pub async fn start(&mut self) -> Result<Txid, Error> {
+    /// Send the signatures as PSBTs instead of raw witnesses, if the server supports them
+    pub fn use_psbt(&mut self, use_psbt: bool) {
+        if use_psbt {
+            self.capabilities.insert(Capabilities::PSBT);
+        } else {
+            self.capabilities.remove(Capabilities::PSBT);
+        }
+    }
+
+    pub async fn start(&mut self) -> Result<PaymentResult, Error> {
         info!("Client running!");
 
+        // Outputs are shuffled later, since their positions are sent to the server. The inputs
+        // are shuffled once here, so that a resumed session keeps matching the proof
+        if self.config.ordering == TxOrdering::Shuffle {
+            self.base_transaction.input.shuffle(&mut rand::thread_rng());
+        }
+        *self.session.lock().unwrap() = None;
+        self.resume = false;
+
+        self.run().await
+    }
+
+    /// Like `start()`, but broadcast `fallback`, a fully signed transaction paying the receiver
+    /// without payjoin, if the negotiation fails or takes longer than `FALLBACK_TIMEOUT`. Returns
+    /// whichever transaction was broadcast
+    ///
+    /// If the negotiation failed after the server got our signatures, the payjoin transaction may
+    /// have been broadcast too: the two spend the same inputs, so only one of them confirms.
+    pub async fn start_with_fallback(
+        &mut self,
+        fallback: Transaction,
+    ) -> Result<PaymentResult, Error> {
+        let receiver_txout = &self.base_transaction.output[self.receiver_output_index];
+        if !fallback.output.iter().any(|txout| {
+            txout.script_pubkey == receiver_txout.script_pubkey
+                && txout.value >= receiver_txout.value
+        }) {
+            return Err(ProtocolError::InvalidFallback.into());
+        }
+
+        let error = match timeout(FALLBACK_TIMEOUT, self.start()).await {
+            Ok(Ok(result)) => return Ok(result),
+            // Stopping means stopping, not paying some other way
+            Ok(Err(Error::Cancelled)) => return Err(Error::Cancelled),
+            Ok(Err(e)) => e,
+            Err(_) => Error::Timeout(TimeoutPhase::Session),
+        };
+        warn!(
+            "Negotiation failed, broadcasting the fallback transaction: {:?}",
+            error
+        );
+
+        let blockchain = Arc::clone(&self.blockchain);
+        let dry_run = self.config.dry_run;
+        let result = run_blocking(move || -> Result<_, Error> {
+            if !dry_run {
+                blockchain.broadcast(&fallback)?;
+            }
+            PaymentResult::new(&*blockchain, fallback, 0)
+        })
+        .await??;
+        if dry_run {
+            info!(
+                "Dry run, not broadcasting fallback transaction {}",
+                result.txid
+            );
+        } else {
+            info!("Broadcast fallback transaction {}", result.txid);
+        }
+
+        Ok(result)
+    }
+
+    /// Wait for `txid`, the payjoin or the fallback transaction, to get `confirmations`, waking
+    /// up whenever the backend reports an update on it. Wrap it in a timeout to give up at some
+    /// point
+    ///
+    /// Returns `false` right away if the backend doesn't track confirmations.
+    pub async fn wait_for_confirmation(
+        &self,
+        txid: Txid,
+        confirmations: u32,
+    ) -> Result<bool, Error> {
+        wait_for_confirmations(Arc::clone(&self.blockchain), txid, confirmations).await
+    }
+
+    /// Make sure the transaction of `result` gets broadcast. When the receiver delayed it, wait
+    /// until the time it promised and broadcast it ourselves if it hasn't propagated by then.
+    /// Returns whether we had to
+    ///
+    /// Meant to run in the background after a successful negotiation: until the transaction is
+    /// out, the inputs it spends are still available to the sender.
+    pub async fn ensure_broadcast(&self, result: &PaymentResult) -> Result<bool, Error> {
+        let will_broadcast_by = match result.will_broadcast_by {
+            Some(will_broadcast_by) if !self.config.dry_run => will_broadcast_by,
+            _ => return Ok(false),
+        };
+        if let Ok(delay) = will_broadcast_by.duration_since(SystemTime::now()) {
+            debug!("Receiver will broadcast within {:?}", delay);
+            delay_for(delay).await;
+        }
+
+        let blockchain = Arc::clone(&self.blockchain);
+        let transaction = result.transaction.clone();
+        run_blocking(move || -> Result<bool, Error> {
+            if is_propagated(&*blockchain, &transaction.txid())? {
+                return Ok(false);
+            }
+
+            warn!(
+                "Receiver didn't broadcast {}, broadcasting it",
+                transaction.txid()
+            );
+            broadcast_with_retry(&*blockchain, &transaction)?;
+            Ok(true)
+        })
+        .await?
+    }
+
+    /// Resume the last negotiation over a new connection to the server, after `start` failed
+    /// because the connection dropped
+    pub async fn resume<T: AsyncStream + 'static>(
+        &mut self,
+        stream: T,
+    ) -> Result<PaymentResult, Error> {
+        if self.session.lock().unwrap().is_none() {
+            return Err(ProtocolError::MissingData.into());
+        }
+        info!("Client resuming!");
+
+        self.stream = Box::new(stream);
+        self.resume = true;
+
+        self.run().await
+    }
+
+    async fn run(&mut self) -> Result<PaymentResult, Error> {
+        debug!("Negotiating with {}", self.server);
+
+        let shutdown = self.shutdown.clone();
         let state = ClientState::new(
             self.base_transaction.clone(),
             self.receiver_output_index,
-            &self.blockchain,
-            &self.signer,
-        );
-        let mut jsonrpc = JsonRpc::new(&mut self.stream, state, Duration::from_secs(10));
-        let (txid, _transaction) = jsonrpc.mainloop().await?;
+            Arc::clone(&self.blockchain),
+            Arc::clone(&self.signer),
+        )
+        .with_config(self.config.clone())
+        .with_capabilities(self.capabilities)
+        .with_token(self.token.clone())
+        .with_invoice(self.invoice.clone())
+        .with_endpoint(self.endpoint.clone())
+        .with_observer(Arc::clone(&self.observer))
+        .with_session(Arc::clone(&self.session), self.resume);
+        let mut jsonrpc = JsonRpc::new(&mut self.stream, state, self.config.message_timeout)
+            .with_session_timeout(self.config.session_timeout)
+            .with_max_message_size(self.config.max_message_size)
+            .with_transcript(self.transcript.clone());
+        let result = tokio::select! {
+            result = jsonrpc.mainloop() => result,
+            _ = shutdown.cancelled() => Err(Error::Cancelled),
+        };
+        if let Err(e) = &result {
+            self.observer.on_error(e);
+        }
+
+        result
+    }
 
-        Ok(txid)
+    #[cfg(feature = "bip78")]
+    pub async fn start_bip78(&mut self, path: &str) -> Result<PaymentResult, Error> {
+        use crate::protocol::bip78;
+
+        info!("Client running in BIP78 mode!");
+
+        let receiver_script = self.base_transaction.output[self.receiver_output_index]
+            .script_pubkey
+            .clone();
+
+        let base_transaction = self.base_transaction.clone();
+        let blockchain = Arc::clone(&self.blockchain);
+        let signer = Arc::clone(&self.signer);
+        let original = run_blocking(move || {
+            bip78::create_original_psbt(&base_transaction, &*blockchain, &*signer)
+        })
+        .await??;
+        let proposal = timeout(
+            self.config.message_timeout,
+            bip78::send_original(&mut self.stream, &self.server, path, &original),
+        )
+        .await
+        .map_err(|_| Error::Timeout(TimeoutPhase::Message))??;
+
+        bip78::check_proposal(&original, &proposal, &receiver_script)?;
+        let blockchain = Arc::clone(&self.blockchain);
+        let signer = Arc::clone(&self.signer);
+        let dry_run = self.config.dry_run;
+        run_blocking(move || -> Result<_, Error> {
+            let sender_inputs = original.global.unsigned_tx.input.len();
+            let transaction = bip78::finalize_proposal(&original, proposal, &*signer)?;
+            if !dry_run {
+                blockchain.broadcast(&transaction)?;
+            }
+
+            let receiver_inputs = transaction.input.len() - sender_inputs;
+            PaymentResult::new(&*blockchain, transaction, receiver_inputs)
+        })
+        .await?
     }
 }
@@ -0,0 +1,360 @@
+//! Selection of the sender's UTXOs funding the base transaction
+//!
+//! Selectors work on effective values: what a UTXO is worth once the fees of the input spending
+//! it are paid, so that adding an input never lowers the amount available.
+
+use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+
+use crate::common::{ScriptType, DUST_LIMIT, SEQUENCE_FINAL};
+use crate::fees::FeeRate;
+use crate::Error;
+
+/// Weight of an input without its witness: outpoint, empty `script_sig` and sequence
+const TXIN_BASE_WEIGHT: usize = (32 + 4 + 1 + 4) * 4;
+/// Witness of a P2WPKH input, the largest single-key one
+const P2WPKH_WITNESS_WEIGHT: usize = 1 + 1 + 72 + 1 + 33;
+/// Witness of a key-path P2TR input
+const P2TR_WITNESS_WEIGHT: usize = 1 + 1 + 64;
+/// `script_sig` of a P2SH-P2WPKH input, pushing the redeem script
+const P2SH_P2WPKH_SCRIPT_SIG_WEIGHT: usize = (1 + 1 + 22) * 4;
+/// A P2WPKH change output
+const CHANGE_OUTPUT_WEIGHT: usize = (8 + 1 + 22) * 4;
+/// Segwit marker and flag
+const SEGWIT_WEIGHT: usize = 2;
+/// Branches explored by `BranchAndBound` before giving up
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// A spendable UTXO, with the weight it adds to a transaction on top of an empty input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WeightedUtxo {
+    pub utxo: OutPoint,
+    pub txout: TxOut,
+    /// Weight of the `script_sig` and witness of the input spending it
+    pub satisfaction_weight: usize,
+}
+
+impl WeightedUtxo {
+    /// Weigh a UTXO spent with a single key. P2SH outputs are assumed to wrap P2WPKH
+    pub fn new(utxo: OutPoint, txout: TxOut) -> Self {
+        let satisfaction_weight = match ScriptType::of(&txout.script_pubkey) {
+            ScriptType::P2tr => P2TR_WITNESS_WEIGHT,
+            ScriptType::P2sh => P2SH_P2WPKH_SCRIPT_SIG_WEIGHT + P2WPKH_WITNESS_WEIGHT,
+            _ => P2WPKH_WITNESS_WEIGHT,
+        };
+
+        WeightedUtxo {
+            utxo,
+            txout,
+            satisfaction_weight,
+        }
+    }
+
+    /// Value left once the input spending it has paid for itself at `fee_rate`, or `None` if it
+    /// costs more than it's worth
+    pub fn effective_value(&self, fee_rate: FeeRate) -> Option<u64> {
+        let fee = fee_rate.fee_wu(TXIN_BASE_WEIGHT + self.satisfaction_weight);
+
+        self.txout.value.checked_sub(fee).filter(|value| *value > 0)
+    }
+}
+
+pub trait CoinSelector: std::fmt::Debug + Send + Sync {
+    /// Pick UTXOs whose effective values at `fee_rate` add up to at least `target`
+    fn select(
+        &self,
+        utxos: &[WeightedUtxo],
+        target: u64,
+        fee_rate: FeeRate,
+    ) -> Result<Vec<WeightedUtxo>, Error>;
+}
+
+/// UTXOs worth spending, paired with their effective values, the largest first
+fn by_effective_value(utxos: &[WeightedUtxo], fee_rate: FeeRate) -> Vec<(u64, &WeightedUtxo)> {
+    let mut utxos = utxos
+        .iter()
+        .filter_map(|utxo| Some((utxo.effective_value(fee_rate)?, utxo)))
+        .collect::<Vec<_>>();
+    utxos.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    utxos
+}
+
+/// Spend the largest UTXOs first, until the target is reached
+#[derive(Debug, Clone, Default)]
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(
+        &self,
+        utxos: &[WeightedUtxo],
+        target: u64,
+        fee_rate: FeeRate,
+    ) -> Result<Vec<WeightedUtxo>, Error> {
+        let mut selected = Vec::new();
+        let mut value = 0;
+        for (effective_value, utxo) in by_effective_value(utxos, fee_rate) {
+            if value >= target {
+                break;
+            }
+
+            value += effective_value;
+            selected.push(utxo.clone());
+        }
+
+        if value >= target {
+            Ok(selected)
+        } else {
+            Err(Error::InsufficientFunds)
+        }
+    }
+}
+
+/// Search for the selection closest to the target, as described by Murch in "An Evaluation of
+/// Coin Selection Strategies", falling back to `LargestFirst` when no selection exceeds the target
+/// by less than the cost of a change output
+#[derive(Debug, Clone, Default)]
+pub struct BranchAndBound;
+
+/// Depth-first search state of `BranchAndBound`
+struct Search<'a> {
+    values: &'a [u64],
+    target: u64,
+    upper_bound: u64,
+    tries: usize,
+    selection: Vec<usize>,
+    best: Option<(u64, Vec<usize>)>,
+}
+
+impl Search<'_> {
+    /// Explore the selections including or excluding the UTXO at `index`, given the value of
+    /// the current selection and the one of the UTXOs left after it
+    fn explore(&mut self, index: usize, value: u64, remaining: u64) {
+        if self.tries == 0 || self.best.as_ref().is_some_and(|(excess, _)| *excess == 0) {
+            return;
+        }
+        self.tries -= 1;
+
+        if value > self.upper_bound {
+            return;
+        } else if value >= self.target {
+            let excess = value - self.target;
+            if self.best.as_ref().is_none_or(|(best, _)| excess < *best) {
+                self.best = Some((excess, self.selection.clone()));
+            }
+            return;
+        } else if index == self.values.len() || value + remaining < self.target {
+            return;
+        }
+
+        let remaining = remaining - self.values[index];
+        self.selection.push(index);
+        self.explore(index + 1, value + self.values[index], remaining);
+        self.selection.pop();
+        self.explore(index + 1, value, remaining);
+    }
+}
+
+impl CoinSelector for BranchAndBound {
+    fn select(
+        &self,
+        utxos: &[WeightedUtxo],
+        target: u64,
+        fee_rate: FeeRate,
+    ) -> Result<Vec<WeightedUtxo>, Error> {
+        let candidates = by_effective_value(utxos, fee_rate);
+        let values = candidates
+            .iter()
+            .map(|(value, _)| *value)
+            .collect::<Vec<_>>();
+        // Adding a change output now and spending it later
+        let cost_of_change =
+            fee_rate.fee_wu(CHANGE_OUTPUT_WEIGHT + TXIN_BASE_WEIGHT + P2WPKH_WITNESS_WEIGHT);
+
+        let mut search = Search {
+            values: &values,
+            target,
+            upper_bound: target.saturating_add(cost_of_change),
+            tries: BNB_MAX_TRIES,
+            selection: Vec::new(),
+            best: None,
+        };
+        search.explore(0, 0, values.iter().sum());
+
+        match search.best {
+            Some((_, selection)) => Ok(selection
+                .into_iter()
+                .map(|index| candidates[index].1.clone())
+                .collect()),
+            None => LargestFirst.select(utxos, target, fee_rate),
+        }
+    }
+}
+
+/// Values of a receiver's UTXO that keep a payjoin of `amount` from the sender's inputs ambiguous
+///
+/// The sender's change is assumed to be whatever is left of its inputs after the payment. A UTXO
+/// larger than the change would leave it as the smallest output (UIH1), and one too small to make
+/// the payment the largest output would leave a sender input unnecessary (UIH2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UihRange {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl UihRange {
+    pub fn new(sender_inputs: &[u64], amount: u64) -> Self {
+        let total = sender_inputs.iter().sum::<u64>();
+        let largest = sender_inputs.iter().max().copied().unwrap_or(0);
+
+        UihRange {
+            min: largest.saturating_sub(amount),
+            max: total.saturating_sub(amount),
+        }
+    }
+
+    pub fn contains(&self, value: u64) -> bool {
+        value >= self.min && value <= self.max
+    }
+}
+
+/// Build a base transaction paying `payment` from some of `utxos`, picked by `selector`, with the
+/// change to `change_script` as the first output
+///
+/// The change is never dust, so that the base transaction can be broadcast as a fallback.
+pub fn base_transaction(
+    selector: &dyn CoinSelector,
+    utxos: &[WeightedUtxo],
+    payment: TxOut,
+    change_script: Script,
+    fee_rate: FeeRate,
+) -> Result<Transaction, Error> {
+    let payment_value = payment.value;
+    let mut transaction = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![],
+        output: vec![
+            TxOut {
+                script_pubkey: change_script,
+                value: 0,
+            },
+            payment,
+        ],
+    };
+
+    let fixed_fee = fee_rate.fee_wu(transaction.get_weight() + SEGWIT_WEIGHT);
+    let target = payment_value + fixed_fee + DUST_LIMIT;
+    let selected = selector.select(utxos, target, fee_rate)?;
+
+    transaction.input = selected
+        .iter()
+        .map(|utxo| TxIn {
+            previous_output: utxo.utxo,
+            sequence: SEQUENCE_FINAL,
+            ..Default::default()
+        })
+        .collect();
+    let weight = transaction.get_weight()
+        + SEGWIT_WEIGHT
+        + selected
+            .iter()
+            .map(|utxo| utxo.satisfaction_weight)
+            .sum::<usize>();
+    let total = selected.iter().map(|utxo| utxo.txout.value).sum::<u64>();
+    transaction.output[0].value = total
+        .checked_sub(payment_value + fee_rate.fee_wu(weight))
+        .filter(|change| *change >= DUST_LIMIT)
+        .ok_or(Error::InsufficientFunds)?;
+
+    Ok(transaction)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::script::Builder;
+
+    use super::*;
+
+    fn utxos(values: &[u64]) -> Vec<WeightedUtxo> {
+        let script = Builder::new()
+            .push_int(0)
+            .push_slice(&[0; 20])
+            .into_script();
+
+        values
+            .iter()
+            .enumerate()
+            .map(|(vout, value)| {
+                WeightedUtxo::new(
+                    OutPoint {
+                        txid: Default::default(),
+                        vout: vout as u32,
+                    },
+                    TxOut {
+                        script_pubkey: script.clone(),
+                        value: *value,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn values(selected: &[WeightedUtxo]) -> Vec<u64> {
+        let mut values = selected
+            .iter()
+            .map(|utxo| utxo.txout.value)
+            .collect::<Vec<_>>();
+        values.sort_unstable();
+
+        values
+    }
+
+    #[test]
+    fn test_coin_selection() {
+        // Effective values are 68 sats lower at 1 sat/vB
+        let fee_rate = FeeRate::default();
+        let utxos = utxos(&[100_000, 30_068, 20_068, 50]);
+        assert_eq!(utxos[3].effective_value(fee_rate), None);
+
+        let largest_first = LargestFirst.select(&utxos, 50_000, fee_rate).unwrap();
+        assert_eq!(values(&largest_first), vec![100_000]);
+        // Exactly the target, without any change
+        let bnb = BranchAndBound.select(&utxos, 50_000, fee_rate).unwrap();
+        assert_eq!(values(&bnb), vec![20_068, 30_068]);
+        // No match close enough, so the largest first
+        let bnb = BranchAndBound.select(&utxos, 10_000, fee_rate).unwrap();
+        assert_eq!(values(&bnb), vec![100_000]);
+
+        assert!(matches!(
+            BranchAndBound.select(&utxos, 200_000, fee_rate),
+            Err(Error::InsufficientFunds)
+        ));
+
+        let payment = TxOut {
+            script_pubkey: Script::new(),
+            value: 60_000,
+        };
+        let transaction =
+            base_transaction(&LargestFirst, &utxos, payment, Script::new(), fee_rate).unwrap();
+        assert_eq!(transaction.input.len(), 1);
+        assert!(transaction.output[0].value >= DUST_LIMIT);
+    }
+
+    #[test]
+    fn test_uih_range() {
+        // 60k of change and a 40k payment
+        let range = UihRange::new(&[70_000, 30_000], 40_000);
+        assert_eq!(
+            range,
+            UihRange {
+                min: 30_000,
+                max: 60_000
+            }
+        );
+        assert!(range.contains(45_000));
+        // Larger than the change
+        assert!(!range.contains(60_001));
+        // The payment output would be smaller than the first input
+        assert!(!range.contains(29_999));
+    }
+}
@@ -1,20 +1,77 @@
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
+use std::fmt;
 use std::ops::Deref;
 
 use serde::{Deserialize, Serialize};
 
 use bitcoin::blockdata::opcodes::all::*;
-use bitcoin::blockdata::script::Builder;
-use bitcoin::consensus::deserialize;
+use bitcoin::blockdata::opcodes::Class;
+use bitcoin::blockdata::script::{Builder, Instruction};
+use bitcoin::consensus::encode::{deserialize, Encodable};
+use bitcoin::hashes::{hash160, sha256, sha256d, Hash, HashEngine};
 use bitcoin::secp256k1::{All, Message as SecpMessage, Secp256k1, Signature};
 use bitcoin::util::bip143::SighashComponents;
-use bitcoin::{PublicKey, Script, Transaction, TxIn, TxOut};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, PublicKey, Script, SigHashType, Transaction, TxIn, TxOut};
 
-use crate::blockchain::Blockchain;
+use crate::blockchain::{Blockchain, TxSource};
 use crate::signer::Signer;
-use crate::{Error, WitnessWrapper};
+use crate::taproot;
+use crate::{Capabilities, Error, WitnessWrapper};
 
 const BTC: u64 = 100_000_000;
+/// Outputs below this value would be rejected as dust
+pub const DUST_LIMIT: u64 = 546;
+
+const SIGHASH_ALL: u8 = 0x01;
+const SIGHASH_ALL_ANYONECANPAY: u8 = 0x81;
+
+/// Sequence of the inputs of a final transaction that doesn't signal RBF
+pub const SEQUENCE_FINAL: u32 = 0xFFFF_FFFF;
+/// Sequence of the inputs of a final transaction with a locktime that doesn't signal RBF. The
+/// locktime is ignored when all the inputs are final
+pub const SEQUENCE_LOCKTIME: u32 = 0xFFFF_FFFE;
+/// Sequence of the inputs of a final transaction that signals RBF, as in BIP125
+pub const SEQUENCE_RBF: u32 = 0xFFFF_FFFD;
+
+/// Locktimes from this value on are timestamps rather than heights
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// How far behind the chain tip the height in the locktime of a proof can be. Wallets doing
+/// anti-fee-sniping sometimes pick an older height to blend in with delayed transactions
+pub const MAX_LOCKTIME_AGE: u32 = 100;
+
+/// Whether any input of `tx` signals RBF
+pub fn signals_rbf(tx: &Transaction) -> bool {
+    tx.input.iter().any(|input| input.sequence < 0xFFFF_FFFE)
+}
+
+/// Sequence of the receiver's inputs in a final transaction built on top of `proof`: they signal
+/// RBF when the sender does, so that the payment can be fee-bumped, and otherwise keep the
+/// locktime enforced
+pub fn receiver_sequence(proof: &Transaction) -> u32 {
+    if signals_rbf(proof) {
+        SEQUENCE_RBF
+    } else if proof.lock_time != 0 {
+        SEQUENCE_LOCKTIME
+    } else {
+        SEQUENCE_FINAL
+    }
+}
+
+/// Whether `lock_time` is either disabled or a height close to `height`, the tip of the chain.
+/// One block ahead is accepted in case the sender has seen a block we haven't yet
+pub fn is_valid_locktime(lock_time: u32, height: Option<u32>) -> bool {
+    match height {
+        _ if lock_time == 0 => true,
+        Some(height) => {
+            lock_time < LOCKTIME_THRESHOLD
+                && lock_time >= height.saturating_sub(MAX_LOCKTIME_AGE)
+                && lock_time <= height.saturating_add(1)
+        }
+        None => false,
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ProofTransactionError {
@@ -25,8 +82,74 @@ pub enum ProofTransactionError {
     InvalidInputSignature(usize),
     MissingUTXO(usize),
     InputIsSpent(usize),
+    /// More inputs than allowed by the `ProofPolicy`
+    TooManyInputs,
+    /// The input spends a UTXO with fewer confirmations than required
+    UnconfirmedInput(usize),
+    /// The inputs are worth less than required
+    InsufficientValue,
+    /// Supported, but not allowed by the `ProofPolicy`
+    DisallowedInputType(usize),
+    /// The inputs signal RBF, which is not allowed by the `ProofPolicy`
+    RbfNotAllowed,
+    /// The signature doesn't use `SIGHASH_ALL`, or `SIGHASH_ALL | SIGHASH_ANYONECANPAY` where
+    /// accepted
+    InvalidSighashType(usize),
+    /// The unconfirmed inputs are too risky for the `ZeroConfPolicy`
+    RiskyUnconfirmedInputs,
 }
 
+impl fmt::Display for ProofTransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProofTransactionError::InvalidVersion => write!(f, "the version must be 2"),
+            ProofTransactionError::InvalidLocktime => {
+                write!(f, "the locktime must be 0 or close to the current height")
+            }
+            ProofTransactionError::InvalidProofOutput => {
+                write!(f, "the only output must be the unspendable proof output")
+            }
+            ProofTransactionError::InvalidInputType(index) => {
+                write!(f, "unsupported type of input #{}", index)
+            }
+            ProofTransactionError::InvalidInputSignature(index) => {
+                write!(f, "invalid signature on input #{}", index)
+            }
+            ProofTransactionError::MissingUTXO(index) => {
+                write!(f, "the UTXO spent by input #{} doesn't exist", index)
+            }
+            ProofTransactionError::InputIsSpent(index) => {
+                write!(f, "the UTXO spent by input #{} is already spent", index)
+            }
+            ProofTransactionError::TooManyInputs => write!(f, "too many inputs"),
+            ProofTransactionError::UnconfirmedInput(index) => {
+                write!(
+                    f,
+                    "the UTXO spent by input #{} is not confirmed enough",
+                    index
+                )
+            }
+            ProofTransactionError::InsufficientValue => {
+                write!(f, "the inputs are not worth enough for the payment")
+            }
+            ProofTransactionError::DisallowedInputType(index) => {
+                write!(f, "type of input #{} not accepted by the receiver", index)
+            }
+            ProofTransactionError::RbfNotAllowed => {
+                write!(f, "the receiver doesn't accept RBF-signaling inputs")
+            }
+            ProofTransactionError::InvalidSighashType(index) => {
+                write!(f, "unexpected sighash type on input #{}", index)
+            }
+            ProofTransactionError::RiskyUnconfirmedInputs => {
+                write!(f, "the receiver doesn't accept these unconfirmed inputs")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProofTransactionError {}
+
 pub trait ValidationContext {}
 
 #[derive(Debug, Clone)]
@@ -49,71 +172,517 @@ impl<C: ValidationContext> ProofTransaction<C> {
     }
 }
 
+/// Signature hash of input `index` for `SIGHASH_ALL | SIGHASH_ANYONECANPAY`, as in BIP143. Like
+/// `sighash_single_anyonecanpay()`, for what `SighashComponents` doesn't cover
+pub fn sighash_all_anyonecanpay(
+    tx: &Transaction,
+    index: usize,
+    script_code: &Script,
+    value: u64,
+) -> sha256d::Hash {
+    let input = &tx.input[index];
+    let mut outputs = sha256d::Hash::engine();
+    for output in &tx.output {
+        output.consensus_encode(&mut outputs).unwrap();
+    }
+
+    let mut engine = sha256d::Hash::engine();
+    tx.version.consensus_encode(&mut engine).unwrap();
+    // No commitment to the other inputs and their sequences
+    engine.input(&[0; 64]);
+    input.previous_output.consensus_encode(&mut engine).unwrap();
+    script_code.consensus_encode(&mut engine).unwrap();
+    value.consensus_encode(&mut engine).unwrap();
+    input.sequence.consensus_encode(&mut engine).unwrap();
+    engine.input(&sha256d::Hash::from_engine(outputs)[..]);
+    tx.lock_time.consensus_encode(&mut engine).unwrap();
+    SigHashType::AllPlusAnyoneCanPay
+        .as_u32()
+        .consensus_encode(&mut engine)
+        .unwrap();
+
+    sha256d::Hash::from_engine(engine)
+}
+
+/// Split the sighash type off a v0 `signature` and hash input `index` accordingly. Only
+/// `SIGHASH_ALL` is accepted, and `SIGHASH_ALL | SIGHASH_ANYONECANPAY` too with `anyonecanpay`
+fn segwit_v0_sighash<'a>(
+    comp: &SighashComponents,
+    tx: &Transaction,
+    index: usize,
+    signature: &'a [u8],
+    script_code: &Script,
+    value: u64,
+    anyonecanpay: bool,
+) -> Result<(&'a [u8], SecpMessage), ProofTransactionError> {
+    let (sighash_type, signature) = signature
+        .split_last()
+        .ok_or(ProofTransactionError::InvalidInputSignature(index))?;
+    let hash = match *sighash_type {
+        SIGHASH_ALL => comp
+            .sighash_all(&tx.input[index], script_code, value)
+            .into_inner(),
+        SIGHASH_ALL_ANYONECANPAY if anyonecanpay => {
+            sighash_all_anyonecanpay(tx, index, script_code, value).into_inner()
+        }
+        _ => return Err(ProofTransactionError::InvalidSighashType(index)),
+    };
+
+    Ok((signature, SecpMessage::from_slice(&hash).unwrap()))
+}
+
+/// Verify the signature of v0 P2WPKH input `index` spending `prev_out`
+pub(crate) fn verify_p2wpkh_input(
+    secp: &Secp256k1<All>,
+    comp: &SighashComponents,
+    tx: &Transaction,
+    index: usize,
+    prev_out: &TxOut,
+    anyonecanpay: bool,
+) -> Result<(), ProofTransactionError> {
+    let input = &tx.input[index];
+    let pubkey_hash = &prev_out.script_pubkey.as_bytes()[2..];
+    let script_code = Builder::new()
+        .push_opcode(OP_DUP)
+        .push_opcode(OP_HASH160)
+        .push_slice(pubkey_hash)
+        .push_opcode(OP_EQUALVERIFY)
+        .push_opcode(OP_CHECKSIG)
+        .into_script();
+    let (signature, pubkey) = match input.witness.as_slice() {
+        [signature, pubkey] => (signature, pubkey),
+        _ => return Err(ProofTransactionError::InvalidInputSignature(index)),
+    };
+    // Otherwise any key would do, as long as it signs the right sighash
+    if hash160::Hash::hash(pubkey).into_inner() != pubkey_hash {
+        return Err(ProofTransactionError::InvalidInputSignature(index));
+    }
+    let (signature, hash) = segwit_v0_sighash(
+        comp,
+        tx,
+        index,
+        signature,
+        &script_code,
+        prev_out.value,
+        anyonecanpay,
+    )?;
+
+    secp.verify(
+        &hash,
+        &Signature::from_der(signature)
+            .map_err(|_| ProofTransactionError::InvalidInputSignature(index))?,
+        &PublicKey::from_slice(pubkey)
+            .map_err(|_| ProofTransactionError::InvalidInputSignature(index))?
+            .key,
+    )
+    .map_err(|_| ProofTransactionError::InvalidInputSignature(index))
+}
+
+/// Verify the signature of P2SH-P2WPKH input `index` spending `prev_out`. The `script_sig` must
+/// only push the P2WPKH redeem script
+pub(crate) fn verify_p2sh_p2wpkh_input(
+    secp: &Secp256k1<All>,
+    comp: &SighashComponents,
+    tx: &Transaction,
+    index: usize,
+    prev_out: &TxOut,
+    anyonecanpay: bool,
+) -> Result<(), ProofTransactionError> {
+    let redeem_script = redeem_script(&tx.input[index].script_sig)
+        .filter(|redeem_script| redeem_script.to_p2sh() == prev_out.script_pubkey)
+        .ok_or(ProofTransactionError::InvalidInputSignature(index))?;
+    if !redeem_script.is_v0_p2wpkh() {
+        return Err(ProofTransactionError::InvalidInputType(index));
+    }
+
+    let prev_out = TxOut {
+        value: prev_out.value,
+        script_pubkey: redeem_script,
+    };
+    verify_p2wpkh_input(secp, comp, tx, index, &prev_out, anyonecanpay)
+}
+
+/// Extract the redeem script from a `script_sig` that only pushes it
+fn redeem_script(script_sig: &Script) -> Option<Script> {
+    match script_sig.iter(true).collect::<Vec<_>>().as_slice() {
+        [Instruction::PushBytes(redeem_script)] => Some(redeem_script.to_vec().into()),
+        _ => None,
+    }
+}
+
+/// Parse a bare `OP_m <pubkeys> OP_n OP_CHECKMULTISIG` script, returning the threshold and the keys
+pub fn parse_multisig(script: &Script) -> Option<(usize, Vec<PublicKey>)> {
+    fn pushnum(instruction: &Instruction) -> Option<usize> {
+        match instruction {
+            Instruction::Op(op) => match op.classify() {
+                Class::PushNum(n) if n > 0 => Some(n as usize),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    let instructions = script.iter(true).collect::<Vec<_>>();
+    let (threshold, instructions) = instructions.split_first()?;
+    let (checkmultisig, instructions) = instructions.split_last()?;
+    let (total, keys) = instructions.split_last()?;
+
+    let threshold = pushnum(threshold)?;
+    let total = pushnum(total)?;
+    if !matches!(checkmultisig, Instruction::Op(op) if *op == OP_CHECKMULTISIG)
+        || keys.len() != total
+        || threshold > total
+    {
+        return None;
+    }
+
+    let keys = keys
+        .iter()
+        .map(|key| match key {
+            Instruction::PushBytes(bytes) => PublicKey::from_slice(bytes).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some((threshold, keys))
+}
+
+/// Verify the signatures of v0 P2WSH input `index` spending `prev_out`. The witness script must be
+/// a bare multisig
+pub(crate) fn verify_p2wsh_input(
+    secp: &Secp256k1<All>,
+    comp: &SighashComponents,
+    tx: &Transaction,
+    index: usize,
+    prev_out: &TxOut,
+    anyonecanpay: bool,
+) -> Result<(), ProofTransactionError> {
+    let input = &tx.input[index];
+    let witness_script: Script = input
+        .witness
+        .last()
+        .ok_or(ProofTransactionError::InvalidInputSignature(index))?
+        .clone()
+        .into();
+    if witness_script.to_v0_p2wsh() != prev_out.script_pubkey {
+        return Err(ProofTransactionError::InvalidInputSignature(index));
+    }
+    let (threshold, keys) =
+        parse_multisig(&witness_script).ok_or(ProofTransactionError::InvalidInputType(index))?;
+
+    // Dummy element consumed by OP_CHECKMULTISIG, followed by the signatures
+    let signatures = match input.witness.split_last() {
+        Some((_, [dummy, signatures @ ..]))
+            if dummy.is_empty() && signatures.len() == threshold =>
+        {
+            signatures
+        }
+        _ => return Err(ProofTransactionError::InvalidInputSignature(index)),
+    };
+
+    // Like OP_CHECKMULTISIG, signatures must be in the same order as the keys
+    let mut keys = keys.iter();
+    for signature in signatures {
+        let (signature, hash) = segwit_v0_sighash(
+            comp,
+            tx,
+            index,
+            signature,
+            &witness_script,
+            prev_out.value,
+            anyonecanpay,
+        )?;
+        let signature = Signature::from_der(signature)
+            .map_err(|_| ProofTransactionError::InvalidInputSignature(index))?;
+
+        if !keys.any(|key| secp.verify(&hash, &signature, &key.key).is_ok()) {
+            return Err(ProofTransactionError::InvalidInputSignature(index));
+        }
+    }
+
+    Ok(())
+}
+
+/// Verify the signature of input `index`, which can be v0 P2WPKH (native or nested in P2SH), v0
+/// P2WSH multisig or a key-path spend of a v1 P2TR. `prev_outs` must contain the outputs spent by
+/// all the inputs of `tx`
+///
+/// Signatures must commit to the whole transaction with `SIGHASH_ALL`. With `anyonecanpay`, v0
+/// signatures can use `SIGHASH_ALL | SIGHASH_ANYONECANPAY` too, like the ones of a proof that only
+/// commit to their own input.
+pub(crate) fn verify_input(
+    secp: &Secp256k1<All>,
+    comp: &SighashComponents,
+    tx: &Transaction,
+    index: usize,
+    prev_outs: &[TxOut],
+    anyonecanpay: bool,
+) -> Result<(), ProofTransactionError> {
+    let prev_out = &prev_outs[index];
+
+    if prev_out.script_pubkey.is_v0_p2wpkh() {
+        verify_p2wpkh_input(secp, comp, tx, index, prev_out, anyonecanpay)
+    } else if prev_out.script_pubkey.is_v0_p2wsh() {
+        verify_p2wsh_input(secp, comp, tx, index, prev_out, anyonecanpay)
+    } else if prev_out.script_pubkey.is_p2sh() {
+        verify_p2sh_p2wpkh_input(secp, comp, tx, index, prev_out, anyonecanpay)
+    } else if taproot::is_p2tr(&prev_out.script_pubkey) {
+        let secp = secp256k1_schnorr::Secp256k1::new();
+
+        if matches!(
+            tx.input[index].witness.as_slice(),
+            [signature] if signature.len() == 65 && signature[64] != taproot::SIGHASH_ALL
+        ) {
+            Err(ProofTransactionError::InvalidSighashType(index))
+        } else if taproot::verify_key_spend(&secp, tx, index, prev_outs) {
+            Ok(())
+        } else {
+            Err(ProofTransactionError::InvalidInputSignature(index))
+        }
+    } else {
+        Err(ProofTransactionError::InvalidInputType(index))
+    }
+}
+
+/// Whether an input spending `script` can be verified by `verify_input()`
+pub(crate) fn is_supported_input(script: &Script) -> bool {
+    script.is_v0_p2wpkh() || script.is_v0_p2wsh() || script.is_p2sh() || taproot::is_p2tr(script)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptType {
+    P2wpkh,
+    P2wsh,
+    P2sh,
+    P2tr,
+    Other,
+}
+
+impl ScriptType {
+    pub fn of(script: &Script) -> Self {
+        if script.is_v0_p2wpkh() {
+            ScriptType::P2wpkh
+        } else if script.is_v0_p2wsh() {
+            ScriptType::P2wsh
+        } else if script.is_p2sh() {
+            ScriptType::P2sh
+        } else if taproot::is_p2tr(script) {
+            ScriptType::P2tr
+        } else {
+            ScriptType::Other
+        }
+    }
+}
+
+/// Receiver's requirements on the inputs of a proof, on top of its validity
+///
+/// Confirmations are only checked when the backend knows them.
+#[derive(Debug, Clone)]
+pub struct ProofPolicy {
+    pub max_inputs: Option<usize>,
+    pub min_confirmations: u32,
+    /// Minimum value of the inputs, as a percentage of the payment
+    pub min_value_percent: u64,
+    pub allowed_script_types: Vec<ScriptType>,
+    /// Accept inputs signaling RBF, in which case ours will signal it too
+    pub allow_rbf: bool,
+}
+
+impl Default for ProofPolicy {
+    fn default() -> Self {
+        ProofPolicy {
+            max_inputs: None,
+            min_confirmations: 0,
+            min_value_percent: 100,
+            allowed_script_types: vec![
+                ScriptType::P2wpkh,
+                ScriptType::P2wsh,
+                ScriptType::P2sh,
+                ScriptType::P2tr,
+            ],
+            allow_rbf: true,
+        }
+    }
+}
+
+/// Outputs spent by the inputs of `tx`, with a single lookup of their transactions
+fn get_prev_outs<B>(tx: &Transaction, blockchain: &B) -> Result<Vec<TxOut>, Error>
+where
+    B: Blockchain,
+    Error: From<<B as TxSource>::Error>,
+{
+    let mut txids = tx
+        .input
+        .iter()
+        .map(|input| input.previous_output.txid)
+        .collect::<Vec<_>>();
+    txids.sort();
+    txids.dedup();
+    let prev_txs = txids
+        .iter()
+        .cloned()
+        .zip(blockchain.get_txs(&txids)?)
+        .collect::<HashMap<_, _>>();
+
+    tx.input
+        .iter()
+        .enumerate()
+        .map(|(index, input)| {
+            prev_txs
+                .get(&input.previous_output.txid)
+                .and_then(|prev_tx| prev_tx.output.get(input.previous_output.vout as usize))
+                .cloned()
+                .ok_or_else(|| ProofTransactionError::MissingUTXO(index).into())
+        })
+        .collect()
+}
+
+impl ProofPolicy {
+    /// Check the inputs of `tx`, a proof for a payment of `amount` satoshis. Meant to run before
+    /// the more expensive validation of the signatures
+    pub fn check<B>(&self, tx: &Transaction, amount: u64, blockchain: &B) -> Result<(), Error>
+    where
+        B: Blockchain,
+        Error: From<<B as TxSource>::Error>,
+    {
+        if self.max_inputs.is_some_and(|max| tx.input.len() > max) {
+            return Err(ProofTransactionError::TooManyInputs.into());
+        } else if !self.allow_rbf && signals_rbf(tx) {
+            return Err(ProofTransactionError::RbfNotAllowed.into());
+        }
+
+        let mut value = 0u64;
+        let prev_outs = get_prev_outs(tx, blockchain)?;
+        for (index, (input, prev_out)) in tx.input.iter().zip(prev_outs).enumerate() {
+            let txid = &input.previous_output.txid;
+            if !is_supported_input(&prev_out.script_pubkey) {
+                return Err(ProofTransactionError::InvalidInputType(index).into());
+            } else if !self
+                .allowed_script_types
+                .contains(&ScriptType::of(&prev_out.script_pubkey))
+            {
+                return Err(ProofTransactionError::DisallowedInputType(index).into());
+            }
+            if blockchain
+                .get_confirmations(txid)?
+                .is_some_and(|confirmations| confirmations < self.min_confirmations)
+            {
+                return Err(ProofTransactionError::UnconfirmedInput(index).into());
+            }
+
+            value = value.saturating_add(prev_out.value);
+        }
+
+        if u128::from(value) * 100 < u128::from(amount) * u128::from(self.min_value_percent) {
+            return Err(ProofTransactionError::InsufficientValue.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// What the output of a proof is bound to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProofContext {
+    /// Endpoint of the receiver, as in its payment URI
+    pub endpoint: Option<String>,
+    /// Invoice picked in the VERSION request
+    pub invoice: Option<String>,
+    /// Challenge of the receiver in its VERSION response
+    pub nonce: Option<String>,
+}
+
+/// The only output of a proof, agreed on by both sides. Whatever it is, it must make the proof
+/// invalid, so that the receiver can never broadcast it
+pub trait ProofScheme: fmt::Debug + Send + Sync {
+    /// Output of a proof for the receiver in `context`
+    fn output(&self, context: &ProofContext) -> TxOut;
+}
+
+/// 21M BTC to an empty script, the same output for every receiver
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LegacyProof;
+
+impl ProofScheme for LegacyProof {
+    fn output(&self, _context: &ProofContext) -> TxOut {
+        TxOut {
+            value: 21_000_000 * BTC,
+            script_pubkey: Script::new(),
+        }
+    }
+}
+
+/// 21M BTC to an `OP_RETURN` committing to the endpoint and the invoice of the receiver, so that a
+/// proof made for one receiver is rejected by any other, and to the nonce of the session, so that
+/// it can't even be relayed to another session of the same receiver
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BoundProof;
+
+impl ProofScheme for BoundProof {
+    fn output(&self, context: &ProofContext) -> TxOut {
+        let mut engine = sha256::Hash::engine();
+        engine.input(b"libp2ep/proof");
+        for field in &[&context.endpoint, &context.invoice, &context.nonce] {
+            let field = field.as_deref().unwrap_or_default().as_bytes();
+            engine.input(&(field.len() as u64).to_le_bytes());
+            engine.input(field);
+        }
+        let commitment = sha256::Hash::from_engine(engine);
+
+        TxOut {
+            value: 21_000_000 * BTC,
+            script_pubkey: Builder::new()
+                .push_opcode(OP_RETURN)
+                .push_slice(&commitment[..])
+                .into_script(),
+        }
+    }
+}
+
+/// Scheme of the proofs in a session with the negotiated `capabilities`
+pub fn proof_scheme(capabilities: Capabilities) -> &'static dyn ProofScheme {
+    if capabilities.contains(Capabilities::BOUND_PROOF) {
+        &BoundProof
+    } else {
+        &LegacyProof
+    }
+}
+
 /// Make sure that a transaction is a valid "proof" transaction
-impl<B> TryFrom<(Transaction, &B)> for ProofTransaction<Validated>
+///
+/// Its only output must be `proof_output`, as given by the `ProofScheme` of the session
+impl<B> TryFrom<(Transaction, &B, &TxOut)> for ProofTransaction<Validated>
 where
     B: Blockchain,
-    Error: From<<B as Blockchain>::Error>,
+    Error: From<<B as TxSource>::Error>,
 {
     type Error = Error;
 
-    fn try_from(data: (Transaction, &B)) -> Result<Self, Self::Error> {
-        let (tx, blockchain) = data;
+    fn try_from(data: (Transaction, &B, &TxOut)) -> Result<Self, Self::Error> {
+        let (tx, blockchain, proof_output) = data;
 
         if tx.version != 2 {
             Err(ProofTransactionError::InvalidVersion.into())
-        } else if tx.lock_time != 0 {
+        } else if !is_valid_locktime(tx.lock_time, blockchain.get_height()?) {
             Err(ProofTransactionError::InvalidLocktime.into())
-        } else if tx.output.len() != 1
-            || tx.output[0].value != 21_000_000 * BTC
-            || !tx.output[0].script_pubkey.is_empty()
-        {
+        } else if tx.output.len() != 1 || tx.output[0] != *proof_output {
             Err(ProofTransactionError::InvalidProofOutput.into())
         } else {
             let secp: Secp256k1<All> = Secp256k1::gen_new();
             let comp = SighashComponents::new(&tx);
 
-            for (index, input) in tx.input.iter().enumerate() {
-                let prev_tx = blockchain.get_tx(&input.previous_output.txid)?;
-                let prev_out = prev_tx
-                    .output
-                    .get(input.previous_output.vout as usize)
-                    .ok_or(ProofTransactionError::MissingUTXO(index))?;
-
-                if !prev_out.script_pubkey.is_v0_p2wpkh() {
+            // Taproot signatures commit to all the spent outputs, so fetch them first
+            let prev_outs = get_prev_outs(&tx, blockchain)?;
+            for (index, (input, prev_out)) in tx.input.iter().zip(&prev_outs).enumerate() {
+                if !is_supported_input(&prev_out.script_pubkey) {
                     return Err(ProofTransactionError::InvalidInputType(index).into());
                 } else if !blockchain.is_unspent(&input.previous_output)? {
                     return Err(ProofTransactionError::InputIsSpent(index).into());
                 }
+            }
 
-                let pubkey = &prev_out.script_pubkey.as_bytes()[2..];
-                let script_code = Builder::new()
-                    .push_opcode(OP_DUP)
-                    .push_opcode(OP_HASH160)
-                    .push_slice(pubkey)
-                    .push_opcode(OP_EQUALVERIFY)
-                    .push_opcode(OP_CHECKSIG)
-                    .into_script();
-                let hash = comp.sighash_all(&input, &script_code, prev_out.value);
-                let signature = input
-                    .witness
-                    .get(0)
-                    .ok_or(ProofTransactionError::InvalidInputSignature(index))?;
-                let pubkey = input
-                    .witness
-                    .get(1)
-                    .ok_or(ProofTransactionError::InvalidInputSignature(index))?;
-                let sig_len = signature.len() - 1;
-
-                secp.verify(
-                    &SecpMessage::from_slice(&hash).unwrap(),
-                    &Signature::from_der(&signature[..sig_len])
-                        .map_err(|_| ProofTransactionError::InvalidInputSignature(index))?,
-                    &PublicKey::from_slice(&pubkey)
-                        .map_err(|_| ProofTransactionError::InvalidInputSignature(index))?
-                        .key,
-                )
-                .map_err(|_| ProofTransactionError::InvalidInputSignature(index))?;
+            for index in 0..tx.input.len() {
+                verify_input(&secp, &comp, &tx, index, &prev_outs, true)?;
             }
 
             Ok(ProofTransaction(tx, std::marker::PhantomData))
@@ -123,38 +692,61 @@ where
 
 /// Turn a normal transaction into a "proof" transaction
 ///
-/// It will strip all the outputs and add the 21M BTC one
-impl<S> TryFrom<(Transaction, &S)> for ProofTransaction<Created>
+/// It will strip all the outputs and add `proof_output`, as given by the `ProofScheme` of the
+/// session
+impl<S> TryFrom<(Transaction, &S, &TxOut)> for ProofTransaction<Created>
 where
     S: Signer,
     Error: From<<S as Signer>::Error>,
 {
     type Error = Error;
 
-    fn try_from(data: (Transaction, &S)) -> Result<Self, Self::Error> {
-        let (mut tx, signer) = data;
+    fn try_from(data: (Transaction, &S, &TxOut)) -> Result<Self, Self::Error> {
+        let (tx, signer, proof_output) = data;
+        let mut tx = unsigned_proof(tx, proof_output)?;
 
-        if tx.version != 2 {
-            Err(ProofTransactionError::InvalidVersion.into())
-        } else if tx.lock_time != 0 {
-            Err(ProofTransactionError::InvalidLocktime.into())
-        } else {
-            tx.output.clear();
-            tx.output.push(TxOut {
-                value: 21_000_000 * BTC,
-                script_pubkey: Script::new(),
-            });
+        let inputs_to_sign = (0..tx.input.len()).collect::<Vec<_>>();
+        signer.sign(&mut tx, &inputs_to_sign)?;
 
-            for input in &mut tx.input {
-                input.script_sig = Script::new();
-                input.witness.clear();
-            }
+        Ok(ProofTransaction(tx, std::marker::PhantomData))
+    }
+}
 
-            let inputs_to_sign = (0..tx.input.len()).collect::<Vec<_>>();
-            signer.sign(&mut tx, &inputs_to_sign)?;
+impl ProofTransaction<Created> {
+    /// Like `try_from()`, but signed with `SIGHASH_ALL | SIGHASH_ANYONECANPAY` if `signer`
+    /// supports it, so that the signatures only commit to their own input and the proof output
+    pub fn anyonecanpay<S>(tx: Transaction, signer: &S, proof_output: &TxOut) -> Result<Self, Error>
+    where
+        S: Signer,
+        Error: From<<S as Signer>::Error>,
+    {
+        let mut proof = unsigned_proof(tx.clone(), proof_output)?;
 
-            Ok(ProofTransaction(tx, std::marker::PhantomData))
+        let inputs_to_sign = (0..proof.input.len()).collect::<Vec<_>>();
+        if signer.sign_all_anyonecanpay(&mut proof, &inputs_to_sign)? {
+            Ok(ProofTransaction(proof, std::marker::PhantomData))
+        } else {
+            ProofTransaction::try_from((tx, signer, proof_output))
+        }
+    }
+}
+
+/// `tx` with `proof_output` as its only output and without signatures
+fn unsigned_proof(mut tx: Transaction, proof_output: &TxOut) -> Result<Transaction, Error> {
+    if tx.version != 2 {
+        Err(ProofTransactionError::InvalidVersion.into())
+    } else if tx.lock_time >= LOCKTIME_THRESHOLD {
+        Err(ProofTransactionError::InvalidLocktime.into())
+    } else {
+        tx.output.clear();
+        tx.output.push(proof_output.clone());
+
+        for input in &mut tx.input {
+            input.script_sig = Script::new();
+            input.witness.clear();
         }
+
+        Ok(tx)
     }
 }
 
@@ -169,22 +761,87 @@ impl<C: ValidationContext> Deref for ProofTransaction<C> {
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum FinalTransactionError {
     NegativeSenderAmount,
+    InvalidSenderOutputIndex,
     InvalidReceiverInputSequence,
     InvalidReceiverInputNonEmptySig,
     InvalidReceiverInputIndex,
     InvalidReceiverOutputIndex,
+    InvalidReceiverPayment,
     InvalidWitness,
+    /// The witness of the sender's input doesn't sign the final transaction
+    InvalidSenderSignature(usize),
+    /// The sender's input isn't signed with `SIGHASH_ALL`
+    InvalidSenderSighashType(usize),
+    PsbtMismatch,
+    /// An output is worth less than `DUST_LIMIT`
+    DustOutput(usize),
+    /// The receiver's change is worth more than its inputs, or shares the script of its payment
+    InvalidReceiverChange,
+}
+
+impl fmt::Display for FinalTransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            FinalTransactionError::NegativeSenderAmount => {
+                "the sender's inputs can't cover the payment and the fees"
+            }
+            FinalTransactionError::InvalidSenderOutputIndex => "invalid position of the change",
+            FinalTransactionError::InvalidReceiverInputSequence => {
+                "invalid sequence of the receiver's input"
+            }
+            FinalTransactionError::InvalidReceiverInputNonEmptySig => {
+                "the receiver's input is already signed"
+            }
+            FinalTransactionError::InvalidReceiverInputIndex => {
+                "invalid position of the receiver's inputs"
+            }
+            FinalTransactionError::InvalidReceiverOutputIndex => {
+                "invalid position of the receiver's output"
+            }
+            FinalTransactionError::InvalidReceiverPayment => {
+                "the receiver is not paid the expected amount"
+            }
+            FinalTransactionError::InvalidReceiverChange => "invalid change of the receiver",
+            FinalTransactionError::InvalidWitness => "invalid witness of the sender",
+            FinalTransactionError::PsbtMismatch => "the PSBT doesn't match the final transaction",
+            FinalTransactionError::InvalidSenderSignature(index) => {
+                return write!(f, "invalid signature of the sender for input #{}", index);
+            }
+            FinalTransactionError::InvalidSenderSighashType(index) => {
+                return write!(
+                    f,
+                    "input #{} of the sender isn't signed with SIGHASH_ALL",
+                    index
+                );
+            }
+            FinalTransactionError::DustOutput(index) => {
+                return write!(f, "output #{} is dust", index);
+            }
+        };
+
+        write!(f, "{}", message)
+    }
 }
 
+impl std::error::Error for FinalTransactionError {}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct FinalTransactionMeta<C: ValidationContext> {
     pub tx: ProofTransaction<C>,
     pub fees: u64,
+    /// Script of the sender's change output, inserted at `sender_output_index`
     pub sender_script: Script,
-    pub receiver_txin: TxIn,
-    pub receiver_input_index: usize,
+    pub sender_output_index: usize,
+    /// Other outputs paid by the sender, like the rest of a batch of payments
+    pub sender_txouts: Vec<TxOut>,
+    /// Inputs contributed by the receiver, inserted at the matching `receiver_input_indexes`
+    pub receiver_txins: Vec<TxIn>,
+    pub receiver_input_indexes: Vec<usize>,
     pub receiver_txout: TxOut,
     pub receiver_output_index: usize,
+    /// Change of the receiver with its index, taken out of the value of its inputs instead of
+    /// being added to `receiver_txout`
+    pub receiver_change: Option<(usize, TxOut)>,
 }
 
 pub trait SignedContext {}
@@ -203,7 +860,7 @@ impl SignedContext for Signed {}
 pub struct FinalTransaction<S: SignedContext> {
     #[serde(serialize_with = "crate::to_hex")]
     transaction: Transaction,
-    receiver_input_index: usize,
+    receiver_input_indexes: Vec<usize>,
 
     phantom: std::marker::PhantomData<S>,
 }
@@ -212,13 +869,27 @@ impl<S: SignedContext> FinalTransaction<S> {
     pub fn into_inner(self) -> Transaction {
         self.transaction
     }
+
+    /// Wrap a transaction that another party has built and validated, like in a chained payjoin
+    /// (see `multiparty`)
+    pub(crate) fn from_parts(transaction: Transaction, receiver_input_indexes: Vec<usize>) -> Self {
+        FinalTransaction {
+            transaction,
+            receiver_input_indexes,
+            phantom: std::marker::PhantomData,
+        }
+    }
+
+    pub(crate) fn receiver_input_indexes(&self) -> &[usize] {
+        &self.receiver_input_indexes
+    }
 }
 
 impl<B, C> TryFrom<(FinalTransactionMeta<C>, &B)> for FinalTransaction<Unsigned>
 where
     C: ValidationContext,
     B: Blockchain,
-    Error: From<<B as Blockchain>::Error>,
+    Error: From<<B as TxSource>::Error>,
 {
     type Error = Error;
 
@@ -228,13 +899,20 @@ where
             tx,
             fees,
             sender_script,
-            receiver_txin,
-            receiver_input_index,
+            sender_output_index,
+            sender_txouts,
+            receiver_txins,
+            receiver_input_indexes,
             mut receiver_txout,
-            receiver_output_index,
+            mut receiver_output_index,
+            mut receiver_change,
         } = meta;
         let mut tx = tx.into_inner();
-        tx.output.clear();
+        // Signatures are dropped, but the `script_sig` of nested segwit inputs only pushes the
+        // redeem script, so it's kept to get the right txid
+        for input in &mut tx.input {
+            input.witness.clear();
+        }
 
         // Sum the value of all the inputs added by the sender
         let mut sender_input_value = 0;
@@ -242,57 +920,197 @@ where
             let prev_tx = blockchain.get_tx(&input.previous_output.txid)?;
             sender_input_value += prev_tx.output[input.previous_output.vout as usize].value;
         }
-        // Add the change output for the sender. Fees are subtracted from this one
-        tx.output.push(TxOut {
+        // The sender's other payments are kept as they are
+        let payments_value = sender_txouts.iter().map(|txout| txout.value).sum::<u64>();
+        tx.output = sender_txouts;
+        // Build the change output for the sender. Fees are subtracted from this one
+        let sender_txout = TxOut {
             script_pubkey: sender_script,
             value: sender_input_value
                 .checked_sub(fees)
                 .ok_or(FinalTransactionError::NegativeSenderAmount)?
                 .checked_sub(receiver_txout.value)
+                .ok_or(FinalTransactionError::NegativeSenderAmount)?
+                .checked_sub(payments_value)
                 .ok_or(FinalTransactionError::NegativeSenderAmount)?,
-        });
-
-        // Check and add the receiver's output
-        let receiver_prev_tx = blockchain.get_tx(&receiver_txin.previous_output.txid)?;
-        let receiver_input_value =
-            receiver_prev_tx.output[receiver_txin.previous_output.vout as usize].value;
-        receiver_txout.value += receiver_input_value;
-        if receiver_output_index > tx.output.len() {
+        };
+
+        // Build the receiver's output
+        let mut receiver_input_value = 0;
+        for txin in &receiver_txins {
+            let prev_tx = blockchain.get_tx(&txin.previous_output.txid)?;
+            receiver_input_value += prev_tx.output[txin.previous_output.vout as usize].value;
+        }
+        let invoice = receiver_txout.clone();
+        // What the receiver keeps of its inputs, the rest going back to its change
+        let receiver_kept_value = match &receiver_change {
+            Some((_, change)) if change.script_pubkey == invoice.script_pubkey => {
+                return Err(FinalTransactionError::InvalidReceiverChange.into())
+            }
+            Some((_, change)) => receiver_input_value
+                .checked_sub(change.value)
+                .ok_or(FinalTransactionError::InvalidReceiverChange)?,
+            None => receiver_input_value,
+        };
+        receiver_txout.value += receiver_kept_value;
+
+        // Check and add the outputs, from the lowest index like the receiver's inputs below
+        if sender_output_index == receiver_output_index
+            || receiver_change.as_ref().is_some_and(|(index, _)| {
+                *index == sender_output_index || *index == receiver_output_index
+            })
+        {
             return Err(FinalTransactionError::InvalidReceiverOutputIndex.into());
+        }
+        let mut outputs = Vec::with_capacity(3);
+        if sender_txout.value >= DUST_LIMIT {
+            outputs.push((
+                sender_output_index,
+                sender_txout,
+                FinalTransactionError::InvalidSenderOutputIndex,
+            ));
         } else {
-            tx.output.insert(receiver_output_index, receiver_txout);
-        }
-        // Check and add the receiver's input
-        if receiver_txin.sequence != 0xFFFF_FFFF {
-            return Err(FinalTransactionError::InvalidReceiverInputSequence.into());
-        } else if !receiver_txin.script_sig.is_empty() || !receiver_txin.witness.is_empty() {
-            return Err(FinalTransactionError::InvalidReceiverInputNonEmptySig.into());
-        } else if receiver_input_index > tx.input.len() {
+            // The change isn't worth creating and goes to the fees. Both sides drop it, so the
+            // receiver's outputs after it move up
+            if receiver_output_index > sender_output_index {
+                receiver_output_index -= 1;
+            }
+            if let Some((index, _)) = &mut receiver_change {
+                if *index > sender_output_index {
+                    *index -= 1;
+                }
+            }
+        }
+        outputs.push((
+            receiver_output_index,
+            receiver_txout,
+            FinalTransactionError::InvalidReceiverOutputIndex,
+        ));
+        if let Some((index, change)) = receiver_change {
+            outputs.push((
+                index,
+                change,
+                FinalTransactionError::InvalidReceiverOutputIndex,
+            ));
+        }
+        outputs.sort_by_key(|(index, _, _)| *index);
+        for (index, txout, error) in outputs {
+            if index > tx.output.len() {
+                return Err(error.into());
+            } else {
+                tx.output.insert(index, txout);
+            }
+        }
+        if let Some(index) = tx.output.iter().position(|txout| txout.value < DUST_LIMIT) {
+            return Err(FinalTransactionError::DustOutput(index).into());
+        }
+        verify_receiver_payment(&tx, &invoice, receiver_kept_value)?;
+        // Check and add the receiver's inputs. Inserting them from the lowest index makes every
+        // index refer to the final position of the input
+        if receiver_txins.is_empty() || receiver_txins.len() != receiver_input_indexes.len() {
+            return Err(FinalTransactionError::InvalidReceiverInputIndex.into());
+        }
+        let mut receiver_inputs = receiver_input_indexes
+            .iter()
+            .cloned()
+            .zip(receiver_txins)
+            .collect::<Vec<_>>();
+        receiver_inputs.sort_by_key(|(index, _)| *index);
+        if receiver_inputs
+            .windows(2)
+            .any(|pair| pair[0].0 == pair[1].0)
+        {
             return Err(FinalTransactionError::InvalidReceiverInputIndex.into());
-        } else {
-            tx.input.insert(receiver_input_index, receiver_txin);
+        }
+        let sequence = receiver_sequence(&tx);
+        for (index, txin) in receiver_inputs {
+            if txin.sequence != sequence {
+                return Err(FinalTransactionError::InvalidReceiverInputSequence.into());
+            } else if !txin.script_sig.is_empty() || !txin.witness.is_empty() {
+                return Err(FinalTransactionError::InvalidReceiverInputNonEmptySig.into());
+            } else if index > tx.input.len() {
+                return Err(FinalTransactionError::InvalidReceiverInputIndex.into());
+            } else {
+                tx.input.insert(index, txin);
+            }
         }
 
         Ok(FinalTransaction {
             transaction: tx,
-            receiver_input_index,
+            receiver_input_indexes,
             phantom: std::marker::PhantomData,
         })
     }
 }
 
-impl<S> TryFrom<(FinalTransaction<Unsigned>, &S)> for FinalTransaction<SenderSigned>
+/// Make sure that `tx` pays exactly `invoice.value` to `invoice.script_pubkey`, on top of the
+/// `receiver_input_value` the receiver gets back from its own input
+fn verify_receiver_payment(
+    tx: &Transaction,
+    invoice: &TxOut,
+    receiver_input_value: u64,
+) -> Result<(), FinalTransactionError> {
+    let received = tx
+        .output
+        .iter()
+        .filter(|output| output.script_pubkey == invoice.script_pubkey)
+        .map(|output| output.value)
+        .sum::<u64>();
+
+    if received.checked_sub(receiver_input_value) == Some(invoice.value) {
+        Ok(())
+    } else {
+        Err(FinalTransactionError::InvalidReceiverPayment)
+    }
+}
+
+fn without_script_sigs(mut transaction: Transaction) -> Transaction {
+    for input in &mut transaction.input {
+        input.script_sig = Script::new();
+    }
+
+    transaction
+}
+
+impl FinalTransaction<Unsigned> {
+    /// Build a PSBT for the unsigned final transaction, including the `witness_utxo` of every input
+    pub fn to_psbt<B>(&self, blockchain: &B) -> Result<PartiallySignedTransaction, Error>
+    where
+        B: Blockchain,
+        Error: From<<B as TxSource>::Error>,
+    {
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(without_script_sigs(
+            self.transaction.clone(),
+        ))
+        .map_err(|_| FinalTransactionError::PsbtMismatch)?;
+
+        for (psbt_input, input) in psbt.inputs.iter_mut().zip(&self.transaction.input) {
+            let prev_tx = blockchain.get_tx(&input.previous_output.txid)?;
+            psbt_input.witness_utxo = prev_tx
+                .output
+                .get(input.previous_output.vout as usize)
+                .cloned();
+            psbt_input.redeem_script = redeem_script(&input.script_sig);
+        }
+
+        Ok(psbt)
+    }
+}
+
+impl<S, B> TryFrom<(FinalTransaction<Unsigned>, &S, &B)> for FinalTransaction<SenderSigned>
 where
     S: Signer,
     Error: From<<S as Signer>::Error>,
+    B: Blockchain,
+    Error: From<<B as TxSource>::Error>,
 {
     type Error = Error;
 
-    fn try_from(data: (FinalTransaction<Unsigned>, &S)) -> Result<Self, Self::Error> {
-        let (final_transaction, signer) = data;
+    fn try_from(data: (FinalTransaction<Unsigned>, &S, &B)) -> Result<Self, Self::Error> {
+        let (final_transaction, signer, blockchain) = data;
         let FinalTransaction {
             mut transaction,
-            receiver_input_index,
+            receiver_input_indexes,
             ..
         } = final_transaction;
 
@@ -302,72 +1120,216 @@ where
         }
 
         let inputs_to_sign = (0..transaction.input.len())
-            .filter(|index| *index != receiver_input_index)
+            .filter(|index| !receiver_input_indexes.contains(index))
             .collect::<Vec<_>>();
-        signer.sign(&mut transaction, &inputs_to_sign)?;
+        // Taproot signatures commit to all the spent outputs, including the receiver's
+        let prev_outs = get_prev_outs(&transaction, blockchain)?;
+        signer.sign_with_prev_outs(&mut transaction, &inputs_to_sign, &prev_outs)?;
 
         Ok(FinalTransaction {
             transaction,
-            receiver_input_index,
+            receiver_input_indexes,
             phantom: std::marker::PhantomData,
         })
     }
 }
 
-impl TryFrom<(FinalTransaction<Unsigned>, &Vec<WitnessWrapper>)>
-    for FinalTransaction<SenderSigned>
-{
+/// Witnesses of the sender, bound to the input each of them signs. On the wire they are sent as a
+/// list following the order of the sender's inputs in the proof
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SignedInputSet(BTreeMap<OutPoint, Vec<Vec<u8>>>);
+
+impl SignedInputSet {
+    pub fn new() -> Self {
+        SignedInputSet::default()
+    }
+
+    /// Bind `witnesses` to `inputs`, in the same order. Both must have the same length
+    pub fn from_wrappers<I>(inputs: I, witnesses: &[WitnessWrapper]) -> Result<Self, Error>
+    where
+        I: ExactSizeIterator<Item = OutPoint>,
+    {
+        if inputs.len() != witnesses.len() {
+            return Err(FinalTransactionError::InvalidWitness.into());
+        }
+
+        let mut set = SignedInputSet::new();
+        for (outpoint, witness) in inputs.zip(witnesses) {
+            let witness =
+                deserialize(witness.as_ref()).map_err(|_| FinalTransactionError::InvalidWitness)?;
+            if set.0.insert(outpoint, witness).is_some() {
+                return Err(FinalTransactionError::InvalidWitness.into());
+            }
+        }
+
+        Ok(set)
+    }
+
+    /// Witnesses to send for `inputs`, in the same order
+    pub fn to_wrappers<I>(&self, inputs: I) -> Result<Vec<WitnessWrapper>, Error>
+    where
+        I: IntoIterator<Item = OutPoint>,
+    {
+        inputs
+            .into_iter()
+            .map(|outpoint| {
+                self.get(&outpoint)
+                    .map(WitnessWrapper::new)
+                    .ok_or_else(|| FinalTransactionError::InvalidWitness.into())
+            })
+            .collect()
+    }
+
+    pub fn insert(&mut self, outpoint: OutPoint, witness: Vec<Vec<u8>>) {
+        self.0.insert(outpoint, witness);
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&Vec<Vec<u8>>> {
+        self.0.get(outpoint)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl TryFrom<(FinalTransaction<Unsigned>, &SignedInputSet)> for FinalTransaction<SenderSigned> {
     type Error = Error;
 
-    fn try_from(
-        data: (FinalTransaction<Unsigned>, &Vec<WitnessWrapper>),
-    ) -> Result<Self, Self::Error> {
+    /// Every input of the sender must have a witness in the set, and the set nothing else
+    fn try_from(data: (FinalTransaction<Unsigned>, &SignedInputSet)) -> Result<Self, Self::Error> {
         let (final_transaction, witnesses) = data;
         let FinalTransaction {
             mut transaction,
-            receiver_input_index,
+            receiver_input_indexes,
             ..
         } = final_transaction;
 
-        for ((_, input), witness) in transaction
+        let mut signed = 0;
+        for (_, input) in transaction
             .input
             .iter_mut()
             .enumerate()
-            .filter(|(index, _)| *index != receiver_input_index)
-            .zip(witnesses)
+            .filter(|(index, _)| !receiver_input_indexes.contains(index))
         {
-            input.witness =
-                deserialize(witness.as_ref()).map_err(|_| FinalTransactionError::InvalidWitness)?;
+            input.witness = witnesses
+                .get(&input.previous_output)
+                .cloned()
+                .ok_or(FinalTransactionError::InvalidWitness)?;
+            signed += 1;
+        }
+        if signed != witnesses.len() {
+            return Err(FinalTransactionError::InvalidWitness.into());
+        }
+
+        Ok(FinalTransaction {
+            transaction,
+            receiver_input_indexes,
+            phantom: std::marker::PhantomData,
+        })
+    }
+}
+
+impl TryFrom<(FinalTransaction<Unsigned>, &PartiallySignedTransaction)>
+    for FinalTransaction<SenderSigned>
+{
+    type Error = Error;
+
+    fn try_from(
+        data: (FinalTransaction<Unsigned>, &PartiallySignedTransaction),
+    ) -> Result<Self, Self::Error> {
+        let (final_transaction, psbt) = data;
+        let FinalTransaction {
+            mut transaction,
+            receiver_input_indexes,
+            ..
+        } = final_transaction;
+
+        if psbt.global.unsigned_tx.txid() != without_script_sigs(transaction.clone()).txid()
+            || psbt.inputs.len() != transaction.input.len()
+        {
+            return Err(FinalTransactionError::PsbtMismatch.into());
+        }
+
+        for (index, (input, psbt_input)) in
+            transaction.input.iter_mut().zip(&psbt.inputs).enumerate()
+        {
+            if receiver_input_indexes.contains(&index) {
+                continue;
+            }
+
+            input.witness = psbt_input
+                .final_script_witness
+                .clone()
+                .ok_or(FinalTransactionError::InvalidWitness)?;
+            input.script_sig = psbt_input.final_script_sig.clone().unwrap_or_default();
         }
 
         Ok(FinalTransaction {
             transaction,
-            receiver_input_index,
+            receiver_input_indexes,
             phantom: std::marker::PhantomData,
         })
     }
 }
 
-impl<S> TryFrom<(FinalTransaction<SenderSigned>, &S)> for FinalTransaction<Signed>
+impl FinalTransaction<SenderSigned> {
+    /// Verify the signatures of the sender's inputs against the outputs they spend, like the ones
+    /// of the proof. Meant to run before the receiver signs its own inputs
+    pub fn verify_sender_inputs<B>(&self, blockchain: &B) -> Result<(), Error>
+    where
+        B: Blockchain,
+        Error: From<<B as TxSource>::Error>,
+    {
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+        let comp = SighashComponents::new(&self.transaction);
+
+        // Taproot signatures commit to all the spent outputs, including the receiver's
+        let prev_outs = get_prev_outs(&self.transaction, blockchain)?;
+        for index in (0..self.transaction.input.len())
+            .filter(|index| !self.receiver_input_indexes.contains(index))
+        {
+            verify_input(&secp, &comp, &self.transaction, index, &prev_outs, false).map_err(
+                |e| match e {
+                    ProofTransactionError::InvalidSighashType(_) => {
+                        FinalTransactionError::InvalidSenderSighashType(index)
+                    }
+                    _ => FinalTransactionError::InvalidSenderSignature(index),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<S, B> TryFrom<(FinalTransaction<SenderSigned>, &S, &B)> for FinalTransaction<Signed>
 where
     S: Signer,
     Error: From<<S as Signer>::Error>,
+    B: Blockchain,
+    Error: From<<B as TxSource>::Error>,
 {
     type Error = Error;
 
-    fn try_from(data: (FinalTransaction<SenderSigned>, &S)) -> Result<Self, Self::Error> {
-        let (final_transaction, signer) = data;
+    fn try_from(data: (FinalTransaction<SenderSigned>, &S, &B)) -> Result<Self, Self::Error> {
+        let (final_transaction, signer, blockchain) = data;
         let FinalTransaction {
             mut transaction,
-            receiver_input_index,
+            receiver_input_indexes,
             ..
         } = final_transaction;
 
-        signer.sign(&mut transaction, &[receiver_input_index])?;
+        let prev_outs = get_prev_outs(&transaction, blockchain)?;
+        signer.sign_with_prev_outs(&mut transaction, &receiver_input_indexes, &prev_outs)?;
 
         Ok(FinalTransaction {
             transaction,
-            receiver_input_index,
+            receiver_input_indexes,
             phantom: std::marker::PhantomData,
         })
     }
@@ -380,3 +1342,633 @@ impl<S: SignedContext> Deref for FinalTransaction<S> {
         &self.transaction
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    use bitcoin::{Address, Network, OutPoint, PrivateKey};
+
+    use super::*;
+    use crate::demo::SoftwareSigner;
+    use crate::testutil::{p2wpkh_key, MockBlockchain};
+
+    #[test]
+    fn test_proof_scheme() {
+        let context = ProofContext {
+            endpoint: Some("example.onion:9000".to_string()),
+            invoice: None,
+            nonce: Some("00ff".to_string()),
+        };
+        let legacy = LegacyProof.output(&context);
+        assert!(legacy.script_pubkey.is_empty());
+        assert_eq!(legacy, LegacyProof.output(&ProofContext::default()));
+
+        let bound = BoundProof.output(&context);
+        assert_eq!(bound.value, 21_000_000 * BTC);
+        assert!(bound.script_pubkey.is_op_return());
+        assert_eq!(bound, BoundProof.output(&context));
+        for other in &[
+            ProofContext::default(),
+            ProofContext {
+                invoice: Some("01".to_string()),
+                ..context.clone()
+            },
+            ProofContext {
+                endpoint: Some("example.onion:9001".to_string()),
+                ..context.clone()
+            },
+            ProofContext {
+                nonce: Some("ff00".to_string()),
+                ..context.clone()
+            },
+        ] {
+            assert_ne!(bound, BoundProof.output(other));
+        }
+
+        assert!(proof_scheme(Capabilities::BOUND_PROOF)
+            .output(&context)
+            .script_pubkey
+            .is_op_return());
+        assert!(proof_scheme(Capabilities::empty())
+            .output(&context)
+            .script_pubkey
+            .is_empty());
+    }
+
+    #[test]
+    fn test_p2wsh_multisig() {
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+        let keys = [
+            "KwDiBf89QgGbjEhKnhXJuH7LrciVrZi3qYjgd9M7rFU73sVHnoWn",
+            "KwDiBf89QgGbjEhKnhXJuH7LrciVrZi3qYjgd9M7rFU74NMTptX4",
+            "KwDiBf89QgGbjEhKnhXJuH7LrciVrZi3qYjgd9M7rFU74sHUHy8S",
+        ]
+        .iter()
+        .map(|key| PrivateKey::from_str(key).unwrap())
+        .collect::<Vec<_>>();
+        let witness_script = keys
+            .iter()
+            .fold(Builder::new().push_int(2), |builder, key| {
+                builder.push_key(&key.public_key(&secp))
+            })
+            .push_int(3)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+        assert_eq!(parse_multisig(&witness_script).unwrap().0, 2);
+
+        let utxo = OutPoint::default();
+        let prev_out = TxOut {
+            value: 100_000,
+            script_pubkey: witness_script.to_v0_p2wsh(),
+        };
+        let mut metadata = HashMap::new();
+        metadata.insert(utxo, (prev_out.value, prev_out.script_pubkey.clone()));
+
+        // Only two of the three keys are needed
+        let mut signer = SoftwareSigner::new(keys[2], metadata);
+        signer.add_cosigner_key(keys[0]);
+        signer.add_witness_script(witness_script);
+
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: utxo,
+                sequence: 0xFFFF_FFFF,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 90_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        signer.sign(&mut tx, &[0]).unwrap();
+
+        let comp = SighashComponents::new(&tx);
+        assert!(verify_p2wsh_input(&secp, &comp, &tx, 0, &prev_out, false).is_ok());
+
+        // Swapping the signatures breaks the OP_CHECKMULTISIG ordering
+        tx.input[0].witness.swap(1, 2);
+        assert!(verify_p2wsh_input(&secp, &comp, &tx, 0, &prev_out, false).is_err());
+    }
+
+    #[test]
+    fn test_p2sh_p2wpkh() {
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+        let key =
+            PrivateKey::from_str("KwDiBf89QgGbjEhKnhXJuH7LrciVrZi3qYjgd9M7rFU73sVHnoWn").unwrap();
+
+        let utxo = OutPoint::default();
+        let prev_out = TxOut {
+            value: 100_000,
+            script_pubkey: Address::p2shwpkh(&key.public_key(&secp), Network::Bitcoin)
+                .script_pubkey(),
+        };
+        let mut metadata = HashMap::new();
+        metadata.insert(utxo, (prev_out.value, prev_out.script_pubkey.clone()));
+        let signer = SoftwareSigner::new(key, metadata);
+
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: utxo,
+                sequence: 0xFFFF_FFFF,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 90_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        signer.sign(&mut tx, &[0]).unwrap();
+
+        let comp = SighashComponents::new(&tx);
+        assert!(verify_p2sh_p2wpkh_input(&secp, &comp, &tx, 0, &prev_out, false).is_ok());
+
+        tx.input[0].script_sig = Script::new();
+        assert!(verify_p2sh_p2wpkh_input(&secp, &comp, &tx, 0, &prev_out, false).is_err());
+    }
+
+    #[test]
+    fn test_receiver_payment() {
+        let invoice = TxOut {
+            value: 30_000,
+            script_pubkey: Builder::new().push_int(1).into_script(),
+        };
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![
+                TxOut {
+                    value: 50_000,
+                    script_pubkey: Script::new(),
+                },
+                TxOut {
+                    value: 130_000,
+                    script_pubkey: invoice.script_pubkey.clone(),
+                },
+            ],
+        };
+        assert!(verify_receiver_payment(&tx, &invoice, 100_000).is_ok());
+        assert_eq!(
+            verify_receiver_payment(&tx, &invoice, 110_000),
+            Err(FinalTransactionError::InvalidReceiverPayment)
+        );
+
+        // Every output to the invoice script counts, so paying more than asked is rejected too
+        tx.output[1].value = 100_000;
+        tx.output[0].script_pubkey = invoice.script_pubkey.clone();
+        assert!(verify_receiver_payment(&tx, &invoice, 100_000).is_err());
+    }
+
+    #[test]
+    fn test_batch_outputs() {
+        let script = |n: i64| Builder::new().push_int(n).into_script();
+        let txout = |n: i64, value: u64| TxOut {
+            value,
+            script_pubkey: script(n),
+        };
+
+        let blockchain = MockBlockchain::new();
+        let sender_utxo = blockchain.fund(script(1), BTC);
+        let receiver_utxo = blockchain.fund(script(2), BTC);
+        let proof = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: sender_utxo,
+                sequence: 0xFFFF_FFFF,
+                ..Default::default()
+            }],
+            output: vec![],
+        };
+
+        let meta = FinalTransactionMeta {
+            tx: ProofTransaction::<Created>(proof, std::marker::PhantomData),
+            fees: 1_000,
+            sender_script: script(1),
+            sender_output_index: 3,
+            sender_txouts: vec![txout(3, 10_000), txout(4, 20_000)],
+            receiver_txins: vec![TxIn {
+                previous_output: receiver_utxo,
+                sequence: 0xFFFF_FFFF,
+                ..Default::default()
+            }],
+            receiver_input_indexes: vec![0],
+            receiver_txout: txout(2, 30_000),
+            receiver_output_index: 0,
+            receiver_change: None,
+        };
+        let final_transaction =
+            FinalTransaction::<Unsigned>::try_from((meta.clone(), &blockchain)).unwrap();
+        assert_eq!(
+            final_transaction.output,
+            vec![
+                txout(2, BTC + 30_000),
+                txout(3, 10_000),
+                txout(4, 20_000),
+                txout(1, BTC - 61_000),
+            ]
+        );
+        assert_eq!(final_transaction.input[0].previous_output, receiver_utxo);
+
+        let mut meta = meta;
+        meta.sender_output_index = 0;
+        assert!(FinalTransaction::<Unsigned>::try_from((meta, &blockchain)).is_err());
+    }
+
+    #[test]
+    fn test_dust_change() {
+        let script = |n: i64| Builder::new().push_int(n).into_script();
+        let txout = |n: i64, value: u64| TxOut {
+            value,
+            script_pubkey: script(n),
+        };
+
+        let blockchain = MockBlockchain::new();
+        let sender_utxo = blockchain.fund(script(1), BTC);
+        let receiver_utxo = blockchain.fund(script(2), BTC);
+        let proof = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: sender_utxo,
+                sequence: 0xFFFF_FFFF,
+                ..Default::default()
+            }],
+            output: vec![],
+        };
+
+        // The change would be worth 500 sats
+        let meta = FinalTransactionMeta {
+            tx: ProofTransaction::<Created>(proof, std::marker::PhantomData),
+            fees: 1_000,
+            sender_script: script(1),
+            sender_output_index: 0,
+            sender_txouts: vec![],
+            receiver_txins: vec![TxIn {
+                previous_output: receiver_utxo,
+                sequence: 0xFFFF_FFFF,
+                ..Default::default()
+            }],
+            receiver_input_indexes: vec![0],
+            receiver_txout: txout(2, BTC - 1_500),
+            receiver_output_index: 1,
+            receiver_change: None,
+        };
+        let final_transaction =
+            FinalTransaction::<Unsigned>::try_from((meta.clone(), &blockchain)).unwrap();
+        assert_eq!(final_transaction.output, vec![txout(2, 2 * BTC - 1_500)]);
+
+        let mut meta = meta;
+        meta.receiver_txout.value = 1_000;
+        meta.sender_txouts = vec![txout(3, 100)];
+        meta.receiver_output_index = 0;
+        meta.sender_output_index = 2;
+        assert!(matches!(
+            FinalTransaction::<Unsigned>::try_from((meta, &blockchain)),
+            Err(Error::Protocol(
+                crate::ProtocolError::InvalidFinalTransaction(FinalTransactionError::DustOutput(1))
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_receiver_change() {
+        let script = |n: i64| Builder::new().push_int(n).into_script();
+        let txout = |n: i64, value: u64| TxOut {
+            value,
+            script_pubkey: script(n),
+        };
+
+        let blockchain = MockBlockchain::new();
+        let sender_utxo = blockchain.fund(script(1), BTC);
+        let receiver_utxo = blockchain.fund(script(2), BTC);
+        let proof = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: sender_utxo,
+                sequence: 0xFFFF_FFFF,
+                ..Default::default()
+            }],
+            output: vec![],
+        };
+
+        let meta = FinalTransactionMeta {
+            tx: ProofTransaction::<Created>(proof, std::marker::PhantomData),
+            fees: 1_000,
+            sender_script: script(1),
+            sender_output_index: 0,
+            sender_txouts: vec![],
+            receiver_txins: vec![TxIn {
+                previous_output: receiver_utxo,
+                sequence: 0xFFFF_FFFF,
+                ..Default::default()
+            }],
+            receiver_input_indexes: vec![1],
+            receiver_txout: txout(2, 30_000),
+            receiver_output_index: 1,
+            receiver_change: Some((2, txout(5, BTC - 10_000))),
+        };
+        let final_transaction =
+            FinalTransaction::<Unsigned>::try_from((meta.clone(), &blockchain)).unwrap();
+        // Only 10k sats of the receiver's input are added to its payment
+        assert_eq!(
+            final_transaction.output,
+            vec![
+                txout(1, BTC - 31_000),
+                txout(2, 40_000),
+                txout(5, BTC - 10_000)
+            ]
+        );
+
+        let invalid = |receiver_change| {
+            let meta = FinalTransactionMeta {
+                receiver_change: Some(receiver_change),
+                ..meta.clone()
+            };
+            match FinalTransaction::<Unsigned>::try_from((meta, &blockchain)) {
+                Err(Error::Protocol(crate::ProtocolError::InvalidFinalTransaction(e))) => Some(e),
+                _ => None,
+            }
+        };
+        assert_eq!(
+            invalid((2, txout(5, BTC + 1))),
+            Some(FinalTransactionError::InvalidReceiverChange)
+        );
+        assert_eq!(
+            invalid((2, txout(2, 10_000))),
+            Some(FinalTransactionError::InvalidReceiverChange)
+        );
+        assert_eq!(
+            invalid((1, txout(5, 10_000))),
+            Some(FinalTransactionError::InvalidReceiverOutputIndex)
+        );
+    }
+
+    #[test]
+    fn test_signed_input_set() {
+        let script = |n: i64| Builder::new().push_int(n).into_script();
+        let txin = |previous_output| TxIn {
+            previous_output,
+            sequence: 0xFFFF_FFFF,
+            ..Default::default()
+        };
+
+        let blockchain = MockBlockchain::new();
+        let sender_utxos = [
+            blockchain.fund(script(1), BTC),
+            blockchain.fund(script(1), BTC),
+        ];
+        let receiver_utxo = blockchain.fund(script(2), BTC);
+        let proof = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: sender_utxos.iter().cloned().map(txin).collect(),
+            output: vec![],
+        };
+        let meta = FinalTransactionMeta {
+            tx: ProofTransaction::<Created>(proof.clone(), std::marker::PhantomData),
+            fees: 1_000,
+            sender_script: script(1),
+            sender_output_index: 0,
+            sender_txouts: vec![],
+            receiver_txins: vec![txin(receiver_utxo)],
+            receiver_input_indexes: vec![1],
+            receiver_txout: TxOut {
+                value: 30_000,
+                script_pubkey: script(2),
+            },
+            receiver_output_index: 1,
+            receiver_change: None,
+        };
+        let unsigned = || FinalTransaction::<Unsigned>::try_from((meta.clone(), &blockchain));
+
+        // Sent in the order of the proof, while the receiver's input sits between the sender's
+        let witnesses = vec![
+            WitnessWrapper::new(&vec![vec![1u8]]),
+            WitnessWrapper::new(&vec![vec![2u8]]),
+        ];
+        let inputs = || proof.input.iter().map(|txin| txin.previous_output);
+        let set = SignedInputSet::from_wrappers(inputs(), &witnesses).unwrap();
+        let signed = FinalTransaction::<SenderSigned>::try_from((unsigned().unwrap(), &set))
+            .unwrap()
+            .into_inner();
+        for input in &signed.input {
+            let expected = match input.previous_output {
+                utxo if utxo == sender_utxos[0] => vec![vec![1]],
+                utxo if utxo == sender_utxos[1] => vec![vec![2]],
+                _ => vec![],
+            };
+            assert_eq!(input.witness, expected);
+        }
+        assert_eq!(
+            set.to_wrappers(inputs())
+                .unwrap()
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<_>>(),
+            witnesses
+                .iter()
+                .cloned()
+                .map(String::from)
+                .collect::<Vec<_>>()
+        );
+
+        // Missing, extra and duplicated witnesses
+        assert!(SignedInputSet::from_wrappers(inputs(), &witnesses[..1]).is_err());
+        assert!(SignedInputSet::from_wrappers(
+            vec![sender_utxos[0], sender_utxos[0]].into_iter(),
+            &witnesses
+        )
+        .is_err());
+        let mut partial = SignedInputSet::new();
+        partial.insert(sender_utxos[0], vec![vec![1]]);
+        assert!(
+            FinalTransaction::<SenderSigned>::try_from((unsigned().unwrap(), &partial)).is_err()
+        );
+        let mut extra = set;
+        extra.insert(receiver_utxo, vec![vec![3]]);
+        assert!(FinalTransaction::<SenderSigned>::try_from((unsigned().unwrap(), &extra)).is_err());
+    }
+
+    #[test]
+    fn test_verify_sender_inputs() {
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+        let key =
+            PrivateKey::from_str("KwDiBf89QgGbjEhKnhXJuH7LrciVrZi3qYjgd9M7rFU73sVHnoWn").unwrap();
+        let script = Address::p2wpkh(&key.public_key(&secp), Network::Bitcoin).script_pubkey();
+        let txin = |previous_output| TxIn {
+            previous_output,
+            sequence: 0xFFFF_FFFF,
+            ..Default::default()
+        };
+
+        let blockchain = MockBlockchain::new();
+        let sender_utxo = blockchain.fund(script.clone(), BTC);
+        let receiver_utxo = blockchain.fund(script.clone(), BTC);
+        let mut metadata = HashMap::new();
+        metadata.insert(sender_utxo, (BTC, script.clone()));
+        let signer = SoftwareSigner::new(key, metadata);
+
+        let proof = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![txin(sender_utxo)],
+            output: vec![],
+        };
+        let meta = FinalTransactionMeta {
+            tx: ProofTransaction::<Created>(proof, std::marker::PhantomData),
+            fees: 1_000,
+            sender_script: script.clone(),
+            sender_output_index: 0,
+            sender_txouts: vec![],
+            receiver_txins: vec![txin(receiver_utxo)],
+            receiver_input_indexes: vec![0],
+            receiver_txout: TxOut {
+                value: 30_000,
+                script_pubkey: Builder::new().push_int(2).into_script(),
+            },
+            receiver_output_index: 1,
+            receiver_change: None,
+        };
+        let unsigned = FinalTransaction::<Unsigned>::try_from((meta.clone(), &blockchain)).unwrap();
+        let signed =
+            FinalTransaction::<SenderSigned>::try_from((unsigned.clone(), &signer, &blockchain))
+                .unwrap();
+        assert!(signed.verify_sender_inputs(&blockchain).is_ok());
+
+        // Valid, but without committing to the receiver's input
+        let mut anyonecanpay = unsigned;
+        assert!(signer
+            .sign_all_anyonecanpay(&mut anyonecanpay.transaction, &[1])
+            .unwrap());
+        let anyonecanpay = FinalTransaction::<SenderSigned> {
+            transaction: anyonecanpay.transaction,
+            receiver_input_indexes: anyonecanpay.receiver_input_indexes,
+            phantom: std::marker::PhantomData,
+        };
+        assert!(matches!(
+            anyonecanpay.verify_sender_inputs(&blockchain),
+            Err(Error::Protocol(
+                crate::ProtocolError::InvalidFinalTransaction(
+                    FinalTransactionError::InvalidSenderSighashType(1)
+                )
+            ))
+        ));
+
+        // A spend of someone else's output, signed with our key
+        let (_, other_script) = p2wpkh_key(7);
+        let other_utxo = blockchain.fund(other_script.clone(), BTC);
+        let mut metadata = HashMap::new();
+        metadata.insert(other_utxo, (BTC, other_script));
+        let thief = SoftwareSigner::new(key, metadata);
+        let mut stolen = meta.clone();
+        stolen.tx.0.input[0].previous_output = other_utxo;
+        let stolen = FinalTransaction::<Unsigned>::try_from((stolen, &blockchain)).unwrap();
+        let stolen =
+            FinalTransaction::<SenderSigned>::try_from((stolen, &thief, &blockchain)).unwrap();
+        assert!(matches!(
+            stolen.verify_sender_inputs(&blockchain),
+            Err(Error::Protocol(
+                crate::ProtocolError::InvalidFinalTransaction(
+                    FinalTransactionError::InvalidSenderSignature(1)
+                )
+            ))
+        ));
+
+        // The same, nested in P2SH
+        let (_, other_redeem_script) = p2wpkh_key(8);
+        let other_utxo = blockchain.fund(other_redeem_script.to_p2sh(), BTC);
+        let mut metadata = HashMap::new();
+        metadata.insert(other_utxo, (BTC, other_redeem_script.clone()));
+        let thief = SoftwareSigner::new(key, metadata);
+        let mut stolen = meta.clone();
+        stolen.tx.0.input[0].previous_output = other_utxo;
+        let stolen = FinalTransaction::<Unsigned>::try_from((stolen, &blockchain)).unwrap();
+        let mut stolen =
+            FinalTransaction::<SenderSigned>::try_from((stolen, &thief, &blockchain)).unwrap();
+        stolen.transaction.input[1].script_sig = Builder::new()
+            .push_slice(other_redeem_script.as_bytes())
+            .into_script();
+        assert!(matches!(
+            stolen.verify_sender_inputs(&blockchain),
+            Err(Error::Protocol(
+                crate::ProtocolError::InvalidFinalTransaction(
+                    FinalTransactionError::InvalidSenderSignature(1)
+                )
+            ))
+        ));
+
+        // A valid signature for another transaction
+        let mut tampered = signed;
+        tampered.transaction.output[0].value -= 1;
+        assert!(matches!(
+            tampered.verify_sender_inputs(&blockchain),
+            Err(Error::Protocol(
+                crate::ProtocolError::InvalidFinalTransaction(
+                    FinalTransactionError::InvalidSenderSignature(1)
+                )
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_anyonecanpay_proof() {
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+        let key =
+            PrivateKey::from_str("KwDiBf89QgGbjEhKnhXJuH7LrciVrZi3qYjgd9M7rFU73sVHnoWn").unwrap();
+        let script = Address::p2wpkh(&key.public_key(&secp), Network::Bitcoin).script_pubkey();
+
+        let blockchain = MockBlockchain::new();
+        let utxos = [
+            blockchain.fund(script.clone(), BTC),
+            blockchain.fund(script.clone(), BTC),
+        ];
+        let metadata = utxos
+            .iter()
+            .map(|utxo| (*utxo, (BTC, script.clone())))
+            .collect();
+        let signer = SoftwareSigner::new(key, metadata);
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: utxos
+                .iter()
+                .map(|utxo| TxIn {
+                    previous_output: *utxo,
+                    sequence: 0xFFFF_FFFF,
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![],
+        };
+
+        let proof_output = LegacyProof.output(&ProofContext::default());
+        let proof = ProofTransaction::<Created>::anyonecanpay(tx, &signer, &proof_output)
+            .unwrap()
+            .into_inner();
+        assert!(proof
+            .input
+            .iter()
+            .all(|input| input.witness[0].last() == Some(&SIGHASH_ALL_ANYONECANPAY)));
+        assert!(ProofTransaction::<Validated>::try_from((
+            proof.clone(),
+            &blockchain,
+            &proof_output
+        ))
+        .is_ok());
+
+        // Each signature stays valid with only its own input
+        let mut single = proof;
+        single.input.remove(0);
+        assert!(
+            ProofTransaction::<Validated>::try_from((single, &blockchain, &proof_output)).is_ok()
+        );
+    }
+}
@@ -0,0 +1,337 @@
+//! Local control interface of a server, for wallet daemons that don't link the crate
+//!
+//! On Unix, `Server::listen_control()` accepts JSON-RPC requests, one per line, on a socket:
+//!
+//! * `get_invoice`: a payment URI for the current address, with a fresh access token when the
+//!   server requires one
+//! * `list_sessions`: the sessions in progress, with their `id`, `peer` and `started` time in
+//!   milliseconds since the UNIX epoch
+//! * `cancel_session`: stop the session `id`, which fails with `Error::Cancelled`
+//! * `get_status`: the endpoint, the number of payments completed and of sessions in progress
+//! * `lock_utxo`: keep the sessions from contributing the UTXO `outpoint`, as `txid:vout`, for
+//!   `seconds`, like while the wallet spends it
+//! * `unlock_utxo`: release a lock taken with `lock_utxo`
+//! * `list_locks`: the locked UTXOs, with the `seconds` left on their lock, if it expires
+//!
+//! Anyone who can open the socket can control the server, so it should be placed in a directory
+//! only accessible to the wallet.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use tracing::debug;
+
+use serde_json::{json, Value};
+
+use bitcoin::OutPoint;
+
+use crate::bip21::Bip21Uri;
+use crate::server::{AccessTokens, UtxoLocks};
+use crate::shutdown::CancellationToken;
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Not part of JSON-RPC, for requests that can't be served right now
+const UNAVAILABLE: i64 = -32000;
+
+#[derive(Debug)]
+struct ActiveSession {
+    peer: Option<SocketAddr>,
+    started: SystemTime,
+    cancel: CancellationToken,
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    invoice: Option<Bip21Uri>,
+    require_token: bool,
+    payments: u32,
+    next_id: u64,
+    sessions: HashMap<u64, ActiveSession>,
+}
+
+/// What the control interface can see and do on a server, kept up to date by the server itself
+#[derive(Debug, Clone)]
+pub struct ControlHandle {
+    state: Arc<Mutex<ControlState>>,
+    tokens: AccessTokens,
+    locks: UtxoLocks,
+}
+
+impl ControlHandle {
+    pub(crate) fn new(tokens: AccessTokens, locks: UtxoLocks) -> Self {
+        ControlHandle {
+            state: Default::default(),
+            tokens,
+            locks,
+        }
+    }
+
+    /// Set the URI handed out by `get_invoice`, without any token
+    pub(crate) fn set_invoice(&self, invoice: Option<Bip21Uri>, require_token: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.invoice = invoice;
+        state.require_token = require_token;
+    }
+
+    pub(crate) fn set_payments(&self, payments: u32) {
+        self.state.lock().unwrap().payments = payments;
+    }
+
+    /// Track a new session until the returned guard is dropped
+    pub(crate) fn start_session(&self, peer: Option<SocketAddr>) -> SessionGuard {
+        let cancel = CancellationToken::new();
+
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.sessions.insert(
+            id,
+            ActiveSession {
+                peer,
+                started: SystemTime::now(),
+                cancel: cancel.clone(),
+            },
+        );
+
+        SessionGuard {
+            handle: self.clone(),
+            id,
+            cancel,
+        }
+    }
+
+    pub fn invoice(&self) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        let mut invoice = state.invoice.clone()?;
+        if state.require_token {
+            invoice.token = Some(self.tokens.issue());
+        }
+
+        Some(invoice.to_string())
+    }
+
+    pub fn sessions(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        let mut ids = state.sessions.keys().cloned().collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        ids.into_iter()
+            .map(|id| {
+                let session = &state.sessions[&id];
+                json!({
+                    "id": id,
+                    "peer": session.peer.map(|peer| peer.to_string()),
+                    "started": millis(session.started),
+                })
+            })
+            .collect()
+    }
+
+    /// Stop the session `id`. Returns `false` if there is no such session
+    pub fn cancel_session(&self, id: u64) -> bool {
+        match self.state.lock().unwrap().sessions.get(&id) {
+            Some(session) => {
+                session.cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn status(&self) -> Value {
+        let state = self.state.lock().unwrap();
+
+        json!({
+            "endpoint": state.invoice.as_ref().and_then(|invoice| invoice.endpoint.clone()),
+            "payments": state.payments,
+            "sessions": state.sessions.len(),
+        })
+    }
+
+    pub fn locks(&self) -> Value {
+        let mut locked = self.locks.locked();
+        locked.sort_unstable_by_key(|(utxo, _)| *utxo);
+
+        locked
+            .into_iter()
+            .map(|(utxo, left)| {
+                json!({
+                    "outpoint": utxo.to_string(),
+                    "seconds": left.map(|left| left.as_secs()),
+                })
+            })
+            .collect()
+    }
+
+    fn call(&self, method: &str, params: &Value) -> Result<Value, (i64, &'static str)> {
+        match method {
+            "get_invoice" => self
+                .invoice()
+                .map(Value::from)
+                .ok_or((UNAVAILABLE, "the server hasn't been set up yet")),
+            "list_sessions" => Ok(self.sessions()),
+            "cancel_session" => {
+                let id = params
+                    .get("id")
+                    .and_then(Value::as_u64)
+                    .ok_or((INVALID_PARAMS, "missing session id"))?;
+                if self.cancel_session(id) {
+                    Ok(true.into())
+                } else {
+                    Err((INVALID_PARAMS, "unknown session"))
+                }
+            }
+            "get_status" => Ok(self.status()),
+            "lock_utxo" => {
+                let utxo = outpoint(params)?;
+                let seconds = params
+                    .get("seconds")
+                    .and_then(Value::as_u64)
+                    .ok_or((INVALID_PARAMS, "missing seconds"))?;
+                let duration = Duration::from_secs(seconds);
+                Instant::now()
+                    .checked_add(duration)
+                    .ok_or((INVALID_PARAMS, "seconds out of range"))?;
+                if self.locks.lock_for(utxo, duration) {
+                    Ok(true.into())
+                } else {
+                    Err((UNAVAILABLE, "already locked"))
+                }
+            }
+            "unlock_utxo" => {
+                if self.locks.unlock(&outpoint(params)?) {
+                    Ok(true.into())
+                } else {
+                    Err((INVALID_PARAMS, "not locked with lock_utxo"))
+                }
+            }
+            "list_locks" => Ok(self.locks()),
+            _ => Err((METHOD_NOT_FOUND, "unknown method")),
+        }
+    }
+
+    /// Answer a single request line
+    fn handle(&self, line: &str) -> Value {
+        let request = match serde_json::from_str::<Value>(line) {
+            Ok(request) => request,
+            Err(_) => return error(Value::Null, PARSE_ERROR, "invalid JSON"),
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let method = request.get("method").and_then(Value::as_str).unwrap_or("");
+        debug!("Control request: {}", method);
+
+        match self.call(method, request.get("params").unwrap_or(&Value::Null)) {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err((code, message)) => error(id, code, message),
+        }
+    }
+
+    /// Listen on the Unix socket at `path`, until `shutdown` is cancelled
+    #[cfg(unix)]
+    pub(crate) fn listen<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+        shutdown: CancellationToken,
+    ) -> Result<(), crate::Error> {
+        use tracing::{info, warn};
+
+        let mut listener = UnixListener::bind(path.as_ref())?;
+        info!("Control interface listening on {}", path.as_ref().display());
+
+        let handle = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    _ = shutdown.cancelled() => break,
+                };
+                match stream {
+                    Ok((stream, _)) => {
+                        tokio::spawn(handle.clone().serve(stream, shutdown.clone()));
+                    }
+                    Err(e) => warn!("Control interface: {:?}", e),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    async fn serve(self, mut stream: UnixStream, shutdown: CancellationToken) {
+        use crate::jsonrpc::MAX_MESSAGE_SIZE;
+
+        let (reader, mut writer) = stream.split();
+        let mut reader = BufReader::new(reader);
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let mut limited = (&mut reader).take(MAX_MESSAGE_SIZE as u64);
+            let read = tokio::select! {
+                read = limited.read_line(&mut line) => read,
+                _ = shutdown.cancelled() => return,
+            };
+            match read {
+                Ok(0) | Err(_) => return,
+                Ok(_) if !line.ends_with('\n') => return,
+                Ok(_) => {}
+            }
+
+            let mut response = serde_json::to_vec(&self.handle(line.trim())).unwrap_or_default();
+            response.push(b'\n');
+            if writer.write_all(&response).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Removes the session from the control interface when dropped
+#[derive(Debug)]
+pub(crate) struct SessionGuard {
+    handle: ControlHandle,
+    id: u64,
+    cancel: CancellationToken,
+}
+
+impl SessionGuard {
+    /// Resolve once the session has been cancelled through the control interface
+    pub(crate) async fn cancelled(&self) {
+        self.cancel.cancelled().await
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.handle.state.lock().unwrap().sessions.remove(&self.id);
+    }
+}
+
+fn outpoint(params: &Value) -> Result<OutPoint, (i64, &'static str)> {
+    params
+        .get("outpoint")
+        .and_then(Value::as_str)
+        .and_then(|outpoint| OutPoint::from_str(outpoint).ok())
+        .ok_or((INVALID_PARAMS, "missing or invalid outpoint"))
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or(0)
+}
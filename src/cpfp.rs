@@ -0,0 +1,177 @@
+//! Child-pays-for-parent fee bumps
+//!
+//! The fee rate of a payjoin transaction is chosen by the sender alone. If it lingers in the
+//! mempool, the receiver can spend its own output of it with a child paying for both.
+
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+
+use crate::blockchain::{Blockchain, TxSource};
+use crate::common::DUST_LIMIT;
+use crate::fees::FeeRate;
+use crate::signer::Signer;
+use crate::Error;
+
+/// Fee paid by `tx`, looking up the value of its inputs on `blockchain`
+fn fee<B>(blockchain: &B, tx: &Transaction) -> Result<u64, Error>
+where
+    B: Blockchain,
+    Error: From<<B as TxSource>::Error>,
+{
+    let mut input_value = 0;
+    for input in &tx.input {
+        let prev_tx = blockchain.get_tx(&input.previous_output.txid)?;
+        input_value += prev_tx
+            .output
+            .get(input.previous_output.vout as usize)
+            .ok_or(Error::InsufficientFunds)?
+            .value;
+    }
+    let output_value = tx.output.iter().map(|output| output.value).sum::<u64>();
+
+    input_value
+        .checked_sub(output_value)
+        .ok_or(Error::InsufficientFunds)
+}
+
+fn sign_child<S>(signer: &S, child: &Transaction, prev_out: &TxOut) -> Result<Transaction, Error>
+where
+    S: Signer,
+    Error: From<<S as Signer>::Error>,
+{
+    let mut psbt =
+        PartiallySignedTransaction::from_unsigned_tx(child.clone()).map_err(|_| Error::Other)?;
+    psbt.inputs[0].witness_utxo = Some(prev_out.clone());
+    signer.sign_psbt(&mut psbt, &[0])?;
+
+    let mut child = child.clone();
+    child.input[0].witness = psbt.inputs[0]
+        .final_script_witness
+        .clone()
+        .unwrap_or_default();
+    child.input[0].script_sig = psbt.inputs[0].final_script_sig.clone().unwrap_or_default();
+
+    Ok(child)
+}
+
+/// Build and sign a child spending output `vout` of `parent` to `script_pubkey`, paying enough
+/// fees to bring the package of both to `fee_rate`
+pub fn child_transaction<B, S>(
+    blockchain: &B,
+    signer: &S,
+    parent: &Transaction,
+    vout: u32,
+    script_pubkey: Script,
+    fee_rate: FeeRate,
+) -> Result<Transaction, Error>
+where
+    B: Blockchain,
+    Error: From<<B as TxSource>::Error>,
+    S: Signer,
+    Error: From<<S as Signer>::Error>,
+{
+    let prev_out = parent
+        .output
+        .get(vout as usize)
+        .ok_or(Error::InsufficientFunds)?;
+    let parent_fee = fee(blockchain, parent)?;
+
+    let mut child = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(parent.txid(), vout),
+            sequence: 0xFFFF_FFFF,
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            script_pubkey,
+            value: prev_out.value,
+        }],
+    };
+    // Sign once to measure the weight, the final signature can only differ by a byte
+    let child_weight = sign_child(signer, &child, prev_out)?.get_weight();
+
+    let package_fee = fee_rate.fee_wu(parent.get_weight() + child_weight);
+    let child_fee = std::cmp::max(
+        package_fee.saturating_sub(parent_fee),
+        fee_rate.fee_wu(child_weight),
+    );
+    child.output[0].value = prev_out
+        .value
+        .checked_sub(child_fee)
+        .filter(|value| *value >= DUST_LIMIT)
+        .ok_or(Error::InsufficientFunds)?;
+
+    sign_child(signer, &child, prev_out)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use bitcoin::{Address, Network, PrivateKey};
+
+    use super::*;
+    use crate::blockchain::Broadcaster;
+    use crate::demo::SoftwareSigner;
+    use crate::testutil::MockBlockchain;
+
+    #[test]
+    fn test_child_transaction() {
+        let secp = Secp256k1::signing_only();
+        let key = PrivateKey {
+            compressed: true,
+            network: Network::Regtest,
+            key: SecretKey::from_slice(&[2; 32]).unwrap(),
+        };
+        let script = Address::p2wpkh(&key.public_key(&secp), Network::Regtest).script_pubkey();
+
+        let blockchain = MockBlockchain::new();
+        let utxo = blockchain.fund(script.clone(), 100_000);
+        let signer = SoftwareSigner::new(
+            key,
+            vec![(utxo, (100_000, script.clone()))]
+                .into_iter()
+                .collect(),
+        );
+        // Paying 1 sat/vB at most
+        let mut parent = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: utxo,
+                sequence: 0xFFFF_FFFF,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                script_pubkey: script.clone(),
+                value: 99_850,
+            }],
+        };
+        signer.sign(&mut parent, &[0]).unwrap();
+        blockchain.add_tx(parent.clone());
+
+        let fee_rate = FeeRate::from_sat_per_vb(10.0);
+        let child =
+            child_transaction(&blockchain, &signer, &parent, 0, script.clone(), fee_rate).unwrap();
+        assert_eq!(
+            child.input[0].previous_output,
+            OutPoint::new(parent.txid(), 0)
+        );
+        let package_fee = 100_000 - child.output[0].value;
+        assert!(package_fee >= fee_rate.fee_wu(parent.get_weight() + child.get_weight()));
+        blockchain.broadcast(&child).unwrap();
+
+        assert!(matches!(
+            child_transaction(
+                &blockchain,
+                &signer,
+                &parent,
+                0,
+                script,
+                FeeRate::from_sat_per_vb(1000.0)
+            ),
+            Err(Error::InsufficientFunds)
+        ));
+    }
+}
@@ -0,0 +1,429 @@
+//! Selection of the decoy UTXOs offered to the sender next to the receiver's
+//!
+//! The sender signs a candidate final transaction for every UTXO offered by the receiver, so a
+//! decoy that could never be the receiver's (dust, a different kind of script, an unconfirmed
+//! output) makes the real one easier to spot.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use tracing::warn;
+
+use rand::seq::SliceRandom;
+
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::{Block, OutPoint, Script, TxOut};
+
+use crate::blockchain::{Blockchain, TxSource};
+use crate::common::ScriptType;
+use crate::{Error, ProtocolError};
+
+/// Draws from the backend per decoy, before giving up on finding distinct ones
+const MAX_DRAWS: usize = 10;
+
+/// A UTXO drawn from the backend, with what's known about it
+#[derive(Debug, Clone)]
+pub struct Decoy {
+    pub utxo: OutPoint,
+    pub txout: TxOut,
+    /// `None` if the backend can't tell
+    pub confirmations: Option<u32>,
+}
+
+pub trait DecoySelector: std::fmt::Debug + Send + Sync {
+    /// Whether `decoy` is plausible as a UTXO of the receiver, who is contributing `ours`
+    fn accept(&self, decoy: &Decoy, ours: &[TxOut]) -> bool;
+}
+
+/// Accept every UTXO returned by the backend
+#[derive(Debug, Clone, Default)]
+pub struct AnyDecoy;
+
+impl DecoySelector for AnyDecoy {
+    fn accept(&self, _decoy: &Decoy, _ours: &[TxOut]) -> bool {
+        true
+    }
+}
+
+/// Only accept the decoys of `inner` worth at least `min_value`, so that every candidate set can
+/// pay for the receiver's change
+#[derive(Debug)]
+pub(crate) struct CoveringDecoys<'a> {
+    pub inner: &'a dyn DecoySelector,
+    pub min_value: u64,
+}
+
+impl DecoySelector for CoveringDecoys<'_> {
+    fn accept(&self, decoy: &Decoy, ours: &[TxOut]) -> bool {
+        decoy.txout.value >= self.min_value && self.inner.accept(decoy, ours)
+    }
+}
+
+/// Only accept decoys that look like the receiver's UTXOs
+///
+/// Confirmations are only checked when the backend knows them.
+#[derive(Debug, Clone)]
+pub struct DecoyFilter {
+    /// Smallest value of a decoy, in satoshis
+    pub min_value: u64,
+    /// Require the same kind of script as one of the receiver's UTXOs
+    pub same_script_type: bool,
+    pub min_confirmations: u32,
+}
+
+impl Default for DecoyFilter {
+    fn default() -> Self {
+        DecoyFilter {
+            min_value: 10_000,
+            same_script_type: true,
+            min_confirmations: 1,
+        }
+    }
+}
+
+impl DecoySelector for DecoyFilter {
+    fn accept(&self, decoy: &Decoy, ours: &[TxOut]) -> bool {
+        let script_type = ScriptType::of(&decoy.txout.script_pubkey);
+
+        decoy.txout.value >= self.min_value
+            && (!self.same_script_type
+                || ours
+                    .iter()
+                    .any(|txout| ScriptType::of(&txout.script_pubkey) == script_type))
+            && decoy
+                .confirmations
+                .is_none_or(|confirmations| confirmations >= self.min_confirmations)
+    }
+}
+
+/// Where the decoys are drawn from, instead of the `Blockchain`
+///
+/// Backends usually draw from the UTXOs they happen to know about, like the ancestors of our
+/// transactions or the wallet's own, which are close to us in the transaction graph. A source
+/// indexing the whole UTXO set, like a `UtxoSnapshot`, gives decoys that look like any other
+/// UTXO of the chain.
+pub trait DecoySource: std::fmt::Debug + Send + Sync {
+    /// A UTXO picked at random, or `None` if there are none
+    fn draw(&self) -> Result<Option<Decoy>, Error>;
+}
+
+/// Snapshot of the UTXO set, or of the outputs created in a range of blocks and not spent in it,
+/// sampled uniformly
+///
+/// It can be saved to a file and loaded back, in a text format with the height of the snapshot on
+/// the first line and then a line per UTXO with its outpoint, the height of its block, its value
+/// and its script in hex. That's also the easiest way to import the UTXO set of a node, like the
+/// one written by `dumptxoutset` once converted. Sampled UTXOs are checked against the backend,
+/// so the snapshot can be older than the tip.
+#[derive(Debug, Clone, Default)]
+pub struct UtxoSnapshot {
+    height: u32,
+    utxos: Vec<(OutPoint, TxOut, u32)>,
+}
+
+impl UtxoSnapshot {
+    /// Empty snapshot, taken at `height`
+    pub fn new(height: u32) -> Self {
+        UtxoSnapshot {
+            height,
+            utxos: Vec::new(),
+        }
+    }
+
+    /// Index the outputs created in `blocks` and not spent in them, in the order of the chain
+    /// starting at `start_height`. Coinbase outputs, which few receivers own, are left out
+    pub fn from_blocks<I: IntoIterator<Item = Block>>(start_height: u32, blocks: I) -> Self {
+        let mut height = start_height;
+        let mut utxos = HashMap::new();
+        for block in blocks {
+            for tx in block.txdata.iter().skip(1) {
+                for input in &tx.input {
+                    utxos.remove(&input.previous_output);
+                }
+
+                let txid = tx.txid();
+                for (vout, txout) in tx.output.iter().enumerate() {
+                    if !txout.script_pubkey.is_provably_unspendable() {
+                        utxos.insert(OutPoint::new(txid, vout as u32), (txout.clone(), height));
+                    }
+                }
+            }
+            height += 1;
+        }
+
+        UtxoSnapshot {
+            height: height.saturating_sub(1),
+            utxos: utxos
+                .into_iter()
+                .map(|(utxo, (txout, height))| (utxo, txout, height))
+                .collect(),
+        }
+    }
+
+    /// Add a UTXO confirmed at `height`
+    pub fn insert(&mut self, utxo: OutPoint, txout: TxOut, height: u32) {
+        self.utxos.push((utxo, txout, height));
+    }
+
+    pub fn len(&self) -> usize {
+        self.utxos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.utxos.is_empty()
+    }
+
+    /// Load a snapshot saved by `save()`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid UTXO snapshot");
+
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let height = lines
+            .next()
+            .ok_or_else(invalid)??
+            .parse()
+            .map_err(|_| invalid())?;
+        let mut snapshot = UtxoSnapshot::new(height);
+        for line in lines {
+            let line = line?;
+            let mut fields = line.split(' ');
+            let mut next = || fields.next().ok_or_else(invalid);
+
+            let utxo = next()?.parse().map_err(|_| invalid())?;
+            let height = next()?.parse().map_err(|_| invalid())?;
+            let value = next()?.parse().map_err(|_| invalid())?;
+            let script_pubkey = Script::from(Vec::<u8>::from_hex(next()?).map_err(|_| invalid())?);
+            snapshot.insert(
+                utxo,
+                TxOut {
+                    value,
+                    script_pubkey,
+                },
+                height,
+            );
+        }
+
+        Ok(snapshot)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "{}", self.height)?;
+        for (utxo, txout, height) in &self.utxos {
+            writeln!(
+                file,
+                "{} {} {} {}",
+                utxo,
+                height,
+                txout.value,
+                txout.script_pubkey.as_bytes().to_hex()
+            )?;
+        }
+        file.flush()?;
+
+        Ok(())
+    }
+}
+
+impl DecoySource for UtxoSnapshot {
+    fn draw(&self) -> Result<Option<Decoy>, Error> {
+        Ok(self
+            .utxos
+            .choose(&mut rand::thread_rng())
+            .map(|(utxo, txout, height)| Decoy {
+                utxo: *utxo,
+                txout: txout.clone(),
+                // At least, blocks may have been found since the snapshot
+                confirmations: Some(self.height.saturating_sub(*height) + 1),
+            }))
+    }
+}
+
+/// Draw `count` decoys from `source`, or from `blockchain` without one, for the receiver's `ours`
+/// UTXOs, keeping the ones accepted by `selector`
+///
+/// Decoys sharing a transaction with each other or with the receiver's UTXOs, and decoys paying
+/// to one of the receiver's scripts, are always skipped. Decoys are never repeated, a sender
+/// seeing the same UTXO twice would know it isn't ours: if the backend can't provide enough of
+/// them, fewer are returned.
+pub(crate) fn select_decoys<B>(
+    blockchain: &B,
+    source: Option<&dyn DecoySource>,
+    selector: &dyn DecoySelector,
+    ours: &[(OutPoint, TxOut)],
+    count: usize,
+) -> Result<Vec<OutPoint>, Error>
+where
+    B: Blockchain,
+    Error: From<<B as TxSource>::Error>,
+{
+    let our_txouts = ours
+        .iter()
+        .map(|(_, txout)| txout.clone())
+        .collect::<Vec<_>>();
+    let mut txids = ours
+        .iter()
+        .map(|(utxo, _)| utxo.txid)
+        .collect::<HashSet<_>>();
+
+    let mut decoys = Vec::with_capacity(count);
+    for _ in 0..count * MAX_DRAWS {
+        if decoys.len() == count {
+            break;
+        }
+
+        let decoy = match source {
+            Some(source) => match source.draw()? {
+                Some(decoy) => decoy,
+                None => break,
+            },
+            None => {
+                let utxo = blockchain.get_random_utxo()?;
+                if txids.contains(&utxo.txid) {
+                    continue;
+                }
+                let txout = match blockchain
+                    .get_tx(&utxo.txid)?
+                    .output
+                    .get(utxo.vout as usize)
+                {
+                    Some(txout) => txout.clone(),
+                    None => continue,
+                };
+
+                Decoy {
+                    utxo,
+                    txout,
+                    confirmations: blockchain.get_confirmations(&utxo.txid)?,
+                }
+            }
+        };
+        if txids.contains(&decoy.utxo.txid)
+            || our_txouts
+                .iter()
+                .any(|ours| ours.script_pubkey == decoy.txout.script_pubkey)
+        {
+            continue;
+        }
+        // The sender checks every candidate, a decoy spent since the snapshot would fail the
+        // session
+        if source.is_some() && !blockchain.is_unspent(&decoy.utxo)? {
+            continue;
+        }
+
+        if selector.accept(&decoy, &our_txouts) {
+            txids.insert(decoy.utxo.txid);
+            decoys.push(decoy.utxo);
+        }
+    }
+
+    if decoys.is_empty() && count > 0 {
+        warn!("No plausible decoy found");
+        return Err(ProtocolError::Busy.into());
+    } else if decoys.len() < count {
+        warn!("Only {} distinct decoys found", decoys.len());
+    }
+
+    Ok(decoys)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::script::Builder;
+
+    use super::*;
+    use crate::testutil::MockBlockchain;
+
+    #[test]
+    fn test_select_decoys() {
+        let p2wpkh = |n: u8| {
+            Builder::new()
+                .push_int(0)
+                .push_slice(&[n; 20])
+                .into_script()
+        };
+        let blockchain = MockBlockchain::new();
+        for n in 0..5 {
+            blockchain.fund(p2wpkh(n), 100_000);
+        }
+        // Dust and a P2WSH output, never plausible
+        blockchain.fund(p2wpkh(5), 500);
+        blockchain.fund(
+            Builder::new()
+                .push_int(0)
+                .push_slice(&[6; 32])
+                .into_script(),
+            100_000,
+        );
+        let our_txout = TxOut {
+            value: 100_000,
+            script_pubkey: p2wpkh(7),
+        };
+        let ours = vec![(blockchain.fund(p2wpkh(7), 100_000), our_txout)];
+
+        let decoys = select_decoys(&blockchain, None, &DecoyFilter::default(), &ours, 20).unwrap();
+        let distinct = decoys.iter().collect::<HashSet<_>>();
+        assert_eq!(decoys.len(), 5);
+        assert_eq!(distinct.len(), 5);
+        assert!(!distinct.contains(&ours[0].0));
+
+        let decoys = select_decoys(&blockchain, None, &AnyDecoy, &ours, 20).unwrap();
+        assert_eq!(decoys.len(), 7);
+        assert_eq!(decoys.iter().collect::<HashSet<_>>().len(), 7);
+    }
+
+    #[test]
+    fn test_utxo_snapshot() {
+        let p2wpkh = |n: u8| {
+            Builder::new()
+                .push_int(0)
+                .push_slice(&[n; 20])
+                .into_script()
+        };
+        let blockchain = MockBlockchain::new();
+        let ours = vec![(
+            blockchain.fund(p2wpkh(0), 100_000),
+            TxOut {
+                value: 100_000,
+                script_pubkey: p2wpkh(0),
+            },
+        )];
+
+        let mut snapshot = UtxoSnapshot::new(1000);
+        let mut unspent = HashSet::new();
+        for n in 1..5 {
+            let utxo = blockchain.fund(p2wpkh(n), 100_000);
+            let txout = blockchain.get_tx(&utxo.txid).unwrap().output[0].clone();
+            snapshot.insert(utxo, txout, 900);
+            unspent.insert(utxo);
+        }
+        // Spent since the snapshot
+        let spent = TxOut {
+            value: 100_000,
+            script_pubkey: p2wpkh(5),
+        };
+        snapshot.insert(OutPoint::default(), spent, 900);
+
+        let mut path = std::env::temp_dir();
+        path.push(format!("libp2ep-utxos-{}", rand::random::<u64>()));
+        snapshot.save(&path).unwrap();
+        let snapshot = UtxoSnapshot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(snapshot.len(), 5);
+        assert_eq!(snapshot.draw().unwrap().unwrap().confirmations, Some(101));
+
+        let decoys = select_decoys(
+            &blockchain,
+            Some(&snapshot),
+            &DecoyFilter::default(),
+            &ours,
+            20,
+        )
+        .unwrap();
+        assert_eq!(decoys.len(), 4);
+        assert_eq!(decoys.iter().collect::<HashSet<_>>().len(), 4);
+        assert!(decoys.iter().all(|decoy| unspent.contains(decoy)));
+    }
+}
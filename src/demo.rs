@@ -1,19 +1,37 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
 
-use log::debug;
+use tracing::{debug, trace};
 
 use crate::blockchain::*;
+use crate::common::{parse_multisig, sighash_all_anyonecanpay};
+use crate::offer::sighash_single_anyonecanpay;
+use crate::redact::Sensitive;
 use crate::signer::*;
+use crate::taproot;
 
+use bitcoin::blockdata::script::Builder;
 use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::hashes::hex::{FromHex, ToHex};
 use bitcoin::hashes::Hash;
 use bitcoin::secp256k1::{All, Message, Secp256k1};
 use bitcoin::util::bip143::SighashComponents;
+use bitcoin::util::psbt::PartiallySignedTransaction;
 use bitcoin::*;
 
+use secp256k1_schnorr::schnorrsig;
+
+/// Height of the demo chain, right after the block of its latest transaction
+const DEMO_HEIGHT: u32 = 1497;
+
 #[derive(Debug, Default)]
-pub struct ElectrumBlockchain {}
+pub struct ElectrumBlockchain {
+    // Nothing is actually broadcast, so the transactions stay in the mempool forever
+    mempool: Mutex<HashSet<Txid>>,
+    broadcasts: Condvar,
+}
 
 impl ElectrumBlockchain {
     pub fn new() -> Self {
@@ -21,7 +39,7 @@ impl ElectrumBlockchain {
     }
 }
 
-impl Blockchain for ElectrumBlockchain {
+impl TxSource for ElectrumBlockchain {
     type Error = ();
 
     fn get_tx(&self, txid: &Txid) -> Result<Transaction, ()> {
@@ -33,7 +51,7 @@ impl Blockchain for ElectrumBlockchain {
         }.unwrap();
 
         let bytes: Vec<u8> = FromHex::from_hex(string).map_err(|_| ())?;
-        Ok(deserialize(&bytes).map_err(|_| ())?)
+        deserialize(&bytes).map_err(|_| ())
     }
 
     fn is_unspent(&self, _txout: &OutPoint) -> Result<bool, Self::Error> {
@@ -50,45 +68,253 @@ impl Blockchain for ElectrumBlockchain {
         })
     }
 
+    fn get_height(&self) -> Result<Option<u32>, Self::Error> {
+        Ok(Some(DEMO_HEIGHT))
+    }
+
+    fn in_mempool(&self, txid: &Txid) -> Result<Option<bool>, Self::Error> {
+        Ok(Some(self.mempool.lock().unwrap().contains(txid)))
+    }
+
+    /// Like a `blockchain.scripthash.subscribe` notification, without checking the script: every
+    /// broadcast is an update
+    fn wait_for_update(
+        &self,
+        _script_pubkey: &Script,
+        timeout: Duration,
+    ) -> Result<bool, Self::Error> {
+        let mempool = self.mempool.lock().unwrap();
+        let (_mempool, result) = self.broadcasts.wait_timeout(mempool, timeout).unwrap();
+
+        Ok(!result.timed_out())
+    }
+}
+
+impl Broadcaster for ElectrumBlockchain {
+    type Error = ();
+
     fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
         let bytes = serialize(tx);
-        debug!("Broadcasting: {}", bytes.to_hex());
+        debug!("Broadcasting: {}", Sensitive(bytes.to_hex()));
+        self.mempool.lock().unwrap().insert(tx.txid());
+        self.broadcasts.notify_all();
         Ok(())
     }
 }
 
-#[derive(Debug)]
 pub struct SoftwareSigner {
     key: PrivateKey,
     metadata: HashMap<OutPoint, (u64, Script)>,
+
+    cosigner_keys: Vec<PrivateKey>,
+    witness_scripts: HashMap<Script, Script>,
+}
+
+// Without the keys, unless unsafe logging is on
+impl fmt::Debug for SoftwareSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SoftwareSigner")
+            .field("key", &Sensitive(&self.key))
+            .field("metadata", &self.metadata)
+            .field("cosigner_keys", &Sensitive(&self.cosigner_keys))
+            .field("witness_scripts", &self.witness_scripts)
+            .finish()
+    }
 }
 
 impl SoftwareSigner {
     pub fn new(key: PrivateKey, metadata: HashMap<OutPoint, (u64, Script)>) -> Self {
-        SoftwareSigner { key, metadata }
+        SoftwareSigner {
+            key,
+            metadata,
+            cosigner_keys: Vec::new(),
+            witness_scripts: HashMap::new(),
+        }
     }
-}
 
-impl Signer for SoftwareSigner {
-    type Error = ();
+    /// Add another key, only used to sign P2WSH multisig inputs
+    pub fn add_cosigner_key(&mut self, key: PrivateKey) {
+        self.cosigner_keys.push(key);
+    }
 
-    fn sign(&self, transaction: &mut Transaction, inputs: &[usize]) -> Result<(), Self::Error> {
-        debug!("signing tx: {:?}", transaction);
+    /// Add the witness script of a P2WSH output we can sign for
+    pub fn add_witness_script(&mut self, witness_script: Script) {
+        self.witness_scripts
+            .insert(witness_script.to_v0_p2wsh(), witness_script);
+    }
+
+    fn private_keys(&self) -> impl Iterator<Item = &PrivateKey> {
+        std::iter::once(&self.key).chain(&self.cosigner_keys)
+    }
+
+    /// P2TR output for our key, with no script tree
+    pub fn p2tr_script(&self) -> Script {
+        let secp = secp256k1_schnorr::Secp256k1::new();
+        let keypair = self.taproot_keypair(&secp);
+        let output_key = schnorrsig::PublicKey::from_keypair(&secp, &keypair);
+
+        Builder::new()
+            .push_int(1)
+            .push_slice(&output_key.serialize())
+            .into_script()
+    }
+
+    fn taproot_keypair(
+        &self,
+        secp: &secp256k1_schnorr::Secp256k1<secp256k1_schnorr::All>,
+    ) -> schnorrsig::KeyPair {
+        let mut keypair = schnorrsig::KeyPair::from_seckey_slice(secp, &self.key.key[..]).unwrap();
+        taproot::tweak_keypair(secp, &mut keypair);
+
+        keypair
+    }
+
+    /// Sign our P2WPKH `inputs` with `sighash_type`, one of the `ANYONECANPAY` ones. Returns `false`
+    /// for other types of inputs
+    fn sign_anyonecanpay(
+        &self,
+        transaction: &mut Transaction,
+        inputs: &[usize],
+        sighash_type: SigHashType,
+    ) -> Result<bool, ()> {
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+        let mut pubkey = self.key.public_key(&secp);
+        pubkey.compressed = true;
+
+        for index in inputs {
+            let input = &transaction.input[*index];
+            let (amount, prev_script) = self.metadata.get(&input.previous_output).ok_or(())?;
+            if !prev_script.is_v0_p2wpkh() {
+                return Ok(false);
+            }
+
+            let script_code = Self::p2wpkh_scriptcode(prev_script);
+            let hash = match sighash_type {
+                SigHashType::SinglePlusAnyoneCanPay => {
+                    sighash_single_anyonecanpay(transaction, *index, &script_code, *amount)
+                }
+                _ => sighash_all_anyonecanpay(transaction, *index, &script_code, *amount),
+            };
+            let sig = secp.sign(
+                &Message::from_slice(&hash.into_inner()[..]).unwrap(),
+                &self.key.key,
+            );
+
+            let mut sig_with_sighash = sig.serialize_der().to_vec();
+            sig_with_sighash.push(sighash_type.as_u32() as u8);
+
+            transaction.input[*index].witness = vec![sig_with_sighash, pubkey.to_bytes().to_vec()];
+        }
+
+        Ok(true)
+    }
+
+    /// Sign `inputs`, using `prev_outs` for the outputs spent by the inputs we don't know about. Our
+    /// own inputs can be among them, like the outputs of a transaction we just received
+    fn sign_inputs(
+        &self,
+        transaction: &mut Transaction,
+        inputs: &[usize],
+        prev_outs: &[Option<TxOut>],
+    ) -> Result<(), ()> {
+        debug!("Signing transaction: {:?}", Sensitive(&*transaction));
 
         let secp: Secp256k1<All> = Secp256k1::gen_new();
-        let comp = SighashComponents::new(&transaction);
+        let comp = SighashComponents::new(transaction);
+
+        // Taproot signatures commit to all the spent outputs
+        let all_prev_outs = transaction
+            .input
+            .iter()
+            .zip(prev_outs)
+            .map(
+                |(input, prev_out)| match self.metadata.get(&input.previous_output) {
+                    Some((value, script_pubkey)) => Some(TxOut {
+                        value: *value,
+                        script_pubkey: script_pubkey.clone(),
+                    }),
+                    None => prev_out.clone(),
+                },
+            )
+            .collect::<Option<Vec<_>>>();
+        let unsigned_transaction = transaction.clone();
 
         for (index, input) in transaction.input.iter_mut().enumerate() {
             if !inputs.contains(&index) {
                 continue;
             }
 
-            let (amount, prev_script) = self.metadata.get(&input.previous_output).unwrap();
+            let (amount, prev_script) = match self.metadata.get(&input.previous_output) {
+                Some((amount, prev_script)) => (amount, prev_script),
+                None => {
+                    let prev_out = prev_outs[index].as_ref().ok_or(())?;
+                    (&prev_out.value, &prev_out.script_pubkey)
+                }
+            };
+            if taproot::is_p2tr(prev_script) {
+                let secp = secp256k1_schnorr::Secp256k1::new();
+                let all_prev_outs = all_prev_outs.as_ref().ok_or(())?;
+
+                input.witness = taproot::sign_key_spend(
+                    &secp,
+                    &self.taproot_keypair(&secp),
+                    &unsigned_transaction,
+                    index,
+                    all_prev_outs,
+                );
+                continue;
+            }
+
+            if prev_script.is_v0_p2wsh() {
+                let witness_script = self.witness_scripts.get(prev_script).ok_or(())?;
+                let (threshold, keys) = parse_multisig(witness_script).ok_or(())?;
+
+                let hash = comp.sighash_all(input, witness_script, *amount);
+                let hash = Message::from_slice(&hash.into_inner()[..]).unwrap();
+                let signatures = keys
+                    .iter()
+                    .filter_map(|key| {
+                        self.private_keys()
+                            .find(|private_key| private_key.public_key(&secp) == *key)
+                    })
+                    .take(threshold)
+                    .map(|private_key| {
+                        let mut sig = secp.sign(&hash, &private_key.key).serialize_der().to_vec();
+                        sig.push(0x01);
+                        sig
+                    })
+                    .collect::<Vec<_>>();
+                if signatures.len() < threshold {
+                    return Err(());
+                }
+
+                input.witness = Self::multisig_witness(witness_script, signatures);
+                continue;
+            }
+
+            let mut pubkey = self.key.public_key(&secp);
+            pubkey.compressed = true;
+
+            // For nested segwit the redeem script is our P2WPKH, pushed in the `script_sig`
+            let prev_script = if prev_script.is_p2sh() {
+                let redeem_script = Address::p2wpkh(&pubkey, Network::Bitcoin).script_pubkey();
+                if redeem_script.to_p2sh() != *prev_script {
+                    return Err(());
+                }
+
+                input.script_sig = Builder::new()
+                    .push_slice(redeem_script.as_bytes())
+                    .into_script();
+                redeem_script
+            } else {
+                prev_script.clone()
+            };
+
             let script_code = Self::p2wpkh_scriptcode(&prev_script);
-            println!(
-                "input: {} scriptcode: {} value: {}",
+            trace!(
+                "Input #{} script code: {} value: {}",
                 index,
-                script_code.to_hex(),
+                Sensitive(script_code.to_hex()),
                 *amount
             );
 
@@ -98,14 +324,75 @@ impl Signer for SoftwareSigner {
                 &self.key.key,
             );
 
-            let mut pubkey = self.key.public_key(&secp);
-            pubkey.compressed = true;
             let mut sig_with_sighash = sig.serialize_der().to_vec();
             sig_with_sighash.push(0x01);
 
             input.witness = vec![sig_with_sighash, pubkey.to_bytes().to_vec()];
 
-            debug!("signature: {:?}", sig);
+            trace!("Signature: {:?}", Sensitive(sig));
+        }
+
+        Ok(())
+    }
+}
+
+impl Signer for SoftwareSigner {
+    type Error = ();
+
+    fn sign(&self, transaction: &mut Transaction, inputs: &[usize]) -> Result<(), Self::Error> {
+        let prev_outs = vec![None; transaction.input.len()];
+        self.sign_inputs(transaction, inputs, &prev_outs)
+    }
+
+    fn sign_with_prev_outs(
+        &self,
+        transaction: &mut Transaction,
+        inputs: &[usize],
+        prev_outs: &[TxOut],
+    ) -> Result<(), Self::Error> {
+        let prev_outs = prev_outs.iter().cloned().map(Some).collect::<Vec<_>>();
+        self.sign_inputs(transaction, inputs, &prev_outs)
+    }
+
+    /// Only for our P2WPKH inputs
+    fn sign_single_anyonecanpay(
+        &self,
+        transaction: &mut Transaction,
+        inputs: &[usize],
+    ) -> Result<bool, Self::Error> {
+        self.sign_anyonecanpay(transaction, inputs, SigHashType::SinglePlusAnyoneCanPay)
+    }
+
+    /// Only for our P2WPKH inputs
+    fn sign_all_anyonecanpay(
+        &self,
+        transaction: &mut Transaction,
+        inputs: &[usize],
+    ) -> Result<bool, Self::Error> {
+        self.sign_anyonecanpay(transaction, inputs, SigHashType::AllPlusAnyoneCanPay)
+    }
+
+    fn sign_psbt(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        inputs: &[usize],
+    ) -> Result<(), Self::Error> {
+        let prev_outs = psbt
+            .inputs
+            .iter()
+            .map(|input| input.witness_utxo.clone())
+            .collect::<Vec<_>>();
+        let mut transaction = psbt.global.unsigned_tx.clone();
+        self.sign_inputs(&mut transaction, inputs, &prev_outs)?;
+
+        for index in inputs {
+            let input = &mut transaction.input[*index];
+            let psbt_input = &mut psbt.inputs[*index];
+
+            psbt_input.final_script_witness = Some(std::mem::take(&mut input.witness));
+            if !input.script_sig.is_empty() {
+                psbt_input.final_script_sig = Some(input.script_sig.clone());
+            }
         }
 
         Ok(())
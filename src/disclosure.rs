@@ -0,0 +1,159 @@
+//! Protection against senders probing for the receiver's UTXOs
+//!
+//! Every session discloses the receiver's UTXOs hidden among decoys. A sender opening many sessions
+//! could spot them by intersecting the sets it's offered, so repeated proofs spending the same
+//! inputs are offered the same set again, and fresh sets are rate limited.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bitcoin::{OutPoint, TxOut};
+
+/// Window of the rate limits
+pub const RATE_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// How long a set of UTXOs is remembered for the same inputs
+const DISCLOSURE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Sliding window of recent events
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter(VecDeque<Instant>);
+
+impl RateLimiter {
+    /// Record a new event, unless there were already `max` in the last `window`
+    pub fn try_acquire(&mut self, max: usize, window: Duration, now: Instant) -> bool {
+        self.prune(window, now);
+
+        if self.0.len() < max {
+            self.0.push_back(now);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forget the events older than `window`
+    pub fn prune(&mut self, window: Duration, now: Instant) {
+        while let Some(oldest) = self.0.front() {
+            if now.duration_since(*oldest) < window {
+                break;
+            }
+            self.0.pop_front();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// UTXOs offered to a proof, with ours among them
+#[derive(Debug, Clone)]
+pub(crate) struct Disclosure {
+    pub utxos: Vec<OutPoint>,
+    pub our_utxos: Vec<(OutPoint, TxOut)>,
+    pub our_utxos_position: usize,
+    created: Instant,
+}
+
+impl Disclosure {
+    pub fn new(
+        utxos: Vec<OutPoint>,
+        our_utxos: Vec<(OutPoint, TxOut)>,
+        our_utxos_position: usize,
+    ) -> Self {
+        Disclosure {
+            utxos,
+            our_utxos,
+            our_utxos_position,
+            created: Instant::now(),
+        }
+    }
+}
+
+/// Sets of UTXOs disclosed so far, keyed by the inputs of the proof and the number of UTXOs we
+/// contribute. Shared by all the sessions of a server
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Disclosures(Arc<Mutex<DisclosuresInner>>);
+
+#[derive(Debug, Default)]
+struct DisclosuresInner {
+    disclosed: HashMap<(Vec<OutPoint>, usize), Disclosure>,
+    limiter: RateLimiter,
+}
+
+/// Key of a proof spending `inputs`, regardless of their order
+fn key(inputs: &[OutPoint], receiver_inputs: usize) -> (Vec<OutPoint>, usize) {
+    let mut inputs = inputs.to_vec();
+    inputs.sort();
+
+    (inputs, receiver_inputs)
+}
+
+impl Disclosures {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set disclosed earlier to a proof spending the same `inputs`
+    pub fn get(&self, inputs: &[OutPoint], receiver_inputs: usize) -> Option<Disclosure> {
+        let inner = self.0.lock().unwrap();
+
+        inner
+            .disclosed
+            .get(&key(inputs, receiver_inputs))
+            .filter(|disclosure| disclosure.created.elapsed() < DISCLOSURE_TTL)
+            .cloned()
+    }
+
+    pub fn forget(&self, inputs: &[OutPoint], receiver_inputs: usize) {
+        self.0
+            .lock()
+            .unwrap()
+            .disclosed
+            .remove(&key(inputs, receiver_inputs));
+    }
+
+    /// Account for a fresh set of UTXOs, unless `max_per_hour` have already been disclosed
+    pub fn try_acquire(&self, max_per_hour: Option<usize>) -> bool {
+        match max_per_hour {
+            Some(max) => {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .limiter
+                    .try_acquire(max, RATE_WINDOW, Instant::now())
+            }
+            None => true,
+        }
+    }
+
+    pub fn insert(&self, inputs: &[OutPoint], receiver_inputs: usize, disclosure: Disclosure) {
+        let mut inner = self.0.lock().unwrap();
+
+        inner
+            .disclosed
+            .retain(|_, disclosure| disclosure.created.elapsed() < DISCLOSURE_TTL);
+        inner
+            .disclosed
+            .insert(key(inputs, receiver_inputs), disclosure);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::default();
+        let window = Duration::from_secs(10);
+        let start = Instant::now();
+
+        assert!(limiter.try_acquire(2, window, start));
+        assert!(limiter.try_acquire(2, window, start + Duration::from_secs(1)));
+        assert!(!limiter.try_acquire(2, window, start + Duration::from_secs(2)));
+        assert!(limiter.try_acquire(2, window, start + Duration::from_secs(10)));
+        assert!(!limiter.try_acquire(2, window, start + Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,47 @@
+//! Progress of the negotiations, for applications that want to show it
+//!
+//! Both `Client` and `Server` report the steps of every session to a `SessionObserver`, like
+//! "verifying proof" or "broadcasting". Observers are called from the blocking thread pool while a
+//! message is being processed, so they should return quickly, for example by forwarding the event
+//! to the UI thread.
+
+use bitcoin::Txid;
+
+use crate::zeroconf::Assessment;
+use crate::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    /// Connected to the peer, before any message
+    Connected,
+    /// The peers agreed on a protocol `version`
+    VersionNegotiated { version: String },
+    /// Receiver: checking the sender's proof before offering our UTXOs
+    VerifyingProof,
+    /// Receiver: the `ZeroConfPolicy` assessed the inputs of the proof, which are rejected unless
+    /// `accepted`
+    ZeroConfAssessed { assessment: Assessment },
+    /// Our message has been sent, waiting for the next one of the peer
+    WaitingForPeer,
+    /// Signing our inputs of the final transaction
+    Signing,
+    /// Broadcasting the final transaction, which the sender only does when the receiver leaves it
+    /// to it
+    Broadcasting,
+    /// Sender: checking the final transaction returned by the receiver
+    VerifyingTransaction,
+    /// The payment has been negotiated
+    Completed { txid: Txid },
+}
+
+pub trait SessionObserver: std::fmt::Debug + Send + Sync {
+    fn on_event(&self, _event: &SessionEvent) {}
+    /// The session failed, no more events follow
+    fn on_error(&self, _error: &Error) {}
+}
+
+/// Ignore every event
+#[derive(Debug, Clone, Default)]
+pub struct NoObserver;
+
+impl SessionObserver for NoObserver {}
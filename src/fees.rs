@@ -0,0 +1,62 @@
+//! Fee rates and their estimation
+
+/// Fee rate in satoshis per virtual byte
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct FeeRate(f32);
+
+impl FeeRate {
+    pub fn from_sat_per_vb(sat_per_vb: f32) -> Self {
+        FeeRate(sat_per_vb)
+    }
+
+    /// Convert from the BTC/kvB rates returned by Electrum servers and `bitcoind`
+    pub fn from_btc_per_kvb(btc_per_kvb: f32) -> Self {
+        FeeRate(btc_per_kvb * 100_000.0)
+    }
+
+    pub fn as_sat_per_vb(&self) -> f32 {
+        self.0
+    }
+
+    /// Fee for a transaction of `weight` weight units, rounded up
+    pub fn fee_wu(&self, weight: usize) -> u64 {
+        let vbytes = weight.div_ceil(4);
+
+        (self.0 * vbytes as f32).ceil() as u64
+    }
+}
+
+/// The minimum relay fee of Bitcoin Core, 1 sat/vB
+impl Default for FeeRate {
+    fn default() -> Self {
+        FeeRate::from_sat_per_vb(1.0)
+    }
+}
+
+pub trait FeeEstimator {
+    type Error;
+
+    /// Fee rate needed to confirm within `target_blocks` blocks
+    fn estimate_fee_rate(&self, target_blocks: usize) -> Result<FeeRate, Self::Error>;
+}
+
+/// A fixed fee rate, regardless of the target
+impl FeeEstimator for FeeRate {
+    type Error = ();
+
+    fn estimate_fee_rate(&self, _target_blocks: usize) -> Result<FeeRate, Self::Error> {
+        Ok(*self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fee_wu() {
+        assert_eq!(FeeRate::from_sat_per_vb(1.0).fee_wu(561), 141);
+        assert_eq!(FeeRate::from_sat_per_vb(2.5).fee_wu(561), 353);
+        assert_eq!(FeeRate::from_btc_per_kvb(0.0001).as_sat_per_vb(), 10.0);
+    }
+}
@@ -0,0 +1,483 @@
+//! Kotlin and Swift bindings of the sender, for mobile wallets
+//!
+//! `send()` pays a payment URI in a single blocking call, like `Client::send()`, so apps should
+//! call it from a background thread. The keys, the chain and the fee estimates stay with the app,
+//! behind the `Wallet` and `ChainSource` interfaces, and the progress of the negotiation is
+//! reported to a `ProgressListener`. Apps that route their traffic through Tor, usually with
+//! Orbot, pass its SOCKS proxy, otherwise the endpoint is reached directly.
+//!
+//! The bindings are generated from the built library:
+//!
+//! ```text
+//! cargo build --release --no-default-features --features ffi
+//! cargo run --features ffi --bin uniffi-bindgen -- generate \
+//!     --library target/release/liblibp2ep.so --language kotlin --out-dir bindings
+//! ```
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, Script, Transaction, TxOut, Txid};
+
+use crate::bip21::Bip21Uri;
+use crate::blockchain::{Broadcaster, TxSource};
+use crate::client::{Client, PaymentResult};
+use crate::coin_selection::WeightedUtxo;
+use crate::events::{SessionEvent, SessionObserver};
+use crate::fees::{FeeEstimator, FeeRate};
+use crate::signer::Signer;
+use crate::transport::{Direct, ExternalTor};
+use crate::wallet::SenderWallet;
+use crate::Error;
+
+#[derive(Debug, uniffi::Error)]
+pub enum PayjoinError {
+    /// Not a valid payment URI
+    InvalidUri { message: String },
+    /// Invalid data handed over by the app, like a transaction that can't be decoded
+    InvalidData { message: String },
+    /// A method of the app failed
+    Callback { message: String },
+    /// The payment failed, the wallet should broadcast its own transaction instead
+    Payment { message: String },
+}
+
+impl fmt::Display for PayjoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PayjoinError::InvalidUri { message } => write!(f, "invalid uri: {}", message),
+            PayjoinError::InvalidData { message } => write!(f, "invalid data: {}", message),
+            PayjoinError::Callback { message } => write!(f, "callback failed: {}", message),
+            PayjoinError::Payment { message } => write!(f, "payment failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for PayjoinError {}
+
+impl From<uniffi::UnexpectedUniFFICallbackError> for PayjoinError {
+    fn from(error: uniffi::UnexpectedUniFFICallbackError) -> Self {
+        PayjoinError::Callback {
+            message: error.reason,
+        }
+    }
+}
+
+impl From<Error> for PayjoinError {
+    fn from(error: Error) -> Self {
+        let mut message = error.to_string();
+        let mut source = std::error::Error::source(&error);
+        while let Some(inner) = source {
+            message = format!("{}: {}", message, inner);
+            source = inner.source();
+        }
+
+        match error {
+            Error::Bip21(_) => PayjoinError::InvalidUri { message },
+            _ => PayjoinError::Payment { message },
+        }
+    }
+}
+
+/// A parsed payment URI
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct PaymentUri {
+    pub address: String,
+    /// Requested amount, in satoshis
+    pub amount: Option<u64>,
+    pub endpoint: Option<String>,
+    pub token: Option<String>,
+    pub invoice: Option<String>,
+    pub offer: Option<String>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+}
+
+impl From<Bip21Uri> for PaymentUri {
+    fn from(uri: Bip21Uri) -> Self {
+        PaymentUri {
+            address: uri.address.to_string(),
+            amount: uri.amount,
+            endpoint: uri.endpoint,
+            token: uri.token,
+            invoice: uri.invoice,
+            offer: uri.offer,
+            label: uri.label,
+            message: uri.message,
+        }
+    }
+}
+
+/// Parse a payment URI, to show it to the user before paying it
+#[uniffi::export]
+pub fn parse_uri(uri: String) -> Result<PaymentUri, PayjoinError> {
+    Bip21Uri::from_str(&uri)
+        .map(PaymentUri::from)
+        .map_err(|e| Error::from(e).into())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, uniffi::Enum)]
+pub enum Network {
+    Bitcoin,
+    Testnet,
+    Regtest,
+}
+
+impl From<Network> for bitcoin::Network {
+    fn from(network: Network) -> Self {
+        match network {
+            Network::Bitcoin => bitcoin::Network::Bitcoin,
+            Network::Testnet => bitcoin::Network::Testnet,
+            Network::Regtest => bitcoin::Network::Regtest,
+        }
+    }
+}
+
+/// An unspent output of the wallet
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct Utxo {
+    pub txid: String,
+    pub vout: u32,
+    pub value: u64,
+    pub script_pubkey: Vec<u8>,
+}
+
+/// Keys and coins of the app's wallet
+#[uniffi::export(with_foreign)]
+pub trait Wallet: Send + Sync {
+    fn list_unspent(&self) -> Result<Vec<Utxo>, PayjoinError>;
+
+    /// A fresh script for the change
+    fn change_script(&self) -> Result<Vec<u8>, PayjoinError>;
+
+    /// Sign and finalize `inputs` of the serialized PSBT, returning the signed PSBT. The PSBT
+    /// only has the unsigned transaction, the wallet adds the UTXOs it spends
+    fn sign_psbt(&self, psbt: Vec<u8>, inputs: Vec<u32>) -> Result<Vec<u8>, PayjoinError>;
+}
+
+/// Chain backend of the app
+#[uniffi::export(with_foreign)]
+pub trait ChainSource: Send + Sync {
+    /// The serialized transaction
+    fn get_tx(&self, txid: String) -> Result<Vec<u8>, PayjoinError>;
+
+    fn is_unspent(&self, txid: String, vout: u32) -> Result<bool, PayjoinError>;
+
+    fn broadcast(&self, transaction: Vec<u8>) -> Result<(), PayjoinError>;
+
+    /// Fee rate, in sat/vB, needed to confirm within `target_blocks` blocks
+    fn estimate_fee_rate(&self, target_blocks: u32) -> Result<f32, PayjoinError>;
+}
+
+/// See `SessionEvent`
+#[derive(Debug, Clone, PartialEq, uniffi::Enum)]
+pub enum Progress {
+    Connected,
+    VersionNegotiated { version: String },
+    VerifyingProof,
+    ZeroConfAssessed { score: u8, accepted: bool },
+    WaitingForPeer,
+    Signing,
+    Broadcasting,
+    VerifyingTransaction,
+    Completed { txid: String },
+}
+
+impl From<&SessionEvent> for Progress {
+    fn from(event: &SessionEvent) -> Self {
+        match event {
+            SessionEvent::Connected => Progress::Connected,
+            SessionEvent::VersionNegotiated { version } => Progress::VersionNegotiated {
+                version: version.clone(),
+            },
+            SessionEvent::VerifyingProof => Progress::VerifyingProof,
+            SessionEvent::ZeroConfAssessed { assessment } => Progress::ZeroConfAssessed {
+                score: assessment.score,
+                accepted: assessment.accepted,
+            },
+            SessionEvent::WaitingForPeer => Progress::WaitingForPeer,
+            SessionEvent::Signing => Progress::Signing,
+            SessionEvent::Broadcasting => Progress::Broadcasting,
+            SessionEvent::VerifyingTransaction => Progress::VerifyingTransaction,
+            SessionEvent::Completed { txid } => Progress::Completed {
+                txid: txid.to_string(),
+            },
+        }
+    }
+}
+
+/// Progress of a payment, to show in the UI of the app. Called from the thread of `send()`
+#[uniffi::export(with_foreign)]
+pub trait ProgressListener: Send + Sync {
+    fn on_progress(&self, progress: Progress);
+}
+
+/// A negotiated payment
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct Payment {
+    pub txid: String,
+    /// The serialized final transaction
+    pub transaction: Vec<u8>,
+    /// Fee paid by the transaction, in satoshis
+    pub fee_paid: u64,
+    pub receiver_input_count: u32,
+    /// Time by which the receiver promised to broadcast the transaction, in seconds since the
+    /// epoch. The app should broadcast it itself if it's not in the mempool by then
+    pub will_broadcast_by: Option<u64>,
+}
+
+impl From<PaymentResult> for Payment {
+    fn from(result: PaymentResult) -> Self {
+        Payment {
+            txid: result.txid.to_string(),
+            transaction: serialize(&result.transaction),
+            fee_paid: result.fee_paid,
+            receiver_input_count: result.receiver_input_count as u32,
+            will_broadcast_by: result
+                .will_broadcast_by
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|since| since.as_secs()),
+        }
+    }
+}
+
+/// Pay `uri`, see `Client::send()`. `amount` is only needed when the URI doesn't request one.
+/// With `socks_proxy`, like `127.0.0.1:9050` for Orbot, the endpoint is reached through Tor
+#[uniffi::export]
+#[allow(clippy::too_many_arguments)]
+pub fn send(
+    uri: String,
+    network: Network,
+    amount: Option<u64>,
+    socks_proxy: Option<String>,
+    wallet: Arc<dyn Wallet>,
+    chain: Arc<dyn ChainSource>,
+    listener: Option<Arc<dyn ProgressListener>>,
+) -> Result<Payment, PayjoinError> {
+    let uri = Bip21Uri::from_str(&uri).map_err(Error::from)?;
+    let socks_proxy = socks_proxy
+        .map(|addr| SocketAddr::from_str(&addr))
+        .transpose()
+        .map_err(|e| PayjoinError::InvalidData {
+            message: e.to_string(),
+        })?;
+
+    let wallet = AppWallet(wallet);
+    let chain = AppChain(chain);
+    let listener = Arc::new(AppListener(listener));
+
+    let mut runtime = tokio::runtime::Runtime::new().map_err(Error::from)?;
+    let result = runtime.block_on(async {
+        match socks_proxy {
+            Some(addr) => {
+                Client::send_with_observer(
+                    &ExternalTor::new(addr, None, None),
+                    &uri,
+                    network.into(),
+                    amount,
+                    &wallet,
+                    chain.clone(),
+                    wallet.clone(),
+                    &chain,
+                    listener,
+                )
+                .await
+            }
+            None => {
+                Client::send_with_observer(
+                    &Direct,
+                    &uri,
+                    network.into(),
+                    amount,
+                    &wallet,
+                    chain.clone(),
+                    wallet.clone(),
+                    &chain,
+                    listener,
+                )
+                .await
+            }
+        }
+    })?;
+
+    Ok(result.into())
+}
+
+/// `Wallet` where the client expects a `SenderWallet` and a `Signer`
+#[derive(Clone)]
+struct AppWallet(Arc<dyn Wallet>);
+
+/// `ChainSource` where the client expects a `Blockchain` and a `FeeEstimator`
+#[derive(Clone)]
+struct AppChain(Arc<dyn ChainSource>);
+
+/// `ProgressListener` where the client expects a `SessionObserver`
+struct AppListener(Option<Arc<dyn ProgressListener>>);
+
+impl fmt::Debug for AppWallet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("AppWallet")
+    }
+}
+
+impl fmt::Debug for AppChain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("AppChain")
+    }
+}
+
+impl fmt::Debug for AppListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("AppListener")
+    }
+}
+
+/// Errors of the app, where the client expects ours
+fn callback_error(error: PayjoinError) -> Error {
+    Error::Transport(error.to_string())
+}
+
+fn decode<T: bitcoin::consensus::Decodable>(bytes: &[u8]) -> Result<T, Error> {
+    deserialize(bytes).map_err(|_| Error::Other)
+}
+
+impl SenderWallet for AppWallet {
+    type Error = Error;
+
+    fn list_unspent(&self) -> Result<Vec<WeightedUtxo>, Self::Error> {
+        self.0
+            .list_unspent()
+            .map_err(callback_error)?
+            .into_iter()
+            .map(|utxo| {
+                let outpoint = OutPoint {
+                    txid: Txid::from_str(&utxo.txid).map_err(|_| Error::Other)?,
+                    vout: utxo.vout,
+                };
+                let txout = TxOut {
+                    value: utxo.value,
+                    script_pubkey: Script::from(utxo.script_pubkey),
+                };
+
+                Ok(WeightedUtxo::new(outpoint, txout))
+            })
+            .collect()
+    }
+
+    fn change_script(&self) -> Result<Script, Self::Error> {
+        Ok(Script::from(
+            self.0.change_script().map_err(callback_error)?,
+        ))
+    }
+}
+
+impl Signer for AppWallet {
+    type Error = Error;
+
+    fn sign(&self, transaction: &mut Transaction, inputs: &[usize]) -> Result<(), Self::Error> {
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(transaction.clone())
+            .map_err(|_| Error::Other)?;
+        self.sign_psbt(&mut psbt, inputs)?;
+
+        for index in inputs {
+            let input = &mut transaction.input[*index];
+            let psbt_input = &mut psbt.inputs[*index];
+
+            input.witness = psbt_input.final_script_witness.take().unwrap_or_default();
+            input.script_sig = psbt_input.final_script_sig.take().unwrap_or_default();
+        }
+
+        Ok(())
+    }
+
+    fn sign_psbt(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        inputs: &[usize],
+    ) -> Result<(), Self::Error> {
+        let indexes = inputs.iter().map(|index| *index as u32).collect();
+        let signed = self
+            .0
+            .sign_psbt(serialize(&*psbt), indexes)
+            .map_err(callback_error)?;
+        *psbt = decode(&signed)?;
+
+        Ok(())
+    }
+}
+
+impl TxSource for AppChain {
+    type Error = Error;
+
+    fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+        decode(&self.0.get_tx(txid.to_string()).map_err(callback_error)?)
+    }
+
+    fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error> {
+        self.0
+            .is_unspent(txout.txid.to_string(), txout.vout)
+            .map_err(callback_error)
+    }
+
+    /// Only receivers draw random UTXOs
+    fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+        Err(Error::Other)
+    }
+}
+
+impl Broadcaster for AppChain {
+    type Error = Error;
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        self.0.broadcast(serialize(tx)).map_err(callback_error)
+    }
+}
+
+impl FeeEstimator for AppChain {
+    type Error = Error;
+
+    fn estimate_fee_rate(&self, target_blocks: usize) -> Result<FeeRate, Self::Error> {
+        let sat_per_vb = self
+            .0
+            .estimate_fee_rate(target_blocks as u32)
+            .map_err(callback_error)?;
+
+        Ok(FeeRate::from_sat_per_vb(sat_per_vb))
+    }
+}
+
+impl SessionObserver for AppListener {
+    fn on_event(&self, event: &SessionEvent) {
+        if let Some(listener) = &self.0 {
+            listener.on_progress(event.into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_uri() {
+        let uri = parse_uri(
+            "bitcoin:bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080?amount=0.001&endpoint=abc.onion:9000"
+                .into(),
+        )
+        .unwrap();
+        assert_eq!(uri.address, "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080");
+        assert_eq!(uri.amount, Some(100_000));
+        assert_eq!(uri.endpoint.as_deref(), Some("abc.onion:9000"));
+        assert_eq!(uri.label, None);
+
+        assert!(matches!(
+            parse_uri("bitcoin:notanaddress".into()),
+            Err(PayjoinError::InvalidUri { .. })
+        ));
+    }
+}
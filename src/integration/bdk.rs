@@ -0,0 +1,502 @@
+//! BDK wallet adapter
+//!
+//! BDK is built on a much newer `bitcoin` crate than this library, so every type crossing the
+//! boundary is converted through its consensus serialization.
+
+use std::fmt;
+use std::sync::Mutex;
+
+use ::bdk::bitcoin as bdk_bitcoin;
+use ::bdk::blockchain::Blockchain as BdkBlockchain;
+use ::bdk::database::BatchDatabase;
+use ::bdk::wallet::AddressIndex;
+use ::bdk::{Error as BdkError, SignOptions, Wallet};
+
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, Script, Transaction, TxOut, Txid};
+
+use crate::blockchain::{Broadcaster, TxSource};
+use crate::coin_selection::UihRange;
+use crate::fees::{FeeEstimator, FeeRate};
+use crate::server::UtxoLocks;
+use crate::signer::Signer;
+use crate::wallet::{select_utxo, ReceiverWallet};
+
+/// Final witness and `script_sig` of a signed input
+type SignedInput = (Vec<Vec<u8>>, Script);
+
+fn to_bdk<T, U>(value: &T) -> Result<U, BdkError>
+where
+    T: bitcoin::consensus::Encodable,
+    U: bdk_bitcoin::consensus::Decodable,
+{
+    bdk_bitcoin::consensus::deserialize(&serialize(value))
+        .map_err(|e| BdkError::Generic(e.to_string()))
+}
+
+fn from_bdk<T, U>(value: &T) -> Result<U, BdkError>
+where
+    T: bdk_bitcoin::consensus::Encodable,
+    U: bitcoin::consensus::Decodable,
+{
+    deserialize(&bdk_bitcoin::consensus::serialize(value))
+        .map_err(|e| BdkError::Generic(e.to_string()))
+}
+
+/// Use a `bdk::Wallet` and one of BDK's blockchain backends with `Client` and `Server`
+///
+/// BDK can't look up arbitrary UTXOs, so some queries fail instead of guessing:
+///
+/// * `is_unspent()` is only answered for outputs owned by the wallet
+/// * `get_random_utxo()` always fails, since the wallet's own UTXOs would be no decoys
+///
+/// A server needs both to check the proofs of its senders and to pick decoys, so it should answer
+/// them from another backend with a `SplitBlockchain` and draw its decoys from a `DecoySource`.
+pub struct BdkAdapter<D, C> {
+    wallet: Mutex<Wallet<D>>,
+    blockchain: C,
+}
+
+impl<D, C> BdkAdapter<D, C>
+where
+    D: BatchDatabase,
+    C: BdkBlockchain,
+{
+    pub fn new(wallet: Wallet<D>, blockchain: C) -> Self {
+        BdkAdapter {
+            wallet: Mutex::new(wallet),
+            blockchain,
+        }
+    }
+
+    pub fn into_inner(self) -> (Wallet<D>, C) {
+        (self.wallet.into_inner().unwrap(), self.blockchain)
+    }
+
+    fn get_bdk_tx(&self, txid: &bdk_bitcoin::Txid) -> Result<bdk_bitcoin::Transaction, BdkError> {
+        let local = self
+            .wallet
+            .lock()
+            .unwrap()
+            .get_tx(txid, true)?
+            .and_then(|details| details.transaction);
+
+        match local {
+            Some(transaction) => Ok(transaction),
+            None => self
+                .blockchain
+                .get_tx(txid)?
+                .ok_or(BdkError::TransactionNotFound),
+        }
+    }
+
+    /// Unspent outputs of the wallet, as contributions to the payjoins
+    fn list_utxos(&self) -> Result<Vec<(OutPoint, TxOut)>, BdkError> {
+        self.wallet
+            .lock()
+            .unwrap()
+            .list_unspent()?
+            .iter()
+            .map(|utxo| Ok((from_bdk(&utxo.outpoint)?, from_bdk(&utxo.txout)?)))
+            .collect()
+    }
+
+    /// Sign `inputs` of `transaction` with the wallet, returning their final witness and
+    /// `script_sig`. Missing entries in `prev_outs` are filled with the wallet's own UTXOs
+    fn sign_inputs(
+        &self,
+        transaction: &Transaction,
+        prev_outs: &[Option<TxOut>],
+        inputs: &[usize],
+    ) -> Result<Vec<SignedInput>, BdkError> {
+        let mut transaction: bdk_bitcoin::Transaction = to_bdk(transaction)?;
+        for input in &mut transaction.input {
+            input.script_sig = bdk_bitcoin::ScriptBuf::new();
+            input.witness.clear();
+        }
+
+        let wallet = self.wallet.lock().unwrap();
+        let mut psbt =
+            bdk_bitcoin::psbt::PartiallySignedTransaction::from_unsigned_tx(transaction.clone())
+                .map_err(|e| BdkError::Generic(e.to_string()))?;
+        for ((psbt_input, input), prev_out) in psbt
+            .inputs
+            .iter_mut()
+            .zip(&transaction.input)
+            .zip(prev_outs)
+        {
+            psbt_input.witness_utxo = match (wallet.get_utxo(input.previous_output)?, prev_out) {
+                (Some(utxo), _) => Some(utxo.txout),
+                (None, Some(prev_out)) => Some(to_bdk(prev_out)?),
+                (None, None) => None,
+            };
+        }
+
+        let sign_options = SignOptions {
+            trust_witness_utxo: true,
+            ..Default::default()
+        };
+        wallet.sign(&mut psbt, sign_options)?;
+
+        inputs
+            .iter()
+            .map(|index| {
+                let psbt_input = &psbt.inputs[*index];
+                let witness = psbt_input
+                    .final_script_witness
+                    .as_ref()
+                    .ok_or_else(|| BdkError::Generic(format!("Can't sign input {}", index)))?
+                    .to_vec();
+                let script_sig = psbt_input
+                    .final_script_sig
+                    .as_ref()
+                    .map(|script_sig| script_sig.to_bytes().into())
+                    .unwrap_or_default();
+
+                Ok((witness, script_sig))
+            })
+            .collect()
+    }
+}
+
+impl<D, C> fmt::Debug for BdkAdapter<D, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BdkAdapter").finish()
+    }
+}
+
+impl<D, C> TxSource for BdkAdapter<D, C>
+where
+    D: BatchDatabase,
+    C: BdkBlockchain,
+{
+    type Error = BdkError;
+
+    fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+        from_bdk(&self.get_bdk_tx(&to_bdk(txid)?)?)
+    }
+
+    /// Only known for the wallet's own outputs
+    fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error> {
+        let outpoint: bdk_bitcoin::OutPoint = to_bdk(txout)?;
+        match self.wallet.lock().unwrap().get_utxo(outpoint)? {
+            Some(utxo) => Ok(!utxo.is_spent),
+            None => Err(BdkError::Generic(format!(
+                "Can't tell if {} is spent, it's not an output of the wallet",
+                txout
+            ))),
+        }
+    }
+
+    fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+        Err(BdkError::Generic(
+            "BDK can't draw decoys, use a DecoySource".into(),
+        ))
+    }
+
+    /// Only known for the wallet's own transactions
+    fn get_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Self::Error> {
+        let details = self.wallet.lock().unwrap().get_tx(&to_bdk(txid)?, false)?;
+
+        match details {
+            Some(details) => match details.confirmation_time {
+                Some(block) => {
+                    let height = self.blockchain.get_height()?;
+                    Ok(Some(height.saturating_sub(block.height) + 1))
+                }
+                None => Ok(Some(0)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn get_height(&self) -> Result<Option<u32>, Self::Error> {
+        Ok(Some(self.blockchain.get_height()?))
+    }
+
+    /// Only known for the wallet's own transactions
+    fn in_mempool(&self, txid: &Txid) -> Result<Option<bool>, Self::Error> {
+        let details = self.wallet.lock().unwrap().get_tx(&to_bdk(txid)?, false)?;
+
+        Ok(details.map(|details| details.confirmation_time.is_none()))
+    }
+}
+
+impl<D, C> Broadcaster for BdkAdapter<D, C>
+where
+    D: BatchDatabase,
+    C: BdkBlockchain,
+{
+    type Error = BdkError;
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        self.blockchain.broadcast(&to_bdk(tx)?)
+    }
+}
+
+/// Estimates from the backend, like Electrum's `blockchain.estimatefee` or Esplora's
+/// `/fee-estimates`
+impl<D, C> FeeEstimator for BdkAdapter<D, C>
+where
+    D: BatchDatabase,
+    C: BdkBlockchain,
+{
+    type Error = BdkError;
+
+    fn estimate_fee_rate(&self, target_blocks: usize) -> Result<FeeRate, Self::Error> {
+        let fee_rate = self.blockchain.estimate_fee(target_blocks)?;
+
+        Ok(FeeRate::from_sat_per_vb(fee_rate.as_sat_per_vb()))
+    }
+}
+
+impl<D, C> Signer for BdkAdapter<D, C>
+where
+    D: BatchDatabase,
+    C: BdkBlockchain,
+{
+    type Error = BdkError;
+
+    fn sign(&self, transaction: &mut Transaction, inputs: &[usize]) -> Result<(), Self::Error> {
+        let prev_outs = vec![None; transaction.input.len()];
+        let signed = self.sign_inputs(transaction, &prev_outs, inputs)?;
+
+        for (index, (witness, script_sig)) in inputs.iter().zip(signed) {
+            transaction.input[*index].witness = witness;
+            transaction.input[*index].script_sig = script_sig;
+        }
+
+        Ok(())
+    }
+
+    fn sign_with_prev_outs(
+        &self,
+        transaction: &mut Transaction,
+        inputs: &[usize],
+        prev_outs: &[TxOut],
+    ) -> Result<(), Self::Error> {
+        let prev_outs = prev_outs.iter().cloned().map(Some).collect::<Vec<_>>();
+        let signed = self.sign_inputs(transaction, &prev_outs, inputs)?;
+
+        for (index, (witness, script_sig)) in inputs.iter().zip(signed) {
+            transaction.input[*index].witness = witness;
+            transaction.input[*index].script_sig = script_sig;
+        }
+
+        Ok(())
+    }
+
+    fn sign_psbt(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        inputs: &[usize],
+    ) -> Result<(), Self::Error> {
+        let prev_outs = psbt
+            .inputs
+            .iter()
+            .map(|input| input.witness_utxo.clone())
+            .collect::<Vec<_>>();
+        let signed = self.sign_inputs(&psbt.global.unsigned_tx, &prev_outs, inputs)?;
+
+        for (index, (witness, script_sig)) in inputs.iter().zip(signed) {
+            psbt.inputs[*index].final_script_witness = Some(witness);
+            if !script_sig.is_empty() {
+                psbt.inputs[*index].final_script_sig = Some(script_sig);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<D, C> ReceiverWallet for BdkAdapter<D, C>
+where
+    D: BatchDatabase,
+    C: BdkBlockchain,
+{
+    type Error = BdkError;
+
+    fn next_utxo(&self, amount: u64, locks: &UtxoLocks) -> Result<(OutPoint, TxOut), Self::Error> {
+        select_utxo(self.list_utxos()?, amount, None, locks).ok_or(BdkError::Generic(
+            "No UTXO available for the payjoin".into(),
+        ))
+    }
+
+    fn next_utxo_within(
+        &self,
+        amount: u64,
+        range: &UihRange,
+        locks: &UtxoLocks,
+    ) -> Result<(OutPoint, TxOut), Self::Error> {
+        select_utxo(self.list_utxos()?, amount, Some(range), locks).ok_or(BdkError::Generic(
+            "No UTXO available for the payjoin".into(),
+        ))
+    }
+
+    fn next_script(&self) -> Result<Script, Self::Error> {
+        let address = self.wallet.lock().unwrap().get_address(AddressIndex::New)?;
+
+        Ok(address.script_pubkey().to_bytes().into())
+    }
+
+    fn change_script(&self) -> Result<Option<Script>, Self::Error> {
+        let address = self
+            .wallet
+            .lock()
+            .unwrap()
+            .get_internal_address(AddressIndex::New)?;
+
+        Ok(Some(address.script_pubkey().to_bytes().into()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    use ::bdk::bitcoin::{absolute, BlockHash, Network};
+    use ::bdk::blockchain::{Capability, GetBlockHash, GetHeight, GetTx, Progress, WalletSync};
+    use ::bdk::database::{BatchOperations, MemoryDatabase};
+    use ::bdk::{FeeRate as BdkFeeRate, KeychainKind, LocalUtxo, TransactionDetails};
+
+    use bitcoin::hashes::hex::FromHex;
+
+    use super::*;
+
+    /// Backend knowing the transactions in `txs`, and nothing else
+    struct MockBlockchain {
+        txs: Vec<bdk_bitcoin::Transaction>,
+    }
+
+    impl BdkBlockchain for MockBlockchain {
+        fn get_capabilities(&self) -> HashSet<Capability> {
+            HashSet::new()
+        }
+        fn broadcast(&self, _tx: &bdk_bitcoin::Transaction) -> Result<(), BdkError> {
+            Ok(())
+        }
+        fn estimate_fee(&self, _target: usize) -> Result<BdkFeeRate, BdkError> {
+            Ok(BdkFeeRate::from_sat_per_vb(1.0))
+        }
+    }
+
+    impl GetHeight for MockBlockchain {
+        fn get_height(&self) -> Result<u32, BdkError> {
+            Ok(100)
+        }
+    }
+
+    impl GetTx for MockBlockchain {
+        fn get_tx(
+            &self,
+            txid: &bdk_bitcoin::Txid,
+        ) -> Result<Option<bdk_bitcoin::Transaction>, BdkError> {
+            Ok(self.txs.iter().find(|tx| tx.txid() == *txid).cloned())
+        }
+    }
+
+    impl GetBlockHash for MockBlockchain {
+        fn get_block_hash(&self, _height: u64) -> Result<BlockHash, BdkError> {
+            Err(BdkError::Generic("No blocks".into()))
+        }
+    }
+
+    impl WalletSync for MockBlockchain {
+        fn wallet_setup<D: BatchDatabase>(
+            &self,
+            _database: &RefCell<D>,
+            _progress_update: Box<dyn Progress>,
+        ) -> Result<(), BdkError> {
+            Ok(())
+        }
+    }
+
+    fn transaction(script_pubkey: bdk_bitcoin::ScriptBuf) -> bdk_bitcoin::Transaction {
+        bdk_bitcoin::Transaction {
+            version: 2,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![Default::default()],
+            output: vec![bdk_bitcoin::TxOut {
+                value: 100_000,
+                script_pubkey,
+            }],
+        }
+    }
+
+    /// Adapter with a spent and an unspent output in the wallet, and a foreign transaction known
+    /// to the backend. Returns the three outpoints
+    fn adapter() -> (BdkAdapter<MemoryDatabase, MockBlockchain>, [OutPoint; 3]) {
+        let descriptor = "wpkh(cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW)";
+        let script_pubkey = Wallet::new(descriptor, None, Network::Regtest, MemoryDatabase::new())
+            .unwrap()
+            .get_address(AddressIndex::Peek(0))
+            .unwrap()
+            .script_pubkey();
+
+        let mut database = MemoryDatabase::new();
+        let mut ours = Vec::new();
+        for is_spent in [false, true] {
+            let mut tx = transaction(script_pubkey.clone());
+            tx.output[0].value += is_spent as u64;
+            let outpoint = bdk_bitcoin::OutPoint::new(tx.txid(), 0);
+            database
+                .set_tx(&TransactionDetails {
+                    txid: tx.txid(),
+                    transaction: Some(tx.clone()),
+                    received: 100_000,
+                    sent: 0,
+                    fee: Some(0),
+                    confirmation_time: None,
+                })
+                .unwrap();
+            database
+                .set_utxo(&LocalUtxo {
+                    outpoint,
+                    txout: tx.output[0].clone(),
+                    keychain: KeychainKind::External,
+                    is_spent,
+                })
+                .unwrap();
+            ours.push(from_bdk(&outpoint).unwrap());
+        }
+        let wallet = Wallet::new(descriptor, None, Network::Regtest, database).unwrap();
+
+        let foreign = transaction(bdk_bitcoin::ScriptBuf::new());
+        let outpoint = OutPoint::new(
+            bitcoin::Txid::from_hex(&foreign.txid().to_string()).unwrap(),
+            0,
+        );
+        let blockchain = MockBlockchain { txs: vec![foreign] };
+
+        (
+            BdkAdapter::new(wallet, blockchain),
+            [ours[0], ours[1], outpoint],
+        )
+    }
+
+    #[test]
+    fn test_is_unspent() {
+        let (adapter, [unspent, spent, foreign]) = adapter();
+
+        assert!(adapter.is_unspent(&unspent).unwrap());
+        assert!(!adapter.is_unspent(&spent).unwrap());
+        // Known to the backend, but it can't tell whether it's spent
+        assert!(adapter.get_tx(&foreign.txid).is_ok());
+        assert!(adapter.is_unspent(&foreign).is_err());
+        assert!(adapter
+            .is_unspent(&OutPoint::from_str(&format!("{}:0", Txid::default())).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_get_random_utxo() {
+        let (adapter, _) = adapter();
+
+        // The wallet has UTXOs, but they can't be decoys
+        assert_eq!(adapter.list_utxos().unwrap().len(), 1);
+        assert!(adapter.get_random_utxo().is_err());
+    }
+}
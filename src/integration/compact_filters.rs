@@ -0,0 +1,491 @@
+//! Backend over BIP157/158 compact block filters
+//!
+//! Unlike with an Electrum server, the scripts we care about never leave the machine: the filter
+//! of every block is matched locally, and only the matching blocks are downloaded in full. The
+//! light client talking to the P2P network, like nakamoto or kyoto, is abstracted behind
+//! `FilterSource`.
+//!
+//! Only the transactions involving a watched script are ever seen, so some queries are answered
+//! on a best-effort basis:
+//!
+//! * `get_tx()` fails for other transactions, unless the source finds them in its mempool
+//! * `is_unspent()` on an output of a known transaction starts watching its script, rescanning
+//!   the blocks since that transaction. Unconfirmed outputs of unknown transactions are considered
+//!   unspent
+//! * decoy UTXOs for the server are picked among those of the watched scripts
+//! * reorgs are not followed, blocks are scanned once as they were first seen
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Mutex;
+
+use tracing::{debug, trace};
+
+use rand::seq::IteratorRandom;
+
+use bitcoin::util::bip158::{self, BlockFilter};
+use bitcoin::{Block, BlockHash, OutPoint, Script, Transaction, Txid};
+
+use crate::blockchain::{Broadcaster, TxSource};
+use crate::Error;
+
+/// Light client serving blocks and their basic filters from the P2P network
+pub trait FilterSource {
+    type Error;
+
+    /// Height of the best chain known to the light client
+    fn get_height(&self) -> Result<u32, Self::Error>;
+    /// Hash and basic filter of the block at `height`
+    fn get_filter(&self, height: u32) -> Result<(BlockHash, BlockFilter), Self::Error>;
+    fn get_block(&self, hash: &BlockHash) -> Result<Block, Self::Error>;
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error>;
+
+    /// Unconfirmed transaction relayed by the peers, or `None` if it's unknown. The default never
+    /// finds any
+    fn get_mempool_tx(&self, _txid: &Txid) -> Result<Option<Transaction>, Self::Error> {
+        Ok(None)
+    }
+}
+
+#[derive(Debug)]
+pub enum CompactFiltersError {
+    /// The transaction doesn't involve any of the watched scripts
+    UnknownTransaction(Txid),
+    /// None of the watched scripts has an unspent output
+    NoUtxos,
+    InvalidFilter(bip158::Error),
+}
+
+impl fmt::Display for CompactFiltersError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompactFiltersError::UnknownTransaction(txid) => {
+                write!(f, "transaction {} is not watched", txid)
+            }
+            CompactFiltersError::NoUtxos => write!(f, "no unspent output of the watched scripts"),
+            CompactFiltersError::InvalidFilter(_) => write!(f, "invalid block filter"),
+        }
+    }
+}
+
+impl std::error::Error for CompactFiltersError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CompactFiltersError::InvalidFilter(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// What the scanned blocks told us about the watched scripts
+#[derive(Debug, Default)]
+struct UtxoView {
+    scripts: HashSet<Script>,
+    /// Transactions involving a watched script, with the height of their block once confirmed
+    transactions: HashMap<Txid, (Transaction, Option<u32>)>,
+    unspent: HashSet<OutPoint>,
+    /// Outputs of the watched scripts that have been spent, and the transaction spending them
+    spent: HashMap<OutPoint, Txid>,
+    /// Height of the next block to scan
+    next_height: u32,
+}
+
+impl UtxoView {
+    fn add_transaction(&mut self, tx: Transaction, height: Option<u32>) {
+        let txid = tx.txid();
+        let mut relevant = false;
+
+        for input in &tx.input {
+            let prev_out = input.previous_output;
+            let watched = self
+                .transactions
+                .get(&prev_out.txid)
+                .and_then(|(prev_tx, _)| prev_tx.output.get(prev_out.vout as usize))
+                .is_some_and(|txout| self.scripts.contains(&txout.script_pubkey));
+            if watched {
+                self.unspent.remove(&prev_out);
+                self.spent.insert(prev_out, txid);
+                relevant = true;
+            }
+        }
+        for (vout, txout) in tx.output.iter().enumerate() {
+            if self.scripts.contains(&txout.script_pubkey) {
+                let outpoint = OutPoint::new(txid, vout as u32);
+                if !self.spent.contains_key(&outpoint) {
+                    self.unspent.insert(outpoint);
+                }
+                relevant = true;
+            }
+        }
+
+        if relevant {
+            self.transactions.insert(txid, (tx, height));
+        }
+    }
+}
+
+/// `Blockchain` following the outputs of a set of scripts through compact block filters
+pub struct CompactFiltersBlockchain<F> {
+    source: F,
+    start_height: u32,
+    view: Mutex<UtxoView>,
+}
+
+impl<F> fmt::Debug for CompactFiltersBlockchain<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CompactFiltersBlockchain")
+            .field("start_height", &self.start_height)
+            .field("view", &self.view)
+            .finish()
+    }
+}
+
+impl<F> CompactFiltersBlockchain<F>
+where
+    F: FilterSource,
+    Error: From<<F as FilterSource>::Error>,
+{
+    /// Scan the blocks from `start_height`, like the birthday of the wallet
+    pub fn new(source: F, start_height: u32) -> Self {
+        CompactFiltersBlockchain {
+            source,
+            start_height,
+            view: Mutex::new(UtxoView {
+                next_height: start_height,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Follow the outputs of `script_pubkey`, rescanning the blocks already seen
+    pub fn watch(&self, script_pubkey: Script) -> Result<(), Error> {
+        let mut view = self.view.lock().unwrap();
+        self.rescan(&mut view, self.start_height, script_pubkey)
+    }
+
+    pub fn into_inner(self) -> F {
+        self.source
+    }
+
+    /// Scan the blocks up to the tip of the source
+    fn sync(&self, view: &mut UtxoView) -> Result<(), Error> {
+        let tip = self.source.get_height()?;
+        let scripts = view.scripts.iter().cloned().collect::<Vec<_>>();
+
+        while view.next_height <= tip {
+            self.scan(view, view.next_height, &scripts)?;
+            view.next_height += 1;
+        }
+        Ok(())
+    }
+
+    fn rescan(&self, view: &mut UtxoView, from: u32, script_pubkey: Script) -> Result<(), Error> {
+        if !view.scripts.insert(script_pubkey.clone()) {
+            return Ok(());
+        }
+
+        debug!("Rescanning from height {} for {}", from, script_pubkey);
+        let scripts = vec![script_pubkey];
+        for height in from..view.next_height {
+            self.scan(view, height, &scripts)?;
+        }
+        Ok(())
+    }
+
+    /// Add the transactions of the block at `height` if its filter matches any of `scripts`
+    fn scan(&self, view: &mut UtxoView, height: u32, scripts: &[Script]) -> Result<(), Error> {
+        // An empty query matches every filter
+        if scripts.is_empty() {
+            return Ok(());
+        }
+
+        let (hash, filter) = self.source.get_filter(height)?;
+        let matches = filter
+            .match_any(&hash, &mut scripts.iter().map(|script| script.as_bytes()))
+            .map_err(CompactFiltersError::InvalidFilter)?;
+        if !matches {
+            return Ok(());
+        }
+
+        trace!("Block {} at height {} matches the filter", hash, height);
+        for tx in self.source.get_block(&hash)?.txdata {
+            view.add_transaction(tx, Some(height));
+        }
+        Ok(())
+    }
+}
+
+impl<F> TxSource for CompactFiltersBlockchain<F>
+where
+    F: FilterSource,
+    Error: From<<F as FilterSource>::Error>,
+{
+    type Error = Error;
+
+    fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+        let mut view = self.view.lock().unwrap();
+        self.sync(&mut view)?;
+        if let Some((tx, _)) = view.transactions.get(txid) {
+            return Ok(tx.clone());
+        }
+
+        self.source
+            .get_mempool_tx(txid)?
+            .ok_or_else(|| CompactFiltersError::UnknownTransaction(*txid).into())
+    }
+    fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error> {
+        let mut view = self.view.lock().unwrap();
+        self.sync(&mut view)?;
+        if view.unspent.contains(txout) {
+            return Ok(true);
+        } else if view.spent.contains_key(txout) {
+            return Ok(false);
+        }
+
+        let (script_pubkey, height) = match view.transactions.get(&txout.txid) {
+            Some((tx, height)) => match tx.output.get(txout.vout as usize) {
+                Some(prev_out) => (prev_out.script_pubkey.clone(), *height),
+                None => return Ok(false),
+            },
+            // The peers can't tell us about spends of the mempool
+            None => match self.source.get_mempool_tx(&txout.txid)? {
+                Some(tx) => return Ok(tx.output.len() > txout.vout as usize),
+                None => return Err(CompactFiltersError::UnknownTransaction(txout.txid).into()),
+            },
+        };
+        if view.scripts.contains(&script_pubkey) {
+            return Ok(false);
+        }
+
+        match height {
+            Some(height) => self.rescan(&mut view, height, script_pubkey)?,
+            // Not confirmed yet, so spent at most by our own broadcasts
+            None => {
+                view.scripts.insert(script_pubkey);
+                view.unspent.insert(*txout);
+            }
+        }
+        Ok(view.unspent.contains(txout))
+    }
+    fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+        let mut view = self.view.lock().unwrap();
+        self.sync(&mut view)?;
+
+        view.unspent
+            .iter()
+            .choose(&mut rand::thread_rng())
+            .cloned()
+            .ok_or_else(|| CompactFiltersError::NoUtxos.into())
+    }
+    fn get_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Self::Error> {
+        let mut view = self.view.lock().unwrap();
+        self.sync(&mut view)?;
+
+        Ok(view.transactions.get(txid).map(|(_, height)| match height {
+            Some(height) => view.next_height - height,
+            None => 0,
+        }))
+    }
+    fn get_height(&self) -> Result<Option<u32>, Self::Error> {
+        Ok(Some(self.source.get_height()?))
+    }
+    fn in_mempool(&self, txid: &Txid) -> Result<Option<bool>, Self::Error> {
+        let mut view = self.view.lock().unwrap();
+        self.sync(&mut view)?;
+
+        Ok(view
+            .transactions
+            .get(txid)
+            .map(|(_, height)| height.is_none()))
+    }
+    fn get_spending_tx(&self, txout: &OutPoint) -> Result<Option<Transaction>, Self::Error> {
+        let mut view = self.view.lock().unwrap();
+        self.sync(&mut view)?;
+
+        Ok(view
+            .spent
+            .get(txout)
+            .and_then(|txid| view.transactions.get(txid))
+            .map(|(tx, _)| tx.clone()))
+    }
+}
+
+impl<F> Broadcaster for CompactFiltersBlockchain<F>
+where
+    F: FilterSource,
+    Error: From<<F as FilterSource>::Error>,
+{
+    type Error = Error;
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        self.source.broadcast(tx)?;
+
+        // Nothing tells us about the mempool, so keep track of our own transactions until they
+        // confirm
+        self.view.lock().unwrap().add_transaction(tx.clone(), None);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+    use bitcoin::blockdata::block::BlockHeader;
+    use bitcoin::hashes::Hash;
+    use bitcoin::util::hash::BitcoinHash;
+    use bitcoin::{TxIn, TxOut};
+
+    use super::*;
+
+    /// Chain of blocks with their filters, up to `height`
+    struct MockSource {
+        blocks: Vec<(Block, BlockFilter)>,
+        height: AtomicU32,
+        downloads: AtomicUsize,
+    }
+
+    impl MockSource {
+        fn new(transactions: Vec<Vec<Transaction>>) -> Self {
+            let mut outputs = HashMap::new();
+            let blocks = transactions
+                .into_iter()
+                .map(|txdata| {
+                    for tx in &txdata {
+                        for (vout, txout) in tx.output.iter().enumerate() {
+                            outputs.insert(
+                                OutPoint::new(tx.txid(), vout as u32),
+                                txout.script_pubkey.clone(),
+                            );
+                        }
+                    }
+
+                    let block = Block {
+                        header: BlockHeader {
+                            version: 1,
+                            prev_blockhash: BlockHash::default(),
+                            merkle_root: Hash::hash(&serialize_txids(&txdata)),
+                            time: 0,
+                            bits: 0,
+                            nonce: 0,
+                        },
+                        txdata,
+                    };
+                    let filter = BlockFilter::new_script_filter(&block, |outpoint| {
+                        outputs
+                            .get(outpoint)
+                            .cloned()
+                            .ok_or(bip158::Error::UtxoMissing(*outpoint))
+                    })
+                    .unwrap();
+
+                    (block, filter)
+                })
+                .collect::<Vec<_>>();
+
+            MockSource {
+                height: AtomicU32::new(blocks.len() as u32 - 1),
+                blocks,
+                downloads: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    fn serialize_txids(txdata: &[Transaction]) -> Vec<u8> {
+        txdata
+            .iter()
+            .flat_map(|tx| tx.txid().into_inner().to_vec())
+            .collect()
+    }
+
+    impl FilterSource for MockSource {
+        type Error = ();
+
+        fn get_height(&self) -> Result<u32, Self::Error> {
+            Ok(self.height.load(Ordering::SeqCst))
+        }
+        fn get_filter(&self, height: u32) -> Result<(BlockHash, BlockFilter), Self::Error> {
+            let (block, filter) = self.blocks.get(height as usize).ok_or(())?;
+            Ok((block.bitcoin_hash(), BlockFilter::new(&filter.content)))
+        }
+        fn get_block(&self, hash: &BlockHash) -> Result<Block, Self::Error> {
+            self.downloads.fetch_add(1, Ordering::SeqCst);
+            self.blocks
+                .iter()
+                .map(|(block, _)| block)
+                .find(|block| block.bitcoin_hash() == *hash)
+                .cloned()
+                .ok_or(())
+        }
+        fn broadcast(&self, _tx: &Transaction) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn transaction(inputs: Vec<OutPoint>, outputs: Vec<&Script>, lock_time: u32) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time,
+            input: inputs
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    script_sig: Script::new(),
+                    sequence: 0xFFFF_FFFF,
+                    witness: vec![],
+                })
+                .collect(),
+            output: outputs
+                .into_iter()
+                .map(|script_pubkey| TxOut {
+                    value: 100_000,
+                    script_pubkey: script_pubkey.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_compact_filters_blockchain() {
+        let ours = Script::from(vec![0x00, 0x14, 0x01]);
+        let theirs = Script::from(vec![0x00, 0x14, 0x02]);
+        let other = Script::from(vec![0x00, 0x14, 0x03]);
+
+        let funding = transaction(vec![OutPoint::default()], vec![&ours, &theirs], 0);
+        let unrelated = transaction(vec![OutPoint::default()], vec![&other], 1);
+        let spend = transaction(vec![OutPoint::new(funding.txid(), 0)], vec![&other], 2);
+        let source = MockSource::new(vec![
+            vec![funding.clone()],
+            vec![unrelated.clone()],
+            vec![
+                transaction(vec![OutPoint::default()], vec![&other], 3),
+                spend.clone(),
+            ],
+        ]);
+        source.height.store(1, Ordering::SeqCst);
+
+        let blockchain = CompactFiltersBlockchain::new(source, 0);
+        blockchain.watch(ours).unwrap();
+        let ours = OutPoint::new(funding.txid(), 0);
+        assert!(blockchain.is_unspent(&ours).unwrap());
+        assert_eq!(blockchain.get_random_utxo().unwrap(), ours);
+        assert_eq!(blockchain.get_tx(&funding.txid()).unwrap(), funding);
+        assert!(blockchain.get_tx(&unrelated.txid()).is_err());
+        assert_eq!(blockchain.source.downloads.load(Ordering::SeqCst), 1);
+
+        blockchain.source.height.store(2, Ordering::SeqCst);
+        assert!(!blockchain.is_unspent(&ours).unwrap());
+        assert_eq!(blockchain.get_spending_tx(&ours).unwrap(), Some(spend));
+        assert_eq!(
+            blockchain.get_confirmations(&funding.txid()).unwrap(),
+            Some(3)
+        );
+        assert!(blockchain.get_random_utxo().is_err());
+
+        // Watched on the fly, from the block of the transaction
+        assert!(blockchain
+            .is_unspent(&OutPoint::new(funding.txid(), 1))
+            .unwrap());
+        assert!(blockchain.get_random_utxo().is_ok());
+    }
+}
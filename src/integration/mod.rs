@@ -0,0 +1,5 @@
+//! Adapters implementing `Blockchain`, `Signer` and `ReceiverWallet` on top of existing wallets
+
+#[cfg(feature = "bdk")]
+pub mod bdk;
+pub mod compact_filters;
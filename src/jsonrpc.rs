@@ -1,17 +1,35 @@
-use std::convert::{TryFrom, TryInto};
+use std::convert::TryFrom;
+#[cfg(not(target_arch = "wasm32"))]
+use std::convert::TryInto;
 
+#[cfg(not(target_arch = "wasm32"))]
 use std::time::Duration;
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::tcp::{ReadHalf, WriteHalf};
-use tokio::net::TcpStream;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::io::{
+    split, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    ReadHalf, WriteHalf,
+};
 
+#[cfg(not(target_arch = "wasm32"))]
 use tokio::time::timeout;
 
-use log::{debug, info, trace};
+use tracing::debug;
+#[cfg(not(target_arch = "wasm32"))]
+use tracing::{info, trace, Span};
 
-use crate::Error;
-use crate::Message;
+#[cfg(not(target_arch = "wasm32"))]
+use serde_json::Value;
+
+use crate::redact::Sensitive;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::transcript::{Direction, Transcript};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{run_blocking, Envelope, Error, TimeoutPhase};
+use crate::{Message, ProtocolError, Request};
+
+/// Default limit on the size of a single line received from the peer, batches included
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
 
 pub trait JsonRpcState: std::fmt::Debug {
     type OutMessage: Into<Message> + TryFrom<Message>;
@@ -27,69 +45,287 @@ pub trait JsonRpcState: std::fmt::Debug {
         &mut self,
         message: Self::InMessage,
     ) -> Result<Option<Self::OutMessage>, Self::Error>;
+
+    /// Handle a recoverable error of the peer (see `ProtocolError::is_recoverable()`), returning
+    /// the request to retry with. `None`, the default, ends the session with the error
+    fn recover(&mut self, _error: &ProtocolError) -> Result<Option<Self::OutMessage>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Handle a notification (a request without id) from the peer. Runs on the reactor, so it
+    /// must not block. Ignored by default
+    fn notification(&mut self, message: Message) -> Result<(), Self::Error> {
+        debug!("Ignoring notification: {:?}", Sensitive(&message));
+        Ok(())
+    }
+
+    /// Advisory messages to send to the peer, which won't get a response. Polled after the setup
+    /// and after every message received
+    fn notifications(&mut self) -> Vec<Request> {
+        Vec::new()
+    }
+
+    #[allow(clippy::result_unit_err)]
     fn done(&self) -> Result<Self::Response, ()>;
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 #[derive(Debug)]
-pub struct JsonRpc<'a, T>
+pub struct JsonRpc<'a, T, S>
 where
     T: JsonRpcState,
 {
-    reader: BufReader<ReadHalf<'a>>,
-    writer: WriteHalf<'a>,
+    reader: BufReader<ReadHalf<&'a mut S>>,
+    writer: WriteHalf<&'a mut S>,
     timeout: Duration,
-    state: T,
+    session_timeout: Option<Duration>,
+    max_message_size: usize,
+    transcript: Option<Transcript>,
+    // Only `None` while a message is being processed on the blocking thread pool
+    state: Option<T>,
+
+    next_id: u64,
+    // Id of the request we sent and are waiting a response for, or of the request we received and
+    // have to respond to
+    pending_id: Option<Value>,
 }
 
-impl<'a, T> JsonRpc<'a, T>
+#[cfg(not(target_arch = "wasm32"))]
+impl<'a, T, S> JsonRpc<'a, T, S>
 where
-    T: JsonRpcState<Error = Error>,
+    S: AsyncRead + AsyncWrite + Unpin,
+    T: JsonRpcState<Error = Error> + Send + 'static,
+    <T as JsonRpcState>::InMessage: Send + 'static,
+    <T as JsonRpcState>::OutMessage: Send + 'static,
     <<T as JsonRpcState>::InMessage as std::convert::TryFrom<Message>>::Error: std::fmt::Debug,
 {
-    pub fn new(stream: &'a mut TcpStream, state: T, timeout: Duration) -> JsonRpc<'a, T> {
-        let (raw_read, writer) = stream.split();
+    pub fn new(stream: &'a mut S, state: T, timeout: Duration) -> JsonRpc<'a, T, S> {
+        let (raw_read, writer) = split(stream);
         let reader = BufReader::new(raw_read);
 
         JsonRpc {
             reader,
             writer,
             timeout,
-            state,
+            session_timeout: None,
+            max_message_size: MAX_MESSAGE_SIZE,
+            transcript: None,
+            state: Some(state),
+
+            next_id: 1,
+            pending_id: None,
         }
     }
 
-    async fn write(&mut self, message: &serde_json::Value) -> Result<(), Error> {
-        debug!("Sending response: {:?}", message);
+    /// Give up on the whole negotiation after `limit`, on top of the timeout on every message
+    pub fn with_session_timeout(mut self, limit: Duration) -> Self {
+        self.session_timeout = Some(limit);
+        self
+    }
+
+    /// Drop the connection when the peer sends a line longer than `limit` bytes
+    pub fn with_max_message_size(mut self, limit: usize) -> Self {
+        self.max_message_size = limit;
+        self
+    }
+
+    /// Record every message sent and received in `transcript`, if any
+    pub fn with_transcript(mut self, transcript: Option<Transcript>) -> Self {
+        self.transcript = transcript;
+        self
+    }
+
+    fn record(&self, direction: Direction, message: &Value) {
+        if let Some(transcript) = &self.transcript {
+            transcript.record(direction, message.clone());
+        }
+    }
+
+    /// Read the next line into `line`, without buffering more than `max_message_size` bytes
+    async fn read_line(&mut self, line: &mut String) -> Result<usize, Error> {
+        let limit = self.max_message_size;
+        let read = (&mut self.reader)
+            .take(limit as u64)
+            .read_line(line)
+            .await?;
+        if read == limit && !line.ends_with('\n') {
+            return Err(Error::MessageTooLarge);
+        }
+
+        Ok(read)
+    }
+
+    async fn process(
+        &mut self,
+        message: <T as JsonRpcState>::InMessage,
+    ) -> Result<Option<<T as JsonRpcState>::OutMessage>, Error> {
+        let mut state = self.state.take().ok_or(Error::Other)?;
+        // Keep the session's span on the blocking thread
+        let span = Span::current();
+        let (state, result) = run_blocking(move || {
+            let _span = span.enter();
+            let result = state.message(message);
+            (state, result)
+        })
+        .await?;
+        self.state = Some(state);
+
+        result
+    }
+
+    /// Let the state retry after the recoverable `error` of the peer
+    async fn process_error(
+        &mut self,
+        error: ProtocolError,
+    ) -> Result<Option<<T as JsonRpcState>::OutMessage>, Error> {
+        let mut state = self.state.take().ok_or(Error::Other)?;
+        let span = Span::current();
+        let (state, result) = run_blocking(move || {
+            let _span = span.enter();
+            let result = state.recover(&error).map(|retry| retry.ok_or(error));
+            (state, result)
+        })
+        .await?;
+        self.state = Some(state);
+
+        match result? {
+            Ok(retry) => Ok(Some(retry)),
+            Err(error) => Err(Error::PeerError(error)),
+        }
+    }
+
+    /// Serialize `message`, with a fresh id for requests or the id of the pending request for
+    /// responses and errors
+    fn encode(&mut self, message: Message) -> Result<Value, Error> {
+        let id = match message {
+            Message::Request { .. } => {
+                let id = Value::from(self.next_id.to_string());
+                self.next_id += 1;
+                self.pending_id = Some(id.clone());
+
+                id
+            }
+            _ => self.pending_id.take().unwrap_or(Value::Null),
+        };
+
+        Ok(serde_json::to_value(Envelope::new(id, message))?)
+    }
+
+    async fn write_raw(&mut self, message: &Value) -> Result<(), Error> {
+        debug!("Sending message: {:?}", Sensitive(message));
+        self.record(Direction::Sent, message);
 
         let mut raw = serde_json::to_vec(message)?;
         raw.extend_from_slice(b"\n");
         self.writer.write_all(&raw).await?;
+        // Framed transports may hold the line until they are flushed
+        self.writer.flush().await?;
+
+        Ok(())
+    }
+
+    async fn write(&mut self, message: Message) -> Result<(), Error> {
+        let message = self.encode(message)?;
+        self.write_raw(&message).await
+    }
+
+    async fn write_notifications(&mut self) -> Result<(), Error> {
+        for request in self.state.as_mut().ok_or(Error::Other)?.notifications() {
+            let message = serde_json::to_value(Envelope::notification(request))?;
+            self.write_raw(&message).await?;
+        }
 
         Ok(())
     }
 
+    /// Check the id of an incoming message, returning the error to report if it's wrong
+    fn check_id(&mut self, message: &Message, id: Value) -> Result<(), ProtocolError> {
+        match message {
+            Message::Request { .. } => {
+                self.pending_id = Some(id);
+                Ok(())
+            }
+            // The peer may not have been able to parse our request
+            Message::Error { .. } if id.is_null() => Ok(()),
+            _ => match self.pending_id.take() {
+                Some(expected) if expected == id => Ok(()),
+                // A response to a request we never sent
+                None => {
+                    self.pending_id = Some(id);
+                    Err(ProtocolError::UnexpectedMessage)
+                }
+                _ => {
+                    // Report the error with the id we received
+                    self.pending_id = Some(id);
+                    Err(ProtocolError::InvalidId)
+                }
+            },
+        }
+    }
+
+    /// Handle a single incoming message, returning the one to send back
+    async fn handle(
+        &mut self,
+        raw: Value,
+    ) -> Result<Option<<T as JsonRpcState>::OutMessage>, Error> {
+        let Envelope { id, message, .. } = serde_json::from_value(raw)?;
+        debug!("Received message {:?}: {:?}", id, Sensitive(&message));
+
+        let id = match (id, &message) {
+            (None, Message::Request { .. }) => {
+                self.state
+                    .as_mut()
+                    .ok_or(Error::Other)?
+                    .notification(message)?;
+                return Ok(None);
+            }
+            (id, _) => id.unwrap_or(Value::Null),
+        };
+        self.check_id(&message, id)?;
+
+        // handle errors separately
+        if let Message::Error { error } = message {
+            if error.is_recoverable() {
+                debug!("Recovering from {:?}", error);
+                return self.process_error(error).await;
+            }
+            return Err(Error::PeerError(error));
+        }
+        let parsed: <T as JsonRpcState>::InMessage = message
+            .try_into()
+            .map_err(|_| ProtocolError::UnexpectedMessage)?;
+        self.process(parsed).await
+    }
+
     pub async fn mainloop(&mut self) -> Result<<T as JsonRpcState>::Response, Error> {
+        match self.session_timeout {
+            Some(limit) => timeout(limit, self.run())
+                .await
+                .unwrap_or(Err(Error::Timeout(TimeoutPhase::Session))),
+            None => self.run().await,
+        }
+    }
+
+    async fn run(&mut self) -> Result<<T as JsonRpcState>::Response, Error> {
         info!("Starting mainloop...");
 
         // Optional setup message
-        if let Some(response) = self.state.setup()? {
-            let cast: Message = response.into();
-            self.write(&cast.as_json("1")?).await?; // TODO: id
+        if let Some(response) = self.state.as_mut().ok_or(Error::Other)?.setup()? {
+            self.write(response.into()).await?;
         }
+        self.write_notifications().await?;
 
         let mut line = String::with_capacity(1024);
         loop {
             line.clear();
 
-            match timeout(self.timeout, self.reader.read_line(&mut line)).await {
-                Err(_) => return Err(Error::Timeout),
+            match timeout(self.timeout, self.read_line(&mut line)).await {
+                Err(_) => return Err(Error::Timeout(TimeoutPhase::Message)),
                 Ok(Err(e)) => {
-                    let e: Error = e.into();
                     if let Error::Protocol(protocol_err) = &e {
                         debug!("Protocol error: {:?}", protocol_err);
 
-                        let cast: Message = protocol_err.clone().into();
-                        self.write(&cast.as_json("1")?).await?;
+                        self.write(protocol_err.clone().into()).await?;
                     }
 
                     return Err(e);
@@ -97,31 +333,385 @@ where
                 Ok(Ok(0)) => return Err(Error::EOF),
                 Ok(Ok(_)) => {}
             }
-            trace!("Received line: `{}`", line.trim());
+            trace!("Received line: `{}`", Sensitive(line.trim()));
 
-            let message = serde_json::from_str::<Message>(line.trim())?;
-            debug!("Received message: {:?}", message);
+            // Batches are handled one message at a time, and answered with a batch
+            let raw = match serde_json::from_str::<Value>(line.trim()) {
+                Ok(raw) => raw,
+                Err(e) => {
+                    self.record(Direction::Received, &line.trim().into());
+                    return Err(e.into());
+                }
+            };
+            self.record(Direction::Received, &raw);
+            let (messages, batch) = match raw {
+                Value::Array(messages) => (messages, true),
+                message => (vec![message], false),
+            };
+            if messages.is_empty() {
+                self.write(ProtocolError::UnexpectedMessage.into()).await?;
+                return Err(ProtocolError::UnexpectedMessage.into());
+            }
 
-            // handle errors separately
-            if let Message::Error { error, .. } = message {
-                return Err(Error::PeerError(error));
+            let mut responses = Vec::new();
+            let mut result = None;
+            for message in messages {
+                let is_request = message.get("method").is_some();
+                match self.handle(message).await {
+                    Ok(Some(response)) => responses.push(self.encode(response.into())?),
+                    Ok(None) => {}
+                    // The peer can send its request again, the session goes on
+                    Err(Error::Protocol(e)) if is_request && e.is_recoverable() => {
+                        debug!("Recoverable protocol error: {:?}", e);
+                        responses.push(self.encode(e.into())?);
+                    }
+                    Err(Error::Protocol(e)) => {
+                        responses.push(self.encode(e.clone().into())?);
+                        result = Some(Err(e.into()));
+                    }
+                    Err(e) => result = Some(Err(e)),
+                }
+                if result.is_some() {
+                    break;
+                }
+
+                if let Ok(response) = self.state.as_ref().ok_or(Error::Other)?.done() {
+                    result = Some(Ok(response));
+                    break;
+                }
             }
-            let parsed: <T as JsonRpcState>::InMessage = message.try_into().unwrap(); // TODO: unwrap
 
-            match self.state.message(parsed) {
-                Ok(Some(response)) => self.write(&response.into().as_json("1")?).await?,
-                Err(Error::Protocol(e)) => {
-                    let cast: Message = e.clone().into();
-                    self.write(&cast.as_json("1")?).await?;
-                    return Err(e.into());
+            self.write_notifications().await?;
+            match responses.len() {
+                0 => {}
+                1 if !batch => self.write_raw(&responses[0]).await?,
+                _ => self.write_raw(&Value::Array(responses)).await?,
+            }
+
+            if let Some(result) = result {
+                return result;
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use tokio::net::UnixStream;
+
+    use super::*;
+    use crate::version::Capabilities;
+    use crate::{Request, Response};
+
+    /// Sends its version and waits for the peer's
+    #[derive(Debug, Default)]
+    struct Ping(Option<String>);
+
+    impl JsonRpcState for Ping {
+        type OutMessage = Request;
+        type InMessage = Response;
+        type Error = Error;
+        type Response = String;
+
+        fn setup(&mut self) -> Result<Option<Request>, Error> {
+            Ok(Some(Request::Version {
+                version: "ping".into(),
+                capabilities: Default::default(),
+                token: None,
+                invoice: None,
+            }))
+        }
+
+        fn message(&mut self, message: Response) -> Result<Option<Request>, Error> {
+            if let Response::Version { version, .. } = message {
+                self.0 = Some(version);
+            }
+            Ok(None)
+        }
+
+        fn done(&self) -> Result<String, ()> {
+            self.0.clone().ok_or(())
+        }
+    }
+
+    /// Like `Ping`, sending its version again without capabilities when they aren't supported
+    #[derive(Debug, Default)]
+    struct Downgrade(Option<String>);
+
+    impl JsonRpcState for Downgrade {
+        type OutMessage = Request;
+        type InMessage = Response;
+        type Error = Error;
+        type Response = String;
+
+        fn setup(&mut self) -> Result<Option<Request>, Error> {
+            Ok(Some(Request::Version {
+                version: "ping".into(),
+                capabilities: Capabilities::PSBT,
+                token: None,
+                invoice: None,
+            }))
+        }
+
+        fn message(&mut self, message: Response) -> Result<Option<Request>, Error> {
+            if let Response::Version { version, .. } = message {
+                self.0 = Some(version);
+            }
+            Ok(None)
+        }
+
+        fn recover(&mut self, error: &ProtocolError) -> Result<Option<Request>, Error> {
+            match error {
+                ProtocolError::Unsupported { .. } => Ok(Some(Request::Version {
+                    version: "retry".into(),
+                    capabilities: Default::default(),
+                    token: None,
+                    invoice: None,
+                })),
+                _ => Ok(None),
+            }
+        }
+
+        fn done(&self) -> Result<String, ()> {
+            self.0.clone().ok_or(())
+        }
+    }
+
+    /// Answers with its own version
+    #[derive(Debug, Default)]
+    struct Pong(Option<String>);
+
+    impl JsonRpcState for Pong {
+        type OutMessage = Response;
+        type InMessage = Request;
+        type Error = Error;
+        type Response = String;
+
+        fn message(&mut self, message: Request) -> Result<Option<Response>, Error> {
+            if let Request::Version {
+                version,
+                capabilities,
+                ..
+            } = message
+            {
+                if capabilities != Capabilities::empty() {
+                    return Err(ProtocolError::Unsupported {
+                        feature: capabilities,
+                    }
+                    .into());
                 }
-                _ => {}
+                self.0 = Some(version);
             }
+            Ok(Some(Response::Version {
+                version: "pong".into(),
+                capabilities: Default::default(),
+                session: None,
+                nonce: None,
+            }))
+        }
 
-            match self.state.done() {
-                Ok(txid) => return Ok(txid),
-                Err(_) => continue,
+        fn done(&self) -> Result<String, ()> {
+            self.0.clone().ok_or(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unix_stream() {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+
+        let mut ping = JsonRpc::new(&mut a, Ping::default(), Duration::from_secs(1));
+        let mut pong = JsonRpc::new(&mut b, Pong::default(), Duration::from_secs(1));
+        let (ping, pong) = tokio::join!(ping.mainloop(), pong.mainloop());
+
+        assert_eq!(ping.unwrap(), "pong");
+        assert_eq!(pong.unwrap(), "ping");
+    }
+
+    #[tokio::test]
+    async fn test_recoverable_error() {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+
+        let mut downgrade = JsonRpc::new(&mut a, Downgrade::default(), Duration::from_secs(1));
+        let mut pong = JsonRpc::new(&mut b, Pong::default(), Duration::from_secs(1));
+        let (downgrade, pong) = tokio::join!(downgrade.mainloop(), pong.mainloop());
+
+        assert_eq!(downgrade.unwrap(), "pong");
+        assert_eq!(pong.unwrap(), "retry");
+    }
+
+    #[tokio::test]
+    async fn test_timeouts() {
+        let (mut a, _b) = UnixStream::pair().unwrap();
+
+        let mut ping = JsonRpc::new(&mut a, Ping::default(), Duration::from_millis(50));
+        assert!(matches!(
+            ping.mainloop().await,
+            Err(Error::Timeout(TimeoutPhase::Message))
+        ));
+
+        let mut ping = JsonRpc::new(&mut a, Ping::default(), Duration::from_secs(1))
+            .with_session_timeout(Duration::from_millis(50));
+        assert!(matches!(
+            ping.mainloop().await,
+            Err(Error::Timeout(TimeoutPhase::Session))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_message_too_large() {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+
+        let mut pong =
+            JsonRpc::new(&mut a, Pong::default(), Duration::from_secs(1)).with_max_message_size(64);
+        let peer = async {
+            let line = format!("{}\n", "x".repeat(128));
+            // The other side stops reading and hangs up halfway through
+            let _ = b.write_all(line.as_bytes()).await;
+        };
+        let (result, _) = tokio::join!(pong.mainloop(), peer);
+
+        assert!(matches!(result, Err(Error::MessageTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_id() {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+
+        let mut ping = JsonRpc::new(&mut a, Ping::default(), Duration::from_secs(1));
+        let peer = async {
+            let mut reader = BufReader::new(&mut b);
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            let request: Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(request["id"], "1");
+
+            let response: Message = Response::Version {
+                version: "pong".into(),
+                capabilities: Default::default(),
+                session: None,
+                nonce: None,
+            }
+            .into();
+            let mut raw = serde_json::to_vec(&Envelope::new("2", response)).unwrap();
+            raw.push(b'\n');
+            reader.get_mut().write_all(&raw).await.unwrap();
+
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+
+            serde_json::from_str::<Value>(&line).unwrap()
+        };
+        let (result, error) = tokio::join!(ping.mainloop(), peer);
+
+        match result {
+            Err(Error::Protocol(ProtocolError::InvalidId)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(error["id"], "2");
+        assert_eq!(error["error"]["code"], -32600);
+        assert_eq!(error["error"]["data"]["error"], "INVALIDID");
+    }
+
+    /// Send `message` to a peer running `state`, returning its result and what it sent back
+    async fn send_unexpected<T>(state: T, message: Message) -> (Result<T::Response, Error>, Value)
+    where
+        T: JsonRpcState<Error = Error> + Send + 'static,
+        T::InMessage: Send + 'static,
+        T::OutMessage: Send + 'static,
+        <T::InMessage as TryFrom<Message>>::Error: std::fmt::Debug,
+    {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+
+        let mut jsonrpc = JsonRpc::new(&mut a, state, Duration::from_secs(1));
+        let peer = async {
+            let mut reader = BufReader::new(&mut b);
+            let mut raw = serde_json::to_vec(&Envelope::new("5", message)).unwrap();
+            raw.push(b'\n');
+            reader.get_mut().write_all(&raw).await.unwrap();
+
+            // Skip the setup message, if any
+            let mut line = String::new();
+            loop {
+                line.clear();
+                reader.read_line(&mut line).await.unwrap();
+                let message = serde_json::from_str::<Value>(&line).unwrap();
+                if message.get("error").is_some() {
+                    return message;
+                }
             }
+        };
+        let (result, error) = tokio::join!(jsonrpc.mainloop(), peer);
+
+        (result, error)
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_message() {
+        let request: Message = Request::Version {
+            version: "ping".into(),
+            capabilities: Default::default(),
+            token: None,
+            invoice: None,
         }
+        .into();
+        let response: Message = Response::Version {
+            version: "pong".into(),
+            capabilities: Default::default(),
+            session: None,
+            nonce: None,
+        }
+        .into();
+
+        // A response sent to the side that only takes requests, and the other way around
+        let (result, error) = send_unexpected(Pong::default(), response).await;
+        assert!(matches!(
+            result,
+            Err(Error::Protocol(ProtocolError::UnexpectedMessage))
+        ));
+        assert_eq!(error["id"], "5");
+        assert_eq!(error["error"]["code"], 1000);
+
+        let (result, error) = send_unexpected(Ping::default(), request).await;
+        assert!(matches!(
+            result,
+            Err(Error::Protocol(ProtocolError::UnexpectedMessage))
+        ));
+        assert_eq!(error["id"], "5");
+        assert_eq!(error["error"]["code"], 1000);
+    }
+
+    #[tokio::test]
+    async fn test_batch() {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+
+        let mut pong = JsonRpc::new(&mut a, Pong::default(), Duration::from_secs(1));
+        let peer = async {
+            let version = |version: &str| Request::Version {
+                version: version.into(),
+                capabilities: Default::default(),
+                token: None,
+                invoice: None,
+            };
+            let batch = serde_json::json!([
+                Envelope::notification(version("notification")),
+                Envelope::new("7", version("ping").into()),
+            ]);
+
+            let mut reader = BufReader::new(&mut b);
+            let mut raw = serde_json::to_vec(&batch).unwrap();
+            raw.push(b'\n');
+            reader.get_mut().write_all(&raw).await.unwrap();
+
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            serde_json::from_str::<Value>(&line).unwrap()
+        };
+        let (result, responses) = tokio::join!(pong.mainloop(), peer);
+
+        // Notifications don't reach `message()` and don't get a response
+        assert_eq!(result.unwrap(), "ping");
+        assert_eq!(responses.as_array().unwrap().len(), 1);
+        assert_eq!(responses[0]["id"], "7");
+        assert_eq!(responses[0]["result"]["version"], "pong");
     }
 }
@@ -5,32 +5,108 @@
 //    <-- TXOUT --
 //    -- SIGS  -->
 //    <-- TXID ---
+//
+// Peers with the same major version negotiate the lowest minor version and the capabilities
+// supported by both (see `version`). With the `PSBT` capability the sender's signatures are sent as
+// PSBTs instead of raw witnesses, with `MULTI_UTXO` the receiver can contribute more than one input
+// and with `SENDER_BROADCAST` it can leave the broadcast of the final transaction to the sender
+//
+// A sender that lost its connection can send RESUME with the token it got in the server's VERSION,
+// and the server repeats its last response (see `session`)
+//
+// Servers waiting for more than one payment hand out an `invoice` identifier in each payment URI,
+// which the sender repeats in its VERSION to pick the output it pays
 
 use std::convert::TryFrom;
+use std::fmt;
 
 use serde::{de, ser};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 
 pub use bitcoin;
 use bitcoin::consensus::{deserialize, serialize, Decodable, Encodable};
 use bitcoin::hashes::hex::{Error as HexError, FromHex, ToHex};
-use bitcoin::{OutPoint, Script, Transaction, Txid};
-
-const VERSION: &str = "1.0";
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, Script, Transaction, TxOut, Txid};
 
+pub mod analysis;
+pub mod bip21;
 pub mod blockchain;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod client;
+pub mod coin_selection;
 pub mod common;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod control;
+pub mod cpfp;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod decoy;
 pub mod demo;
+#[cfg(not(target_arch = "wasm32"))]
+mod disclosure;
+pub mod events;
+pub mod fees;
+#[cfg(all(feature = "ffi", not(target_arch = "wasm32")))]
+pub mod ffi;
+pub mod integration;
 pub mod jsonrpc;
+pub mod metrics;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod multiparty;
+#[cfg(all(feature = "noise", not(target_arch = "wasm32")))]
+pub mod noise;
+pub mod offer;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod p2p;
+pub mod protocol;
+#[cfg(all(feature = "python", not(target_arch = "wasm32")))]
+pub mod python;
+pub mod redact;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod replay;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod server;
+pub mod session;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod shutdown;
 pub mod signer; // TODO: not pub
-
-pub use blockchain::Blockchain;
-pub use client::Client;
-pub use server::Server;
+pub mod taproot;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod test_vectors;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod testutil;
+#[cfg(all(feature = "tls", not(target_arch = "wasm32")))]
+pub mod tls;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod transcript;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod transport;
+pub mod version;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod wallet;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+pub mod wasm;
+#[cfg(all(feature = "websocket", not(target_arch = "wasm32")))]
+pub mod websocket;
+pub mod zeroconf;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use blockchain::AsyncBlockchain;
+pub use blockchain::{Blockchain, Broadcaster, SplitBlockchain, TxSource};
+#[cfg(not(target_arch = "wasm32"))]
+pub use client::{Client, ClientBuilder, PaymentResult};
+pub use fees::{FeeEstimator, FeeRate};
+#[cfg(not(target_arch = "wasm32"))]
+pub use server::{Server, ServerBuilder};
+#[cfg(not(target_arch = "wasm32"))]
+pub use shutdown::CancellationToken;
 pub use signer::Signer;
+pub use version::{Capabilities, Version};
+#[cfg(not(target_arch = "wasm32"))]
+pub use wallet::ReceiverWallet;
+
+#[cfg(all(feature = "ffi", not(target_arch = "wasm32")))]
+uniffi::setup_scaffolding!();
 
 macro_rules! impl_error {
     ( $err:ident, $from:ty, $to:ident ) => {
@@ -62,6 +138,95 @@ where
     bytes.to_hex().serialize(serializer)
 }
 
+fn from_hex_option<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Decodable,
+    D: de::Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| {
+            let bytes: Vec<u8> = FromHex::from_hex(&s).map_err(de::Error::custom)?;
+            deserialize(&bytes).map_err(de::Error::custom)
+        })
+        .transpose()
+}
+
+fn to_hex_option<S, T>(data: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Encodable,
+    S: ser::Serializer,
+{
+    data.as_ref()
+        .map(|data| serialize(data).to_hex())
+        .serialize(serializer)
+}
+
+fn from_hex_vec<'de, T, D>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    T: Decodable,
+    D: de::Deserializer<'de>,
+{
+    let strings = Vec::<String>::deserialize(deserializer)?;
+    strings
+        .iter()
+        .map(|s| {
+            let bytes: Vec<u8> = FromHex::from_hex(s).map_err(de::Error::custom)?;
+            deserialize(&bytes).map_err(de::Error::custom)
+        })
+        .collect()
+}
+
+fn to_hex_vec<S, T>(data: &[T], serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Encodable,
+    S: ser::Serializer,
+{
+    data.iter()
+        .map(|item| serialize(item).to_hex())
+        .collect::<Vec<_>>()
+        .serialize(serializer)
+}
+
+fn one() -> usize {
+    1
+}
+
+/// A single item is sent as a plain value, the way peers without the `MULTI_UTXO` capability
+/// expect it
+mod one_or_many {
+    use serde::{de, ser};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    pub fn serialize<S, T>(data: &[T], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Serialize,
+        S: ser::Serializer,
+    {
+        match data {
+            [item] => item.serialize(serializer),
+            data => data.serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: de::Deserializer<'de>,
+    {
+        match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(item) => Ok(vec![item]),
+            OneOrMany::Many(items) => Ok(items),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 #[serde(try_from = "String", into = "String")]
 pub struct WitnessWrapper(Vec<u8>);
@@ -86,9 +251,9 @@ impl TryFrom<String> for WitnessWrapper {
     }
 }
 
-impl Into<String> for WitnessWrapper {
-    fn into(self) -> String {
-        self.0.to_hex()
+impl From<WitnessWrapper> for String {
+    fn from(other: WitnessWrapper) -> String {
+        other.0.to_hex()
     }
 }
 
@@ -98,21 +263,73 @@ impl Into<String> for WitnessWrapper {
 pub enum Request {
     Version {
         version: String,
+        // Missing for peers predating version 1.1
+        #[serde(default)]
+        capabilities: Capabilities,
+        /// Access token from the payment URI, for servers that require one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+        /// Identifier from the payment URI, for servers waiting for more than one payment
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        invoice: Option<String>,
     },
+    /// Sent instead of VERSION to resume a session after a dropped connection
+    Resume { session: String },
     Proof {
         #[serde(deserialize_with = "from_hex", serialize_with = "to_hex")]
         transaction: Transaction,
     },
     Witnesses {
         fees: u64,
+        /// Paid to the receiver, for receivers accepting any amount. Missing for older peers
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        amount: Option<u64>,
         change_script: Script,
-        receiver_input_position: usize,
+        // Missing for peers without the `BATCH` capability, which always put the change output
+        // next to the receiver's one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        change_position: Option<usize>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        payments: Vec<TxOut>,
+        #[serde(rename = "receiver_input_position", with = "one_or_many")]
+        receiver_input_positions: Vec<usize>,
         receiver_output_position: usize,
+        /// Where the change of the receiver goes, when it has one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        receiver_change_position: Option<usize>,
         witnesses: Vec<Vec<WitnessWrapper>>,
     },
+    Psbts {
+        fees: u64,
+        /// Paid to the receiver, for receivers accepting any amount. Missing for older peers
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        amount: Option<u64>,
+        change_script: Script,
+        // Missing for peers without the `BATCH` capability, which always put the change output
+        // next to the receiver's one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        change_position: Option<usize>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        payments: Vec<TxOut>,
+        #[serde(rename = "receiver_input_position", with = "one_or_many")]
+        receiver_input_positions: Vec<usize>,
+        receiver_output_position: usize,
+        /// Where the change of the receiver goes, when it has one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        receiver_change_position: Option<usize>,
+        #[serde(deserialize_with = "from_hex_vec", serialize_with = "to_hex_vec")]
+        psbts: Vec<PartiallySignedTransaction>,
+    },
+    /// Sent by a server chaining the receiver into the payjoin of its own sender, with the
+    /// `MULTIPARTY` capability. Every input is signed but the receiver's (see `multiparty`)
+    Cosign {
+        #[serde(deserialize_with = "from_hex", serialize_with = "to_hex")]
+        transaction: Transaction,
+    },
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Body of a JSON-RPC message, serialized without the envelope (see `Envelope`)
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum Message {
     Request {
@@ -123,6 +340,7 @@ pub enum Message {
         result: Response,
     },
     Error {
+        #[serde(with = "rpc_error")]
         error: ProtocolError,
     },
 }
@@ -167,18 +385,57 @@ impl TryFrom<Message> for Response {
 }
 
 impl Message {
-    pub fn as_json(&self, id: &str) -> Result<serde_json::Value, Error> {
-        let mut data = match self {
-            Message::Request { request, .. } => serde_json::to_value(request)?,
-            Message::Response { result, .. } => json!({"result": serde_json::to_value(result)?}),
-            Message::Error { error, .. } => json!({"error": serde_json::to_value(error)?}),
-        };
+    #[deprecated(note = "serialize an `Envelope` instead")]
+    pub fn as_json<I: Into<serde_json::Value>>(&self, id: I) -> Result<serde_json::Value, Error> {
+        Ok(serde_json::to_value(Envelope::new(id, self.clone()))?)
+    }
+}
 
-        data["jsonrpc"] = "2.0".into();
-        data["id"] = id.into();
+/// A `Message` with the JSON-RPC 2.0 envelope around it, as sent on the wire
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Envelope {
+    #[serde(default = "jsonrpc_version")]
+    pub jsonrpc: String,
+    /// Missing for notifications, and `null` for errors about a message whose id is unknown
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub message: Message,
+}
 
-        Ok(data)
+impl Envelope {
+    pub fn new<I: Into<serde_json::Value>>(id: I, message: Message) -> Self {
+        Envelope {
+            jsonrpc: jsonrpc_version(),
+            id: Some(id.into()),
+            message,
+        }
     }
+
+    /// A request the peer doesn't answer
+    pub fn notification(request: Request) -> Self {
+        Envelope {
+            jsonrpc: jsonrpc_version(),
+            id: None,
+            message: request.into(),
+        }
+    }
+}
+
+fn jsonrpc_version() -> String {
+    "2.0".into()
+}
+
+/// Keep an explicit `null`, which `Option` would otherwise read as a missing value
+fn present<'de, D>(deserializer: D) -> Result<Option<serde_json::Value>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    serde_json::Value::deserialize(deserializer).map(Some)
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -186,14 +443,38 @@ impl Message {
 pub enum Response {
     Version {
         version: String,
+        #[serde(default)]
+        capabilities: Capabilities,
+        /// Token to resume the session with, if the server supports it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+        /// Challenge the proof commits to, with the `BOUND_PROOF` capability
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nonce: Option<String>,
     },
+    /// Candidate sets of receiver inputs, split in consecutive groups of `receiver_inputs` UTXOs
     Utxos {
         utxos: Vec<OutPoint>,
+        // Missing for peers without the `MULTI_UTXO` capability
+        #[serde(default = "one")]
+        receiver_inputs: usize,
+        /// Change of the receiver, with the `RECEIVER_CHANGE` capability. Its value is taken out
+        /// of the receiver's inputs, only the rest is added to the payment
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        receiver_change: Option<TxOut>,
     },
     Txid {
         txid: Txid,
         #[serde(deserialize_with = "from_hex", serialize_with = "to_hex")]
         transaction: Transaction,
+        /// Time by which the receiver broadcasts the transaction, in seconds since the UNIX epoch,
+        /// when it doesn't do it right away
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        will_broadcast_by: Option<u64>,
+        /// The receiver won't broadcast the transaction, the sender has to. Only set with the
+        /// `SENDER_BROADCAST` capability
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        sender_broadcasts: bool,
     },
 }
 
@@ -207,6 +488,42 @@ pub enum ProtocolError {
     InvalidFinalTransaction(common::FinalTransactionError),
     InvalidUtxo,
     MissingData,
+    Busy,
+    InvalidId,
+    MissingCapability(Capabilities),
+    InvalidSession,
+    InvalidToken,
+    RateLimited,
+    ProofMismatch,
+    InvalidFallback,
+    TxidMismatch,
+    TransactionMismatch,
+    InvalidSignature(usize),
+    /// More witnesses or PSBTs than candidate sets of UTXOs, or than inputs in the proof
+    TooManyWitnesses,
+    UnknownInvoice,
+    /// Below the minimum amount of a donation endpoint, in satoshis
+    AmountTooLow(u64),
+    /// Past the `exp` of the payment URI, the receiver doesn't accept payments anymore
+    InvoiceExpired,
+    /// The second receiver of a chained payjoin didn't sign its inputs (see `multiparty`)
+    ChainFailed,
+    /// The message relies on a capability that wasn't negotiated. The session goes on, and the
+    /// request can be sent again without it
+    Unsupported {
+        feature: Capabilities,
+    },
+    /// The inputs of the proof were recently used by a different proof (see `replay`)
+    ReplayedProof,
+    /// The payjoin would be given away by the unnecessary input heuristics (see `analysis`)
+    Detectable,
+    #[cfg(feature = "bip78")]
+    Bip78(protocol::bip78::Bip78Error),
+    /// Reported by a peer with a code we don't know about
+    Other {
+        code: i64,
+        message: String,
+    },
 }
 
 impl_error!(ProtocolError, common::ProofTransactionError, InvalidProof);
@@ -215,23 +532,365 @@ impl_error!(
     common::FinalTransactionError,
     InvalidFinalTransaction
 );
+#[cfg(feature = "bip78")]
+impl_error!(ProtocolError, protocol::bip78::Bip78Error, Bip78);
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::UnexpectedMessage => write!(f, "unexpected message"),
+            ProtocolError::Expected(expected) => write!(f, "expected a {} message", expected),
+            ProtocolError::InvalidVersion(version) => {
+                write!(f, "unsupported protocol version `{}`", version)
+            }
+            ProtocolError::InvalidProof(_) => write!(f, "invalid proof transaction"),
+            ProtocolError::InvalidFinalTransaction(_) => write!(f, "invalid final transaction"),
+            ProtocolError::InvalidUtxo => write!(f, "invalid or spent UTXO"),
+            ProtocolError::MissingData => write!(f, "missing data"),
+            ProtocolError::Busy => write!(f, "no UTXO available at the moment"),
+            ProtocolError::InvalidId => write!(f, "invalid JSON-RPC id"),
+            ProtocolError::MissingCapability(capabilities) => write!(
+                f,
+                "the peer doesn't support the required capabilities {:?}",
+                capabilities
+            ),
+            ProtocolError::InvalidSession => write!(f, "unknown or expired session"),
+            ProtocolError::InvalidToken => write!(f, "missing or already used access token"),
+            ProtocolError::RateLimited => write!(f, "too many requests, try again later"),
+            ProtocolError::ProofMismatch => {
+                write!(
+                    f,
+                    "the final transaction doesn't spend the inputs of the proof"
+                )
+            }
+            ProtocolError::InvalidFallback => {
+                write!(f, "the fallback transaction doesn't pay the receiver")
+            }
+            ProtocolError::TxidMismatch => {
+                write!(f, "the txid doesn't match the returned transaction")
+            }
+            ProtocolError::TransactionMismatch => {
+                write!(f, "the returned transaction is not one we signed")
+            }
+            ProtocolError::InvalidSignature(index) => {
+                write!(f, "invalid signature on input #{}", index)
+            }
+            ProtocolError::TooManyWitnesses => {
+                write!(f, "more witnesses than candidate UTXOs and proof inputs")
+            }
+            ProtocolError::UnknownInvoice => write!(f, "unknown or already paid invoice"),
+            ProtocolError::AmountTooLow(min) => {
+                write!(f, "the amount is below the minimum of {} sat", min)
+            }
+            ProtocolError::InvoiceExpired => write!(f, "the invoice has expired"),
+            ProtocolError::ChainFailed => {
+                write!(f, "the second receiver didn't sign the transaction")
+            }
+            ProtocolError::Unsupported { feature } => {
+                write!(f, "{:?} was not negotiated, retry without it", feature)
+            }
+            ProtocolError::ReplayedProof => {
+                write!(f, "the inputs of the proof were used by a different proof")
+            }
+            ProtocolError::Detectable => {
+                write!(f, "the transaction would be detectable as a payjoin")
+            }
+            #[cfg(feature = "bip78")]
+            ProtocolError::Bip78(_) => write!(f, "BIP78 error"),
+            ProtocolError::Other { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl ProtocolError {
+    /// Code of the JSON-RPC error object. An invalid id gets the standard code for invalid
+    /// requests, the other errors are grouped by kind: 1xxx for the messages, 2xxx for the
+    /// transactions and 3xxx for the state of the receiver
+    pub fn code(&self) -> i64 {
+        match self {
+            ProtocolError::InvalidId => -32600,
+
+            ProtocolError::UnexpectedMessage => 1000,
+            ProtocolError::Expected(_) => 1001,
+            ProtocolError::InvalidVersion(_) => 1002,
+            ProtocolError::MissingData => 1003,
+            ProtocolError::MissingCapability(_) => 1004,
+            ProtocolError::TooManyWitnesses => 1005,
+            ProtocolError::Unsupported { .. } => 1006,
+
+            ProtocolError::InvalidProof(_) => 2000,
+            ProtocolError::InvalidFinalTransaction(_) => 2001,
+            ProtocolError::InvalidUtxo => 2002,
+            ProtocolError::ProofMismatch => 2003,
+            ProtocolError::InvalidFallback => 2004,
+            ProtocolError::TxidMismatch => 2005,
+            ProtocolError::TransactionMismatch => 2006,
+            ProtocolError::InvalidSignature(_) => 2007,
+            #[cfg(feature = "bip78")]
+            ProtocolError::Bip78(_) => 2008,
+            ProtocolError::ReplayedProof => 2009,
+            ProtocolError::Detectable => 2010,
+
+            ProtocolError::Busy => 3000,
+            ProtocolError::InvalidSession => 3001,
+            ProtocolError::InvalidToken => 3002,
+            ProtocolError::RateLimited => 3003,
+            ProtocolError::UnknownInvoice => 3004,
+            ProtocolError::AmountTooLow(_) => 3005,
+            ProtocolError::InvoiceExpired => 3006,
+            ProtocolError::ChainFailed => 3007,
+
+            ProtocolError::Other { code, .. } => *code,
+        }
+    }
+
+    /// Whether the session survives the error, with the peer expected to retry its last request
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, ProtocolError::Unsupported { .. })
+    }
+
+    /// Index of the input or output of the transaction the error is about, if any
+    pub fn index(&self) -> Option<usize> {
+        use common::{FinalTransactionError, ProofTransactionError};
+
+        match self {
+            ProtocolError::InvalidSignature(index)
+            | ProtocolError::InvalidProof(
+                ProofTransactionError::InvalidInputType(index)
+                | ProofTransactionError::InvalidInputSignature(index)
+                | ProofTransactionError::MissingUTXO(index)
+                | ProofTransactionError::InputIsSpent(index)
+                | ProofTransactionError::UnconfirmedInput(index)
+                | ProofTransactionError::DisallowedInputType(index)
+                | ProofTransactionError::InvalidSighashType(index),
+            )
+            | ProtocolError::InvalidFinalTransaction(
+                FinalTransactionError::InvalidSenderSignature(index)
+                | FinalTransactionError::InvalidSenderSighashType(index)
+                | FinalTransactionError::DustOutput(index),
+            ) => Some(*index),
+            _ => None,
+        }
+    }
+}
+
+/// `ProtocolError`s are sent as JSON-RPC error objects, with a numeric `code`, a human readable
+/// `message` and in `data` the error itself along with the index of the offending input or
+/// output. Peers predating them send the bare error, which is accepted too
+mod rpc_error {
+    use serde::{de, ser};
+    use serde::{Deserialize, Serialize};
+    use serde_json::Value;
+
+    use super::ProtocolError;
+
+    #[derive(Serialize, Deserialize)]
+    struct RpcError {
+        code: i64,
+        message: String,
+        #[serde(default, skip_serializing_if = "Value::is_null")]
+        data: Value,
+    }
+
+    #[derive(Serialize)]
+    struct Data<'a> {
+        error: &'a ProtocolError,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        index: Option<usize>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum AnyError {
+        Object(RpcError),
+        Bare(ProtocolError),
+    }
+
+    pub fn serialize<S>(error: &ProtocolError, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        // Along with the details of the inner error, like the reason a proof is invalid
+        let mut message = error.to_string();
+        let mut source = std::error::Error::source(error);
+        while let Some(inner) = source {
+            message = format!("{}: {}", message, inner);
+            source = inner.source();
+        }
+
+        let data = Data {
+            error,
+            index: error.index(),
+        };
+        RpcError {
+            code: error.code(),
+            message,
+            data: serde_json::to_value(data).map_err(ser::Error::custom)?,
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<ProtocolError, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        match AnyError::deserialize(deserializer)? {
+            AnyError::Bare(error) => Ok(error),
+            AnyError::Object(RpcError {
+                code,
+                message,
+                data,
+            }) => Ok(data
+                .get("error")
+                .and_then(|error| ProtocolError::deserialize(error).ok())
+                .unwrap_or(ProtocolError::Other { code, message })),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::InvalidProof(e) => Some(e),
+            ProtocolError::InvalidFinalTransaction(e) => Some(e),
+            #[cfg(feature = "bip78")]
+            ProtocolError::Bip78(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Error {
     Serde(serde_json::Error),
     IO(std::io::Error),
+    #[cfg(not(target_arch = "wasm32"))]
     Socks(tokio_socks::Error),
+    Bip21(bip21::Bip21Error),
+    Offer(offer::OfferError),
+    CompactFilters(integration::compact_filters::CompactFiltersError),
 
     Protocol(ProtocolError),
     PeerError(ProtocolError),
-    Timeout,
+    Timeout(TimeoutPhase),
+    /// The peer sent a message larger than we are willing to buffer
+    MessageTooLarge,
     EOF,
+    /// Stopped through a `CancellationToken`
+    Cancelled,
+    Transport(String),
+    /// An output is not worth enough to pay for the fees
+    InsufficientFunds,
+    /// An input of the final transaction has already been spent by another transaction
+    ConflictingTransaction,
+    /// A setting of the configuration is out of range
+    InvalidConfig(&'static str),
     Other,
+
+    #[cfg(feature = "bdk")]
+    Bdk(bdk::Error),
 }
 
 impl_error!(Error, serde_json::Error, Serde);
 impl_error!(Error, std::io::Error, IO);
+#[cfg(not(target_arch = "wasm32"))]
 impl_error!(Error, tokio_socks::Error, Socks);
+impl_error!(Error, bip21::Bip21Error, Bip21);
+impl_error!(Error, offer::OfferError, Offer);
+impl_error!(
+    Error,
+    integration::compact_filters::CompactFiltersError,
+    CompactFilters
+);
+#[cfg(feature = "bdk")]
+impl_error!(Error, bdk::Error, Bdk);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Serde(_) => write!(f, "invalid JSON message"),
+            Error::IO(_) => write!(f, "I/O error"),
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Socks(_) => write!(f, "SOCKS proxy error"),
+            Error::Bip21(_) => write!(f, "invalid BIP21 URI"),
+            Error::Offer(e) => write!(f, "{}", e),
+            Error::CompactFilters(e) => write!(f, "{}", e),
+            Error::Protocol(_) => write!(f, "protocol error"),
+            Error::PeerError(_) => write!(f, "the peer reported an error"),
+            Error::Timeout(phase) => write!(f, "timed out waiting for {}", phase),
+            Error::MessageTooLarge => write!(f, "message too large"),
+            Error::EOF => write!(f, "connection closed by the peer"),
+            Error::Cancelled => write!(f, "cancelled"),
+            Error::Transport(e) => write!(f, "transport error: {}", e),
+            Error::InsufficientFunds => write!(f, "insufficient funds to pay the fees"),
+            Error::ConflictingTransaction => {
+                write!(f, "an input has been spent by a conflicting transaction")
+            }
+            Error::InvalidConfig(setting) => write!(f, "invalid configuration: {}", setting),
+            Error::Other => write!(f, "unknown error"),
+            #[cfg(feature = "bdk")]
+            Error::Bdk(_) => write!(f, "BDK error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Serde(e) => Some(e),
+            Error::IO(e) => Some(e),
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Socks(e) => Some(e),
+            Error::Bip21(e) => Some(e),
+            Error::Offer(e) => Some(e),
+            Error::CompactFilters(e) => Some(e),
+            Error::Protocol(e) | Error::PeerError(e) => Some(e),
+            #[cfg(feature = "bdk")]
+            Error::Bdk(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// What we were waiting for when an `Error::Timeout` happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutPhase {
+    /// Tor to bootstrap, or the onion service to be published
+    Bootstrap,
+    /// A connection to the server
+    Connect,
+    /// The handshake of the transport on a connection accepted by the server
+    Handshake,
+    /// The next message of the peer
+    Message,
+    /// The end of the whole negotiation
+    Session,
+}
+
+impl fmt::Display for TimeoutPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimeoutPhase::Bootstrap => write!(f, "the Tor bootstrap"),
+            TimeoutPhase::Connect => write!(f, "a connection"),
+            TimeoutPhase::Handshake => write!(f, "the transport handshake"),
+            TimeoutPhase::Message => write!(f, "a message"),
+            TimeoutPhase::Session => write!(f, "the negotiation to complete"),
+        }
+    }
+}
+
+/// Run `f` on the blocking thread pool, so that state transitions querying the `Blockchain` never
+/// stall the reactor
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn run_blocking<F, R>(f: F) -> Result<R, Error>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|_| Error::Other)
+}
 
 impl From<()> for Error {
     fn from(_other: ()) -> Self {
@@ -253,10 +912,97 @@ mod test {
     fn test() {
         let msg = ProtocolError::MissingData;
         let msg: Message = msg.into();
-        let json = msg.as_json("42").unwrap();
+        let json = serde_json::to_value(Envelope::new("42", msg)).unwrap();
         println!("{:#?}", json);
 
         let msg: Message = serde_json::from_value(json).unwrap();
         println!("{:?}", msg);
     }
+
+    #[test]
+    fn test_error_source() {
+        use std::error::Error as _;
+
+        let err: Error = common::ProofTransactionError::InputIsSpent(1).into();
+        assert_eq!(err.to_string(), "protocol error");
+
+        let protocol = err.source().unwrap();
+        assert_eq!(protocol.to_string(), "invalid proof transaction");
+        assert_eq!(
+            protocol.source().unwrap().to_string(),
+            "the UTXO spent by input #1 is already spent"
+        );
+    }
+
+    #[test]
+    fn test_envelope() {
+        let request = Request::Version {
+            version: "1.0".into(),
+            capabilities: Default::default(),
+            token: None,
+            invoice: None,
+        };
+        let json = serde_json::to_value(Envelope::notification(request)).unwrap();
+        assert_eq!(json["jsonrpc"], "2.0");
+        assert_eq!(json["method"], "VERSION");
+        assert!(json.get("id").is_none());
+        let envelope: Envelope = serde_json::from_value(json).unwrap();
+        assert!(envelope.id.is_none());
+
+        // Errors about a message whose id we couldn't read
+        let json = serde_json::to_value(Envelope::new(
+            serde_json::Value::Null,
+            ProtocolError::InvalidId.into(),
+        ))
+        .unwrap();
+        assert!(json["id"].is_null());
+        let envelope: Envelope = serde_json::from_value(json).unwrap();
+        assert_eq!(envelope.id, Some(serde_json::Value::Null));
+        assert!(matches!(
+            envelope.message,
+            Message::Error {
+                error: ProtocolError::InvalidId
+            }
+        ));
+    }
+
+    #[test]
+    fn test_rpc_error() {
+        let error = ProtocolError::InvalidProof(common::ProofTransactionError::InputIsSpent(1));
+        let json = serde_json::to_value(Envelope::new("42", error.into())).unwrap();
+        assert_eq!(
+            json["error"],
+            serde_json::json!({
+                "code": 2000,
+                "message": "invalid proof transaction: the UTXO spent by input #1 is already spent",
+                "data": {
+                    "error": {"INVALIDPROOF": {"InputIsSpent": 1}},
+                    "index": 1,
+                },
+            })
+        );
+        assert!(matches!(
+            serde_json::from_value(json),
+            Ok(Message::Error {
+                error: ProtocolError::InvalidProof(common::ProofTransactionError::InputIsSpent(1))
+            })
+        ));
+
+        // From older peers, and from implementations that don't send our errors in `data`
+        assert!(matches!(
+            serde_json::from_value(serde_json::json!({"error": "BUSY"})),
+            Ok(Message::Error {
+                error: ProtocolError::Busy
+            })
+        ));
+        let error = serde_json::from_value::<Message>(serde_json::json!({
+            "error": {"code": 3000, "message": "busy"},
+        }));
+        match error {
+            Ok(Message::Error {
+                error: ProtocolError::Other { code, message },
+            }) => assert_eq!((code, message.as_str()), (3000, "busy")),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,114 @@
+//! Counters for operators monitoring a long-running server
+//!
+//! The server reports to a `Metrics` implementation, which can forward the events to any metrics
+//! system. `Counters` keeps them in memory.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::{Error, ProtocolError};
+
+pub trait Metrics: std::fmt::Debug + Send + Sync {
+    fn session_started(&self) {}
+    fn session_completed(&self) {}
+    fn session_failed(&self, _error: &Error) {}
+}
+
+/// Discard every event
+#[derive(Debug, Clone, Default)]
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+/// Count the sessions, and the validation failures by type
+#[derive(Debug, Default)]
+pub struct Counters {
+    sessions_started: AtomicU64,
+    sessions_completed: AtomicU64,
+    sessions_failed: AtomicU64,
+    validation_failures: Mutex<HashMap<String, u64>>,
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn sessions_started(&self) -> u64 {
+        self.sessions_started.load(Ordering::Relaxed)
+    }
+
+    pub fn sessions_completed(&self) -> u64 {
+        self.sessions_completed.load(Ordering::Relaxed)
+    }
+
+    pub fn sessions_failed(&self) -> u64 {
+        self.sessions_failed.load(Ordering::Relaxed)
+    }
+
+    /// Failed validations of the messages sent by the senders, keyed by the name of the error
+    pub fn validation_failures(&self) -> HashMap<String, u64> {
+        self.validation_failures.lock().unwrap().clone()
+    }
+}
+
+impl Metrics for Counters {
+    fn session_started(&self) {
+        self.sessions_started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn session_completed(&self) {
+        self.sessions_completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn session_failed(&self, error: &Error) {
+        self.sessions_failed.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(kind) = validation_failure(error) {
+            *self
+                .validation_failures
+                .lock()
+                .unwrap()
+                .entry(kind)
+                .or_default() += 1;
+        }
+    }
+}
+
+/// Name of the validation that failed, without its data
+fn validation_failure(error: &Error) -> Option<String> {
+    let name = match error {
+        Error::Protocol(ProtocolError::InvalidProof(e)) => format!("{:?}", e),
+        Error::Protocol(ProtocolError::InvalidFinalTransaction(e)) => format!("{:?}", e),
+        Error::Protocol(e) => format!("{:?}", e),
+        _ => return None,
+    };
+
+    Some(name.split('(').next().unwrap_or_default().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::ProofTransactionError;
+    use crate::TimeoutPhase;
+
+    #[test]
+    fn test_counters() {
+        let counters = Counters::new();
+        counters.session_started();
+        counters.session_started();
+        counters.session_completed();
+        counters.session_failed(&ProofTransactionError::InputIsSpent(0).into());
+        counters.session_failed(&Error::Timeout(TimeoutPhase::Message));
+
+        assert_eq!(counters.sessions_started(), 2);
+        assert_eq!(counters.sessions_completed(), 1);
+        assert_eq!(counters.sessions_failed(), 2);
+        assert_eq!(
+            counters.validation_failures(),
+            vec![("InputIsSpent".to_string(), 1)].into_iter().collect()
+        );
+    }
+}
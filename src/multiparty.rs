@@ -0,0 +1,387 @@
+//! Experimental payjoins with more than one receiver
+//!
+//! A server with a `Chain` pays part of every payment on to a second receiver, in the same
+//! transaction. When a proof comes in, the server proves one of its own UTXOs to the second
+//! receiver, acting as its client, and gets back the UTXOs it contributes. They are offered to
+//! the sender in every candidate set next to the server's, and the output of the second receiver
+//! is presented as the server's change, so the sender only has to support `MULTI_UTXO` and
+//! `RECEIVER_CHANGE`. Once the sender has signed, the server signs its own inputs and sends the
+//! transaction to the second receiver with COSIGN: it makes sure it's paid, adds its witnesses
+//! and hands the transaction back to the server, which broadcasts it.
+//!
+//! The second receiver trusts the server with its UTXOs, which it offers without decoys. The
+//! sender sees them in every candidate set, so only the UTXOs of the server are hidden from it.
+//! If the second receiver can't be reached before the sender has signed, the payjoin goes on
+//! without it.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Arc;
+
+use bitcoin::secp256k1::{All, Secp256k1};
+use bitcoin::util::bip143::SighashComponents;
+use bitcoin::{OutPoint, Transaction, TxIn, TxOut};
+
+use crate::analysis::prev_outs;
+use crate::blockchain::{Blockchain, TxSource};
+use crate::common::*;
+use crate::protocol::receiver::ServerState;
+use crate::signer::Signer;
+use crate::wallet::ReceiverWallet;
+use crate::{Capabilities, Error, ProtocolError, Request, Response, Version};
+
+/// Second receiver of a chained payjoin, starting a new session for every payjoin of the server
+pub trait Downstream: Send + Sync {
+    fn session(&self) -> Result<Box<dyn DownstreamSession>, Error>;
+}
+
+impl<F> Downstream for F
+where
+    F: Fn() -> Result<Box<dyn DownstreamSession>, Error> + Send + Sync,
+{
+    fn session(&self) -> Result<Box<dyn DownstreamSession>, Error> {
+        self()
+    }
+}
+
+/// Session with the second receiver, where the server is the client
+pub trait DownstreamSession: fmt::Debug + Send {
+    /// Send `request` to the second receiver and wait for its response
+    fn message(&mut self, request: Request) -> Result<Response, Error>;
+}
+
+/// A second receiver in the same process. Its errors are reported as coming from a peer
+impl<B, S, W> DownstreamSession for ServerState<B, S, W>
+where
+    B: Blockchain + fmt::Debug + Send + Sync + 'static,
+    Error: From<<B as TxSource>::Error>,
+    S: Signer + fmt::Debug + Send + Sync + 'static,
+    Error: From<<S as Signer>::Error>,
+    W: ReceiverWallet + fmt::Debug + Send + Sync + 'static,
+    Error: From<<W as ReceiverWallet>::Error>,
+{
+    fn message(&mut self, request: Request) -> Result<Response, Error> {
+        match self.step(request) {
+            Ok(Some(response)) => Ok(response),
+            Ok(None) => Err(ProtocolError::UnexpectedMessage.into()),
+            Err(Error::Protocol(e)) => Err(Error::PeerError(e)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Payment of the server to a second receiver, chained into the payjoins of its senders
+#[derive(Clone)]
+pub struct Chain {
+    downstream: Arc<dyn Downstream>,
+    txout: TxOut,
+}
+
+impl fmt::Debug for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Chain").field("txout", &self.txout).finish()
+    }
+}
+
+impl Chain {
+    /// Pay `txout` to the receiver behind `downstream`, out of the UTXOs of the server
+    pub fn new(downstream: Arc<dyn Downstream>, txout: TxOut) -> Self {
+        Chain { downstream, txout }
+    }
+
+    /// Prove `ours` to the second receiver, and get the UTXOs it contributes
+    pub(crate) fn start<B, S>(
+        &self,
+        ours: &[(OutPoint, TxOut)],
+        blockchain: &B,
+        signer: &S,
+    ) -> Result<Chained, Error>
+    where
+        B: Blockchain,
+        Error: From<<B as TxSource>::Error>,
+        S: Signer,
+        Error: From<<S as Signer>::Error>,
+    {
+        let mut session = self.downstream.session()?;
+
+        let (capabilities, nonce) = match session.message(Request::Version {
+            version: Version::CURRENT.to_string(),
+            capabilities: Capabilities::MULTIPARTY | Capabilities::MULTI_UTXO,
+            token: None,
+            invoice: None,
+        })? {
+            Response::Version {
+                capabilities,
+                nonce,
+                ..
+            } => (capabilities, nonce),
+            _ => return Err(ProtocolError::UnexpectedMessage.into()),
+        };
+        if !capabilities.contains(Capabilities::MULTIPARTY) {
+            return Err(ProtocolError::MissingCapability(Capabilities::MULTIPARTY).into());
+        }
+
+        // Only the inputs of the proof matter, the output is replaced
+        let transaction = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: ours
+                .iter()
+                .map(|(utxo, _)| TxIn {
+                    previous_output: *utxo,
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![self.txout.clone()],
+        };
+        let proof_output = proof_scheme(capabilities).output(&ProofContext {
+            nonce,
+            ..Default::default()
+        });
+        let proof = ProofTransaction::<Created>::try_from((transaction, signer, &proof_output))?;
+
+        let utxos = match session.message(Request::Proof {
+            transaction: proof.into_inner(),
+        })? {
+            Response::Utxos {
+                utxos,
+                receiver_inputs,
+                ..
+            } if utxos.len() == receiver_inputs => utxos,
+            Response::Utxos { .. } => return Err(ProtocolError::InvalidUtxo.into()),
+            _ => return Err(ProtocolError::UnexpectedMessage.into()),
+        };
+
+        let mut theirs = Vec::with_capacity(utxos.len());
+        for utxo in utxos {
+            let txout = blockchain
+                .get_tx(&utxo.txid)?
+                .output
+                .get(utxo.vout as usize)
+                .cloned()
+                .ok_or(ProtocolError::InvalidUtxo)?;
+            if !blockchain.is_unspent(&utxo)? {
+                return Err(ProtocolError::InvalidUtxo.into());
+            }
+
+            theirs.push((utxo, txout));
+        }
+        let txout = TxOut {
+            script_pubkey: self.txout.script_pubkey.clone(),
+            value: self.txout.value + theirs.iter().map(|(_, txout)| txout.value).sum::<u64>(),
+        };
+
+        Ok(Chained {
+            session,
+            utxos: theirs,
+            txout,
+        })
+    }
+}
+
+/// Session with the second receiver, once it has contributed its UTXOs
+#[derive(Debug)]
+pub(crate) struct Chained {
+    session: Box<dyn DownstreamSession>,
+    /// UTXOs of the second receiver, with the outputs they spend
+    pub utxos: Vec<(OutPoint, TxOut)>,
+    /// Output of the second receiver, paying back its UTXOs on top of its payment
+    pub txout: TxOut,
+}
+
+impl Chained {
+    /// Value of the UTXOs of the second receiver
+    pub fn value(&self) -> u64 {
+        self.utxos.iter().map(|(_, txout)| txout.value).sum()
+    }
+
+    /// Add the UTXOs of the second receiver to every candidate set of `size` UTXOs in `utxos`
+    pub fn extend(&self, utxos: &[OutPoint], size: usize) -> Vec<OutPoint> {
+        utxos
+            .chunks(size)
+            .flat_map(|set| {
+                set.iter()
+                    .cloned()
+                    .chain(self.utxos.iter().map(|(utxo, _)| *utxo))
+            })
+            .collect()
+    }
+
+    /// Sign our inputs of `final_transaction`, and have the second receiver sign its own
+    pub fn cosign<B, S>(
+        mut self,
+        final_transaction: FinalTransaction<SenderSigned>,
+        blockchain: &B,
+        signer: &S,
+    ) -> Result<FinalTransaction<Signed>, Error>
+    where
+        B: Blockchain,
+        Error: From<<B as TxSource>::Error>,
+        S: Signer,
+        Error: From<<S as Signer>::Error>,
+    {
+        let receiver_input_indexes = final_transaction.receiver_input_indexes().to_vec();
+        let mut transaction = final_transaction.into_inner();
+        let (theirs, ours): (Vec<usize>, Vec<usize>) =
+            receiver_input_indexes.iter().partition(|index| {
+                let utxo = transaction.input[**index].previous_output;
+                self.utxos.iter().any(|(their_utxo, _)| *their_utxo == utxo)
+            });
+        // Same txid, so the cosigned transaction spends them too
+        let prev_outs = prev_outs(blockchain, &transaction)?;
+        signer.sign_with_prev_outs(&mut transaction, &ours, &prev_outs)?;
+
+        let cosigned = match self.session.message(Request::Cosign {
+            transaction: transaction.clone(),
+        })? {
+            Response::Txid { transaction, .. } => transaction,
+            _ => return Err(ProtocolError::UnexpectedMessage.into()),
+        };
+        if cosigned.txid() != transaction.txid() {
+            return Err(ProtocolError::TransactionMismatch.into());
+        }
+
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+        let comp = SighashComponents::new(&cosigned);
+        for index in theirs {
+            verify_input(&secp, &comp, &cosigned, index, &prev_outs, false)
+                .map_err(|_| ProtocolError::InvalidSignature(index))?;
+            transaction.input[index] = cosigned.input[index].clone();
+        }
+
+        Ok(FinalTransaction::from_parts(
+            transaction,
+            receiver_input_indexes,
+        ))
+    }
+}
+
+/// Check the transaction of a COSIGN request, sent by the server chaining us: it has to spend
+/// the inputs of its proof and our UTXOs, and pay us `our_txout` on top of what they're worth
+pub(crate) fn check_cosign<B>(
+    transaction: Transaction,
+    proof: &Transaction,
+    our_utxos: &[OutPoint],
+    our_txout: &TxOut,
+    blockchain: &B,
+) -> Result<FinalTransaction<SenderSigned>, Error>
+where
+    B: Blockchain,
+    Error: From<<B as TxSource>::Error>,
+{
+    let spends = |utxo: &OutPoint| {
+        transaction
+            .input
+            .iter()
+            .any(|txin| txin.previous_output == *utxo)
+    };
+    if !proof.input.iter().all(|txin| spends(&txin.previous_output))
+        || !our_utxos.iter().all(spends)
+    {
+        return Err(ProtocolError::ProofMismatch.into());
+    }
+
+    let prev_outs = prev_outs(blockchain, &transaction)?;
+    let mut receiver_input_indexes = vec![];
+    let mut our_value = 0u64;
+    for (index, (txin, prev_out)) in transaction.input.iter().zip(&prev_outs).enumerate() {
+        if our_utxos.contains(&txin.previous_output) {
+            receiver_input_indexes.push(index);
+            our_value += prev_out.value;
+        }
+    }
+    let received = transaction
+        .output
+        .iter()
+        .filter(|txout| txout.script_pubkey == our_txout.script_pubkey)
+        .map(|txout| txout.value)
+        .sum::<u64>();
+    if received
+        .checked_sub(our_value)
+        .is_none_or(|paid| paid < our_txout.value)
+    {
+        return Err(FinalTransactionError::InvalidReceiverPayment.into());
+    }
+
+    Ok(FinalTransaction::from_parts(
+        transaction,
+        receiver_input_indexes,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::Script;
+
+    use super::*;
+    use crate::testutil::{p2wpkh_key, MockBlockchain};
+
+    #[test]
+    fn test_check_cosign() {
+        let blockchain = MockBlockchain::new();
+        let theirs = blockchain.fund(Script::new(), 100_000);
+        let ours = blockchain.fund(Script::new(), 50_000);
+        let our_txout = TxOut {
+            script_pubkey: p2wpkh_key(2).1,
+            value: 10_000,
+        };
+        let input = |previous_output| TxIn {
+            previous_output,
+            ..Default::default()
+        };
+        let proof = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![input(theirs)],
+            output: vec![],
+        };
+        let transaction = |inputs: Vec<OutPoint>, value| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: inputs.into_iter().map(input).collect(),
+            output: vec![
+                TxOut {
+                    script_pubkey: Script::new(),
+                    value: 85_000,
+                },
+                TxOut {
+                    script_pubkey: our_txout.script_pubkey.clone(),
+                    value,
+                },
+            ],
+        };
+
+        let checked = check_cosign(
+            transaction(vec![theirs, ours], 60_000),
+            &proof,
+            &[ours],
+            &our_txout,
+            &blockchain,
+        )
+        .unwrap();
+        assert_eq!(checked.receiver_input_indexes(), &[1]);
+
+        assert!(matches!(
+            check_cosign(
+                transaction(vec![ours], 60_000),
+                &proof,
+                &[ours],
+                &our_txout,
+                &blockchain,
+            ),
+            Err(Error::Protocol(ProtocolError::ProofMismatch))
+        ));
+        assert!(matches!(
+            check_cosign(
+                transaction(vec![theirs, ours], 55_000),
+                &proof,
+                &[ours],
+                &our_txout,
+                &blockchain,
+            ),
+            Err(Error::Protocol(ProtocolError::InvalidFinalTransaction(
+                FinalTransactionError::InvalidReceiverPayment
+            )))
+        ));
+    }
+}
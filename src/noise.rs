@@ -0,0 +1,407 @@
+//! Noise encryption on top of another transport
+//!
+//! An alternative to TLS for clearnet deployments that don't want to deal with certificates. The
+//! connection is encrypted with the `Noise_XK` handshake, where the sender knows the static key of
+//! the server in advance: the server advertises it in its endpoint, as `<key>@host:port`, so that it
+//! travels in the BIP21 URI together with the address.
+//!
+//! Every Noise message is prefixed with its length as a big-endian `u16`.
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{Builder, HandshakeState, TransportState};
+
+use bitcoin::hashes::hex::{FromHex, ToHex};
+
+use crate::transport::{BoxedStream, Transport};
+use crate::Error;
+
+const PATTERN: &str = "Noise_XK_25519_ChaChaPoly_BLAKE2s";
+const MAX_MESSAGE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+/// Largest payload that fits in a message together with its tag
+const MAX_PAYLOAD_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+
+fn builder() -> Builder<'static> {
+    Builder::new(PATTERN.parse().expect("Valid Noise pattern"))
+}
+
+fn noise_error(err: snow::Error) -> Error {
+    Error::Transport(format!("Noise: {}", err))
+}
+
+/// Generate a new static private key for `Noise::server()`
+pub fn generate_private_key() -> [u8; 32] {
+    let keypair = builder()
+        .generate_keypair()
+        .expect("The default resolver supports 25519");
+
+    let mut private_key = [0; 32];
+    private_key.copy_from_slice(&keypair.private);
+    private_key
+}
+
+/// Public key of a static private key, as advertised by the server
+pub fn public_key(private_key: &[u8; 32]) -> [u8; 32] {
+    let mut dh = DefaultResolver
+        .resolve_dh(&DHChoice::Curve25519)
+        .expect("The default resolver supports 25519");
+    dh.set(private_key);
+
+    let mut public_key = [0; 32];
+    public_key.copy_from_slice(dh.pubkey());
+    public_key
+}
+
+enum Role {
+    Client,
+    Server([u8; 32]),
+}
+
+/// Encrypt the connections of `inner` with Noise
+///
+/// A `Noise` built with `client()` can only connect, one built with `server()` can only accept.
+pub struct Noise<T> {
+    inner: T,
+    role: Role,
+}
+
+impl<T: Transport> Noise<T> {
+    /// Connect to endpoints in the `<key>@host:port` form
+    pub fn client(inner: T) -> Self {
+        Noise {
+            inner,
+            role: Role::Client,
+        }
+    }
+
+    /// Accept connections for the static `private_key`, which should be persisted to keep the same
+    /// endpoint across restarts
+    pub fn server(inner: T, private_key: [u8; 32]) -> Self {
+        Noise {
+            inner,
+            role: Role::Server(private_key),
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for Noise<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let role = match &self.role {
+            Role::Client => "Client".to_string(),
+            Role::Server(private_key) => format!("Server({})", public_key(private_key).to_hex()),
+        };
+        f.debug_struct("Noise").field("role", &role).finish()
+    }
+}
+
+async fn read_message(stream: &mut BoxedStream) -> Result<Vec<u8>, Error> {
+    let len = stream.read_u16().await?;
+    let mut message = vec![0; len as usize];
+    stream.read_exact(&mut message).await?;
+
+    Ok(message)
+}
+
+async fn write_message(stream: &mut BoxedStream, message: &[u8]) -> Result<(), Error> {
+    stream.write_u16(message.len() as u16).await?;
+    stream.write_all(message).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+/// Run the three messages of the XK handshake
+async fn handshake(
+    mut stream: BoxedStream,
+    mut state: HandshakeState,
+) -> Result<NoiseStream, Error> {
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state.write_message(&[], &mut buf).map_err(noise_error)?;
+            write_message(&mut stream, &buf[..len]).await?;
+        } else {
+            let message = read_message(&mut stream).await?;
+            state
+                .read_message(&message, &mut buf)
+                .map_err(noise_error)?;
+        }
+    }
+
+    Ok(NoiseStream::new(
+        stream,
+        state.into_transport_mode().map_err(noise_error)?,
+    ))
+}
+
+#[async_trait]
+impl<T: Transport> Transport for Noise<T> {
+    async fn connect(&self, addr: &str) -> Result<BoxedStream, Error> {
+        if let Role::Server(_) = self.role {
+            return Err(Error::Transport("Not a Noise client".into()));
+        }
+
+        let mut parts = addr.splitn(2, '@');
+        let (remote_key, addr) = match (parts.next(), parts.next()) {
+            (Some(key), Some(addr)) => (key, addr),
+            _ => {
+                return Err(Error::Transport(format!(
+                    "Missing the Noise key of the server: {}",
+                    addr
+                )))
+            }
+        };
+        let remote_key = Vec::<u8>::from_hex(remote_key)
+            .ok()
+            .filter(|key| key.len() == 32)
+            .ok_or_else(|| Error::Transport(format!("Invalid Noise key: {}", remote_key)))?;
+
+        // The server doesn't authenticate us, so a fresh static key is as good as any
+        let local_key = generate_private_key();
+        let state = builder()
+            .local_private_key(&local_key)
+            .remote_public_key(&remote_key)
+            .build_initiator()
+            .map_err(noise_error)?;
+
+        let stream = self.inner.connect(addr).await?;
+        Ok(Box::new(handshake(stream, state).await?))
+    }
+
+    fn socks_port(&self) -> Option<u16> {
+        self.inner.socks_port()
+    }
+
+    async fn wait_ready(&self) -> Result<(), Error> {
+        self.inner.wait_ready().await
+    }
+
+    async fn expose(&self, local_addr: SocketAddr, port: u16) -> Result<String, Error> {
+        let endpoint = self.inner.expose(local_addr, port).await?;
+        match &self.role {
+            Role::Server(private_key) => {
+                Ok(format!("{}@{}", public_key(private_key).to_hex(), endpoint))
+            }
+            Role::Client => Ok(endpoint),
+        }
+    }
+
+    async fn accept(&self, stream: TcpStream) -> Result<BoxedStream, Error> {
+        let private_key = match &self.role {
+            Role::Server(private_key) => private_key,
+            Role::Client => return Err(Error::Transport("Not a Noise server".into())),
+        };
+
+        let state = builder()
+            .local_private_key(private_key)
+            .build_responder()
+            .map_err(noise_error)?;
+
+        let stream = self.inner.accept(stream).await?;
+        Ok(Box::new(handshake(stream, state).await?))
+    }
+}
+
+fn io_error(err: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Stream of length-prefixed Noise messages
+struct NoiseStream {
+    inner: BoxedStream,
+    state: TransportState,
+    /// Raw bytes read so far of the next message
+    read_raw: Vec<u8>,
+    /// Decrypted payload not returned yet
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    /// Encrypted messages not written yet
+    write_raw: Vec<u8>,
+}
+
+impl NoiseStream {
+    fn new(inner: BoxedStream, state: TransportState) -> Self {
+        NoiseStream {
+            inner,
+            state,
+            read_raw: Vec::new(),
+            read_buf: Vec::new(),
+            read_pos: 0,
+            write_raw: Vec::new(),
+        }
+    }
+
+    /// Decrypt the next message, if it has been read completely
+    fn decrypt(&mut self) -> io::Result<bool> {
+        if self.read_raw.len() < 2 {
+            return Ok(false);
+        }
+        let len = u16::from_be_bytes([self.read_raw[0], self.read_raw[1]]) as usize;
+        if self.read_raw.len() < 2 + len {
+            return Ok(false);
+        }
+
+        let message: Vec<u8> = self.read_raw.drain(..2 + len).skip(2).collect();
+        self.read_buf.resize(len, 0);
+        let len = self
+            .state
+            .read_message(&message, &mut self.read_buf)
+            .map_err(io_error)?;
+        self.read_buf.truncate(len);
+        self.read_pos = 0;
+
+        Ok(true)
+    }
+
+    fn poll_write_raw(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_raw.is_empty() {
+            let written = match Pin::new(&mut self.inner).poll_write(cx, &self.write_raw) {
+                Poll::Ready(result) => result?,
+                Poll::Pending => return Poll::Pending,
+            };
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_raw.drain(..written);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for NoiseStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // Empty messages are skipped rather than returned as an EOF
+        while this.read_pos == this.read_buf.len() {
+            if this.decrypt()? {
+                continue;
+            }
+
+            let mut chunk = [0; 4096];
+            let read = match Pin::new(&mut this.inner).poll_read(cx, &mut chunk) {
+                Poll::Ready(result) => result?,
+                Poll::Pending => return Poll::Pending,
+            };
+            if read == 0 {
+                return Poll::Ready(Ok(0));
+            }
+            this.read_raw.extend_from_slice(&chunk[..read]);
+        }
+
+        let len = std::cmp::min(buf.len(), this.read_buf.len() - this.read_pos);
+        buf[..len].copy_from_slice(&this.read_buf[this.read_pos..this.read_pos + len]);
+        this.read_pos += len;
+
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for NoiseStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // Only encrypt more data once the previous messages have been written
+        match this.poll_write_raw(cx) {
+            Poll::Ready(result) => result?,
+            Poll::Pending => return Poll::Pending,
+        }
+
+        let payload = &buf[..std::cmp::min(buf.len(), MAX_PAYLOAD_LEN)];
+        let mut message = vec![0; payload.len() + TAG_LEN];
+        let len = this
+            .state
+            .write_message(payload, &mut message)
+            .map_err(io_error)?;
+        this.write_raw
+            .extend_from_slice(&(len as u16).to_be_bytes());
+        this.write_raw.extend_from_slice(&message[..len]);
+
+        Poll::Ready(Ok(payload.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        match this.poll_write_raw(cx) {
+            Poll::Ready(result) => result?,
+            Poll::Pending => return Poll::Pending,
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        match this.poll_write_raw(cx) {
+            Poll::Ready(result) => result?,
+            Poll::Pending => return Poll::Pending,
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::transport::Direct;
+
+    async fn exchange(private_key: [u8; 32], remote_key: [u8; 32]) -> Result<Vec<u8>, Error> {
+        let server = Noise::server(Direct, private_key);
+        let mut listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server_task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = server.accept(stream).await?;
+            stream.write_all(b"hello").await?;
+            stream.flush().await?;
+
+            Ok::<_, Error>(())
+        });
+
+        let client = Noise::client(Direct);
+        let mut stream = client
+            .connect(&format!("{}@{}", remote_key.to_hex(), addr))
+            .await?;
+        let mut received = vec![0; 5];
+        stream.read_exact(&mut received).await?;
+        server_task.await.unwrap()?;
+
+        Ok(received)
+    }
+
+    #[tokio::test]
+    async fn test_noise_server_key() {
+        let private_key = generate_private_key();
+        let public = public_key(&private_key);
+
+        assert_eq!(exchange(private_key, public).await.unwrap(), b"hello");
+        // Someone else's key
+        let other = public_key(&generate_private_key());
+        assert!(exchange(private_key, other).await.is_err());
+    }
+}
@@ -0,0 +1,379 @@
+//! Non-interactive fallback, for receivers that can't stay online
+//!
+//! Like in SNICKER, one side prepares a proposal that the other can complete on its own, but here
+//! it is the receiver: an `Offer` spends one of its UTXOs into an output receiving it back together
+//! with the payment, signed with `SIGHASH_SINGLE | SIGHASH_ANYONECANPAY`. The signature only
+//! commits to that input and to the output at the same position, so a sender can add its inputs
+//! and its change, sign them and broadcast a transaction shaped like any other payjoin, without
+//! the receiver being around.
+//!
+//! Offers are published as hex PSBTs, usually in the `offer` parameter of a payment URI. Each one
+//! reveals the receiver's UTXO to whoever sees it and can only be taken once: the `Server` keeps
+//! the UTXO locked until `Server::claim_offers()` finds it spent. Only P2WPKH UTXOs can be offered.
+
+use std::fmt;
+use std::str::FromStr;
+
+use rand::Rng;
+
+use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::encode::{deserialize, serialize, Encodable};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::{hash160, sha256d, Hash, HashEngine};
+use bitcoin::secp256k1::{Message, Secp256k1, Signature};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, PublicKey, Script, SigHashType, Transaction, TxIn, TxOut};
+
+use crate::blockchain::{Blockchain, TxSource};
+use crate::common::{DUST_LIMIT, SEQUENCE_FINAL};
+use crate::fees::FeeRate;
+use crate::signer::Signer;
+use crate::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OfferError {
+    /// Not a PSBT with a single signed input and a single output
+    InvalidFormat,
+    /// The offered UTXO is not P2WPKH
+    UnsupportedScript,
+    /// The receiver's signature is missing, invalid or doesn't use
+    /// `SIGHASH_SINGLE | SIGHASH_ANYONECANPAY`
+    InvalidSignature,
+    /// The offered UTXO has been spent, or doesn't match the offer
+    InvalidUtxo,
+    /// The transaction doesn't pay the script and amount of the offer
+    PaymentMismatch,
+    /// The signer doesn't support `SIGHASH_SINGLE | SIGHASH_ANYONECANPAY`
+    UnsupportedSigner,
+}
+
+impl fmt::Display for OfferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OfferError::InvalidFormat => write!(f, "invalid offer"),
+            OfferError::UnsupportedScript => write!(f, "only P2WPKH UTXOs can be offered"),
+            OfferError::InvalidSignature => write!(f, "invalid signature of the receiver"),
+            OfferError::InvalidUtxo => write!(f, "the offered UTXO is spent or unknown"),
+            OfferError::PaymentMismatch => {
+                write!(f, "the transaction doesn't pay the amount of the offer")
+            }
+            OfferError::UnsupportedSigner => {
+                write!(f, "the signer can't sign offers")
+            }
+        }
+    }
+}
+
+impl std::error::Error for OfferError {}
+
+/// Signature hash of input `index` for `SIGHASH_SINGLE | SIGHASH_ANYONECANPAY`, as in BIP143. The
+/// `SighashComponents` of rust-bitcoin only covers `SIGHASH_ALL`
+pub fn sighash_single_anyonecanpay(
+    tx: &Transaction,
+    index: usize,
+    script_code: &Script,
+    value: u64,
+) -> sha256d::Hash {
+    let input = &tx.input[index];
+    let hash_outputs = match tx.output.get(index) {
+        Some(output) => sha256d::Hash::hash(&serialize(output)).into_inner(),
+        None => [0; 32],
+    };
+
+    let mut engine = sha256d::Hash::engine();
+    tx.version.consensus_encode(&mut engine).unwrap();
+    // No commitment to the other inputs and their sequences
+    engine.input(&[0; 64]);
+    input.previous_output.consensus_encode(&mut engine).unwrap();
+    script_code.consensus_encode(&mut engine).unwrap();
+    value.consensus_encode(&mut engine).unwrap();
+    input.sequence.consensus_encode(&mut engine).unwrap();
+    engine.input(&hash_outputs);
+    tx.lock_time.consensus_encode(&mut engine).unwrap();
+    SigHashType::SinglePlusAnyoneCanPay
+        .as_u32()
+        .consensus_encode(&mut engine)
+        .unwrap();
+
+    sha256d::Hash::from_engine(engine)
+}
+
+/// Half of a payjoin transaction signed in advance by the receiver
+#[derive(Debug, Clone, PartialEq)]
+pub struct Offer {
+    transaction: Transaction,
+    prev_out: TxOut,
+}
+
+impl Offer {
+    /// Offer `utxo`, which spends `prev_out`, for a payment of `amount` satoshis to `script_pubkey`
+    pub fn new<S>(
+        utxo: OutPoint,
+        prev_out: TxOut,
+        script_pubkey: Script,
+        amount: u64,
+        signer: &S,
+    ) -> Result<Self, Error>
+    where
+        S: Signer,
+        Error: From<<S as Signer>::Error>,
+    {
+        if !prev_out.script_pubkey.is_v0_p2wpkh() {
+            return Err(OfferError::UnsupportedScript.into());
+        }
+
+        let mut transaction = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: utxo,
+                sequence: SEQUENCE_FINAL,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                script_pubkey,
+                value: prev_out.value + amount,
+            }],
+        };
+        if !signer.sign_single_anyonecanpay(&mut transaction, &[0])? {
+            return Err(OfferError::UnsupportedSigner.into());
+        }
+
+        let offer = Offer {
+            transaction,
+            prev_out,
+        };
+        offer.verify()?;
+
+        Ok(offer)
+    }
+
+    /// UTXO of the receiver spent by the offer
+    pub fn utxo(&self) -> OutPoint {
+        self.transaction.input[0].previous_output
+    }
+
+    /// Output receiving the UTXO back together with the payment
+    pub fn txout(&self) -> &TxOut {
+        &self.transaction.output[0]
+    }
+
+    /// Amount the sender has to pay
+    pub fn amount(&self) -> u64 {
+        self.txout().value - self.prev_out.value
+    }
+
+    /// Check the signature of the receiver
+    fn verify(&self) -> Result<(), OfferError> {
+        let input = &self.transaction.input[0];
+        if !self.prev_out.script_pubkey.is_v0_p2wpkh() {
+            return Err(OfferError::UnsupportedScript);
+        } else if self.txout().value <= self.prev_out.value || input.witness.len() != 2 {
+            return Err(OfferError::InvalidFormat);
+        }
+
+        let (signature, sighash_type) = match input.witness[0].split_last() {
+            Some((&sighash_type, signature)) => (signature, sighash_type),
+            None => return Err(OfferError::InvalidSignature),
+        };
+        let pubkey =
+            PublicKey::from_slice(&input.witness[1]).map_err(|_| OfferError::InvalidSignature)?;
+        if u32::from(sighash_type) != SigHashType::SinglePlusAnyoneCanPay.as_u32()
+            || hash160::Hash::hash(&input.witness[1])[..]
+                != self.prev_out.script_pubkey.as_bytes()[2..]
+        {
+            return Err(OfferError::InvalidSignature);
+        }
+
+        let script_code = Builder::new()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_slice(&self.prev_out.script_pubkey.as_bytes()[2..])
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        let hash =
+            sighash_single_anyonecanpay(&self.transaction, 0, &script_code, self.prev_out.value);
+        Secp256k1::verification_only()
+            .verify(
+                &Message::from_slice(&hash).unwrap(),
+                &Signature::from_der(signature).map_err(|_| OfferError::InvalidSignature)?,
+                &pubkey.key,
+            )
+            .map_err(|_| OfferError::InvalidSignature)
+    }
+
+    /// Take the offer with `base_transaction`, which pays it in `receiver_output_index` and sends
+    /// the change to `change_output_index`
+    ///
+    /// The payment is replaced by the output of the offer, next to the receiver's input at a random
+    /// position, and the change pays for that input at `fee_rate`. The inputs of the sender are
+    /// signed, so the transaction is ready to broadcast.
+    pub fn complete<B, S>(
+        &self,
+        base_transaction: &Transaction,
+        receiver_output_index: usize,
+        change_output_index: usize,
+        fee_rate: FeeRate,
+        blockchain: &B,
+        signer: &S,
+    ) -> Result<Transaction, Error>
+    where
+        B: Blockchain,
+        Error: From<<B as TxSource>::Error>,
+        S: Signer,
+        Error: From<<S as Signer>::Error>,
+    {
+        self.verify()?;
+        let utxo = self.utxo();
+        let known = blockchain
+            .get_tx(&utxo.txid)?
+            .output
+            .get(utxo.vout as usize)
+            .is_some_and(|prev_out| *prev_out == self.prev_out);
+        if !known || !blockchain.is_unspent(&utxo)? {
+            return Err(OfferError::InvalidUtxo.into());
+        }
+
+        let payment = base_transaction
+            .output
+            .get(receiver_output_index)
+            .ok_or(OfferError::PaymentMismatch)?;
+        if payment.script_pubkey != self.txout().script_pubkey || payment.value != self.amount() {
+            return Err(OfferError::PaymentMismatch.into());
+        }
+
+        let mut transaction = base_transaction.clone();
+        // Both are committed to by the signature of the receiver
+        transaction.version = self.transaction.version;
+        transaction.lock_time = self.transaction.lock_time;
+        for input in &mut transaction.input {
+            input.script_sig = Script::new();
+            input.witness.clear();
+        }
+
+        let receiver_input = self.transaction.input[0].clone();
+        let receiver_input_weight = serialize(&TxIn {
+            witness: vec![],
+            ..receiver_input.clone()
+        })
+        .len()
+            * 4
+            + serialize(&receiver_input.witness).len();
+        let change = transaction
+            .output
+            .get_mut(change_output_index)
+            .ok_or(Error::InsufficientFunds)?;
+        change.value = change
+            .value
+            .checked_sub(fee_rate.fee_wu(receiver_input_weight))
+            .filter(|value| *value >= DUST_LIMIT)
+            .ok_or(Error::InsufficientFunds)?;
+
+        transaction.output.remove(receiver_output_index);
+        let position = rand::thread_rng()
+            .gen_range(0, transaction.input.len().min(transaction.output.len()) + 1);
+        transaction.input.insert(
+            position,
+            TxIn {
+                witness: vec![],
+                ..receiver_input.clone()
+            },
+        );
+        transaction.output.insert(position, self.txout().clone());
+
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(transaction.clone())
+            .map_err(|_| OfferError::InvalidFormat)?;
+        for (psbt_input, input) in psbt.inputs.iter_mut().zip(&transaction.input) {
+            psbt_input.witness_utxo = if input.previous_output == utxo {
+                Some(self.prev_out.clone())
+            } else {
+                let prev_tx = blockchain.get_tx(&input.previous_output.txid)?;
+                prev_tx
+                    .output
+                    .get(input.previous_output.vout as usize)
+                    .cloned()
+            };
+        }
+        let sender_inputs = (0..transaction.input.len())
+            .filter(|index| *index != position)
+            .collect::<Vec<_>>();
+        signer.sign_psbt(&mut psbt, &sender_inputs)?;
+
+        for index in sender_inputs {
+            let psbt_input = &mut psbt.inputs[index];
+            transaction.input[index].witness =
+                psbt_input.final_script_witness.take().unwrap_or_default();
+            transaction.input[index].script_sig =
+                psbt_input.final_script_sig.take().unwrap_or_default();
+        }
+        transaction.input[position].witness = receiver_input.witness;
+
+        Ok(transaction)
+    }
+}
+
+impl fmt::Display for Offer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut unsigned = self.transaction.clone();
+        let witness = std::mem::take(&mut unsigned.input[0].witness);
+        let mut psbt =
+            PartiallySignedTransaction::from_unsigned_tx(unsigned).map_err(|_| fmt::Error)?;
+        psbt.inputs[0].witness_utxo = Some(self.prev_out.clone());
+        psbt.inputs[0].final_script_witness = Some(witness);
+
+        write!(f, "{}", serialize(&psbt).to_hex())
+    }
+}
+
+impl FromStr for Offer {
+    type Err = OfferError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes: Vec<u8> = FromHex::from_hex(s).map_err(|_| OfferError::InvalidFormat)?;
+        let psbt: PartiallySignedTransaction =
+            deserialize(&bytes).map_err(|_| OfferError::InvalidFormat)?;
+        let mut transaction = psbt.global.unsigned_tx;
+        if transaction.input.len() != 1 || transaction.output.len() != 1 {
+            return Err(OfferError::InvalidFormat);
+        }
+
+        let input = &psbt.inputs[0];
+        let prev_out = input
+            .witness_utxo
+            .clone()
+            .ok_or(OfferError::InvalidFormat)?;
+        transaction.input[0].witness = input
+            .final_script_witness
+            .clone()
+            .ok_or(OfferError::InvalidSignature)?;
+
+        let offer = Offer {
+            transaction,
+            prev_out,
+        };
+        offer.verify()?;
+
+        Ok(offer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Nested P2SH-P2WSH example of BIP143, signed with `SIGHASH_SINGLE | SIGHASH_ANYONECANPAY`
+    #[test]
+    fn test_bip143_vector() {
+        let tx: Transaction = deserialize(&Vec::<u8>::from_hex("010000000136641869ca081e70f394c6948e8af409e18b619df2ed74aa106c1ca29787b96e0100000000ffffffff0200e9a435000000001976a914389ffce9cd9ae88dcc0631e88a821ffdbe9bfe2688acc0832f05000000001976a9147480a33f950689af511e6e84c138dbbd3c3ee41588ac00000000").unwrap()).unwrap();
+        let witness_script = Script::from(Vec::<u8>::from_hex("56210307b8ae49ac90a048e9b53357a2354b3334e9c8bee813ecb98e99a7e07e8c3ba32103b28f0c28bfab54554ae8c658ac5c3e0ce6e79ad336331f78c428dd43eea8449b21034b8113d703413d57761b8b9781957b8c0ac1dfe69f492580ca4195f50376ba4a21033400f6afecb833092a9a21cfdf1ed1376e58c5d1f47de74683123987e967a8f42103a6d48b1131e94ba04d9737d61acdaa1322008af9602b3b14862c07a1789aac162102d8b661b0b3302ee2f162b09e07a55ad5dfbe673a9f01d9f0c19617681024306b56ae").unwrap());
+
+        assert_eq!(
+            sighash_single_anyonecanpay(&tx, 0, &witness_script, 987654321)
+                .into_inner()
+                .to_hex(),
+            "511e8e52ed574121fc1b654970395502128263f62662e076dc6baf05c2e6a99b"
+        );
+    }
+}
@@ -0,0 +1,330 @@
+//! Broadcast through the Bitcoin P2P network
+//!
+//! Handing the final transaction to the Electrum server we also query ties the payjoin to
+//! everything else that server knows about us. `P2pBroadcaster` announces it instead to a few
+//! random peers, through any `Transport` so that the connections can go over Tor, and is meant to
+//! be paired with the usual backend in a `SplitBlockchain`.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Handle;
+
+use tracing::{debug, debug_span, warn, Instrument};
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use bitcoin::consensus::encode::{self, deserialize, serialize};
+use bitcoin::network::address::Address;
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::network::message_network::VersionMessage;
+use bitcoin::{Network, Transaction};
+
+use crate::blockchain::Broadcaster;
+use crate::transport::{BoxedStream, Transport};
+use crate::{Error, TimeoutPhase};
+
+/// Size of the header of every P2P message: magic, command, length and checksum
+const HEADER_SIZE: usize = 24;
+/// Largest message accepted from a peer, like the limit of Bitcoin Core
+const MAX_PAYLOAD_SIZE: usize = 4_000_000;
+/// Same user agent as a recent Bitcoin Core, to blend in with the other nodes
+const USER_AGENT: &str = "/Satoshi:0.20.1/";
+
+/// `Broadcaster` announcing transactions to `peer_count` peers picked at random among `peers`
+#[derive(Clone)]
+pub struct P2pBroadcaster {
+    transport: Arc<dyn Transport>,
+    network: Network,
+    peers: Vec<String>,
+    peer_count: usize,
+    timeout: Duration,
+    handle: Handle,
+}
+
+impl std::fmt::Debug for P2pBroadcaster {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("P2pBroadcaster")
+            .field("network", &self.network)
+            .field("peers", &self.peers)
+            .field("peer_count", &self.peer_count)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl P2pBroadcaster {
+    /// Connect to `peers`, in the `host:port` form, through `transport`. Must be called from
+    /// within a tokio runtime
+    pub fn new(transport: Arc<dyn Transport>, network: Network, peers: Vec<String>) -> Self {
+        P2pBroadcaster {
+            transport,
+            network,
+            peers,
+            peer_count: 3,
+            timeout: Duration::from_secs(30),
+            handle: Handle::current(),
+        }
+    }
+
+    /// Peers the transaction is announced to
+    pub fn peer_count(mut self, peer_count: usize) -> Self {
+        self.peer_count = peer_count;
+        self
+    }
+
+    /// Time allowed for each peer to complete the handshake and fetch the transaction
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn broadcast_async(&self, tx: &Transaction) -> Result<(), Error> {
+        let peers = self
+            .peers
+            .choose_multiple(&mut rand::thread_rng(), self.peer_count)
+            .cloned()
+            .collect::<Vec<_>>();
+        if peers.is_empty() {
+            return Err(Error::Transport("No P2P peers to broadcast to".into()));
+        }
+
+        let handles = peers
+            .into_iter()
+            .map(|peer| {
+                let transport = Arc::clone(&self.transport);
+                let network = self.network;
+                let tx = tx.clone();
+                let span = debug_span!("p2p_broadcast", %peer);
+                let exchange = announce(transport, network, peer, tx);
+
+                tokio::spawn(tokio::time::timeout(self.timeout, exchange).instrument(span))
+            })
+            .collect::<Vec<_>>();
+
+        let mut result = Err(Error::Timeout(TimeoutPhase::Message));
+        for handle in handles {
+            match handle.await.map_err(|_| Error::Other)? {
+                Ok(Ok(())) => result = Ok(()),
+                Ok(Err(e)) => {
+                    warn!("A peer didn't take the transaction: {:?}", e);
+                    if result.is_err() {
+                        result = Err(e);
+                    }
+                }
+                Err(_) => warn!("A peer timed out"),
+            }
+        }
+
+        result
+    }
+}
+
+impl Broadcaster for P2pBroadcaster {
+    type Error = Error;
+
+    /// Succeeds if at least one of the peers took the transaction
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        self.handle.block_on(self.broadcast_async(tx))
+    }
+}
+
+async fn send(
+    stream: &mut BoxedStream,
+    network: Network,
+    payload: NetworkMessage,
+) -> Result<(), Error> {
+    let message = RawNetworkMessage {
+        magic: network.magic(),
+        payload,
+    };
+    stream.write_all(&serialize(&message)).await?;
+
+    Ok(())
+}
+
+/// Next message of the peer, or `None` for the ones we don't know about
+async fn receive(
+    stream: &mut BoxedStream,
+    network: Network,
+) -> Result<Option<NetworkMessage>, Error> {
+    let mut message = vec![0; HEADER_SIZE];
+    stream.read_exact(&mut message).await?;
+
+    let mut length = [0; 4];
+    length.copy_from_slice(&message[16..20]);
+    let length = u32::from_le_bytes(length) as usize;
+    if length > MAX_PAYLOAD_SIZE {
+        return Err(Error::MessageTooLarge);
+    }
+    message.resize(HEADER_SIZE + length, 0);
+    stream.read_exact(&mut message[HEADER_SIZE..]).await?;
+
+    match deserialize::<RawNetworkMessage>(&message) {
+        Ok(message) if message.magic == network.magic() => Ok(Some(message.payload)),
+        Ok(_) => Err(Error::Transport("Peer on another network".into())),
+        Err(encode::Error::UnrecognizedNetworkCommand(command)) => {
+            debug!("Ignoring `{}` message", command);
+            Ok(None)
+        }
+        Err(e) => Err(Error::Transport(format!("Invalid P2P message: {}", e))),
+    }
+}
+
+/// Handshake with `peer`, announce `tx` and send it once requested
+async fn announce(
+    transport: Arc<dyn Transport>,
+    network: Network,
+    peer: String,
+    tx: Transaction,
+) -> Result<(), Error> {
+    let mut stream = transport.connect(&peer).await?;
+
+    // Our address would give us away, and over Tor we don't know the peer's either
+    let unknown = Address::new(
+        &SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        ServiceFlags::NONE,
+    );
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let mut version = VersionMessage::new(
+        ServiceFlags::WITNESS,
+        timestamp,
+        unknown.clone(),
+        unknown,
+        rand::thread_rng().gen(),
+        USER_AGENT.to_string(),
+        0,
+    );
+    // We only relay this transaction, not the ones of the peer
+    version.relay = false;
+    send(&mut stream, network, NetworkMessage::Version(version)).await?;
+
+    let (mut got_version, mut got_verack) = (false, false);
+    while !(got_version && got_verack) {
+        match receive(&mut stream, network).await? {
+            Some(NetworkMessage::Version(_)) => {
+                got_version = true;
+                send(&mut stream, network, NetworkMessage::Verack).await?;
+            }
+            Some(NetworkMessage::Verack) => got_verack = true,
+            Some(NetworkMessage::Ping(nonce)) => {
+                send(&mut stream, network, NetworkMessage::Pong(nonce)).await?
+            }
+            _ => {}
+        }
+    }
+    debug!("Connected");
+
+    let txid = tx.txid();
+    send(
+        &mut stream,
+        network,
+        NetworkMessage::Inv(vec![Inventory::Transaction(txid)]),
+    )
+    .await?;
+    loop {
+        match receive(&mut stream, network).await? {
+            Some(NetworkMessage::GetData(inventory))
+                if inventory.iter().any(|item| match item {
+                    Inventory::Transaction(requested) => *requested == txid,
+                    // BIP144 asks for witness transactions by txid
+                    Inventory::WitnessTransaction(requested) => {
+                        requested.as_hash() == txid.as_hash()
+                    }
+                    _ => false,
+                }) =>
+            {
+                send(&mut stream, network, NetworkMessage::Tx(tx)).await?;
+                debug!("Sent {}", txid);
+                return Ok(());
+            }
+            Some(NetworkMessage::Ping(nonce)) => {
+                send(&mut stream, network, NetworkMessage::Pong(nonce)).await?
+            }
+            Some(NetworkMessage::Reject(reject)) => {
+                return Err(Error::Transport(format!(
+                    "Transaction rejected: {}",
+                    reject.reason
+                )));
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+
+    use bitcoin::{TxIn, Wtxid};
+
+    use super::*;
+    use crate::transport::Direct;
+
+    /// Peer on the other side of a local connection, returning the transactions it fetched. Like
+    /// Core with a peer advertising `WITNESS`, it asks for them with `MSG_WITNESS_TX`
+    async fn peer(mut listener: TcpListener) -> Result<Transaction, Error> {
+        let (stream, _) = listener.accept().await?;
+        let mut stream: BoxedStream = Box::new(stream);
+        let network = Network::Regtest;
+
+        loop {
+            match receive(&mut stream, network).await? {
+                Some(NetworkMessage::Version(version)) => {
+                    send(&mut stream, network, NetworkMessage::Version(version)).await?;
+                    send(&mut stream, network, NetworkMessage::Verack).await?;
+                }
+                Some(NetworkMessage::Inv(inventory)) => {
+                    let inventory = inventory
+                        .into_iter()
+                        .map(|item| match item {
+                            Inventory::Transaction(txid) => {
+                                Inventory::WitnessTransaction(Wtxid::from_hash(txid.as_hash()))
+                            }
+                            item => item,
+                        })
+                        .collect();
+                    send(&mut stream, network, NetworkMessage::GetData(inventory)).await?
+                }
+                Some(NetworkMessage::Tx(tx)) => return Ok(tx),
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_p2p_broadcast() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = tokio::spawn(peer(listener));
+
+        // With a witness, so that the txid and the wtxid differ
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                witness: vec![vec![1]],
+                ..Default::default()
+            }],
+            output: vec![],
+        };
+        assert_ne!(tx.txid().as_hash(), tx.wtxid().as_hash());
+        let broadcaster =
+            P2pBroadcaster::new(Arc::new(Direct), Network::Regtest, vec![addr.to_string()]);
+        let broadcast = tx.clone();
+        tokio::task::spawn_blocking(move || broadcaster.broadcast(&broadcast))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(peer.await.unwrap().unwrap(), tx);
+    }
+}
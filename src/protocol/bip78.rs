@@ -0,0 +1,713 @@
+//! BIP78 (Payjoin) compatibility
+//!
+//! The sender POSTs a fully signed "original" PSBT to the receiver's endpoint, the receiver adds
+//! one of its inputs, signs it and replies with the "proposal" PSBT. The sender then checks that
+//! the proposal doesn't touch its inputs or outputs, signs its own inputs and broadcasts the
+//! final transaction.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use tracing::{debug, trace};
+
+use rand::Rng;
+
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::secp256k1::{All, Secp256k1};
+use bitcoin::util::bip143::SighashComponents;
+use bitcoin::util::psbt::{Input, PartiallySignedTransaction};
+use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+
+use crate::blockchain::{Blockchain, TxSource};
+use crate::common::{is_supported_input, verify_input, ProofTransactionError};
+use crate::signer::Signer;
+use crate::{Error, ProtocolError};
+
+pub const VERSION: u32 = 1;
+
+const MAX_HEADERS: usize = 64;
+const MAX_LINE: usize = 8 * 1024;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Bip78Error {
+    // Well-known error codes returned by the receiver
+    Unavailable,
+    NotEnoughMoney,
+    VersionUnsupported,
+    OriginalPsbtRejected,
+
+    // Checks run by the sender on the proposal
+    InvalidPsbt,
+    InvalidHttpMessage,
+    ChangedVersion,
+    ChangedLocktime,
+    MissingSenderInput(usize),
+    ModifiedSenderInput(usize),
+    SignedSenderInput(usize),
+    ReceiverInputNotFinalized(usize),
+    ReceiverInputSequence(usize),
+    MissingSenderOutput(usize),
+    ModifiedSenderOutput(usize),
+    UnexpectedOutput(usize),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ErrorResponse {
+    #[serde(rename = "errorCode")]
+    error_code: Bip78Error,
+    message: String,
+}
+
+impl Bip78Error {
+    fn is_well_known(&self) -> bool {
+        matches!(
+            self,
+            Bip78Error::Unavailable
+                | Bip78Error::NotEnoughMoney
+                | Bip78Error::VersionUnsupported
+                | Bip78Error::OriginalPsbtRejected
+        )
+    }
+}
+
+impl fmt::Display for Bip78Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bip78Error::Unavailable => write!(f, "the receiver is unavailable"),
+            Bip78Error::NotEnoughMoney => write!(f, "the receiver has no UTXO to contribute"),
+            Bip78Error::VersionUnsupported => write!(f, "unsupported BIP78 version"),
+            Bip78Error::OriginalPsbtRejected => write!(f, "the original PSBT was rejected"),
+            Bip78Error::InvalidPsbt => write!(f, "invalid PSBT"),
+            Bip78Error::InvalidHttpMessage => write!(f, "invalid HTTP message"),
+            Bip78Error::ChangedVersion => write!(f, "the proposal changed the version"),
+            Bip78Error::ChangedLocktime => write!(f, "the proposal changed the locktime"),
+            Bip78Error::MissingSenderInput(index) => {
+                write!(f, "the proposal is missing the sender's input #{}", index)
+            }
+            Bip78Error::ModifiedSenderInput(index) => {
+                write!(f, "the proposal modified the sender's input #{}", index)
+            }
+            Bip78Error::SignedSenderInput(index) => {
+                write!(f, "the proposal kept the signature of input #{}", index)
+            }
+            Bip78Error::ReceiverInputNotFinalized(index) => {
+                write!(f, "the receiver's input #{} is not finalized", index)
+            }
+            Bip78Error::ReceiverInputSequence(index) => {
+                write!(f, "invalid sequence of the receiver's input #{}", index)
+            }
+            Bip78Error::MissingSenderOutput(index) => {
+                write!(f, "the proposal is missing the sender's output #{}", index)
+            }
+            Bip78Error::ModifiedSenderOutput(index) => {
+                write!(f, "the proposal modified the sender's output #{}", index)
+            }
+            Bip78Error::UnexpectedOutput(index) => write!(f, "unexpected output #{}", index),
+        }
+    }
+}
+
+impl std::error::Error for Bip78Error {}
+
+pub fn encode_psbt(psbt: &PartiallySignedTransaction) -> String {
+    base64::encode(serialize(psbt))
+}
+
+pub fn decode_psbt(data: &str) -> Result<PartiallySignedTransaction, Bip78Error> {
+    let bytes = base64::decode(data.trim()).map_err(|_| Bip78Error::InvalidPsbt)?;
+    deserialize(&bytes).map_err(|_| Bip78Error::InvalidPsbt)
+}
+
+/// Sign `base_transaction` and wrap it in the "original" PSBT
+pub fn create_original_psbt<B, S>(
+    base_transaction: &Transaction,
+    blockchain: &B,
+    signer: &S,
+) -> Result<PartiallySignedTransaction, Error>
+where
+    B: Blockchain,
+    Error: From<<B as TxSource>::Error>,
+    S: Signer,
+    Error: From<<S as Signer>::Error>,
+{
+    let mut unsigned_tx = base_transaction.clone();
+    for input in &mut unsigned_tx.input {
+        input.script_sig = Script::new();
+        input.witness.clear();
+    }
+
+    let mut signed_tx = unsigned_tx.clone();
+    let inputs_to_sign = (0..signed_tx.input.len()).collect::<Vec<_>>();
+    signer.sign(&mut signed_tx, &inputs_to_sign)?;
+
+    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(unsigned_tx)
+        .map_err(|_| Bip78Error::InvalidPsbt)?;
+    for (psbt_input, input) in psbt.inputs.iter_mut().zip(signed_tx.input) {
+        let prev_tx = blockchain.get_tx(&input.previous_output.txid)?;
+        let prev_out = prev_tx
+            .output
+            .get(input.previous_output.vout as usize)
+            .ok_or(ProtocolError::MissingData)?;
+
+        psbt_input.witness_utxo = Some(prev_out.clone());
+        psbt_input.final_script_witness = Some(input.witness);
+        if !input.script_sig.is_empty() {
+            psbt_input.final_script_sig = Some(input.script_sig);
+        }
+    }
+
+    Ok(psbt)
+}
+
+fn is_finalized(input: &Input) -> bool {
+    input.final_script_witness.is_some() || input.final_script_sig.is_some()
+}
+
+/// Make sure the receiver's proposal only adds inputs and increases the value of its own output
+pub fn check_proposal(
+    original: &PartiallySignedTransaction,
+    proposal: &PartiallySignedTransaction,
+    receiver_script: &Script,
+) -> Result<(), Bip78Error> {
+    let original_tx = &original.global.unsigned_tx;
+    let proposal_tx = &proposal.global.unsigned_tx;
+
+    if proposal.inputs.len() != proposal_tx.input.len()
+        || proposal.outputs.len() != proposal_tx.output.len()
+    {
+        return Err(Bip78Error::InvalidPsbt);
+    } else if proposal_tx.version != original_tx.version {
+        return Err(Bip78Error::ChangedVersion);
+    } else if proposal_tx.lock_time != original_tx.lock_time {
+        return Err(Bip78Error::ChangedLocktime);
+    }
+
+    let sender_sequence = original_tx.input[0].sequence;
+    for (index, original_input) in original_tx.input.iter().enumerate() {
+        let position = proposal_tx
+            .input
+            .iter()
+            .position(|input| input.previous_output == original_input.previous_output)
+            .ok_or(Bip78Error::MissingSenderInput(index))?;
+
+        if proposal_tx.input[position].sequence != original_input.sequence {
+            return Err(Bip78Error::ModifiedSenderInput(index));
+        }
+        let psbt_input = &proposal.inputs[position];
+        if is_finalized(psbt_input) || !psbt_input.partial_sigs.is_empty() {
+            return Err(Bip78Error::SignedSenderInput(index));
+        }
+    }
+    for (index, input) in proposal_tx.input.iter().enumerate() {
+        if original_tx
+            .input
+            .iter()
+            .any(|original_input| original_input.previous_output == input.previous_output)
+        {
+            continue;
+        }
+
+        if !is_finalized(&proposal.inputs[index]) {
+            return Err(Bip78Error::ReceiverInputNotFinalized(index));
+        } else if input.sequence != sender_sequence {
+            return Err(Bip78Error::ReceiverInputSequence(index));
+        }
+    }
+
+    for (index, original_output) in original_tx.output.iter().enumerate() {
+        let output = proposal_tx
+            .output
+            .iter()
+            .find(|output| output.script_pubkey == original_output.script_pubkey)
+            .ok_or(Bip78Error::MissingSenderOutput(index))?;
+
+        if &output.script_pubkey == receiver_script {
+            if output.value < original_output.value {
+                return Err(Bip78Error::ModifiedSenderOutput(index));
+            }
+        } else if output.value != original_output.value {
+            return Err(Bip78Error::ModifiedSenderOutput(index));
+        }
+    }
+    for (index, output) in proposal_tx.output.iter().enumerate() {
+        if !original_tx
+            .output
+            .iter()
+            .any(|original_output| original_output.script_pubkey == output.script_pubkey)
+        {
+            return Err(Bip78Error::UnexpectedOutput(index));
+        }
+    }
+
+    Ok(())
+}
+
+/// Sign the sender's inputs in a proposal that has already been checked
+pub fn finalize_proposal<S>(
+    original: &PartiallySignedTransaction,
+    proposal: PartiallySignedTransaction,
+    signer: &S,
+) -> Result<Transaction, Error>
+where
+    S: Signer,
+    Error: From<<S as Signer>::Error>,
+{
+    let PartiallySignedTransaction { global, inputs, .. } = proposal;
+    let mut transaction = global.unsigned_tx;
+
+    let mut inputs_to_sign = Vec::new();
+    for (index, (input, psbt_input)) in transaction.input.iter_mut().zip(inputs).enumerate() {
+        if original
+            .global
+            .unsigned_tx
+            .input
+            .iter()
+            .any(|original_input| original_input.previous_output == input.previous_output)
+        {
+            input.script_sig = Script::new();
+            input.witness.clear();
+
+            inputs_to_sign.push(index);
+        } else {
+            input.script_sig = psbt_input.final_script_sig.unwrap_or_else(Script::new);
+            input.witness = psbt_input.final_script_witness.unwrap_or_default();
+        }
+    }
+    signer.sign(&mut transaction, &inputs_to_sign)?;
+
+    Ok(transaction)
+}
+
+/// Validate the sender's original PSBT and build the proposal contributing `our_utxo`
+pub fn process_original<B, S>(
+    original: PartiallySignedTransaction,
+    our_utxo: OutPoint,
+    our_txout: &TxOut,
+    blockchain: &B,
+    signer: &S,
+) -> Result<PartiallySignedTransaction, Error>
+where
+    B: Blockchain,
+    Error: From<<B as TxSource>::Error>,
+    S: Signer,
+    Error: From<<S as Signer>::Error>,
+{
+    let original_tx = original.clone().extract_tx();
+    if original_tx.input.is_empty()
+        || original.inputs.len() != original_tx.input.len()
+        || !original.inputs.iter().all(is_finalized)
+    {
+        return Err(Bip78Error::OriginalPsbtRejected.into());
+    }
+
+    let secp: Secp256k1<All> = Secp256k1::gen_new();
+    let comp = SighashComponents::new(&original_tx);
+    let mut prev_outs = Vec::with_capacity(original_tx.input.len());
+    for (index, input) in original_tx.input.iter().enumerate() {
+        if input.previous_output == our_utxo {
+            return Err(Bip78Error::OriginalPsbtRejected.into());
+        }
+
+        let prev_tx = blockchain.get_tx(&input.previous_output.txid)?;
+        let prev_out = prev_tx
+            .output
+            .get(input.previous_output.vout as usize)
+            .ok_or(ProofTransactionError::MissingUTXO(index))?;
+
+        if !is_supported_input(&prev_out.script_pubkey) {
+            return Err(ProofTransactionError::InvalidInputType(index).into());
+        } else if !blockchain.is_unspent(&input.previous_output)? {
+            return Err(ProofTransactionError::InputIsSpent(index).into());
+        }
+
+        prev_outs.push(prev_out.clone());
+    }
+    for index in 0..original_tx.input.len() {
+        verify_input(&secp, &comp, &original_tx, index, &prev_outs, false)?;
+    }
+
+    let our_output_index = original_tx
+        .output
+        .iter()
+        .position(|output| {
+            output.script_pubkey == our_txout.script_pubkey && output.value >= our_txout.value
+        })
+        .ok_or(Bip78Error::OriginalPsbtRejected)?;
+
+    let our_prev_tx = blockchain.get_tx(&our_utxo.txid)?;
+    let our_prev_out = our_prev_tx
+        .output
+        .get(our_utxo.vout as usize)
+        .ok_or(ProtocolError::InvalidUtxo)?
+        .clone();
+
+    let mut transaction = original.global.unsigned_tx.clone();
+    transaction.output[our_output_index].value += our_prev_out.value;
+
+    let our_input_index = rand::thread_rng().gen_range(0, transaction.input.len() + 1);
+    transaction.input.insert(
+        our_input_index,
+        TxIn {
+            previous_output: our_utxo,
+            sequence: transaction.input[0].sequence,
+            ..Default::default()
+        },
+    );
+
+    let mut proposal = PartiallySignedTransaction::from_unsigned_tx(transaction.clone())
+        .map_err(|_| Bip78Error::InvalidPsbt)?;
+
+    // Taproot signatures commit to the sender's prevouts too
+    let all_prev_outs = crate::analysis::prev_outs(blockchain, &transaction)?;
+    signer.sign_with_prev_outs(&mut transaction, &[our_input_index], &all_prev_outs)?;
+    let our_input = transaction.input.remove(our_input_index);
+    proposal.inputs[our_input_index] = Input {
+        witness_utxo: Some(our_prev_out),
+        final_script_witness: Some(our_input.witness),
+        final_script_sig: if our_input.script_sig.is_empty() {
+            None
+        } else {
+            Some(our_input.script_sig)
+        },
+        ..Default::default()
+    };
+
+    Ok(proposal)
+}
+
+#[derive(Debug)]
+struct HttpMessage {
+    start_line: String,
+    body: Vec<u8>,
+}
+
+/// Read the next line into `line`, without buffering more than `MAX_LINE` bytes
+async fn read_http_line<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    line: &mut String,
+) -> Result<usize, Error> {
+    let read = reader.take(MAX_LINE as u64).read_line(line).await?;
+    if read == MAX_LINE && !line.ends_with('\n') {
+        return Err(Bip78Error::InvalidHttpMessage.into());
+    }
+
+    Ok(read)
+}
+
+async fn read_http_message<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+) -> Result<HttpMessage, Error> {
+    let mut start_line = String::new();
+    if read_http_line(reader, &mut start_line).await? == 0 {
+        return Err(Error::EOF);
+    }
+
+    // Count the lines, repeated names would only overwrite each other in the map
+    let mut headers = HashMap::new();
+    for lines in 0.. {
+        let mut line = String::new();
+        if read_http_line(reader, &mut line).await? == 0 {
+            return Err(Error::EOF);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        } else if lines >= MAX_HEADERS {
+            return Err(Bip78Error::InvalidHttpMessage.into());
+        }
+
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or_default().trim().to_lowercase();
+        let value = parts.next().unwrap_or_default().trim().to_string();
+        headers.insert(name, value);
+    }
+
+    let length = headers
+        .get("content-length")
+        .map(|length| length.parse::<usize>())
+        .transpose()
+        .map_err(|_| Bip78Error::InvalidHttpMessage)?
+        .unwrap_or(0);
+    if length > MAX_BODY_SIZE {
+        return Err(Bip78Error::InvalidHttpMessage.into());
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+
+    trace!(
+        "Received HTTP message: `{}` {:?}",
+        start_line.trim(),
+        headers
+    );
+
+    Ok(HttpMessage {
+        start_line: start_line.trim().to_string(),
+        body,
+    })
+}
+
+fn parse_query(target: &str) -> HashMap<String, String> {
+    target
+        .split_once('?')
+        .map(|(_, query)| query)
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            (
+                parts.next().unwrap_or_default().to_string(),
+                parts.next().unwrap_or_default().to_string(),
+            )
+        })
+        .collect()
+}
+
+/// POST the original PSBT to `path` and wait for the receiver's proposal
+pub async fn send_original<T>(
+    stream: &mut T,
+    host: &str,
+    path: &str,
+    original: &PartiallySignedTransaction,
+) -> Result<PartiallySignedTransaction, Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let body = encode_psbt(original);
+    let separator = if path.contains('?') { '&' } else { '?' };
+    let request = format!(
+        "POST {}{}v={}&disableoutputsubstitution=true HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        separator,
+        VERSION,
+        host,
+        body.len(),
+        body
+    );
+    debug!("Sending original PSBT to {}{}", host, path);
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut reader = BufReader::new(stream);
+    let response = read_http_message(&mut reader).await?;
+    let status = response
+        .start_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or(Bip78Error::InvalidHttpMessage)?;
+    let body = String::from_utf8(response.body).map_err(|_| Bip78Error::InvalidHttpMessage)?;
+
+    if status == 200 {
+        Ok(decode_psbt(&body)?)
+    } else {
+        let error_code = serde_json::from_str::<ErrorResponse>(&body)
+            .map(|response| response.error_code)
+            .ok()
+            .filter(Bip78Error::is_well_known)
+            .unwrap_or(Bip78Error::Unavailable);
+
+        Err(Error::PeerError(ProtocolError::Bip78(error_code)))
+    }
+}
+
+/// Read the original PSBT sent by a BIP78 sender
+pub async fn receive_original<T>(stream: &mut T) -> Result<PartiallySignedTransaction, Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut reader = BufReader::new(stream);
+    let request = read_http_message(&mut reader).await?;
+
+    let mut parts = request.start_line.split_whitespace();
+    if parts.next() != Some("POST") {
+        return Err(Bip78Error::InvalidHttpMessage.into());
+    }
+    let query = parse_query(parts.next().unwrap_or_default());
+    if query.get("v").map(String::as_str) != Some(&VERSION.to_string()) {
+        return Err(Bip78Error::VersionUnsupported.into());
+    }
+
+    let body = String::from_utf8(request.body).map_err(|_| Bip78Error::OriginalPsbtRejected)?;
+    decode_psbt(&body).map_err(|_| Bip78Error::OriginalPsbtRejected.into())
+}
+
+/// Reply to a BIP78 sender with either the proposal or an error
+pub async fn respond<T>(
+    stream: &mut T,
+    result: Result<&PartiallySignedTransaction, &Error>,
+) -> Result<(), Error>
+where
+    T: AsyncWrite + Unpin,
+{
+    let (status, content_type, body) = match result {
+        Ok(proposal) => ("200 OK", "text/plain", encode_psbt(proposal)),
+        Err(e) => {
+            let error_code = match e {
+                Error::Protocol(ProtocolError::Bip78(code)) if code.is_well_known() => code.clone(),
+                Error::Protocol(_) => Bip78Error::OriginalPsbtRejected,
+                _ => Bip78Error::Unavailable,
+            };
+            let status = match error_code {
+                Bip78Error::Unavailable => "503 Service Unavailable",
+                _ => "400 Bad Request",
+            };
+            let response = ErrorResponse {
+                error_code,
+                message: "The payjoin request has been rejected".into(),
+            };
+
+            (
+                status,
+                "application/json",
+                serde_json::to_string(&response)?,
+            )
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::secp256k1::{All, Secp256k1};
+    use bitcoin::*;
+
+    use super::*;
+    use crate::demo::*;
+
+    #[test]
+    fn test_payjoin() {
+        let secp: Secp256k1<All> = Secp256k1::gen_new();
+
+        let sender_sk =
+            PrivateKey::from_str("cVt4o7BGAig1UXywgGSmARhxMdzP5qvQsxKkSsc1XEkw3tDTQFpy").unwrap();
+        let sender_address = Address::p2wpkh(&sender_sk.public_key(&secp), Network::Regtest);
+        let sender_utxo = OutPoint {
+            txid: Txid::from_hex(
+                "c790622f0b33ff5b99ee10f8cb4bfb9271390ed7cfeb596209be75fb6d86e088",
+            )
+            .unwrap(),
+            vout: 0,
+        };
+        let mut sender_meta = HashMap::new();
+        sender_meta.insert(sender_utxo, (100_000_000, sender_address.script_pubkey()));
+        let sender_signer = SoftwareSigner::new(sender_sk, sender_meta);
+
+        let receiver_sk =
+            PrivateKey::from_str("KwDiBf89QgGbjEhKnhXJuH7LrciVrZi3qYjgd9M7rFU73sVHnoWn").unwrap();
+        let receiver_address = Address::p2wpkh(&receiver_sk.public_key(&secp), Network::Regtest);
+        let receiver_utxo = OutPoint {
+            txid: Txid::from_hex(
+                "17eb46f996ebfbc404080872e29352cc55dc3906458ceb279bc9eb768727c5e0",
+            )
+            .unwrap(),
+            vout: 0,
+        };
+        let mut receiver_meta = HashMap::new();
+        receiver_meta.insert(
+            receiver_utxo,
+            (200_000_000, receiver_address.script_pubkey()),
+        );
+        let receiver_signer = SoftwareSigner::new(receiver_sk, receiver_meta);
+
+        let receiver_txout = TxOut {
+            script_pubkey: receiver_address.script_pubkey(),
+            value: 3_000_000,
+        };
+        let base_transaction = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: sender_utxo,
+                sequence: 0xFFFF_FFFF,
+                ..Default::default()
+            }],
+            output: vec![
+                TxOut {
+                    script_pubkey: sender_address.script_pubkey(),
+                    value: 100_000_000 - 3_000_000 - 5000,
+                },
+                receiver_txout.clone(),
+            ],
+        };
+
+        let blockchain = ElectrumBlockchain::new();
+        let original =
+            create_original_psbt(&base_transaction, &blockchain, &sender_signer).unwrap();
+        let original = decode_psbt(&encode_psbt(&original)).unwrap();
+
+        let proposal = process_original(
+            original.clone(),
+            receiver_utxo,
+            &receiver_txout,
+            &blockchain,
+            &receiver_signer,
+        )
+        .unwrap();
+        check_proposal(&original, &proposal, &receiver_txout.script_pubkey).unwrap();
+
+        let transaction = finalize_proposal(&original, proposal, &sender_signer).unwrap();
+        assert_eq!(transaction.input.len(), 2);
+        assert!(transaction
+            .input
+            .iter()
+            .all(|input| input.witness.len() == 2));
+        assert!(transaction
+            .output
+            .iter()
+            .any(|output| output.value == 203_000_000));
+    }
+
+    #[tokio::test]
+    async fn test_read_http_message() {
+        let read = |raw: String| async move {
+            let mut reader = BufReader::new(raw.as_bytes());
+            read_http_message(&mut reader).await
+        };
+        let invalid = |result: Result<HttpMessage, crate::Error>| {
+            matches!(
+                result,
+                Err(crate::Error::Protocol(ProtocolError::Bip78(
+                    Bip78Error::InvalidHttpMessage
+                )))
+            )
+        };
+
+        let message = read("POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nok".into())
+            .await
+            .unwrap();
+        assert_eq!(message.start_line, "POST / HTTP/1.1");
+        assert_eq!(message.body, b"ok");
+
+        // A single endless header line
+        let long = format!("POST / HTTP/1.1\r\nX: {}\r\n\r\n", "x".repeat(MAX_LINE));
+        assert!(invalid(read(long).await));
+        // Repeating the same header only fills one entry of the map
+        let repeated = format!(
+            "POST / HTTP/1.1\r\n{}\r\n",
+            "X: x\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert!(invalid(read(repeated).await));
+        let allowed = format!("POST / HTTP/1.1\r\n{}\r\n", "X: x\r\n".repeat(MAX_HEADERS));
+        assert!(read(allowed).await.is_ok());
+    }
+}
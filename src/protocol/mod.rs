@@ -0,0 +1,11 @@
+//! State machines of the protocols
+//!
+//! `sender` and `receiver` implement the native VERS/PROOF/TXOUT/SIGS flow without doing any I/O,
+//! `client` and `server` run them over a transport. The other modules speak other payjoin dialects
+//! on top of the same `Blockchain` and `Signer` traits.
+
+#[cfg(feature = "bip78")]
+pub mod bip78;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod receiver;
+pub mod sender;
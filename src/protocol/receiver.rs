@@ -0,0 +1,1567 @@
+//! Receiver side of the protocol
+//!
+//! `ServerState` turns the requests of a sender into responses without any I/O of its own, apart
+//! from the calls to the `Blockchain`, `Signer` and `ReceiverWallet`. `Server` drives one for every
+//! connection it accepts.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+
+use tracing::{debug, debug_span, warn};
+
+use bitcoin::{Network, OutPoint, Transaction, TxIn, TxOut};
+
+use crate::analysis::{analyze, prev_outs, Metadata, UihPolicy};
+use crate::blockchain::{broadcast_with_retry, Blockchain, TxSource};
+use crate::coin_selection::UihRange;
+use crate::common::*;
+use crate::decoy::{select_decoys, CoveringDecoys, DecoyFilter, DecoySelector, DecoySource};
+use crate::disclosure::{Disclosure, Disclosures};
+use crate::events::{NoObserver, SessionEvent, SessionObserver};
+use crate::jsonrpc::MAX_MESSAGE_SIZE;
+use crate::multiparty::{check_cosign, Chain, Chained};
+use crate::replay::{check_replay, MemoryProofStore, ProofStore};
+use crate::session::{is_valid_token, new_token, MemoryStore, Session, SessionStore};
+use crate::signer::Signer;
+use crate::transport::ONION_PORT;
+use crate::wallet::ReceiverWallet;
+use crate::zeroconf::ZeroConfPolicy;
+use crate::{Capabilities, Version};
+use crate::{Error, ProtocolError, Request, Response};
+
+/// Receiver UTXOs currently committed to a session, shared between concurrent sessions
+///
+/// Sessions hold their locks through a `UtxoGuard`, and other wallet processes can take their own
+/// with `lock_for()`, like while they spend a UTXO themselves. With a timeout, the locks of the
+/// sessions are also released after that long, in case a session never releases them. The UTXOs
+/// spent by a final transaction stay locked.
+#[derive(Debug, Clone, Default)]
+pub struct UtxoLocks(Arc<Mutex<LockTable>>);
+
+/// Picks of the wallet that `UtxoLocks::lock_next()` tries to lock
+const LOCK_ATTEMPTS: usize = 8;
+
+#[derive(Debug, Default)]
+struct LockTable {
+    locks: HashMap<OutPoint, Lock>,
+    timeout: Option<Duration>,
+    next_id: u64,
+}
+
+#[derive(Debug)]
+struct Lock {
+    /// Id of the guard of the session, `None` for the locks of `lock_for()`
+    guard: Option<u64>,
+    /// `None` for the locks that never expire, like the ones of spent UTXOs
+    expires: Option<Instant>,
+}
+
+impl LockTable {
+    fn is_locked(&self, utxo: &OutPoint) -> bool {
+        match self.locks.get(utxo) {
+            Some(Lock {
+                expires: Some(expires),
+                ..
+            }) => *expires > Instant::now(),
+            Some(Lock { expires: None, .. }) => true,
+            None => false,
+        }
+    }
+}
+
+impl UtxoLocks {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Release the locks of the sessions after `timeout`
+    pub fn with_timeout(timeout: Duration) -> Self {
+        let locks = UtxoLocks::new();
+        locks.set_timeout(Some(timeout));
+
+        locks
+    }
+
+    /// Change the timeout of the locks taken from now on
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        self.0.lock().unwrap().timeout = timeout;
+    }
+
+    /// Lock `utxo`, unless another session is already using it
+    pub fn try_lock(&self, utxo: OutPoint) -> Option<UtxoGuard> {
+        let mut table = self.0.lock().unwrap();
+        if table.is_locked(&utxo) {
+            return None;
+        }
+
+        let id = table.next_id;
+        table.next_id += 1;
+        // A timeout too long to be represented never expires
+        let expires = table
+            .timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+        table.locks.insert(
+            utxo,
+            Lock {
+                guard: Some(id),
+                expires,
+            },
+        );
+
+        Some(UtxoGuard {
+            locks: self.clone(),
+            utxo,
+            id,
+            spent: false,
+        })
+    }
+
+    /// Lock the UTXO picked by `next`, like `ReceiverWallet::next_utxo()`. Another session may
+    /// lock the same pick first, then the wallet is asked again since it skips the locked UTXOs.
+    /// Returns the guard with the output spent by the UTXO, or `None` if it keeps losing that race
+    pub(crate) fn lock_next<E>(
+        &self,
+        mut next: impl FnMut() -> Result<(OutPoint, TxOut), E>,
+    ) -> Result<Option<(UtxoGuard, TxOut)>, E> {
+        for _ in 0..LOCK_ATTEMPTS {
+            let (utxo, prev_out) = next()?;
+            if let Some(guard) = self.try_lock(utxo) {
+                return Ok(Some((guard, prev_out)));
+            }
+            debug!("UTXO {} locked by another session, picking again", utxo);
+        }
+
+        Ok(None)
+    }
+
+    /// Keep the sessions from using `utxo` for `duration`, or until `unlock()`. Returns `false`
+    /// if it's already locked. A `duration` too long to be represented never expires
+    pub fn lock_for(&self, utxo: OutPoint, duration: Duration) -> bool {
+        let mut table = self.0.lock().unwrap();
+        if table.is_locked(&utxo) {
+            return false;
+        }
+
+        table.locks.insert(
+            utxo,
+            Lock {
+                guard: None,
+                expires: Instant::now().checked_add(duration),
+            },
+        );
+        true
+    }
+
+    /// Release a lock taken with `lock_for()`. Returns `false` if `utxo` wasn't locked that way
+    pub fn unlock(&self, utxo: &OutPoint) -> bool {
+        let mut table = self.0.lock().unwrap();
+        match table.locks.get(utxo) {
+            Some(Lock { guard: None, .. }) if table.is_locked(utxo) => {
+                table.locks.remove(utxo);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_locked(&self, utxo: &OutPoint) -> bool {
+        self.0.lock().unwrap().is_locked(utxo)
+    }
+
+    /// The UTXOs locked right now, with the time left on the locks that expire
+    pub fn locked(&self) -> Vec<(OutPoint, Option<Duration>)> {
+        let mut table = self.0.lock().unwrap();
+        let now = Instant::now();
+        table.locks.retain(|_, lock| match lock.expires {
+            Some(expires) => expires > now,
+            None => true,
+        });
+
+        table
+            .locks
+            .iter()
+            .map(|(utxo, lock)| (*utxo, lock.expires.map(|expires| expires - now)))
+            .collect()
+    }
+}
+
+/// One-time tokens handed out in the payment URIs, each one good for a single session
+#[derive(Debug, Clone, Default)]
+pub struct AccessTokens(Arc<Mutex<HashSet<String>>>);
+
+impl AccessTokens {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn issue(&self) -> String {
+        let token = new_token();
+        self.0.lock().unwrap().insert(token.clone());
+
+        token
+    }
+
+    /// Spend `token`, returning whether it was valid and unspent
+    pub fn redeem(&self, token: &str) -> bool {
+        self.0.lock().unwrap().remove(token)
+    }
+}
+
+/// A payment a server waits for next to its default one, picked by the sender with the `invoice`
+/// of its VERSION request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invoice {
+    /// Output the sender has to pay
+    pub txout: TxOut,
+    /// UTXO contributed to the payment, with the output it spends. Picked from the wallet when
+    /// missing
+    pub utxo: Option<(OutPoint, TxOut)>,
+}
+
+/// Invoices waiting for their payment, shared by all the sessions of a server. Each one is
+/// forgotten once it has been paid
+#[derive(Debug, Clone, Default)]
+pub struct Invoices(Arc<Mutex<HashMap<String, Invoice>>>);
+
+impl Invoices {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Register `invoice`, returning the identifier to put in its payment URI
+    pub fn insert(&self, invoice: Invoice) -> String {
+        let id = new_token();
+        self.0.lock().unwrap().insert(id.clone(), invoice);
+
+        id
+    }
+
+    pub fn get(&self, id: &str) -> Option<Invoice> {
+        self.0.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn remove(&self, id: &str) -> Option<Invoice> {
+        self.0.lock().unwrap().remove(id)
+    }
+
+    /// Identifiers of the invoices still waiting for their payment
+    pub fn ids(&self) -> Vec<String> {
+        self.0.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+/// Releases the lock on a UTXO when dropped, unless it has been spent
+#[derive(Debug)]
+pub struct UtxoGuard {
+    locks: UtxoLocks,
+    utxo: OutPoint,
+    id: u64,
+    spent: bool,
+}
+
+impl UtxoGuard {
+    pub fn utxo(&self) -> OutPoint {
+        self.utxo
+    }
+
+    /// Whether the lock has expired, letting other sessions use the UTXO again
+    pub fn is_expired(&self) -> bool {
+        let table = self.locks.0.lock().unwrap();
+        table.locks.get(&self.utxo).and_then(|lock| lock.guard) != Some(self.id)
+            || !table.is_locked(&self.utxo)
+    }
+
+    /// Keep the UTXO locked forever, since it has been spent by a final transaction
+    pub fn spent(&mut self) {
+        self.spent = true;
+
+        self.locks.0.lock().unwrap().locks.insert(
+            self.utxo,
+            Lock {
+                guard: Some(self.id),
+                expires: None,
+            },
+        );
+    }
+}
+
+impl Drop for UtxoGuard {
+    fn drop(&mut self) {
+        let mut table = self.locks.0.lock().unwrap();
+        // Unless it expired and has been locked again since
+        if !self.spent && table.locks.get(&self.utxo).and_then(|lock| lock.guard) == Some(self.id) {
+            table.locks.remove(&self.utxo);
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub(crate) enum StateVariant {
+    WaitingVersion,
+    ClientVersion {
+        version: String,
+    },
+    ClientProof {
+        version: String,
+        proof: ProofTransaction<Validated>,
+        utxos: Vec<OutPoint>,
+        our_utxos: Vec<OutPoint>,
+        our_utxos_position: usize,
+        change: Option<TxOut>,
+        /// Session with the second receiver, when its output is our change
+        chained: Option<Chained>,
+    },
+    ClientWitnesses {
+        version: String,
+        final_transaction: Transaction,
+    },
+}
+
+impl StateVariant {
+    fn name(&self) -> &'static str {
+        match self {
+            StateVariant::WaitingVersion => "waiting_version",
+            StateVariant::ClientVersion { .. } => "client_version",
+            StateVariant::ClientProof { .. } => "client_proof",
+            StateVariant::ClientWitnesses { .. } => "client_witnesses",
+        }
+    }
+}
+
+/// State machine of the receiver. Feed every request of the sender to `step()` and send back what
+/// it returns, until `result()` is there
+#[derive(Debug)]
+pub struct ServerState<B, S, W> {
+    our_txout: TxOut,
+    config: ServerConfig,
+    decoys: Arc<dyn DecoySelector>,
+    decoy_source: Option<Arc<dyn DecoySource>>,
+    chain: Option<Chain>,
+    disclosures: Disclosures,
+    tokens: AccessTokens,
+    invoices: Invoices,
+    // Picked by the sender in its VERSION, with the UTXO to contribute to it, if any
+    invoice: Option<String>,
+    contribution: Option<(OutPoint, TxOut)>,
+    endpoint: Option<String>,
+    expires_at: Option<SystemTime>,
+    // Challenge of the session, with the `BOUND_PROOF` capability
+    nonce: Option<String>,
+    // Ours until the client sends its version, then the negotiated ones
+    capabilities: Capabilities,
+
+    pub(crate) state: StateVariant,
+    session: Option<String>,
+    sessions: Arc<dyn SessionStore>,
+    proofs: Arc<dyn ProofStore>,
+
+    locks: UtxoLocks,
+    our_utxo_guards: Vec<UtxoGuard>,
+    observer: Arc<dyn SessionObserver>,
+
+    blockchain: Arc<B>,
+    signer: Arc<S>,
+    wallet: Arc<W>,
+}
+
+impl<B, S, W> ServerState<B, S, W>
+where
+    B: Blockchain + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<B as TxSource>::Error>,
+    S: Signer + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<S as Signer>::Error>,
+    W: ReceiverWallet + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<W as ReceiverWallet>::Error>,
+{
+    /// Get paid to `our_txout`, contributing UTXOs from `wallet`
+    pub fn new(
+        our_txout: TxOut,
+        blockchain: Arc<B>,
+        signer: Arc<S>,
+        wallet: Arc<W>,
+    ) -> ServerState<B, S, W> {
+        ServerState {
+            our_txout,
+            config: ServerConfig::default(),
+            decoys: Arc::new(DecoyFilter::default()),
+            decoy_source: None,
+            chain: None,
+            disclosures: Disclosures::new(),
+            tokens: AccessTokens::new(),
+            invoices: Invoices::new(),
+            invoice: None,
+            contribution: None,
+            endpoint: None,
+            expires_at: None,
+            nonce: None,
+            capabilities: Capabilities::supported(),
+            state: StateVariant::WaitingVersion,
+            session: None,
+            sessions: Arc::new(MemoryStore::new()),
+            proofs: Arc::new(MemoryProofStore::new()),
+            locks: UtxoLocks::new(),
+            our_utxo_guards: Vec::new(),
+            observer: Arc::new(NoObserver),
+            blockchain,
+            signer,
+            wallet,
+        }
+    }
+
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_decoys(mut self, decoys: Arc<dyn DecoySelector>) -> Self {
+        self.decoys = decoys;
+        self
+    }
+
+    /// Draw the decoys from `decoy_source` rather than from the `Blockchain`
+    pub fn with_decoy_source(mut self, decoy_source: Option<Arc<dyn DecoySource>>) -> Self {
+        self.decoy_source = decoy_source;
+        self
+    }
+
+    /// Chain a second receiver into the payjoins of the senders that support it, see `multiparty`
+    pub fn with_chain(mut self, chain: Option<Chain>) -> Self {
+        self.chain = chain;
+        self
+    }
+
+    /// Store of the sessions, to be resumed by a later `ServerState`
+    pub fn with_sessions(mut self, sessions: Arc<dyn SessionStore>) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    /// Proofs seen by the earlier sessions, to reject the ones replaying their inputs
+    pub fn with_proofs(mut self, proofs: Arc<dyn ProofStore>) -> Self {
+        self.proofs = proofs;
+        self
+    }
+
+    /// Locks on our UTXOs, shared with the other sessions running at the same time
+    pub fn with_locks(mut self, locks: UtxoLocks) -> Self {
+        self.locks = locks;
+        self
+    }
+
+    /// Tokens accepted when `ServerConfig::require_token` is set
+    pub fn with_tokens(mut self, tokens: AccessTokens) -> Self {
+        self.tokens = tokens;
+        self
+    }
+
+    /// Payments the sender can pick instead of `our_txout`
+    pub fn with_invoices(mut self, invoices: Invoices) -> Self {
+        self.invoices = invoices;
+        self
+    }
+
+    /// Our endpoint, as in the payment URIs. Proofs are only bound to us when it's known
+    pub fn with_endpoint(mut self, endpoint: Option<String>) -> Self {
+        self.endpoint = endpoint;
+        self
+    }
+
+    /// Time after which new sessions are rejected, as in the `exp` of the payment URIs
+    pub fn with_expiry(mut self, expires_at: Option<SystemTime>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    pub fn with_observer(mut self, observer: Arc<dyn SessionObserver>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    pub(crate) fn with_disclosures(mut self, disclosures: Disclosures) -> Self {
+        self.disclosures = disclosures;
+        self
+    }
+
+    /// Handle a request of the sender, returning the response to send back, if any
+    pub fn step(&mut self, message: Request) -> Result<Option<Response>, Error> {
+        let _span = debug_span!("transition", from = self.state.name()).entered();
+
+        let response = self.transition(message)?;
+        if let Some(response) = &response {
+            self.save_session(response)?;
+        }
+
+        Ok(response)
+    }
+
+    /// Final transaction, once it has been signed by both sides and broadcast
+    pub fn result(&self) -> Option<Transaction> {
+        match &self.state {
+            StateVariant::ClientWitnesses {
+                final_transaction, ..
+            } => Some(final_transaction.clone()),
+            _ => None,
+        }
+    }
+
+    /// Save the session after a transition that produced `response`, or forget it once the payment
+    /// is complete
+    fn save_session(&self, response: &Response) -> Result<(), Error> {
+        let token = match &self.session {
+            Some(token) => token,
+            None => return Ok(()),
+        };
+
+        let session = match &self.state {
+            StateVariant::WaitingVersion => return Ok(()),
+            // The session with the second receiver can't be restored
+            StateVariant::ClientProof {
+                chained: Some(_), ..
+            } => return Ok(()),
+            StateVariant::ClientWitnesses { .. } => return self.sessions.remove(token),
+            StateVariant::ClientVersion { version } => Session {
+                version: version.clone(),
+                capabilities: self.capabilities,
+                our_txout: self.our_txout.clone(),
+                invoice: self.invoice.clone(),
+                nonce: self.nonce.clone(),
+                proof: None,
+                utxos: vec![],
+                our_utxos: vec![],
+                our_utxos_position: 0,
+                change: None,
+                last_response: response.clone(),
+            },
+            StateVariant::ClientProof {
+                version,
+                proof,
+                utxos,
+                our_utxos,
+                our_utxos_position,
+                change,
+                ..
+            } => Session {
+                version: version.clone(),
+                capabilities: self.capabilities,
+                our_txout: self.our_txout.clone(),
+                invoice: self.invoice.clone(),
+                nonce: self.nonce.clone(),
+                proof: Some((**proof).clone()),
+                utxos: utxos.clone(),
+                our_utxos: our_utxos.clone(),
+                our_utxos_position: *our_utxos_position,
+                change: change.clone(),
+                last_response: response.clone(),
+            },
+        };
+
+        self.sessions.save(token, &session)
+    }
+
+    /// Output of the proofs of this session, once the capabilities and the invoice are known
+    fn proof_output(&self) -> TxOut {
+        proof_scheme(self.capabilities).output(&ProofContext {
+            endpoint: self.endpoint.clone(),
+            invoice: self.invoice.clone(),
+            nonce: self.nonce.clone(),
+        })
+    }
+
+    /// Restore the session saved as `token`, returning the response to repeat
+    fn resume(&mut self, token: String) -> Result<Response, Error> {
+        if !is_valid_token(&token) {
+            return Err(ProtocolError::InvalidSession.into());
+        }
+        let session = self
+            .sessions
+            .load(&token)?
+            .ok_or(ProtocolError::InvalidSession)?;
+        debug!("Resuming session {}", token);
+
+        self.capabilities = session.capabilities;
+        self.our_txout = session.our_txout;
+        if let Some(id) = &session.invoice {
+            // Paid by another session in the meantime
+            let invoice = self.invoices.get(id).ok_or(ProtocolError::UnknownInvoice)?;
+            self.contribution = invoice.utxo;
+        }
+        self.invoice = session.invoice;
+        self.nonce = session.nonce;
+        self.state = match session.proof {
+            None => StateVariant::ClientVersion {
+                version: session.version,
+            },
+            Some(proof) => {
+                // The inputs could have been spent in the meantime
+                let proof = ProofTransaction::<Validated>::try_from((
+                    proof,
+                    &*self.blockchain,
+                    &self.proof_output(),
+                ))?;
+                for utxo in &session.our_utxos {
+                    let guard = self.locks.try_lock(*utxo).ok_or(ProtocolError::Busy)?;
+                    self.our_utxo_guards.push(guard);
+                }
+
+                StateVariant::ClientProof {
+                    version: session.version,
+                    proof,
+                    utxos: session.utxos,
+                    our_utxos: session.our_utxos,
+                    our_utxos_position: session.our_utxos_position,
+                    change: session.change,
+                    chained: None,
+                }
+            }
+        };
+        self.session = Some(token);
+
+        Ok(session.last_response)
+    }
+
+    fn transition(&mut self, message: Request) -> Result<Option<Response>, Error> {
+        match &self.state {
+            StateVariant::WaitingVersion => match message {
+                Request::Version {
+                    version,
+                    capabilities,
+                    token,
+                    invoice,
+                } => {
+                    if self
+                        .expires_at
+                        .is_some_and(|expires_at| expires_at <= SystemTime::now())
+                    {
+                        return Err(ProtocolError::InvoiceExpired.into());
+                    }
+                    if self.config.require_token
+                        && !token.is_some_and(|token| self.tokens.redeem(&token))
+                    {
+                        return Err(ProtocolError::InvalidToken.into());
+                    }
+                    if let Some(id) = invoice {
+                        let invoice = self
+                            .invoices
+                            .get(&id)
+                            .ok_or(ProtocolError::UnknownInvoice)?;
+                        debug!("Receiving invoice {}", id);
+
+                        self.our_txout = invoice.txout;
+                        self.contribution = invoice.utxo;
+                        self.invoice = Some(id);
+                    }
+
+                    let version = version
+                        .parse::<Version>()
+                        .ok()
+                        .and_then(|theirs| Version::CURRENT.negotiate(&theirs))
+                        .ok_or(ProtocolError::InvalidVersion(version))?
+                        .to_string();
+                    self.capabilities = self.capabilities & capabilities;
+                    if self.endpoint.is_none() {
+                        self.capabilities.remove(Capabilities::BOUND_PROOF);
+                    }
+                    debug!(
+                        "Negotiated version {} with capabilities {:?}",
+                        version, self.capabilities
+                    );
+
+                    self.state = StateVariant::ClientVersion {
+                        version: version.clone(),
+                    };
+                    self.session = Some(new_token());
+                    if self.capabilities.contains(Capabilities::BOUND_PROOF) {
+                        self.nonce = Some(new_token());
+                    }
+                    self.observer.on_event(&SessionEvent::VersionNegotiated {
+                        version: version.clone(),
+                    });
+                    self.observer.on_event(&SessionEvent::WaitingForPeer);
+
+                    Ok(Some(Response::Version {
+                        version,
+                        capabilities: self.capabilities,
+                        session: self.session.clone(),
+                        nonce: self.nonce.clone(),
+                    }))
+                }
+                Request::Resume { session } => Ok(Some(self.resume(session)?)),
+                _ => Err(ProtocolError::Expected("VERSION".into()).into()),
+            },
+            StateVariant::ClientVersion { version } => match message {
+                Request::Proof { transaction } => {
+                    let version = version.to_string();
+                    self.observer.on_event(&SessionEvent::VerifyingProof);
+                    self.config.proof_policy.check(
+                        &transaction,
+                        self.our_txout.value,
+                        &*self.blockchain,
+                    )?;
+                    let proof = ProofTransaction::<Validated>::try_from((
+                        transaction,
+                        &*self.blockchain,
+                        &self.proof_output(),
+                    ))?;
+                    if let Some(policy) = &self.config.zero_conf_policy {
+                        let assessment = policy.assess(&proof, &*self.blockchain)?;
+                        let accepted = assessment.accepted;
+                        self.observer
+                            .on_event(&SessionEvent::ZeroConfAssessed { assessment });
+                        if !accepted {
+                            return Err(ProofTransactionError::RiskyUnconfirmedInputs.into());
+                        }
+                    }
+                    if let Some(window) = self.config.replay_window {
+                        check_replay(&*self.proofs, &proof, window)?;
+                    }
+                    let (utxos, our_utxos, our_utxos_position, change, chained) =
+                        self.disclose(&proof)?;
+                    // Every candidate set has as many UTXOs as ours
+                    let receiver_inputs = our_utxos.len();
+
+                    self.state = StateVariant::ClientProof {
+                        version,
+                        proof,
+                        utxos: utxos.clone(),
+                        our_utxos,
+                        our_utxos_position,
+                        change: change.clone(),
+                        chained,
+                    };
+                    self.observer.on_event(&SessionEvent::WaitingForPeer);
+
+                    Ok(Some(Response::Utxos {
+                        utxos,
+                        receiver_inputs,
+                        receiver_change: change,
+                    }))
+                }
+                _ => Err(ProtocolError::Expected("PROOF".into()).into()),
+            },
+            StateVariant::ClientProof {
+                version,
+                proof,
+                utxos,
+                our_utxos,
+                our_utxos_position,
+                change,
+                ..
+            } => match message {
+                Request::Witnesses { ref witnesses, .. }
+                    if !self.capabilities.contains(Capabilities::PSBT) =>
+                {
+                    let sets = utxos.len() / our_utxos.len();
+                    if witnesses.len() > sets
+                        || witnesses.iter().any(|set| set.len() > proof.input.len())
+                    {
+                        return Err(ProtocolError::TooManyWitnesses.into());
+                    }
+
+                    let final_transaction =
+                        self.unsigned_final_transaction(proof, our_utxos, change, &message)?;
+                    let witnesses = SignedInputSet::from_wrappers(
+                        proof.input.iter().map(|txin| txin.previous_output),
+                        witnesses
+                            .get(*our_utxos_position)
+                            .ok_or(ProtocolError::MissingData)?,
+                    )?;
+                    let final_transaction = FinalTransaction::<SenderSigned>::try_from((
+                        final_transaction,
+                        &witnesses,
+                    ))?;
+                    check_proof_inputs(proof, our_utxos, &final_transaction)?;
+                    self.check_uih(our_utxos, change.as_ref(), &final_transaction)?;
+
+                    let version = version.to_string();
+                    let chained = self.take_chained();
+                    self.complete(version, final_transaction, chained)
+                }
+                Request::Psbts { ref psbts, .. }
+                    if self.capabilities.contains(Capabilities::PSBT) =>
+                {
+                    if psbts.len() > utxos.len() / our_utxos.len() {
+                        return Err(ProtocolError::TooManyWitnesses.into());
+                    }
+
+                    let final_transaction =
+                        self.unsigned_final_transaction(proof, our_utxos, change, &message)?;
+                    let final_transaction = FinalTransaction::<SenderSigned>::try_from((
+                        final_transaction,
+                        psbts
+                            .get(*our_utxos_position)
+                            .ok_or(ProtocolError::MissingData)?,
+                    ))?;
+                    check_proof_inputs(proof, our_utxos, &final_transaction)?;
+                    self.check_uih(our_utxos, change.as_ref(), &final_transaction)?;
+
+                    let version = version.to_string();
+                    let chained = self.take_chained();
+                    self.complete(version, final_transaction, chained)
+                }
+                Request::Cosign { transaction }
+                    if self.capabilities.contains(Capabilities::MULTIPARTY) =>
+                {
+                    let final_transaction = check_cosign(
+                        transaction,
+                        proof,
+                        our_utxos,
+                        &self.our_txout,
+                        &*self.blockchain,
+                    )?;
+
+                    self.complete(version.to_string(), final_transaction, None)
+                }
+                Request::Psbts { .. } => Err(ProtocolError::Unsupported {
+                    feature: Capabilities::PSBT,
+                }
+                .into()),
+                _ if self.capabilities.contains(Capabilities::PSBT) => {
+                    Err(ProtocolError::Expected("PSBTS".into()).into())
+                }
+                _ => Err(ProtocolError::Expected("WITNESSES".into()).into()),
+            },
+            _ => Err(ProtocolError::UnexpectedMessage.into()),
+        }
+    }
+
+    /// UTXOs to offer to `proof`, with the position of ours among them, our change and the
+    /// session of the second receiver whose UTXOs are added to every set, if any. A proof spending
+    /// the same inputs as an earlier one is offered the same set, as long as our UTXOs are still
+    /// unspent
+    #[allow(clippy::type_complexity)]
+    fn disclose(
+        &mut self,
+        proof: &Transaction,
+    ) -> Result<
+        (
+            Vec<OutPoint>,
+            Vec<OutPoint>,
+            usize,
+            Option<TxOut>,
+            Option<Chained>,
+        ),
+        Error,
+    > {
+        let inputs = proof
+            .input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect::<Vec<_>>();
+        let count = self.receiver_inputs();
+
+        if let Some(disclosure) = self.disclosures.get(&inputs, count) {
+            let our_utxos = disclosure
+                .our_utxos
+                .iter()
+                .map(|(utxo, _)| *utxo)
+                .collect::<Vec<_>>();
+            if self.relock_utxos(&our_utxos)? {
+                debug!("Offering the same UTXOs to a repeated proof");
+                let chained = self.chain(&disclosure.our_utxos);
+                let change = self.change(&disclosure.our_utxos, chained.as_ref())?;
+                let (utxos, our_utxos) = match &chained {
+                    Some(chained) => (
+                        chained.extend(&disclosure.utxos, our_utxos.len()),
+                        chained.extend(&our_utxos, our_utxos.len()),
+                    ),
+                    None => (disclosure.utxos, our_utxos),
+                };
+                return Ok((
+                    utxos,
+                    our_utxos,
+                    disclosure.our_utxos_position,
+                    change,
+                    chained,
+                ));
+            }
+
+            // Ours have been spent in the meantime, so a fresh set doesn't reveal them
+            self.disclosures.forget(&inputs, count);
+        }
+
+        let sender_inputs = prev_outs(&*self.blockchain, proof)?
+            .iter()
+            .map(|prev_out| prev_out.value)
+            .collect::<Vec<_>>();
+        let ours = self.lock_utxos(&UihRange::new(&sender_inputs, self.our_txout.value))?;
+        if !self
+            .disclosures
+            .try_acquire(self.config.max_disclosures_per_hour)
+        {
+            warn!("Too many UTXO sets disclosed in the last hour");
+            return Err(ProtocolError::RateLimited.into());
+        }
+        let our_utxos = ours.iter().map(|(utxo, _)| *utxo).collect::<Vec<_>>();
+
+        let receiver_inputs = our_utxos.len();
+        let chained = self.chain(&ours);
+        let change = self.change(&ours, chained.as_ref())?;
+        // Decoys smaller than their share of our change would give ours away. The UTXOs of the
+        // second receiver are in every set, they pay for their own part
+        let covered = change.as_ref().map_or(0, |change| {
+            change.value - chained.as_ref().map_or(0, Chained::value)
+        });
+        let decoys = CoveringDecoys {
+            inner: &*self.decoys,
+            min_value: covered.div_ceil(receiver_inputs as u64),
+        };
+        let mut rng = rand::thread_rng();
+        let decoy_sets = if self.capabilities.contains(Capabilities::MULTIPARTY) {
+            // The server chaining us has to know our UTXOs to offer them to its sender
+            0
+        } else {
+            rng.gen_range(
+                self.config.min_decoys,
+                self.config.max_decoys.max(self.config.min_decoys) + 1,
+            )
+        };
+        let mut utxos = select_decoys(
+            &*self.blockchain,
+            self.decoy_source.as_deref(),
+            &decoys,
+            &ours,
+            decoy_sets * receiver_inputs,
+        )?;
+        // Without enough distinct decoys offer fewer sets, never the same UTXO twice
+        let decoy_sets = if utxos.len() < decoy_sets * receiver_inputs {
+            let found = utxos.len() / receiver_inputs;
+            if found == 0 {
+                warn!("Not enough decoys for a single set");
+                return Err(ProtocolError::Busy.into());
+            }
+            utxos.truncate(found * receiver_inputs);
+            found
+        } else {
+            decoy_sets
+        };
+        let our_utxos_position = rng.gen_range(0, decoy_sets + 1);
+        let offset = our_utxos_position * receiver_inputs;
+        utxos.splice(offset..offset, our_utxos.iter().cloned());
+
+        self.disclosures.insert(
+            &inputs,
+            count,
+            Disclosure::new(utxos.clone(), ours, our_utxos_position),
+        );
+
+        match chained {
+            Some(chained) => Ok((
+                chained.extend(&utxos, receiver_inputs),
+                chained.extend(&our_utxos, receiver_inputs),
+                our_utxos_position,
+                change,
+                Some(chained),
+            )),
+            None => Ok((utxos, our_utxos, our_utxos_position, change, None)),
+        }
+    }
+
+    /// Start a session with the second receiver, if any and if the sender supports its UTXOs and
+    /// its output. The payjoin goes on without it if it fails
+    fn chain(&self, ours: &[(OutPoint, TxOut)]) -> Option<Chained> {
+        let chain = self.chain.as_ref()?;
+        if !self
+            .capabilities
+            .contains(Capabilities::MULTI_UTXO | Capabilities::RECEIVER_CHANGE)
+        {
+            return None;
+        }
+
+        match chain.start(ours, &*self.blockchain, &*self.signer) {
+            Ok(chained) => {
+                debug!(
+                    "Chained {} UTXOs of the second receiver",
+                    chained.utxos.len()
+                );
+                Some(chained)
+            }
+            Err(e) => {
+                warn!("Unable to chain the second receiver: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Session with the second receiver, once the sender has signed
+    fn take_chained(&mut self) -> Option<Chained> {
+        match &mut self.state {
+            StateVariant::ClientProof { chained, .. } => chained.take(),
+            _ => None,
+        }
+    }
+
+    /// Output taking back what `ours` are worth past `max_contribution`, if the sender supports
+    /// it and the wallet has a change script. With a second receiver, its output takes the place
+    /// of our change
+    fn change(
+        &self,
+        ours: &[(OutPoint, TxOut)],
+        chained: Option<&Chained>,
+    ) -> Result<Option<TxOut>, Error> {
+        if let Some(chained) = chained {
+            return Ok(Some(chained.txout.clone()));
+        }
+        let max_contribution = match self.config.max_contribution {
+            Some(max) if self.capabilities.contains(Capabilities::RECEIVER_CHANGE) => max,
+            _ => return Ok(None),
+        };
+        let value = ours
+            .iter()
+            .map(|(_, txout)| txout.value)
+            .sum::<u64>()
+            .saturating_sub(max_contribution);
+        if value < DUST_LIMIT {
+            return Ok(None);
+        }
+
+        Ok(self.wallet.change_script()?.map(|script_pubkey| TxOut {
+            value,
+            script_pubkey,
+        }))
+    }
+
+    /// Lock again the UTXOs offered to an earlier session. Returns `false` if any of them has
+    /// been spent
+    fn relock_utxos(&mut self, our_utxos: &[OutPoint]) -> Result<bool, Error> {
+        for utxo in our_utxos {
+            if !self.blockchain.is_unspent(utxo)? {
+                self.our_utxo_guards.clear();
+                return Ok(false);
+            }
+
+            let guard = self.locks.try_lock(*utxo).ok_or(ProtocolError::Busy)?;
+            self.our_utxo_guards.push(guard);
+        }
+
+        Ok(true)
+    }
+
+    /// Apply the `uih_policy` to the final transaction, before we sign it
+    fn check_uih(
+        &self,
+        our_utxos: &[OutPoint],
+        change: Option<&TxOut>,
+        transaction: &Transaction,
+    ) -> Result<(), Error> {
+        let mut receiver_scripts = vec![self.our_txout.script_pubkey.clone()];
+        receiver_scripts.extend(change.map(|change| change.script_pubkey.clone()));
+        let metadata = Metadata {
+            receiver_inputs: our_utxos.to_vec(),
+            receiver_scripts,
+        };
+        let prev_outs = prev_outs(&*self.blockchain, transaction)?;
+        let analysis = analyze(transaction, &prev_outs, &metadata)?;
+
+        self.config.uih_policy.check(&analysis.privacy)
+    }
+
+    /// Number of UTXOs we'd like to contribute
+    fn receiver_inputs(&self) -> usize {
+        if self.capabilities.contains(Capabilities::MULTI_UTXO) {
+            self.config.receiver_inputs.max(1)
+        } else {
+            1
+        }
+    }
+
+    /// Pick and lock the UTXOs to contribute, starting from the one of the invoice. Without the
+    /// `MULTI_UTXO` capability, or if the wallet runs out of UTXOs, fewer than `receiver_inputs`
+    /// are used, but always at least one. The wallet is asked for UTXOs in `range` first
+    fn lock_utxos(&mut self, range: &UihRange) -> Result<Vec<(OutPoint, TxOut)>, Error> {
+        let count = self.receiver_inputs();
+
+        let mut our_utxos = Vec::with_capacity(count);
+        if let Some((our_utxo, our_prev_out)) = self.contribution.clone() {
+            let guard = self.locks.try_lock(our_utxo).ok_or(ProtocolError::Busy)?;
+            self.our_utxo_guards.push(guard);
+            our_utxos.push((our_utxo, our_prev_out));
+        }
+        while our_utxos.len() < count {
+            let picked = self.locks.lock_next(|| {
+                self.wallet
+                    .next_utxo_within(self.our_txout.value, range, &self.locks)
+            });
+            let (guard, our_prev_out) = match picked {
+                Ok(Some(locked)) => locked,
+                Ok(None) | Err(_) if !our_utxos.is_empty() => break,
+                Ok(None) => return Err(ProtocolError::Busy.into()),
+                Err(e) => return Err(e.into()),
+            };
+            let our_utxo = guard.utxo();
+            self.our_utxo_guards.push(guard);
+            debug!(
+                "Contributing UTXO {} ({} sat)",
+                our_utxo, our_prev_out.value
+            );
+
+            our_utxos.push((our_utxo, our_prev_out));
+        }
+
+        Ok(our_utxos)
+    }
+
+    fn unsigned_final_transaction(
+        &self,
+        proof: &ProofTransaction<Validated>,
+        our_utxos: &[OutPoint],
+        change: &Option<TxOut>,
+        request: &Request,
+    ) -> Result<FinalTransaction<Unsigned>, Error> {
+        let (
+            fees,
+            amount,
+            change_script,
+            change_position,
+            payments,
+            receiver_input_positions,
+            receiver_output_position,
+            receiver_change_position,
+        ) = match request {
+            Request::Witnesses {
+                fees,
+                amount,
+                change_script,
+                change_position,
+                payments,
+                receiver_input_positions,
+                receiver_output_position,
+                receiver_change_position,
+                ..
+            }
+            | Request::Psbts {
+                fees,
+                amount,
+                change_script,
+                change_position,
+                payments,
+                receiver_input_positions,
+                receiver_output_position,
+                receiver_change_position,
+                ..
+            } => (
+                *fees,
+                *amount,
+                change_script,
+                *change_position,
+                payments,
+                receiver_input_positions,
+                *receiver_output_position,
+                *receiver_change_position,
+            ),
+            _ => return Err(ProtocolError::UnexpectedMessage.into()),
+        };
+        if !payments.is_empty() && !self.capabilities.contains(Capabilities::BATCH) {
+            return Err(ProtocolError::Unsupported {
+                feature: Capabilities::BATCH,
+            }
+            .into());
+        }
+        // Without other payments the change used to go right before or after our output
+        let change_position =
+            change_position.unwrap_or(if receiver_output_position == 0 { 1 } else { 0 });
+
+        let mut receiver_txout = self.our_txout.clone();
+        if self.config.donations {
+            // Our amount is only the minimum, the sender decides how much it pays
+            let amount = amount.ok_or(ProtocolError::MissingData)?;
+            if amount < self.our_txout.value {
+                return Err(ProtocolError::AmountTooLow(self.our_txout.value).into());
+            }
+            receiver_txout.value = amount;
+        }
+
+        // The sender has to place our change, it's not up to it to drop it
+        let receiver_change = match (change, receiver_change_position) {
+            (Some(change), Some(position)) => Some((position, change.clone())),
+            (Some(_), None) => return Err(ProtocolError::MissingData.into()),
+            (None, _) => None,
+        };
+
+        let receiver_txins = our_utxos
+            .iter()
+            .map(|utxo| TxIn {
+                sequence: receiver_sequence(proof),
+                previous_output: *utxo,
+                ..Default::default()
+            })
+            .collect();
+        let final_transaction_meta = FinalTransactionMeta {
+            tx: proof.clone(),
+            fees,
+            sender_script: change_script.clone(),
+            sender_output_index: change_position,
+            sender_txouts: payments.clone(),
+            receiver_txins,
+            receiver_input_indexes: receiver_input_positions.clone(),
+            receiver_txout,
+            receiver_output_index: receiver_output_position,
+            receiver_change,
+        };
+
+        FinalTransaction::<Unsigned>::try_from((final_transaction_meta, &*self.blockchain))
+    }
+
+    fn complete(
+        &mut self,
+        version: String,
+        final_transaction: FinalTransaction<SenderSigned>,
+        chained: Option<Chained>,
+    ) -> Result<Option<Response>, Error> {
+        // An invalid witness would make the transaction unbroadcastable after we've signed and
+        // revealed our input
+        final_transaction.verify_sender_inputs(&*self.blockchain)?;
+
+        self.observer.on_event(&SessionEvent::Signing);
+        let final_transaction = match chained {
+            Some(chained) => chained
+                .cosign(final_transaction, &*self.blockchain, &*self.signer)
+                .map_err(|e| {
+                    warn!("The second receiver didn't sign: {:?}", e);
+                    ProtocolError::ChainFailed
+                })?,
+            None => FinalTransaction::<Signed>::try_from((
+                final_transaction,
+                &*self.signer,
+                &*self.blockchain,
+            ))?,
+        };
+
+        // A server chaining us into its payjoin broadcasts the transaction itself
+        let sender_broadcasts = !self.config.dry_run
+            && (self.capabilities.contains(Capabilities::MULTIPARTY)
+                || (self.config.broadcast_delay == BroadcastDelay::Sender
+                    && self.capabilities.contains(Capabilities::SENDER_BROADCAST)));
+        // A delayed broadcast is left to the server, once the session is over
+        let will_broadcast_by = match self.config.broadcast_delay.max() {
+            _ if sender_broadcasts => None,
+            _ if self.config.dry_run => {
+                debug!("Dry run, not broadcasting {}", final_transaction.txid());
+                None
+            }
+            Some(max) => Some(match SystemTime::now().checked_add(max) {
+                Some(time) => time
+                    .duration_since(UNIX_EPOCH)
+                    .map(|time| time.as_secs() + u64::from(time.subsec_nanos() > 0))
+                    .unwrap_or(0),
+                // Rejected by `Server::setup`, only sessions of a server never set up get here
+                None => u64::MAX,
+            }),
+            None => {
+                self.observer.on_event(&SessionEvent::Broadcasting);
+                // The sender has already revealed its signatures, so a failure here doesn't end
+                // the session: the server keeps rebroadcasting the transaction once the session is
+                // over
+                if let Err(e) = broadcast_with_retry(&*self.blockchain, &final_transaction) {
+                    warn!(
+                        "Unable to broadcast {}, will retry later: {:?}",
+                        final_transaction.txid(),
+                        Error::from(e)
+                    );
+                }
+                None
+            }
+        };
+        // In a dry run our UTXOs and the invoice are still there for the next session
+        if !self.config.dry_run {
+            for guard in &mut self.our_utxo_guards {
+                guard.spent();
+            }
+            if let Some(id) = &self.invoice {
+                self.invoices.remove(id);
+            }
+        }
+
+        self.state = StateVariant::ClientWitnesses {
+            version,
+            final_transaction: final_transaction.clone().into_inner(),
+        };
+        self.observer.on_event(&SessionEvent::Completed {
+            txid: final_transaction.txid(),
+        });
+
+        Ok(Some(Response::Txid {
+            txid: final_transaction.txid(),
+            transaction: final_transaction.into_inner(),
+            will_broadcast_by,
+            sender_broadcasts,
+        }))
+    }
+}
+
+/// Make sure the final transaction spends exactly the inputs of the proof, plus `our_utxos`
+fn check_proof_inputs(
+    proof: &Transaction,
+    our_utxos: &[OutPoint],
+    final_transaction: &Transaction,
+) -> Result<(), Error> {
+    let mut expected = proof
+        .input
+        .iter()
+        .map(|txin| txin.previous_output)
+        .chain(our_utxos.iter().cloned())
+        .collect::<Vec<_>>();
+    let mut spent = final_transaction
+        .input
+        .iter()
+        .map(|txin| txin.previous_output)
+        .collect::<Vec<_>>();
+    expected.sort();
+    spent.sort();
+
+    if expected != spent {
+        return Err(ProtocolError::ProofMismatch.into());
+    }
+
+    Ok(())
+}
+
+/// When the receiver broadcasts the final transaction of a session
+///
+/// Broadcasting it as soon as the negotiation is over links the transaction to the time of the
+/// session, which the sender and any observer of the connection know. Delaying it blurs that link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BroadcastDelay {
+    /// Right away, before answering the sender
+    #[default]
+    Immediate,
+    /// After a random delay of up to the given duration
+    Random(Duration),
+    /// Once the next block is found, or after the given duration at most. Backends that don't
+    /// know the height of the chain wait for the whole duration
+    NextBlock(Duration),
+    /// Left to the sender, if it has the `SENDER_BROADCAST` capability, so that the transaction
+    /// never comes from the receiver's node. The server only steps in if it hasn't propagated a
+    /// while later. Senders without the capability get an immediate broadcast
+    Sender,
+}
+
+impl BroadcastDelay {
+    /// Longest delay of the receiver's broadcast, or `None` if it doesn't wait
+    pub fn max(&self) -> Option<Duration> {
+        match self {
+            BroadcastDelay::Immediate | BroadcastDelay::Sender => None,
+            BroadcastDelay::Random(max) | BroadcastDelay::NextBlock(max) => Some(*max),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Keep accepting new sessions after a successful payment
+    pub keep_running: bool,
+    /// Stop after this many successful payments
+    pub max_payments: Option<u32>,
+    /// Number of UTXOs contributed to every payment, when the sender supports more than one
+    pub receiver_inputs: usize,
+    /// Bounds of the number of decoy candidates offered next to ours, picked at random for every
+    /// session
+    pub min_decoys: usize,
+    pub max_decoys: usize,
+    /// Fresh sets of UTXOs offered per hour, across all the sessions. Repeated proofs spending the
+    /// same inputs don't count, since they are offered the same set
+    pub max_disclosures_per_hour: Option<usize>,
+    /// Connections accepted per hour from the same IP address. Connections through an onion
+    /// service all come from the local Tor daemon, so they share the same limit
+    pub max_connections_per_peer: Option<usize>,
+    /// Public port of the onion service, or 0 for a random one. Ignored by `Direct`
+    pub onion_port: u16,
+    /// Reject the senders that don't present a token issued by `Server::issue_token()`. Resumed
+    /// sessions don't need one
+    pub require_token: bool,
+    /// Accept any amount of at least the expected one, like a donation endpoint. The payment URIs
+    /// have no amount, and the senders tell how much they pay along with their signatures
+    pub donations: bool,
+    /// When the final transactions are broadcast. Senders are told the time by which it happens,
+    /// so that they can broadcast them themselves if it doesn't. Delayed broadcasts run in a task
+    /// of their own, so the runtime has to outlive them
+    pub broadcast_delay: BroadcastDelay,
+    /// How long the payment URIs are valid, from the first call to `Server::setup()`. Past
+    /// their `exp`, new sessions are rejected and the main loops stop. `Server::setup()` fails
+    /// with `Error::InvalidConfig` if the expiry is past what the clock can represent, and so for
+    /// the longest `broadcast_delay`
+    pub invoice_expiry: Option<Duration>,
+    /// Most of the value of our UTXOs folded into the payment output, in satoshis. The rest goes
+    /// back to `ReceiverWallet::change_script()` in an output of its own, with the senders that
+    /// support it. `None` to always contribute whole UTXOs
+    pub max_contribution: Option<u64>,
+    /// Requirements on the inputs of the senders' proofs
+    pub proof_policy: ProofPolicy,
+    /// Scrutiny of the proofs spending unconfirmed outputs, `None` to accept them as long as
+    /// `proof_policy` does
+    pub zero_conf_policy: Option<ZeroConfPolicy>,
+    /// Time allowed for the handshake of the transport and for every message of the sender
+    pub message_timeout: Duration,
+    /// Time allowed for every negotiation, from the first message of the sender
+    pub session_timeout: Duration,
+    /// Longest line accepted from the senders, in bytes
+    pub max_message_size: usize,
+    /// Time allowed for the transport to expose the server and, in `wait_ready()`, for the
+    /// senders to be able to reach it
+    pub bootstrap_timeout: Duration,
+    /// Network of the addresses in our payment URIs
+    pub network: Network,
+    /// Time after which the UTXOs locked by a session are released, even if it's still running.
+    /// Only a safety net for the sessions that never end, it should be well above
+    /// `session_timeout`
+    pub utxo_lock_timeout: Option<Duration>,
+    /// How long the inputs of a proof can't be used by a different proof, `None` to accept them
+    /// right away
+    pub replay_window: Option<Duration>,
+    /// What to do when the final transaction would be given away by the unnecessary input
+    /// heuristics. Our UTXOs are picked to avoid them whenever the wallet has a suitable one
+    pub uih_policy: UihPolicy,
+    /// Go through the whole negotiation without broadcasting anything or spending our UTXOs, to
+    /// test wallets against a staging server. See `Server::dry_runs()` for the final transactions
+    pub dry_run: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            keep_running: false,
+            max_payments: None,
+            receiver_inputs: 1,
+            min_decoys: 80,
+            max_decoys: 120,
+            max_disclosures_per_hour: Some(60),
+            max_connections_per_peer: None,
+            onion_port: ONION_PORT,
+            require_token: false,
+            donations: false,
+            broadcast_delay: BroadcastDelay::Immediate,
+            invoice_expiry: None,
+            max_contribution: None,
+            proof_policy: ProofPolicy::default(),
+            zero_conf_policy: None,
+            message_timeout: Duration::from_secs(10),
+            max_message_size: MAX_MESSAGE_SIZE,
+            session_timeout: Duration::from_secs(60),
+            bootstrap_timeout: Duration::from_secs(180),
+            network: Network::Bitcoin,
+            utxo_lock_timeout: Some(Duration::from_secs(600)),
+            replay_window: Some(Duration::from_secs(24 * 60 * 60)),
+            uih_policy: UihPolicy::default(),
+            dry_run: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_utxo_locks() {
+        let locks = UtxoLocks::new();
+        let utxo = OutPoint::default();
+
+        let guard = locks.try_lock(utxo).unwrap();
+        assert!(locks.try_lock(utxo).is_none());
+        drop(guard);
+        assert!(!locks.is_locked(&utxo));
+
+        let mut guard = locks.try_lock(utxo).unwrap();
+        guard.spent();
+        drop(guard);
+        assert!(locks.try_lock(utxo).is_none());
+        assert_eq!(locks.locked(), vec![(utxo, None)]);
+        assert!(!locks.unlock(&utxo));
+    }
+
+    #[test]
+    fn test_utxo_lock_timeout() {
+        let locks = UtxoLocks::with_timeout(Duration::from_millis(50));
+        let utxo = OutPoint::default();
+
+        let stale = locks.try_lock(utxo).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!locks.is_locked(&utxo));
+        assert!(stale.is_expired());
+        let guard = locks.try_lock(utxo).unwrap();
+        // The expired guard doesn't release the new lock
+        drop(stale);
+        assert!(locks.is_locked(&utxo));
+        drop(guard);
+        assert!(locks.locked().is_empty());
+
+        // Taken by another wallet process
+        assert!(locks.lock_for(utxo, Duration::from_secs(60)));
+        assert!(locks.try_lock(utxo).is_none());
+        assert!(!locks.lock_for(utxo, Duration::from_secs(60)));
+        assert!(locks.unlock(&utxo));
+        assert!(locks.try_lock(utxo).is_some());
+
+        assert!(locks.lock_for(utxo, Duration::from_millis(50)));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(locks.try_lock(utxo).is_some());
+
+        // Too long to expire
+        let utxo = OutPoint::new(Default::default(), 1);
+        assert!(locks.lock_for(utxo, Duration::from_secs(u64::MAX)));
+        assert!(locks.locked().contains(&(utxo, None)));
+        assert!(locks.unlock(&utxo));
+        locks.set_timeout(Some(Duration::from_secs(u64::MAX)));
+        assert!(locks.try_lock(utxo).is_some());
+    }
+
+    #[test]
+    fn test_lock_next() {
+        let locks = UtxoLocks::new();
+        let utxo = |vout| (OutPoint::new(Default::default(), vout), TxOut::default());
+        let _taken = locks.try_lock(utxo(0).0).unwrap();
+
+        // Another session locked the first pick in the meantime
+        let mut picks = vec![utxo(1), utxo(0)];
+        let (guard, _) = locks
+            .lock_next(|| Ok::<_, ()>(picks.pop().unwrap()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(guard.utxo(), utxo(1).0);
+        assert!(!guard.is_expired());
+
+        // A wallet ignoring the locks doesn't keep us looping
+        assert!(matches!(locks.lock_next(|| Ok::<_, ()>(utxo(0))), Ok(None)));
+        assert!(matches!(locks.lock_next(|| Err(())), Err(())));
+    }
+
+    #[test]
+    fn test_check_proof_inputs() {
+        let outpoint = |vout| OutPoint {
+            txid: Default::default(),
+            vout,
+        };
+        let tx = |outpoints: &[OutPoint]| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: outpoints
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![],
+        };
+
+        let proof = tx(&[outpoint(0), outpoint(1)]);
+        let ours = [outpoint(2)];
+        let ok = tx(&[outpoint(2), outpoint(0), outpoint(1)]);
+        assert!(check_proof_inputs(&proof, &ours, &ok).is_ok());
+
+        for inputs in &[
+            vec![outpoint(0), outpoint(2)],
+            vec![outpoint(0), outpoint(3), outpoint(2)],
+            vec![outpoint(0), outpoint(1), outpoint(2), outpoint(3)],
+        ] {
+            assert!(matches!(
+                check_proof_inputs(&proof, &ours, &tx(inputs)),
+                Err(Error::Protocol(ProtocolError::ProofMismatch))
+            ));
+        }
+    }
+}
@@ -0,0 +1,1076 @@
+//! Sender side of the protocol
+//!
+//! `ClientState` only turns the responses of the receiver into the next requests, without any I/O
+//! of its own. `Client` drives it over a `JsonRpc` connection, but anything that can carry
+//! `Request`s and `Response`s can do the same.
+
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::{debug, debug_span, trace, warn};
+
+use rand::seq::index::sample;
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::bip143::SighashComponents;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{Network, OutPoint, Script, Transaction, TxIn, TxOut, Txid};
+
+use crate::analysis::{analyze, prev_outs, Metadata, UihPolicy};
+use crate::blockchain::{
+    broadcast_with_retry, is_propagated, Blockchain, CachedBlockchain, TxSource,
+};
+use crate::common::*;
+use crate::events::{NoObserver, SessionEvent, SessionObserver};
+use crate::fees::FeeRate;
+use crate::jsonrpc::MAX_MESSAGE_SIZE;
+use crate::signer::Signer;
+use crate::{Capabilities, Version};
+use crate::{Error, ProtocolError, Request, Response, WitnessWrapper};
+
+/// Weight of the witness of each receiver input, which is unknown to the sender. Assumes P2WPKH,
+/// the largest single-key input
+const RECEIVER_WITNESS_WEIGHT: usize = 1 + 1 + 72 + 1 + 33;
+
+#[allow(dead_code)]
+#[derive(Debug)]
+enum StateVariant {
+    WaitingVersion,
+    Resuming {
+        token: String,
+    },
+    ServerVersion {
+        version: String,
+    },
+    ServerUtxos {
+        version: String,
+        utxos: Vec<OutPoint>,
+        receiver_inputs: usize,
+        receiver_input_indexes: Vec<usize>,
+        receiver_change: Option<TxOut>,
+        /// Unsigned final transaction for every candidate set of receiver UTXOs
+        candidates: Vec<Transaction>,
+        proof: ProofTransaction<Created>,
+    },
+    ServerTxid {
+        version: String,
+        result: PaymentResult,
+    },
+}
+
+impl StateVariant {
+    fn name(&self) -> &'static str {
+        match self {
+            StateVariant::WaitingVersion => "waiting_version",
+            StateVariant::Resuming { .. } => "resuming",
+            StateVariant::ServerVersion { .. } => "server_version",
+            StateVariant::ServerUtxos { .. } => "server_utxos",
+            StateVariant::ServerTxid { .. } => "server_txid",
+        }
+    }
+}
+
+/// State machine of the sender. Start with the request returned by `start()`, then feed every
+/// response of the receiver to `step()` and send back what it returns, until `result()` is there
+#[derive(Debug)]
+pub struct ClientState<B, S> {
+    base_transaction: Transaction,
+    receiver_output_index: usize,
+    // Ours until the server replies, then the negotiated ones
+    capabilities: Capabilities,
+    config: ClientConfig,
+    token: Option<String>,
+    invoice: Option<String>,
+    endpoint: Option<String>,
+    // Challenge of the receiver, with the `BOUND_PROOF` capability
+    nonce: Option<String>,
+    lock_time: u32,
+
+    state: StateVariant,
+    session: Arc<Mutex<Option<ClientSession>>>,
+    observer: Arc<dyn SessionObserver>,
+
+    blockchain: Arc<B>,
+    signer: Arc<S>,
+}
+
+/// Session opened by the server, kept to resume the negotiation if the connection drops
+#[derive(Debug, Clone)]
+pub(crate) struct ClientSession {
+    token: String,
+    version: String,
+    capabilities: Capabilities,
+    // The proof must be the same when resuming
+    lock_time: u32,
+    nonce: Option<String>,
+}
+
+impl<B, S> ClientState<B, S>
+where
+    B: Blockchain + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<B as TxSource>::Error>,
+    S: Signer + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<S as Signer>::Error>,
+{
+    /// Pay the receiver with the output `receiver_output_index` of `base_transaction`
+    pub fn new(
+        base_transaction: Transaction,
+        receiver_output_index: usize,
+        blockchain: Arc<B>,
+        signer: Arc<S>,
+    ) -> ClientState<B, S> {
+        ClientState {
+            lock_time: base_transaction.lock_time,
+            base_transaction,
+            receiver_output_index,
+            capabilities: Capabilities::TAPROOT
+                | Capabilities::MULTI_UTXO
+                | Capabilities::BATCH
+                | Capabilities::SENDER_BROADCAST
+                | Capabilities::BOUND_PROOF
+                | Capabilities::RECEIVER_CHANGE,
+            config: ClientConfig::default(),
+            token: None,
+            invoice: None,
+            endpoint: None,
+            nonce: None,
+            state: StateVariant::WaitingVersion,
+            session: Arc::new(Mutex::new(None)),
+            observer: Arc::new(NoObserver),
+            blockchain,
+            signer,
+        }
+    }
+
+    pub fn with_config(mut self, config: ClientConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Capabilities offered to the receiver
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Access token presented to the receiver in the VERSION request
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Invoice picked in the VERSION request, for receivers waiting for more than one payment
+    pub fn with_invoice(mut self, invoice: Option<String>) -> Self {
+        self.invoice = invoice;
+        self
+    }
+
+    /// Endpoint of the receiver, as in its payment URI. The proof is only bound to the receiver
+    /// when it's known
+    pub fn with_endpoint(mut self, endpoint: Option<String>) -> Self {
+        self.endpoint = endpoint;
+        self
+    }
+
+    pub fn with_observer(mut self, observer: Arc<dyn SessionObserver>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Keep the session opened by the receiver in `session`, shared across connections. With
+    /// `resume` the negotiation picks up from the session saved there, if any
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn with_session(
+        mut self,
+        session: Arc<Mutex<Option<ClientSession>>>,
+        resume: bool,
+    ) -> Self {
+        if let Some(saved) = &*session.lock().unwrap() {
+            if resume {
+                self.state = StateVariant::Resuming {
+                    token: saved.token.clone(),
+                };
+            }
+        }
+        self.session = session;
+        self
+    }
+
+    /// First request to send to the receiver
+    pub fn start(&mut self) -> Request {
+        self.observer.on_event(&SessionEvent::Connected);
+        if let StateVariant::Resuming { token } = &self.state {
+            return Request::Resume {
+                session: token.clone(),
+            };
+        }
+
+        // Without the endpoint, the proof can't be bound to the receiver
+        if self.endpoint.is_none() {
+            self.capabilities.remove(Capabilities::BOUND_PROOF);
+        }
+
+        Request::Version {
+            version: Version::CURRENT.to_string(),
+            capabilities: self.capabilities,
+            token: self.token.clone(),
+            invoice: self.invoice.clone(),
+        }
+    }
+
+    /// Handle a response of the receiver, returning the next request to send, if any
+    pub fn step(&mut self, message: Response) -> Result<Option<Request>, Error> {
+        let _span = debug_span!("transition", from = self.state.name()).entered();
+
+        self.transition(message)
+    }
+
+    /// Handle a recoverable error of the receiver, returning the request to retry with when we can
+    /// do without the feature it doesn't support
+    pub fn recover(&mut self, error: &ProtocolError) -> Result<Option<Request>, Error> {
+        let _span = debug_span!("recover", from = self.state.name()).entered();
+
+        match (error, &self.state) {
+            // Sign the same candidates again, and send raw witnesses
+            (
+                ProtocolError::Unsupported { feature },
+                StateVariant::ServerUtxos {
+                    version,
+                    utxos,
+                    receiver_inputs,
+                    receiver_change,
+                    ..
+                },
+            ) if *feature == Capabilities::PSBT
+                && self.capabilities.contains(Capabilities::PSBT) =>
+            {
+                self.capabilities.remove(Capabilities::PSBT);
+                if let Some(session) = &mut *self.session.lock().unwrap() {
+                    session.capabilities = self.capabilities;
+                }
+
+                let utxos = Response::Utxos {
+                    utxos: utxos.clone(),
+                    receiver_inputs: *receiver_inputs,
+                    receiver_change: receiver_change.clone(),
+                };
+                self.state = StateVariant::ServerVersion {
+                    version: version.clone(),
+                };
+                self.transition(utxos)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Outcome of the payment, once the receiver has returned a valid final transaction
+    pub fn result(&self) -> Option<PaymentResult> {
+        match &self.state {
+            StateVariant::ServerTxid { result, .. } => Some(result.clone()),
+            _ => None,
+        }
+    }
+
+    /// Index of the change output in the base transaction. It can only be left out with two
+    /// outputs, where it's the one not paying the receiver
+    fn change_output_index(&self) -> Result<usize, Error> {
+        let outputs = self.base_transaction.output.len();
+
+        match self.config.change_output_index {
+            Some(index) if index < outputs && index != self.receiver_output_index => Ok(index),
+            None if outputs == 2 => Ok(if self.receiver_output_index == 0 {
+                1
+            } else {
+                0
+            }),
+            _ => Err(ProtocolError::MissingData.into()),
+        }
+    }
+
+    /// Apply the `uih_policy` to the final transaction returned by the receiver
+    fn check_uih(
+        &self,
+        transaction: &Transaction,
+        receiver_input_indexes: &[usize],
+        receiver_change: Option<&TxOut>,
+    ) -> Result<(), Error> {
+        let mut receiver_scripts = vec![self.base_transaction.output[self.receiver_output_index]
+            .script_pubkey
+            .clone()];
+        receiver_scripts.extend(receiver_change.map(|change| change.script_pubkey.clone()));
+        let metadata = Metadata {
+            receiver_inputs: receiver_input_indexes
+                .iter()
+                .filter_map(|index| transaction.input.get(*index))
+                .map(|input| input.previous_output)
+                .collect(),
+            receiver_scripts,
+        };
+        let prev_outs = prev_outs(&*self.blockchain, transaction)?;
+        let analysis = analyze(transaction, &prev_outs, &metadata)?;
+
+        self.config.uih_policy.check(&analysis.privacy)
+    }
+
+    /// Outputs of the base transaction paying someone other than the receiver and us
+    fn payments(&self) -> Result<Vec<TxOut>, Error> {
+        let change_output_index = self.change_output_index()?;
+
+        Ok(self
+            .base_transaction
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| {
+                *index != change_output_index && *index != self.receiver_output_index
+            })
+            .map(|(_, txout)| txout.clone())
+            .collect())
+    }
+
+    /// Locktime of the final transaction. With anti-fee-sniping it's the current height, or
+    /// sometimes an older one like Bitcoin Core does, unless the base transaction already has one
+    fn choose_lock_time(&self) -> Result<u32, Error> {
+        if !self.config.anti_fee_sniping || self.base_transaction.lock_time != 0 {
+            return Ok(self.base_transaction.lock_time);
+        }
+
+        let height = match self.blockchain.get_height()? {
+            Some(height) => height,
+            None => {
+                warn!("Unknown height of the chain, the locktime is left at 0");
+                return Ok(0);
+            }
+        };
+        let mut rng = rand::thread_rng();
+        if rng.gen_ratio(1, 10) {
+            Ok(height.saturating_sub(rng.gen_range(0, MAX_LOCKTIME_AGE)))
+        } else {
+            Ok(height)
+        }
+    }
+
+    /// Proof of the inputs of the base transaction, which signal RBF if configured to. Signatures
+    /// are deterministic, so it's the same every time
+    fn proof_transaction(&self) -> Result<ProofTransaction<Created>, Error> {
+        let mut base_transaction = self.base_transaction.clone();
+        base_transaction.lock_time = self.lock_time;
+        for input in &mut base_transaction.input {
+            if self.config.rbf {
+                input.sequence = SEQUENCE_RBF;
+            } else if self.lock_time != 0 && input.sequence == SEQUENCE_FINAL {
+                input.sequence = SEQUENCE_LOCKTIME;
+            }
+        }
+
+        let proof_output = proof_scheme(self.capabilities).output(&ProofContext {
+            endpoint: self.endpoint.clone(),
+            invoice: self.invoice.clone(),
+            nonce: self.nonce.clone(),
+        });
+
+        if self.config.anyonecanpay_proof
+            && self.capabilities.contains(Capabilities::ANYONECANPAY_PROOF)
+        {
+            ProofTransaction::<Created>::anyonecanpay(
+                base_transaction,
+                &*self.signer,
+                &proof_output,
+            )
+        } else {
+            ProofTransaction::<Created>::try_from((base_transaction, &*self.signer, &proof_output))
+        }
+    }
+
+    /// Fees for the final transaction at our fee rate. The candidate final transactions only
+    /// differ in the receiver's prevouts, so the first one is signed to measure the size of all
+    fn fees(
+        &self,
+        blockchain: &CachedBlockchain<&B>,
+        meta: &FinalTransactionMeta<Created>,
+        utxos: &[OutPoint],
+    ) -> Result<u64, Error> {
+        let meta = with_receiver_utxos(meta, utxos);
+
+        let final_transaction = FinalTransaction::<Unsigned>::try_from((meta, blockchain))?;
+        let final_transaction = FinalTransaction::<SenderSigned>::try_from((
+            final_transaction,
+            &*self.signer,
+            blockchain,
+        ))?;
+
+        Ok(self
+            .config
+            .fee_rate
+            .fee_wu(final_transaction.get_weight() + utxos.len() * RECEIVER_WITNESS_WEIGHT))
+    }
+
+    fn transition(&mut self, message: Response) -> Result<Option<Request>, Error> {
+        match &self.state {
+            StateVariant::WaitingVersion => match message {
+                Response::Version {
+                    version,
+                    capabilities,
+                    session,
+                    nonce,
+                } => {
+                    // The server must pick a version we can speak
+                    match version.parse::<Version>() {
+                        Ok(theirs) if Version::CURRENT.negotiate(&theirs) == Some(theirs) => {}
+                        _ => return Err(ProtocolError::InvalidVersion(version).into()),
+                    }
+                    self.capabilities = self.capabilities & capabilities;
+                    debug!(
+                        "Negotiated version {} with capabilities {:?}",
+                        version, self.capabilities
+                    );
+                    if !self.payments()?.is_empty()
+                        && !self.capabilities.contains(Capabilities::BATCH)
+                    {
+                        return Err(ProtocolError::MissingCapability(Capabilities::BATCH).into());
+                    }
+                    if self.capabilities.contains(Capabilities::BOUND_PROOF) && nonce.is_none() {
+                        return Err(ProtocolError::MissingData.into());
+                    }
+                    self.nonce = nonce;
+                    self.lock_time = self.choose_lock_time()?;
+                    *self.session.lock().unwrap() = session.map(|token| ClientSession {
+                        token,
+                        version: version.clone(),
+                        capabilities: self.capabilities,
+                        lock_time: self.lock_time,
+                        nonce: self.nonce.clone(),
+                    });
+                    self.observer.on_event(&SessionEvent::VersionNegotiated {
+                        version: version.clone(),
+                    });
+                    self.state = StateVariant::ServerVersion { version };
+
+                    let transaction = self.proof_transaction()?.into_inner();
+                    self.observer.on_event(&SessionEvent::WaitingForPeer);
+
+                    Ok(Some(Request::Proof { transaction }))
+                }
+                _ => Err(ProtocolError::Expected("VERSION".into()).into()),
+            },
+            // The server repeats its last response, pick up from there
+            StateVariant::Resuming { .. } => {
+                let session = self
+                    .session
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .ok_or(ProtocolError::InvalidSession)?;
+                debug!("Resumed session {}", session.token);
+
+                match message {
+                    Response::Version { .. } => {
+                        self.state = StateVariant::WaitingVersion;
+                    }
+                    Response::Utxos { .. } => {
+                        self.capabilities = session.capabilities;
+                        self.lock_time = session.lock_time;
+                        self.nonce = session.nonce;
+                        self.state = StateVariant::ServerVersion {
+                            version: session.version,
+                        };
+                    }
+                    _ => return Err(ProtocolError::UnexpectedMessage.into()),
+                }
+
+                self.transition(message)
+            }
+            StateVariant::ServerVersion { version } => match message {
+                Response::Utxos {
+                    utxos,
+                    receiver_inputs,
+                    receiver_change,
+                } => {
+                    let tx = &self.base_transaction;
+
+                    if receiver_inputs == 0
+                        || utxos.len() % receiver_inputs != 0
+                        || (receiver_inputs > 1
+                            && !self.capabilities.contains(Capabilities::MULTI_UTXO))
+                    {
+                        return Err(ProtocolError::InvalidUtxo.into());
+                    }
+                    if receiver_change.is_some()
+                        && !self.capabilities.contains(Capabilities::RECEIVER_CHANGE)
+                    {
+                        return Err(ProtocolError::MissingCapability(
+                            Capabilities::RECEIVER_CHANGE,
+                        )
+                        .into());
+                    }
+
+                    let change_output_index = self.change_output_index()?;
+                    let change_script = tx.output[change_output_index].script_pubkey.clone();
+                    let mut payments = self.payments()?;
+
+                    let proof_transaction = self.proof_transaction()?;
+                    let receiver_txins = vec![
+                        TxIn {
+                            sequence: receiver_sequence(&proof_transaction),
+                            ..Default::default()
+                        };
+                        receiver_inputs
+                    ];
+                    let receiver_txout = tx.output[self.receiver_output_index].clone();
+                    let amount = receiver_txout.value;
+
+                    // The receiver's change goes after the other outputs, unless they're shuffled
+                    let outputs = payments.len() + 2 + receiver_change.is_some() as usize;
+                    let (
+                        receiver_input_indexes,
+                        change_output_index,
+                        receiver_output_index,
+                        receiver_change_index,
+                    ) = match self.config.ordering {
+                        TxOrdering::Shuffle => {
+                            let mut rng = rand::thread_rng();
+                            payments.shuffle(&mut rng);
+
+                            let mut receiver_input_indexes =
+                                sample(&mut rng, tx.input.len() + receiver_inputs, receiver_inputs)
+                                    .into_vec();
+                            receiver_input_indexes.sort();
+                            let output_indexes =
+                                sample(&mut rng, outputs, 2 + receiver_change.is_some() as usize);
+
+                            (
+                                receiver_input_indexes,
+                                output_indexes.index(0),
+                                output_indexes.index(1),
+                                output_indexes.iter().nth(2).unwrap_or(outputs - 1),
+                            )
+                        }
+                        TxOrdering::Untouched => (
+                            (tx.input.len()..tx.input.len() + receiver_inputs).collect(),
+                            change_output_index,
+                            self.receiver_output_index,
+                            outputs - 1,
+                        ),
+                    };
+                    let receiver_change_position =
+                        receiver_change.as_ref().map(|_| receiver_change_index);
+
+                    let mut final_transaction_meta = FinalTransactionMeta {
+                        tx: proof_transaction.clone(),
+                        fees: 0,
+                        sender_script: change_script.clone(),
+                        sender_output_index: change_output_index,
+                        sender_txouts: payments.clone(),
+                        receiver_txins,
+                        receiver_input_indexes: receiver_input_indexes.clone(),
+                        receiver_txout,
+                        receiver_output_index,
+                        receiver_change: receiver_change
+                            .clone()
+                            .map(|change| (receiver_change_index, change)),
+                    };
+                    // Every candidate spends the same inputs of ours, look them up only once
+                    let blockchain = CachedBlockchain::new(&*self.blockchain);
+                    let fees = self.fees(
+                        &blockchain,
+                        &final_transaction_meta,
+                        utxos
+                            .chunks(receiver_inputs)
+                            .next()
+                            .ok_or(ProtocolError::MissingData)?,
+                    )?;
+                    debug!("Paying {} sat of fees", fees);
+                    final_transaction_meta.fees = fees;
+
+                    self.observer.on_event(&SessionEvent::Signing);
+                    let candidates = utxos.chunks(receiver_inputs).collect::<Vec<_>>();
+                    let signed = parallel_map(&candidates, |candidate| {
+                        sign_candidate(
+                            &blockchain,
+                            &*self.signer,
+                            &final_transaction_meta,
+                            candidate,
+                            self.capabilities.contains(Capabilities::PSBT),
+                        )
+                    })?;
+
+                    let mut witnesses = Vec::new();
+                    let mut psbts = Vec::new();
+                    let mut candidates = Vec::new();
+                    for (transaction, signatures) in signed {
+                        candidates.push(transaction);
+                        match signatures {
+                            CandidateSignatures::Witnesses(this_utxo_witnesses) => {
+                                witnesses.push(this_utxo_witnesses)
+                            }
+                            CandidateSignatures::Psbt(psbt) => psbts.push(psbt),
+                        }
+                    }
+
+                    self.state = StateVariant::ServerUtxos {
+                        version: version.to_string(),
+                        proof: proof_transaction,
+                        utxos,
+                        receiver_inputs,
+                        receiver_input_indexes: receiver_input_indexes.clone(),
+                        receiver_change,
+                        candidates,
+                    };
+                    self.observer.on_event(&SessionEvent::WaitingForPeer);
+
+                    if self.capabilities.contains(Capabilities::PSBT) {
+                        Ok(Some(Request::Psbts {
+                            fees,
+                            amount: Some(amount),
+                            change_script,
+                            change_position: Some(change_output_index),
+                            payments,
+                            receiver_input_positions: receiver_input_indexes,
+                            receiver_output_position: receiver_output_index,
+                            receiver_change_position,
+                            psbts,
+                        }))
+                    } else {
+                        Ok(Some(Request::Witnesses {
+                            fees,
+                            amount: Some(amount),
+                            change_script,
+                            change_position: Some(change_output_index),
+                            payments,
+                            receiver_input_positions: receiver_input_indexes,
+                            receiver_output_position: receiver_output_index,
+                            receiver_change_position,
+                            witnesses,
+                        }))
+                    }
+                }
+                _ => Err(ProtocolError::Expected("UTXOS".into()).into()),
+            },
+            StateVariant::ServerUtxos {
+                version,
+                receiver_inputs,
+                receiver_input_indexes,
+                receiver_change,
+                candidates,
+                ..
+            } => match message {
+                Response::Txid {
+                    txid,
+                    transaction,
+                    will_broadcast_by,
+                    sender_broadcasts,
+                } => {
+                    self.observer.on_event(&SessionEvent::VerifyingTransaction);
+                    verify_final_transaction(
+                        &*self.blockchain,
+                        candidates,
+                        receiver_input_indexes,
+                        &txid,
+                        &transaction,
+                    )?;
+                    self.check_uih(
+                        &transaction,
+                        receiver_input_indexes,
+                        receiver_change.as_ref(),
+                    )?;
+
+                    if sender_broadcasts {
+                        if !self.capabilities.contains(Capabilities::SENDER_BROADCAST) {
+                            return Err(ProtocolError::MissingCapability(
+                                Capabilities::SENDER_BROADCAST,
+                            )
+                            .into());
+                        }
+                        if self.config.dry_run {
+                            debug!("Dry run, not broadcasting {}", txid);
+                        } else {
+                            self.observer.on_event(&SessionEvent::Broadcasting);
+                            broadcast_final_transaction(&*self.blockchain, &transaction)?;
+                        }
+                    }
+
+                    let mut result =
+                        PaymentResult::new(&*self.blockchain, transaction, *receiver_inputs)?;
+                    result.will_broadcast_by =
+                        will_broadcast_by.map(|time| UNIX_EPOCH + Duration::from_secs(time));
+                    self.state = StateVariant::ServerTxid {
+                        version: version.to_string(),
+                        result,
+                    };
+                    self.observer.on_event(&SessionEvent::Completed { txid });
+
+                    Ok(None)
+                }
+                _ => Err(ProtocolError::Expected("TXID".into()).into()),
+            },
+            _ => Err(ProtocolError::UnexpectedMessage.into()),
+        }
+    }
+}
+
+/// Broadcast the final transaction when the receiver leaves it to us, unless one of its inputs
+/// has already been spent by another transaction. A transaction that already propagated, like
+/// after resuming the session, is left alone
+pub(crate) fn broadcast_final_transaction<B>(
+    blockchain: &B,
+    transaction: &Transaction,
+) -> Result<(), Error>
+where
+    B: Blockchain,
+    Error: From<<B as TxSource>::Error>,
+{
+    if is_propagated(blockchain, &transaction.txid())? {
+        return Ok(());
+    }
+    for input in &transaction.input {
+        if !blockchain.is_unspent(&input.previous_output)? {
+            return Err(Error::ConflictingTransaction);
+        }
+    }
+
+    Ok(broadcast_with_retry(blockchain, transaction)?)
+}
+
+/// Outcome of a successful payment
+#[derive(Debug, Clone)]
+pub struct PaymentResult {
+    pub txid: Txid,
+    /// The transaction that was broadcast, or would have been in a dry run
+    pub transaction: Transaction,
+    /// Fees of the whole transaction, in satoshis
+    pub fee_paid: u64,
+    /// UTXOs contributed by the receiver, zero if the payment fell back to a regular transaction
+    pub receiver_input_count: usize,
+    /// Time by which the receiver promised to broadcast the transaction, if it didn't right away
+    /// (see `Client::ensure_broadcast()`)
+    pub will_broadcast_by: Option<SystemTime>,
+}
+
+impl PaymentResult {
+    /// Looks up the prevouts of `transaction` on `blockchain` to compute its fees
+    pub(crate) fn new<B>(
+        blockchain: &B,
+        transaction: Transaction,
+        receiver_input_count: usize,
+    ) -> Result<Self, Error>
+    where
+        B: Blockchain,
+        Error: From<<B as TxSource>::Error>,
+    {
+        let mut input_value = 0;
+        for txin in &transaction.input {
+            let prev_tx = blockchain.get_tx(&txin.previous_output.txid)?;
+            input_value += prev_tx
+                .output
+                .get(txin.previous_output.vout as usize)
+                .ok_or(ProtocolError::InvalidUtxo)?
+                .value;
+        }
+        let output_value = transaction.output.iter().map(|txout| txout.value).sum();
+
+        Ok(PaymentResult {
+            txid: transaction.txid(),
+            fee_paid: input_value
+                .checked_sub(output_value)
+                .ok_or(ProtocolError::InvalidUtxo)?,
+            transaction,
+            receiver_input_count,
+            will_broadcast_by: None,
+        })
+    }
+}
+
+/// Make sure that `transaction`, returned by the server as `txid`, is one of the `candidates` we
+/// signed with valid signatures on every input. The receiver may only fill the `script_sig` of its
+/// inputs and the witnesses
+fn verify_final_transaction<B>(
+    blockchain: &B,
+    candidates: &[Transaction],
+    receiver_input_indexes: &[usize],
+    txid: &Txid,
+    transaction: &Transaction,
+) -> Result<(), Error>
+where
+    B: Blockchain,
+    Error: From<<B as TxSource>::Error>,
+{
+    if transaction.txid() != *txid {
+        return Err(ProtocolError::TxidMismatch.into());
+    }
+
+    let mut unsigned = transaction.clone();
+    for (index, input) in unsigned.input.iter_mut().enumerate() {
+        input.witness.clear();
+        if receiver_input_indexes.contains(&index) {
+            input.script_sig = Script::new();
+        }
+    }
+    if !candidates.contains(&unsigned) {
+        return Err(ProtocolError::TransactionMismatch.into());
+    }
+
+    let mut prev_outs = Vec::with_capacity(transaction.input.len());
+    for input in &transaction.input {
+        let prev_tx = blockchain.get_tx(&input.previous_output.txid)?;
+        prev_outs.push(
+            prev_tx
+                .output
+                .get(input.previous_output.vout as usize)
+                .ok_or(ProtocolError::InvalidUtxo)?
+                .clone(),
+        );
+    }
+    let secp = Secp256k1::new();
+    let comp = SighashComponents::new(transaction);
+    for index in 0..transaction.input.len() {
+        verify_input(&secp, &comp, transaction, index, &prev_outs, false)
+            .map_err(|_| ProtocolError::InvalidSignature(index))?;
+    }
+
+    Ok(())
+}
+
+/// Copy of `meta` spending `utxos` from the receiver
+fn with_receiver_utxos(
+    meta: &FinalTransactionMeta<Created>,
+    utxos: &[OutPoint],
+) -> FinalTransactionMeta<Created> {
+    let mut meta = meta.clone();
+    for (txin, utxo) in meta.receiver_txins.iter_mut().zip(utxos) {
+        txin.previous_output = *utxo;
+    }
+
+    meta
+}
+
+/// What we send back for a candidate final transaction, depending on the negotiated capabilities
+enum CandidateSignatures {
+    Witnesses(Vec<WitnessWrapper>),
+    Psbt(PartiallySignedTransaction),
+}
+
+/// Build the final transaction spending `utxos` from the receiver and sign our inputs, returning
+/// it unsigned along with our signatures
+fn sign_candidate<B, S>(
+    blockchain: &B,
+    signer: &S,
+    meta: &FinalTransactionMeta<Created>,
+    utxos: &[OutPoint],
+    psbt: bool,
+) -> Result<(Transaction, CandidateSignatures), Error>
+where
+    B: Blockchain,
+    Error: From<<B as TxSource>::Error>,
+    S: Signer,
+    Error: From<<S as Signer>::Error>,
+{
+    for utxo in utxos {
+        if !blockchain.is_unspent(utxo)? {
+            trace!("Invalid prev_out (wrong type or spent)");
+            return Err(ProtocolError::InvalidUtxo.into());
+        }
+    }
+
+    let final_transaction =
+        FinalTransaction::<Unsigned>::try_from((with_receiver_utxos(meta, utxos), blockchain))?;
+    let inputs_to_sign = (0..final_transaction.input.len())
+        .filter(|i| !meta.receiver_input_indexes.contains(i))
+        .collect::<Vec<_>>();
+    let unsigned = (*final_transaction).clone();
+
+    if psbt {
+        let mut psbt = final_transaction.to_psbt(blockchain)?;
+        signer.sign_psbt(&mut psbt, &inputs_to_sign)?;
+
+        return Ok((unsigned, CandidateSignatures::Psbt(psbt)));
+    }
+
+    let final_transaction =
+        FinalTransaction::<SenderSigned>::try_from((final_transaction, signer, blockchain))?;
+    let mut signed = SignedInputSet::new();
+    for index in inputs_to_sign {
+        let input = &final_transaction.input[index];
+        signed.insert(input.previous_output, input.witness.clone());
+    }
+    let witnesses = signed.to_wrappers(meta.tx.input.iter().map(|txin| txin.previous_output))?;
+
+    Ok((unsigned, CandidateSignatures::Witnesses(witnesses)))
+}
+
+/// `f` applied to every item, spread over as many threads as there are CPUs. Signing is CPU bound
+/// and lookups on a remote backend wait on the network, so candidates are handled side by side.
+/// Results keep the order of `items`, and the first error in that order is returned
+fn parallel_map<T, R, F>(items: &[T], f: F) -> Result<Vec<R>, Error>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R, Error> + Sync,
+{
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    if threads == 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let chunk_size = items.len().div_ceil(threads);
+    let f = &f;
+    thread::scope(|scope| {
+        let handles = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(f).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("signing thread panicked"))
+            .collect()
+    })
+}
+
+/// Order of the inputs and outputs of the final transaction
+///
+/// BIP69 is not an option: the receiver's UTXO is only known to the sender as one of the
+/// candidates, so every candidate would end up with a different order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TxOrdering {
+    /// Random positions for every input and output
+    #[default]
+    Shuffle,
+    /// Keep the order of the base transaction, with the receiver's inputs at the end
+    Untouched,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Fee rate of the final transaction. The fees are paid by the sender's change output
+    pub fee_rate: FeeRate,
+    /// Index of the change output in the base transaction. Required when the base transaction
+    /// pays more than the receiver, with the other outputs kept as they are
+    pub change_output_index: Option<usize>,
+    pub ordering: TxOrdering,
+    /// Signal RBF in the final transaction, so that it can be fee-bumped. The fallback transaction
+    /// is broadcast as it is
+    pub rbf: bool,
+    /// Set the locktime of the final transaction to the current height, to discourage miners from
+    /// reorging the chain to take its fees. Ignored if the base transaction has a locktime
+    pub anti_fee_sniping: bool,
+    /// Time allowed for every attempt to connect and for every message of the server
+    pub message_timeout: Duration,
+    /// Time allowed for the whole negotiation, once connected
+    pub session_timeout: Duration,
+    /// Longest line accepted from the server, in bytes
+    pub max_message_size: usize,
+    /// Time allowed for the transport to get ready before connecting, like for Tor to bootstrap
+    pub bootstrap_timeout: Duration,
+    /// Network we are on. Payment URIs for other networks are rejected
+    pub network: Network,
+    /// Sign the proof with `SIGHASH_ALL | SIGHASH_ANYONECANPAY` when the server and the signer
+    /// support it, so that its signatures can't be reused with any other input
+    pub anyonecanpay_proof: bool,
+    /// What to do when the final transaction would be given away by the unnecessary input
+    /// heuristics. With `Abort` the sender doesn't broadcast it, but the receiver may have already
+    pub uih_policy: UihPolicy,
+    /// Go through the whole negotiation without broadcasting anything, to test against a staging
+    /// receiver. The final transaction is still returned in the `PaymentResult`
+    pub dry_run: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            fee_rate: FeeRate::default(),
+            change_output_index: None,
+            ordering: TxOrdering::default(),
+            rbf: false,
+            anti_fee_sniping: false,
+            message_timeout: Duration::from_secs(10),
+            max_message_size: MAX_MESSAGE_SIZE,
+            session_timeout: Duration::from_secs(60),
+            bootstrap_timeout: Duration::from_secs(180),
+            network: Network::Bitcoin,
+            anyonecanpay_proof: false,
+            uih_policy: UihPolicy::default(),
+            dry_run: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::Payjoin;
+
+    #[test]
+    fn test_parallel_map() {
+        let items = (0..100).collect::<Vec<u32>>();
+
+        let doubled = parallel_map(&items, |item| Ok(item * 2)).unwrap();
+        assert_eq!(
+            doubled,
+            items.iter().map(|item| item * 2).collect::<Vec<_>>()
+        );
+
+        let failed = parallel_map(&items, |item| match item {
+            42 => Err(ProtocolError::InvalidUtxo.into()),
+            60 => Err(ProtocolError::MissingData.into()),
+            _ => Ok(*item),
+        });
+        assert!(matches!(
+            failed,
+            Err(Error::Protocol(ProtocolError::InvalidUtxo))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_final_transaction() {
+        let payjoin = Payjoin::new(3_000_000).await.unwrap();
+        let blockchain = &payjoin.blockchain;
+        let fallback = &payjoin.fallback;
+
+        let mut conflicting = fallback.clone();
+        conflicting.output[0].value -= 1000;
+        broadcast_final_transaction(blockchain, &conflicting).unwrap();
+        assert!(matches!(
+            broadcast_final_transaction(blockchain, fallback),
+            Err(Error::ConflictingTransaction)
+        ));
+        // Already there, like after resuming
+        broadcast_final_transaction(blockchain, &conflicting).unwrap();
+        assert_eq!(blockchain.broadcasts().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_verify_final_transaction() {
+        let payjoin = Payjoin::new(3_000_000).await.unwrap();
+        let signed = payjoin.fallback.clone();
+        let mut unsigned = signed.clone();
+        unsigned.input[0].witness.clear();
+        let candidates = vec![unsigned];
+        let verify = |transaction: &Transaction, txid: &Txid| {
+            verify_final_transaction(&payjoin.blockchain, &candidates, &[], txid, transaction)
+        };
+
+        assert!(verify(&signed, &signed.txid()).is_ok());
+        assert!(matches!(
+            verify(&signed, &Default::default()),
+            Err(Error::Protocol(ProtocolError::TxidMismatch))
+        ));
+
+        let mut tampered = signed.clone();
+        tampered.output[0].value -= 1;
+        assert!(matches!(
+            verify(&tampered, &tampered.txid()),
+            Err(Error::Protocol(ProtocolError::TransactionMismatch))
+        ));
+
+        let mut tampered = signed.clone();
+        tampered.input[0].witness[0][4] ^= 1;
+        assert!(matches!(
+            verify(&tampered, &tampered.txid()),
+            Err(Error::Protocol(ProtocolError::InvalidSignature(0)))
+        ));
+    }
+}
@@ -0,0 +1,368 @@
+//! Python bindings of the server, for merchant tooling
+//!
+//! The server runs on a tokio runtime owned by the Python object, on threads of its own, so it
+//! never holds the GIL while waiting for senders. `Server.mainloop()` returns an asyncio future,
+//! resolved on the running event loop once the server stops, and `Server.stop()` stops it. Every
+//! other method blocks until it's done.
+//!
+//! The chain is either a `MockBlockchain`, for tests, or any Python object with the methods below,
+//! called from the threads of the server. Transactions are always in hex and UTXOs are
+//! `txid:vout` strings.
+//!
+//! ```python
+//! class Chain:
+//!     def get_tx(self, txid: str) -> str: ...
+//!     def is_unspent(self, outpoint: str) -> bool: ...
+//!     def get_random_utxo(self) -> str: ...
+//!     def broadcast(self, transaction: str) -> None: ...
+//!
+//! server = libp2ep.Server(Chain(), wif, [("txid:0", 100_000, "0014...")], ["0014..."], 30_000)
+//! print(server.setup())
+//! await server.mainloop()
+//! ```
+
+// Triggered by the code generated by `#[pymethods]` for every fallible method
+#![allow(clippy::useless_conversion)]
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+
+use tokio::runtime::Runtime;
+
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::{Network, OutPoint, PrivateKey, Script, Transaction, TxOut, Txid};
+
+use crate::blockchain::{Broadcaster, TxSource};
+use crate::demo::SoftwareSigner;
+use crate::server::{Server, ServerBuilder};
+use crate::shutdown::CancellationToken;
+use crate::testutil;
+use crate::transport::{Direct, ExternalTor};
+use crate::wallet::SimpleReceiverWallet;
+use crate::Error;
+
+type PayjoinServer = Server<PyChain, SoftwareSigner, SimpleReceiverWallet>;
+
+fn to_py(error: Error) -> PyErr {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(&error);
+    while let Some(inner) = source {
+        message = format!("{}: {}", message, inner);
+        source = inner.source();
+    }
+
+    PyRuntimeError::new_err(message)
+}
+
+fn invalid<E: fmt::Debug>(error: E) -> PyErr {
+    PyValueError::new_err(format!("{:?}", error))
+}
+
+fn from_hex<T: bitcoin::consensus::Decodable>(hex: &str) -> Result<T, Error> {
+    let bytes = Vec::<u8>::from_hex(hex).map_err(|_| Error::Other)?;
+    deserialize(&bytes).map_err(|_| Error::Other)
+}
+
+/// In-memory chain of `testutil`, where every transaction confirms as soon as it's broadcast
+#[pyclass(name = "MockBlockchain")]
+#[derive(Clone)]
+pub struct PyMockBlockchain(testutil::MockBlockchain);
+
+#[pymethods]
+impl PyMockBlockchain {
+    #[new]
+    fn new() -> Self {
+        PyMockBlockchain(testutil::MockBlockchain::new())
+    }
+
+    /// Create a UTXO of `value` satoshis locked by `script_pubkey`, in hex
+    fn fund(&self, script_pubkey: &str, value: u64) -> PyResult<String> {
+        let script = Vec::<u8>::from_hex(script_pubkey).map_err(invalid)?;
+
+        Ok(self.0.fund(Script::from(script), value).to_string())
+    }
+
+    fn add_tx(&self, transaction: &str) -> PyResult<String> {
+        let transaction = from_hex(transaction).map_err(to_py)?;
+
+        Ok(self.0.add_tx(transaction).to_string())
+    }
+
+    fn mine(&self) {
+        self.0.mine()
+    }
+
+    /// Transactions broadcast so far, in hex
+    fn broadcasts(&self) -> Vec<String> {
+        self.0
+            .broadcasts()
+            .iter()
+            .map(|tx| serialize(tx).to_hex())
+            .collect()
+    }
+}
+
+/// Chain the server was given from Python
+enum PyChain {
+    Mock(testutil::MockBlockchain),
+    Object(PyObject),
+}
+
+impl fmt::Debug for PyChain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PyChain::Mock(mock) => mock.fmt(f),
+            PyChain::Object(_) => f.write_str("PyChain"),
+        }
+    }
+}
+
+impl PyChain {
+    fn call<T: for<'py> FromPyObject<'py>>(
+        object: &PyObject,
+        method: &str,
+        args: impl IntoPy<Py<pyo3::types::PyTuple>>,
+    ) -> Result<T, Error> {
+        Python::with_gil(|py| {
+            object
+                .call_method1(py, method, args)
+                .and_then(|result| result.extract(py))
+                .map_err(|e| Error::Transport(format!("{}() failed: {}", method, e)))
+        })
+    }
+}
+
+impl TxSource for PyChain {
+    type Error = Error;
+
+    fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+        match self {
+            PyChain::Mock(mock) => Ok(mock.get_tx(txid)?),
+            PyChain::Object(object) => from_hex(&Self::call::<String>(
+                object,
+                "get_tx",
+                (txid.to_string(),),
+            )?),
+        }
+    }
+
+    fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error> {
+        match self {
+            PyChain::Mock(mock) => Ok(mock.is_unspent(txout)?),
+            PyChain::Object(object) => Self::call(object, "is_unspent", (txout.to_string(),)),
+        }
+    }
+
+    fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+        match self {
+            PyChain::Mock(mock) => Ok(mock.get_random_utxo()?),
+            PyChain::Object(object) => {
+                let utxo = Self::call::<String>(object, "get_random_utxo", ())?;
+                OutPoint::from_str(&utxo).map_err(|_| Error::Other)
+            }
+        }
+    }
+
+    fn get_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Self::Error> {
+        match self {
+            PyChain::Mock(mock) => Ok(mock.get_confirmations(txid)?),
+            PyChain::Object(_) => Ok(None),
+        }
+    }
+}
+
+impl Broadcaster for PyChain {
+    type Error = Error;
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        match self {
+            PyChain::Mock(mock) => Ok(mock.broadcast(tx)?),
+            PyChain::Object(object) => {
+                Self::call::<PyObject>(object, "broadcast", (serialize(tx).to_hex(),)).map(|_| ())
+            }
+        }
+    }
+}
+
+/// Payjoin receiver, waiting for payments of `amount` satoshis
+#[pyclass(name = "Server")]
+pub struct PyServer {
+    // Only taken when dropped
+    runtime: Option<Runtime>,
+    // Taken while `mainloop()` runs
+    server: Option<PayjoinServer>,
+    shutdown: CancellationToken,
+}
+
+// The workers of the runtime may be waiting for the GIL, which must be released while they stop
+impl Drop for PyServer {
+    fn drop(&mut self) {
+        let (runtime, server) = (self.runtime.take(), self.server.take());
+        Python::with_gil(|py| py.allow_threads(move || drop((server, runtime))));
+    }
+}
+
+impl PyServer {
+    fn runtime(&mut self) -> &mut Runtime {
+        self.runtime.as_mut().expect("Dropped")
+    }
+
+    fn server(&mut self) -> PyResult<&mut PayjoinServer> {
+        self.server
+            .as_mut()
+            .ok_or_else(|| PyRuntimeError::new_err("The server is running"))
+    }
+}
+
+#[pymethods]
+impl PyServer {
+    /// `utxos` are `(outpoint, value, script_pubkey)` tuples of the wallet of `key`, in WIF, and
+    /// payments are received on `scripts`, in turn. The server is exposed through Tor, using the
+    /// daemon on `tor_control` if given, or directly with `direct`
+    #[new]
+    #[pyo3(signature = (
+        blockchain, key, utxos, scripts, amount, bind = "127.0.0.1:0", network = "regtest",
+        direct = false, tor_control = None, tor_socks = "127.0.0.1:9050", tor_password = None
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        blockchain: &Bound<'_, PyAny>,
+        key: &str,
+        utxos: Vec<(String, u64, String)>,
+        scripts: Vec<String>,
+        amount: u64,
+        bind: &str,
+        network: &str,
+        direct: bool,
+        tor_control: Option<&str>,
+        tor_socks: &str,
+        tor_password: Option<String>,
+    ) -> PyResult<Self> {
+        let blockchain = match blockchain.extract::<PyMockBlockchain>() {
+            Ok(mock) => PyChain::Mock(mock.0),
+            Err(_) => PyChain::Object(blockchain.clone().unbind()),
+        };
+        let key = PrivateKey::from_wif(key).map_err(invalid)?;
+        let network = Network::from_str(network).map_err(invalid)?;
+        let utxos = utxos
+            .into_iter()
+            .map(|(outpoint, value, script)| {
+                let txout = TxOut {
+                    value,
+                    script_pubkey: Script::from(Vec::<u8>::from_hex(&script).map_err(invalid)?),
+                };
+                Ok((OutPoint::from_str(&outpoint).map_err(invalid)?, txout))
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+        let scripts = scripts
+            .iter()
+            .map(|script| Ok(Script::from(Vec::<u8>::from_hex(script).map_err(invalid)?)))
+            .collect::<PyResult<Vec<_>>>()?;
+        if scripts.is_empty() {
+            return Err(PyValueError::new_err("No receiving script"));
+        }
+
+        let signer = SoftwareSigner::new(
+            key,
+            utxos
+                .iter()
+                .map(|(outpoint, txout)| (*outpoint, (txout.value, txout.script_pubkey.clone())))
+                .collect(),
+        );
+        let wallet = SimpleReceiverWallet::new(utxos, scripts);
+        let mut builder = ServerBuilder::new(blockchain, signer, wallet, amount).network(network);
+        if direct {
+            builder = builder.transport(Direct);
+        } else if let Some(control) = tor_control {
+            let control = SocketAddr::from_str(control).map_err(invalid)?;
+            let socks = SocketAddr::from_str(tor_socks).map_err(invalid)?;
+            builder = builder.transport(ExternalTor::new(socks, Some(control), tor_password));
+        }
+
+        let mut runtime = Runtime::new()?;
+        let server = runtime.block_on(builder.bind(bind)).map_err(to_py)?;
+
+        Ok(PyServer {
+            runtime: Some(runtime),
+            shutdown: server.cancellation_token(),
+            server: Some(server),
+        })
+    }
+
+    /// Expose the server and return its payment URI
+    fn setup(&mut self, py: Python<'_>) -> PyResult<String> {
+        let server = self
+            .server
+            .as_mut()
+            .ok_or_else(|| PyRuntimeError::new_err("The server is running"))?;
+        let runtime = self.runtime.as_mut().expect("Dropped");
+
+        py.allow_threads(|| runtime.block_on(server.setup()))
+            .map_err(to_py)
+    }
+
+    /// Number of payments received so far
+    fn payments(&mut self) -> PyResult<u32> {
+        Ok(self.server()?.payments())
+    }
+
+    /// One-time token for the next sender, when the server requires them
+    fn issue_token(&mut self) -> PyResult<String> {
+        Ok(self.server()?.issue_token())
+    }
+
+    /// Receive payments until `stop()`. Returns an asyncio future, resolved once the server has
+    /// stopped
+    fn mainloop<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let mut server = self
+            .server
+            .take()
+            .ok_or_else(|| PyRuntimeError::new_err("The server is already running"))?;
+        let event_loop = py
+            .import_bound("asyncio")?
+            .call_method0("get_running_loop")?;
+        let future = event_loop.call_method0("create_future")?;
+
+        let (event_loop, done) = (event_loop.unbind(), future.clone().unbind());
+        self.runtime().spawn(async move {
+            let result = server.mainloop().await;
+            Python::with_gil(|py| {
+                let (method, value) = match result {
+                    Ok(()) => ("set_result", py.None()),
+                    Err(e) => ("set_exception", to_py(e).into_py(py)),
+                };
+                // The future may have been cancelled in the meantime
+                let resolve = |py| -> PyResult<()> {
+                    if !done.call_method0(py, "done")?.extract::<bool>(py)? {
+                        let method = done.getattr(py, method)?;
+                        event_loop.call_method1(py, "call_soon_threadsafe", (method, value))?;
+                    }
+                    Ok(())
+                };
+                if let Err(e) = resolve(py) {
+                    e.print(py);
+                }
+            });
+        });
+
+        Ok(future)
+    }
+
+    /// Stop the server, dropping the current session if any
+    fn stop(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+#[pymodule]
+fn libp2ep(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyMockBlockchain>()?;
+    m.add_class::<PyServer>()?;
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+//! Redaction of sensitive material in the logs
+//!
+//! Keys, signatures, witnesses and the transactions and messages carrying them are wrapped in
+//! `Sensitive` before being logged, and show up as `<redacted>` unless unsafe logging is turned on
+//! with `set_unsafe_logging()`. Like the logger, the switch is global to the process:
+//! `Sensitive::fmt_with()` formats with a switch of the caller's instead.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static UNSAFE_LOGGING: AtomicBool = AtomicBool::new(false);
+
+/// Log sensitive material as it is, only meant for debugging on test networks
+pub fn set_unsafe_logging(enabled: bool) {
+    UNSAFE_LOGGING.store(enabled, Ordering::Relaxed);
+}
+
+pub fn unsafe_logging() -> bool {
+    UNSAFE_LOGGING.load(Ordering::Relaxed)
+}
+
+/// A value that is only formatted with unsafe logging
+pub struct Sensitive<T>(pub T);
+
+impl<T> Sensitive<T> {
+    /// Format with `unsafe_logging` instead of the global switch, for callers keeping their own
+    pub fn fmt_with(&self, unsafe_logging: bool) -> Redacted<'_, T> {
+        Redacted {
+            value: &self.0,
+            unsafe_logging,
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Sensitive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.fmt_with(unsafe_logging()), f)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Sensitive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.fmt_with(unsafe_logging()), f)
+    }
+}
+
+/// A `Sensitive` value with the switch it's formatted with, see `Sensitive::fmt_with()`
+pub struct Redacted<'a, T> {
+    value: &'a T,
+    unsafe_logging: bool,
+}
+
+impl<T: fmt::Debug> fmt::Debug for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.unsafe_logging {
+            true => fmt::Debug::fmt(self.value, f),
+            false => write!(f, "<redacted>"),
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.unsafe_logging {
+            true => fmt::Display::fmt(self.value, f),
+            false => write!(f, "<redacted>"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sensitive() {
+        assert_eq!(format!("{}", Sensitive("secret")), "<redacted>");
+        assert_eq!(format!("{:?}", Sensitive(42)), "<redacted>");
+
+        // Flipping the global switch would leak the secrets of the tests running next to this one
+        assert_eq!(
+            format!("{}", Sensitive("secret").fmt_with(false)),
+            "<redacted>"
+        );
+        assert_eq!(format!("{:?}", Sensitive(42).fmt_with(false)), "<redacted>");
+        assert_eq!(format!("{}", Sensitive("secret").fmt_with(true)), "secret");
+        assert_eq!(format!("{:?}", Sensitive(42).fmt_with(true)), "42");
+        assert_eq!(
+            format!("{:?}", Sensitive("secret").fmt_with(true)),
+            "\"secret\""
+        );
+    }
+}
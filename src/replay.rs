@@ -0,0 +1,236 @@
+//! Detection of proofs replayed with a different payload
+//!
+//! A sender probing for the receiver's UTXOs can keep the inputs of its proof and change the
+//! rest, like the outputs, to be treated as a new sender every time. The inputs of every proof are
+//! recorded in a `ProofStore`, with its txid, and for `ServerConfig::replay_window` a different
+//! proof spending any of them is rejected with `ProtocolError::ReplayedProof`. The same proof sent
+//! again, like after a dropped connection, is accepted.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use tracing::debug;
+
+use bitcoin::{OutPoint, Transaction, Txid};
+
+use crate::{Error, ProtocolError};
+
+/// The last proof seen spending an input
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SeenProof {
+    pub txid: Txid,
+    /// Seconds since the UNIX epoch
+    pub seen: u64,
+}
+
+pub trait ProofStore: std::fmt::Debug + Send + Sync {
+    /// Record `proof` as the last one spending `inputs`, unless one of them has been spent by a
+    /// different proof seen at or after `since`, whose txid is returned instead. Records older
+    /// than `since` can be forgotten
+    fn record(
+        &self,
+        inputs: &[OutPoint],
+        proof: SeenProof,
+        since: u64,
+    ) -> Result<Option<Txid>, Error>;
+}
+
+/// Check `proof` against the ones seen in the last `window`, then record it
+pub fn check_replay(
+    store: &dyn ProofStore,
+    proof: &Transaction,
+    window: Duration,
+) -> Result<(), Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0);
+    let inputs = proof
+        .input
+        .iter()
+        .map(|txin| txin.previous_output)
+        .collect::<Vec<_>>();
+    let seen = SeenProof {
+        txid: proof.txid(),
+        seen: now,
+    };
+
+    match store.record(&inputs, seen, now.saturating_sub(window.as_secs()))? {
+        Some(earlier) => {
+            debug!("Proof {} replays the inputs of {}", seen.txid, earlier);
+            Err(ProtocolError::ReplayedProof.into())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Shared by the stores: the proof conflicting with `proof`, or records it
+fn record(
+    proofs: &mut HashMap<OutPoint, SeenProof>,
+    inputs: &[OutPoint],
+    proof: SeenProof,
+    since: u64,
+) -> Option<Txid> {
+    proofs.retain(|_, seen| seen.seen >= since);
+
+    let conflict = inputs
+        .iter()
+        .filter_map(|input| proofs.get(input))
+        .find(|seen| seen.txid != proof.txid);
+    if let Some(seen) = conflict {
+        return Some(seen.txid);
+    }
+
+    for input in inputs {
+        proofs.insert(*input, proof);
+    }
+    None
+}
+
+/// Keep the proofs in memory, forgotten when the server restarts
+#[derive(Debug, Default)]
+pub struct MemoryProofStore(Mutex<HashMap<OutPoint, SeenProof>>);
+
+impl MemoryProofStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl ProofStore for MemoryProofStore {
+    fn record(
+        &self,
+        inputs: &[OutPoint],
+        proof: SeenProof,
+        since: u64,
+    ) -> Result<Option<Txid>, Error> {
+        Ok(record(&mut self.0.lock().unwrap(), inputs, proof, since))
+    }
+}
+
+/// Keep the proofs in a JSON file, also surviving restarts
+#[derive(Debug)]
+pub struct FileProofStore {
+    path: PathBuf,
+    proofs: Mutex<HashMap<OutPoint, SeenProof>>,
+}
+
+impl FileProofStore {
+    /// Store the proofs in the file at `path`, loading the ones already there
+    pub fn new<P: Into<PathBuf>>(path: P) -> Result<Self, Error> {
+        let path = path.into();
+        let proofs = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice::<Vec<(OutPoint, SeenProof)>>(&data)?
+                .into_iter()
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(FileProofStore {
+            path,
+            proofs: Mutex::new(proofs),
+        })
+    }
+}
+
+impl ProofStore for FileProofStore {
+    fn record(
+        &self,
+        inputs: &[OutPoint],
+        proof: SeenProof,
+        since: u64,
+    ) -> Result<Option<Txid>, Error> {
+        let mut proofs = self.proofs.lock().unwrap();
+        if let Some(txid) = record(&mut proofs, inputs, proof, since) {
+            return Ok(Some(txid));
+        }
+
+        // Write and rename, so that a crash never leaves a truncated file behind
+        let entries = proofs.iter().collect::<Vec<_>>();
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&entries)?)?;
+        fs::rename(tmp, &self.path)?;
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+
+    use bitcoin::TxIn;
+
+    use super::*;
+    use crate::session::new_token;
+
+    fn proof(inputs: &[u32], lock_time: u32) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time,
+            input: inputs
+                .iter()
+                .map(|vout| TxIn {
+                    previous_output: OutPoint {
+                        txid: Default::default(),
+                        vout: *vout,
+                    },
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![],
+        }
+    }
+
+    #[test]
+    fn test_check_replay() {
+        let store = MemoryProofStore::new();
+        let window = Duration::from_secs(60);
+
+        check_replay(&store, &proof(&[0, 1], 0), window).unwrap();
+        // Sent again
+        check_replay(&store, &proof(&[0, 1], 0), window).unwrap();
+        assert!(matches!(
+            check_replay(&store, &proof(&[1, 2], 1), window),
+            Err(Error::Protocol(ProtocolError::ReplayedProof))
+        ));
+        check_replay(&store, &proof(&[2, 3], 1), window).unwrap();
+
+        // Out of the window
+        assert_eq!(
+            store
+                .record(
+                    &[OutPoint::default()],
+                    SeenProof {
+                        txid: proof(&[0], 2).txid(),
+                        seen: u64::MAX,
+                    },
+                    u64::MAX,
+                )
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_file_proof_store() {
+        let mut path = env::temp_dir();
+        path.push(format!("libp2ep-proofs-{}.json", new_token()));
+        let window = Duration::from_secs(60);
+
+        let store = FileProofStore::new(&path).unwrap();
+        check_replay(&store, &proof(&[0], 0), window).unwrap();
+        drop(store);
+
+        let store = FileProofStore::new(&path).unwrap();
+        assert!(check_replay(&store, &proof(&[0], 1), window).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,333 +1,1790 @@
-use std::convert::TryFrom;
-use std::fs::File;
-use std::io::Read;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::{delay_for, delay_until, timeout, timeout_at};
 
-use tokio::net::{TcpListener, ToSocketAddrs};
+use tracing::{debug, info, info_span, warn, Instrument};
 
-use log::{debug, info, warn};
+use rand::Rng;
 
-use bitcoin::{Address, Network, OutPoint, Script, Transaction, TxIn, TxOut, Txid};
+use bitcoin::{Address, Network, Transaction, TxOut, Txid};
 
-use libtor::{HiddenServiceVersion, Tor, TorAddress, TorFlag};
-
-use crate::blockchain::Blockchain;
-use crate::common::*;
+use crate::bip21::{Bip21Error, Bip21Uri};
+use crate::blockchain::{
+    broadcast_with_retry, is_propagated, wait_for_block, wait_for_confirmations, Blockchain,
+    Instrumented, TxSource,
+};
+use crate::control::ControlHandle;
+use crate::cpfp::child_transaction;
+use crate::decoy::{DecoyFilter, DecoySelector, DecoySource};
+use crate::disclosure::{Disclosures, RateLimiter, RATE_WINDOW};
+use crate::events::{NoObserver, SessionEvent, SessionObserver};
+use crate::fees::FeeRate;
 use crate::jsonrpc::*;
+use crate::metrics::{Metrics, NoMetrics};
+use crate::multiparty::Chain;
+use crate::offer::Offer;
+use crate::replay::{MemoryProofStore, ProofStore};
+use crate::session::{MemoryStore, SessionStore};
+use crate::shutdown::CancellationToken;
 use crate::signer::Signer;
-use crate::{Error, ProtocolError, Request, Response, VERSION};
-
-#[derive(Debug)]
-enum StateVariant {
-    WaitingVersion,
-    ClientVersion {
-        version: String,
-    },
-    ClientProof {
-        version: String,
-        proof: ProofTransaction<Validated>,
-        utxos: Vec<OutPoint>,
-        our_utxo_position: usize,
-    },
-    ClientWitnesses {
-        version: String,
-        final_transaction: Transaction,
-    },
-}
+use crate::transcript::TranscriptLog;
+use crate::transport::{BoxedStream, Transport};
+use crate::wallet::ReceiverWallet;
+use crate::{run_blocking, Error, ProtocolError, Request, Response, TimeoutPhase};
 
-#[derive(Debug)]
-struct ServerState<'a, B, S> {
-    our_utxo: OutPoint,
-    our_txout: TxOut,
+use crate::protocol::receiver::ServerState;
+pub use crate::protocol::receiver::{
+    AccessTokens, BroadcastDelay, Invoice, Invoices, ServerConfig, UtxoGuard, UtxoLocks,
+};
 
-    state: StateVariant,
+/// Time between two checks on the final transaction of a completed session
+const REBROADCAST_INTERVAL: Duration = Duration::from_secs(60);
+/// Checks on the final transaction before giving up on it
+const REBROADCAST_ATTEMPTS: usize = 60;
+
+impl<B, S, W> JsonRpcState for ServerState<B, S, W>
+where
+    B: Blockchain + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<B as TxSource>::Error>,
+    S: Signer + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<S as Signer>::Error>,
+    W: ReceiverWallet + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<W as ReceiverWallet>::Error>,
+{
+    type OutMessage = Response;
+    type InMessage = Request;
+    type Response = Transaction;
+    type Error = Error;
 
-    blockchain: &'a B,
-    signer: &'a S,
+    fn message(
+        &mut self,
+        message: Self::InMessage,
+    ) -> Result<Option<Self::OutMessage>, Self::Error> {
+        self.step(message)
+    }
+
+    fn done(&self) -> Result<Self::Response, ()> {
+        self.result().ok_or(())
+    }
 }
 
-impl<'a, B, S> ServerState<'a, B, S>
+/// Broadcast `transaction` again every `REBROADCAST_INTERVAL`, until it has propagated
+async fn rebroadcast<B>(blockchain: Arc<B>, transaction: Transaction)
 where
-    B: Blockchain + std::fmt::Debug,
-    Error: From<<B as Blockchain>::Error>,
-    S: Signer + std::fmt::Debug,
-    Error: From<<S as Signer>::Error>,
+    B: Blockchain + Send + Sync + 'static,
+    Error: From<<B as TxSource>::Error>,
 {
-    fn new(
-        our_utxo: OutPoint,
-        our_txout: TxOut,
-        blockchain: &'a B,
-        signer: &'a S,
-    ) -> ServerState<'a, B, S> {
-        ServerState {
-            our_utxo,
-            our_txout,
-            state: StateVariant::WaitingVersion,
-            blockchain,
-            signer,
+    let txid = transaction.txid();
+
+    for _ in 0..REBROADCAST_ATTEMPTS {
+        let blockchain = Arc::clone(&blockchain);
+        let transaction = transaction.clone();
+        let seen = run_blocking(move || -> Result<bool, Error> {
+            if is_propagated(&*blockchain, &txid)? {
+                return Ok(true);
+            }
+
+            blockchain.broadcast(&transaction)?;
+            Ok(false)
+        })
+        .await
+        .and_then(|result| result);
+
+        match seen {
+            Ok(true) => {
+                debug!("Transaction {} is in the mempool", txid);
+                return;
+            }
+            Ok(false) => debug!("Rebroadcast transaction {}", txid),
+            Err(e) => warn!("Unable to rebroadcast {}: {:?}", txid, e),
         }
+
+        delay_for(REBROADCAST_INTERVAL).await;
     }
 
-    fn transition(&mut self, message: Request) -> Result<Option<Response>, Error> {
-        match &self.state {
-            StateVariant::WaitingVersion => match message {
-                Request::Version { version } if version == VERSION => {
-                    self.state = StateVariant::ClientVersion { version };
+    warn!(
+        "Giving up on transaction {}, which never reached the mempool",
+        txid
+    );
+}
 
-                    Ok(Some(Response::Version {
-                        version: VERSION.to_string(),
-                    }))
-                }
-                Request::Version { version } => Err(ProtocolError::InvalidVersion(version).into()),
-                _ => Err(ProtocolError::Expected("VERSION".into()).into()),
-            },
-            StateVariant::ClientVersion { version } => match message {
-                Request::Proof { transaction } => {
-                    let proof =
-                        ProofTransaction::<Validated>::try_from((transaction, self.blockchain))?;
-
-                    let mut utxos = Vec::with_capacity(100);
-                    for _i in 0..99 {
-                        utxos.push(self.blockchain.get_random_utxo()?);
-                    }
-                    let our_utxo_position = rand::thread_rng().gen_range(0, 100);
-                    utxos.insert(our_utxo_position, self.our_utxo.clone());
+/// Wait for the time set by `delay`, then `rebroadcast()` the transaction
+async fn scheduled_broadcast<B>(blockchain: Arc<B>, transaction: Transaction, delay: BroadcastDelay)
+where
+    B: Blockchain + Send + Sync + 'static,
+    Error: From<<B as TxSource>::Error>,
+{
+    match delay {
+        BroadcastDelay::Immediate => {}
+        // Only if the sender didn't broadcast it
+        BroadcastDelay::Sender => delay_for(REBROADCAST_INTERVAL).await,
+        BroadcastDelay::Random(max) => {
+            let delay = max.mul_f64(rand::thread_rng().gen());
+            delay_for(delay).await;
+        }
+        BroadcastDelay::NextBlock(max) => {
+            let deadline = Instant::now() + max;
+            let script_pubkey = transaction.output[0].script_pubkey.clone();
+            let next_block = wait_for_block(Arc::clone(&blockchain), script_pubkey);
+            if let Ok(Ok(false)) | Ok(Err(_)) = timeout_at(deadline.into(), next_block).await {
+                delay_until(deadline.into()).await;
+            }
+        }
+    }
+    debug!("Broadcasting {}", transaction.txid());
 
-                    self.state = StateVariant::ClientProof {
-                        version: version.to_string(),
-                        proof,
-                        utxos: utxos.clone(),
-                        our_utxo_position,
-                    };
+    rebroadcast(blockchain, transaction).await
+}
 
-                    Ok(Some(Response::Utxos { utxos }))
-                }
-                _ => Err(ProtocolError::Expected("PROOF".into()).into()),
-            },
-            StateVariant::ClientProof {
-                version,
-                proof,
-                our_utxo_position,
-                ..
-            } => match message {
-                Request::Witnesses {
-                    witnesses,
-                    change_script,
-                    fees,
-                    receiver_input_position,
-                    receiver_output_position,
-                } => {
-                    let receiver_txin = TxIn {
-                        sequence: 0xFFFF_FFFF,
-                        previous_output: self.our_utxo,
-                        ..Default::default()
-                    };
-                    let final_transaction_meta = FinalTransactionMeta {
-                        tx: proof.clone(),
-                        fees,
-                        sender_script: change_script,
-                        receiver_txin,
-                        receiver_input_index: receiver_input_position,
-                        receiver_txout: self.our_txout.clone(),
-                        receiver_output_index: receiver_output_position,
-                    };
-                    let final_transaction = FinalTransaction::<Unsigned>::try_from((
-                        final_transaction_meta,
-                        self.blockchain,
-                    ))?;
-                    let final_transaction = FinalTransaction::<SenderSigned>::try_from((
-                        final_transaction,
-                        witnesses
-                            .get(*our_utxo_position)
-                            .ok_or(ProtocolError::MissingData)?,
-                    ))?;
-                    let final_transaction =
-                        FinalTransaction::<Signed>::try_from((final_transaction, self.signer))?;
-
-                    self.blockchain.broadcast(&final_transaction)?;
-
-                    self.state = StateVariant::ClientWitnesses {
-                        version: version.to_string(),
-                        final_transaction: final_transaction.clone().into_inner(),
-                    };
-
-                    Ok(Some(Response::Txid {
-                        txid: final_transaction.txid(),
-                        transaction: final_transaction.into_inner(),
-                    }))
-                }
-                _ => Err(ProtocolError::Expected("WITNESSES".into()).into()),
-            },
-            _ => Err(ProtocolError::UnexpectedMessage.into()),
+/// Resolve once `expires_at` has passed, or never without one
+async fn expiry(expires_at: Option<SystemTime>) {
+    match expires_at {
+        Some(expires_at) => {
+            let left = expires_at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            delay_for(left).await
         }
+        None => future::pending().await,
     }
 }
 
-impl<'a, B, S> JsonRpcState for ServerState<'a, B, S>
+/// Hand a new connection to the transport, giving up on handshakes slower than `limit`
+async fn accept(
+    transport: &dyn Transport,
+    stream: TcpStream,
+    limit: Duration,
+) -> Result<BoxedStream, Error> {
+    timeout(limit, transport.accept(stream))
+        .await
+        .unwrap_or(Err(Error::Timeout(TimeoutPhase::Handshake)))
+}
+
+/// Settings of a `Server`, on top of the parts it can't do without
+///
+/// ```ignore
+/// let server = ServerBuilder::new(blockchain, signer, wallet, amount)
+///     .decoy_count(20, 40)
+///     .transport(Direct)
+///     .bind("127.0.0.1:0")
+///     .await?;
+/// ```
+pub struct ServerBuilder<B, S, W> {
+    blockchain: B,
+    signer: S,
+    wallet: W,
+    expected_amount: u64,
+
+    config: ServerConfig,
+    transport: Option<Arc<dyn Transport>>,
+    decoys: Arc<dyn DecoySelector>,
+    decoy_source: Option<Arc<dyn DecoySource>>,
+    chain: Option<Chain>,
+    sessions: Arc<dyn SessionStore>,
+    proofs: Arc<dyn ProofStore>,
+    metrics: Arc<dyn Metrics>,
+    observer: Arc<dyn SessionObserver>,
+    shutdown: CancellationToken,
+    transcripts: Option<TranscriptLog>,
+}
+
+impl<B, S, W> ServerBuilder<B, S, W>
 where
-    B: Blockchain + std::fmt::Debug,
-    Error: From<<B as Blockchain>::Error>,
-    S: Signer + std::fmt::Debug,
+    B: Blockchain + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<B as TxSource>::Error>,
+    S: Signer + std::fmt::Debug + Send + Sync + 'static,
     Error: From<<S as Signer>::Error>,
+    W: ReceiverWallet + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<W as ReceiverWallet>::Error>,
 {
-    type OutMessage = Response;
-    type InMessage = Request;
-    type Response = Txid;
-    type Error = Error;
+    /// Start building a server receiving payments of `expected_amount` satoshis, with the
+    /// receiving script and the UTXOs contributed to every session taken from `wallet`
+    pub fn new(blockchain: B, signer: S, wallet: W, expected_amount: u64) -> Self {
+        ServerBuilder {
+            blockchain,
+            signer,
+            wallet,
+            expected_amount,
 
-    fn message(
-        &mut self,
-        message: Self::InMessage,
-    ) -> Result<Option<Self::OutMessage>, Self::Error> {
-        Ok(self.transition(message)?)
+            config: ServerConfig::default(),
+            transport: None,
+            decoys: Arc::new(DecoyFilter::default()),
+            decoy_source: None,
+            chain: None,
+            sessions: Arc::new(MemoryStore::new()),
+            proofs: Arc::new(MemoryProofStore::new()),
+            metrics: Arc::new(NoMetrics),
+            observer: Arc::new(NoObserver),
+            shutdown: CancellationToken::new(),
+            transcripts: None,
+        }
     }
 
-    fn done(&self) -> Result<Self::Response, ()> {
-        if let StateVariant::ClientWitnesses {
-            final_transaction, ..
-        } = &self.state
-        {
-            Ok(final_transaction.txid())
-        } else {
-            Err(())
-        }
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Bounds of the number of decoys offered next to our UTXOs
+    pub fn decoy_count(mut self, min: usize, max: usize) -> Self {
+        self.config.min_decoys = min;
+        self.config.max_decoys = max;
+        self
+    }
+
+    /// Time allowed for the handshake of the transport and for every message of the sender
+    pub fn message_timeout(mut self, message_timeout: Duration) -> Self {
+        self.config.message_timeout = message_timeout;
+        self
+    }
+
+    /// Time allowed for every negotiation, from the first message of the sender
+    pub fn session_timeout(mut self, session_timeout: Duration) -> Self {
+        self.config.session_timeout = session_timeout;
+        self
+    }
+
+    /// Time allowed for the transport to expose the server and for the senders to be able to
+    /// reach it
+    pub fn bootstrap_timeout(mut self, bootstrap_timeout: Duration) -> Self {
+        self.config.bootstrap_timeout = bootstrap_timeout;
+        self
+    }
+
+    /// Network of the addresses in our payment URIs
+    pub fn network(mut self, network: Network) -> Self {
+        self.config.network = network;
+        self
+    }
+
+    /// Accept any amount of at least `expected_amount`, see `ServerConfig::donations`
+    pub fn donations(mut self, donations: bool) -> Self {
+        self.config.donations = donations;
+        self
+    }
+
+    /// Negotiate without broadcasting anything, see `ServerConfig::dry_run`
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.config.dry_run = dry_run;
+        self
+    }
+
+    /// When the final transactions are broadcast, see `ServerConfig::broadcast_delay`
+    pub fn broadcast_delay(mut self, broadcast_delay: BroadcastDelay) -> Self {
+        self.config.broadcast_delay = broadcast_delay;
+        self
+    }
+
+    /// How the server is exposed to the senders, like a `LibTor` with a custom configuration.
+    /// Defaults to `LibTor` when the feature is enabled, `Direct` otherwise
+    pub fn transport<T: Transport + 'static>(mut self, transport: T) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// See `Server::set_decoy_selector()`
+    pub fn decoy_selector<D: DecoySelector + 'static>(mut self, decoys: D) -> Self {
+        self.decoys = Arc::new(decoys);
+        self
+    }
+
+    /// See `Server::set_decoy_source()`
+    pub fn decoy_source<D: DecoySource + 'static>(mut self, decoy_source: D) -> Self {
+        self.decoy_source = Some(Arc::new(decoy_source));
+        self
+    }
+
+    /// See `Server::set_chain()`
+    pub fn chain(mut self, chain: Chain) -> Self {
+        self.chain = Some(chain);
+        self
+    }
+
+    /// See `Server::set_session_store()`
+    pub fn session_store<T: SessionStore + 'static>(mut self, sessions: T) -> Self {
+        self.sessions = Arc::new(sessions);
+        self
+    }
+
+    /// See `Server::set_proof_store()`
+    pub fn proof_store<T: ProofStore + 'static>(mut self, proofs: T) -> Self {
+        self.proofs = Arc::new(proofs);
+        self
+    }
+
+    /// See `Server::set_metrics()`
+    pub fn metrics<M: Metrics + 'static>(mut self, metrics: Arc<M>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// See `Server::set_observer()`
+    pub fn observer<O: SessionObserver + 'static>(mut self, observer: Arc<O>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Stop the server through `token`, shared with the rest of the application. Otherwise it gets
+    /// one of its own, see `Server::cancellation_token()`
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.shutdown = token;
+        self
+    }
+
+    /// See `Server::set_transcripts()`
+    pub fn transcripts(mut self, transcripts: TranscriptLog) -> Self {
+        self.transcripts = Some(transcripts);
+        self
+    }
+
+    /// Listen on `bind` and build the server
+    pub async fn bind<A: ToSocketAddrs>(self, bind: A) -> Result<Server<B, S, W>, Error> {
+        let expected_script = self.wallet.next_script()?;
+        // Catch it now rather than when building the URI
+        Address::from_script(&expected_script, self.config.network)
+            .ok_or(Bip21Error::UnsupportedScript)?;
+
+        let tokens = AccessTokens::new();
+        let locks = UtxoLocks::new();
+        locks.set_timeout(self.config.utxo_lock_timeout);
+        Ok(Server {
+            listener: TcpListener::bind(bind).await?,
+            blockchain: Arc::new(Instrumented(self.blockchain)),
+            signer: Arc::new(self.signer),
+            wallet: Arc::new(self.wallet),
+
+            our_txout: TxOut {
+                script_pubkey: expected_script,
+                value: self.expected_amount,
+            },
+            decoys: self.decoys,
+            decoy_source: self.decoy_source,
+            chain: self.chain,
+            sessions: self.sessions,
+            proofs: self.proofs,
+            disclosures: Disclosures::new(),
+            control: ControlHandle::new(tokens.clone(), locks.clone()),
+            tokens,
+            invoices: Invoices::new(),
+            offers: Vec::new(),
+            #[cfg(feature = "bip78")]
+            proposals: Vec::new(),
+            peers: HashMap::new(),
+            metrics: self.metrics,
+            observer: self.observer,
+
+            transport: self
+                .transport
+                .unwrap_or_else(Server::<B, S, W>::default_transport),
+            endpoint: None,
+            expires_at: None,
+            locks,
+            shutdown: self.shutdown,
+            transcripts: self.transcripts,
+
+            config: self.config,
+            payments: 0,
+            dry_runs: Vec::new(),
+        })
     }
 }
 
-pub struct Server<B, S>
+pub struct Server<B, S, W>
 where
-    B: Blockchain + std::fmt::Debug,
-    S: Signer + std::fmt::Debug,
+    B: Blockchain + std::fmt::Debug + Send + Sync + 'static,
+    S: Signer + std::fmt::Debug + Send + Sync + 'static,
+    W: ReceiverWallet + std::fmt::Debug + Send + Sync + 'static,
 {
     listener: TcpListener,
-    blockchain: B,
-    signer: S,
+    blockchain: Arc<Instrumented<B>>,
+    signer: Arc<S>,
+    wallet: Arc<W>,
 
-    our_utxo: OutPoint,
     our_txout: TxOut,
+    decoys: Arc<dyn DecoySelector>,
+    decoy_source: Option<Arc<dyn DecoySource>>,
+    chain: Option<Chain>,
+    sessions: Arc<dyn SessionStore>,
+    proofs: Arc<dyn ProofStore>,
+    disclosures: Disclosures,
+    tokens: AccessTokens,
+    invoices: Invoices,
+    offers: Vec<(Offer, UtxoGuard)>,
+    /// UTXOs of the BIP78 proposals we signed, until they are spent or their lock expires
+    #[cfg(feature = "bip78")]
+    proposals: Vec<UtxoGuard>,
+    control: ControlHandle,
+    peers: HashMap<IpAddr, RateLimiter>,
+    metrics: Arc<dyn Metrics>,
+    observer: Arc<dyn SessionObserver>,
 
-    tor_hs: Option<String>,
+    transport: Arc<dyn Transport>,
+    endpoint: Option<String>,
+    expires_at: Option<SystemTime>,
+    locks: UtxoLocks,
+    shutdown: CancellationToken,
+    transcripts: Option<TranscriptLog>,
+
+    config: ServerConfig,
+    payments: u32,
+    dry_runs: Vec<Transaction>,
 }
 
-impl<B, S> Server<B, S>
+impl<B, S, W> Server<B, S, W>
 where
-    B: Blockchain + std::fmt::Debug,
-    Error: From<<B as Blockchain>::Error>,
-    S: Signer + std::fmt::Debug,
+    B: Blockchain + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<B as TxSource>::Error>,
+    S: Signer + std::fmt::Debug + Send + Sync + 'static,
     Error: From<<S as Signer>::Error>,
+    W: ReceiverWallet + std::fmt::Debug + Send + Sync + 'static,
+    Error: From<<W as ReceiverWallet>::Error>,
 {
+    /// Listen on `bind` for payments of `expected_amount` satoshis. The receiving script and the
+    /// UTXOs contributed to every session are taken from `wallet`
+    ///
+    /// Everything else has its default, use a `ServerBuilder` to change it upfront.
     pub async fn new<A: ToSocketAddrs>(
         bind: A,
         blockchain: B,
         signer: S,
-        our_utxo: OutPoint,
-        expected_script: Script,
+        wallet: W,
         expected_amount: u64,
-    ) -> Result<Server<B, S>, Error> {
-        Ok(Server {
-            listener: TcpListener::bind(bind).await?,
-            blockchain,
-            signer,
+    ) -> Result<Server<B, S, W>, Error> {
+        ServerBuilder::new(blockchain, signer, wallet, expected_amount)
+            .bind(bind)
+            .await
+    }
 
-            our_utxo,
-            our_txout: TxOut {
-                script_pubkey: expected_script,
-                value: expected_amount,
-            },
+    pub fn set_config(&mut self, config: ServerConfig) {
+        self.locks.set_timeout(config.utxo_lock_timeout);
+        self.config = config;
+    }
+
+    /// Number of payments completed so far
+    pub fn payments(&self) -> u32 {
+        self.payments
+    }
+
+    /// Final transactions of the sessions completed in a dry run, which were never broadcast
+    pub fn dry_runs(&self) -> &[Transaction] {
+        &self.dry_runs
+    }
+
+    /// Account for a successful payment and prepare for the next one, moving to a fresh script if
+    /// `used_script` is set. Returns `false` if the server should stop
+    fn payment_completed(&mut self, txid: &Txid, used_script: bool) -> bool {
+        info!("Completed payment with txid: {}", txid);
+        self.payments += 1;
+        self.control.set_payments(self.payments);
+
+        if !self.config.keep_running {
+            return false;
+        } else if let Some(max_payments) = self.config.max_payments {
+            if self.payments >= max_payments {
+                info!("Reached the maximum number of payments");
+                return false;
+            }
+        }
+        if !used_script {
+            return true;
+        }
+
+        match self.wallet.next_script() {
+            Ok(script) => {
+                debug!("Next payment will be received on {}", script);
+
+                self.our_txout.script_pubkey = script;
+                if self.endpoint.is_some() {
+                    self.control.set_invoice(
+                        self.invoice(&self.our_txout).ok(),
+                        self.config.require_token,
+                    );
+                }
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "No fresh script available for the next payment: {:?}",
+                    Error::from(e)
+                );
+                false
+            }
+        }
+    }
+
+    /// Account for a new connection from `peer`. Returns `false` if it should be dropped
+    fn accept_peer(&mut self, peer: SocketAddr) -> bool {
+        let max = match self.config.max_connections_per_peer {
+            Some(max) => max,
+            None => return true,
+        };
+
+        let now = Instant::now();
+        // Forget the peers without recent connections
+        self.peers.retain(|_, limiter| {
+            limiter.prune(RATE_WINDOW, now);
+            !limiter.is_empty()
+        });
+        if self
+            .peers
+            .entry(peer.ip())
+            .or_default()
+            .try_acquire(max, RATE_WINDOW, now)
+        {
+            true
+        } else {
+            warn!("Too many connections from {}, dropping", peer.ip());
+            false
+        }
+    }
+
+    /// Keep an eye on the final transaction of a completed session and account for the payment.
+    /// Returns `false` if the server should stop
+    fn session_completed(&mut self, transaction: Transaction) -> bool {
+        let txid = transaction.txid();
+        // Invoices have scripts of their own, leaving ours unused
+        let used_script = transaction
+            .output
+            .iter()
+            .any(|txout| txout.script_pubkey == self.our_txout.script_pubkey);
+        self.metrics.session_completed();
+        if self.config.dry_run {
+            self.dry_runs.push(transaction);
+            // Nothing was paid to our script, so there's no need for a fresh one
+            return self.payment_completed(&txid, false);
+        }
+        tokio::spawn(scheduled_broadcast(
+            Arc::clone(&self.blockchain),
+            transaction,
+            self.config.broadcast_delay,
+        ));
+
+        self.payment_completed(&txid, used_script)
+    }
+
+    fn new_session(&self) -> ServerState<Instrumented<B>, S, W> {
+        self.metrics.session_started();
+        self.observer.on_event(&SessionEvent::Connected);
+        ServerState::new(
+            self.our_txout.clone(),
+            Arc::clone(&self.blockchain),
+            Arc::clone(&self.signer),
+            Arc::clone(&self.wallet),
+        )
+        .with_config(self.config.clone())
+        .with_decoys(Arc::clone(&self.decoys))
+        .with_decoy_source(self.decoy_source.clone())
+        .with_chain(self.chain.clone())
+        .with_sessions(Arc::clone(&self.sessions))
+        .with_proofs(Arc::clone(&self.proofs))
+        .with_locks(self.locks.clone())
+        .with_tokens(self.tokens.clone())
+        .with_invoices(self.invoices.clone())
+        .with_endpoint(self.endpoint.clone())
+        .with_expiry(self.expires_at)
+        .with_observer(Arc::clone(&self.observer))
+        .with_disclosures(self.disclosures.clone())
+    }
+
+    fn session_failed(&self, error: &Error) {
+        warn!("{:?}", error);
+        self.metrics.session_failed(error);
+        self.observer.on_error(error);
+    }
+
+    /// Report the sessions to `metrics`, like a `Counters` shared with a monitoring endpoint
+    pub fn set_metrics<M: Metrics + 'static>(&mut self, metrics: Arc<M>) {
+        self.metrics = metrics;
+    }
+
+    /// Report the progress of every session to `observer`, like the UI of a wallet
+    pub fn set_observer<O: SessionObserver + 'static>(&mut self, observer: Arc<O>) {
+        self.observer = observer;
+    }
+
+    /// Set where sessions are saved to be resumed by the senders. Defaults to a `MemoryStore`,
+    /// which doesn't survive a restart of the server
+    pub fn set_session_store<T: SessionStore + 'static>(&mut self, sessions: T) {
+        self.sessions = Arc::new(sessions);
+    }
+
+    /// Set where the inputs of the proofs are recorded, to reject the proofs replaying them (see
+    /// `replay`). Defaults to a `MemoryProofStore`, which doesn't survive a restart of the server
+    pub fn set_proof_store<T: ProofStore + 'static>(&mut self, proofs: T) {
+        self.proofs = Arc::new(proofs);
+    }
+
+    /// Set how the decoy UTXOs are picked among the ones returned by the `Blockchain`. Defaults to
+    /// a `DecoyFilter` with its default settings
+    pub fn set_decoy_selector<D: DecoySelector + 'static>(&mut self, decoys: D) {
+        self.decoys = Arc::new(decoys);
+    }
+
+    /// Draw the decoy UTXOs from `decoy_source`, like a `UtxoSnapshot`, rather than from the
+    /// `Blockchain`. The decoys it returns are still checked to be unspent through the `Blockchain`
+    pub fn set_decoy_source<D: DecoySource + 'static>(&mut self, decoy_source: D) {
+        self.decoy_source = Some(Arc::new(decoy_source));
+    }
+
+    /// Pay part of every payment on to a second receiver, in the same transaction. Experimental,
+    /// see `multiparty`
+    pub fn set_chain(&mut self, chain: Chain) {
+        self.chain = Some(chain);
+    }
+
+    /// Address the server is listening on, before going through the transport
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Locks on the receiver UTXOs, shared by all the sessions of this server
+    pub fn utxo_locks(&self) -> UtxoLocks {
+        self.locks.clone()
+    }
+
+    /// Token to stop the server from another task. Once cancelled, the main loops drop the
+    /// sessions in progress, tear down the transport and return `Ok(())`
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// What the control interface sees of the server, to drive it from the same process
+    pub fn control_handle(&self) -> ControlHandle {
+        self.control.clone()
+    }
+
+    /// Serve the control interface on a Unix socket at `path`, until the server is stopped. See
+    /// `control` for the requests it takes
+    #[cfg(unix)]
+    pub fn listen_control<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), Error> {
+        self.control.listen(path, self.shutdown.clone())
+    }
+
+    /// Record the messages of every session in a new transcript of `transcripts`. Keep a clone of
+    /// it to read them while the server is running
+    pub fn set_transcripts(&mut self, transcripts: TranscriptLog) {
+        self.transcripts = Some(transcripts);
+    }
+
+    /// Tear down the transport after the main loop has been cancelled
+    async fn stop(&self) -> Result<(), Error> {
+        info!("Shutting down");
+        self.transport.shutdown().await
+    }
 
-            tor_hs: None,
+    #[cfg(feature = "libtor")]
+    fn default_transport() -> Arc<dyn Transport> {
+        Arc::new(crate::transport::LibTor::default())
+    }
+
+    #[cfg(not(feature = "libtor"))]
+    fn default_transport() -> Arc<dyn Transport> {
+        Arc::new(crate::transport::Direct)
+    }
+
+    /// Set how the server is exposed to the senders. Must be called before `setup()`
+    pub fn set_transport<T: Transport + 'static>(&mut self, transport: T) {
+        self.transport = Arc::new(transport);
+    }
+
+    /// Resolve once the senders can reach the server, like when the descriptor of its onion
+    /// service has been published. Meant to be called after `setup()`, before sharing the URI
+    pub async fn wait_ready(&self) -> Result<(), Error> {
+        if self.endpoint.is_none() {
+            return Err(Error::Transport(
+                "The server hasn't been exposed yet".into(),
+            ));
+        }
+
+        timeout(self.config.bootstrap_timeout, self.transport.wait_ready())
+            .await
+            .unwrap_or(Err(Error::Timeout(TimeoutPhase::Bootstrap)))
+    }
+
+    /// Wait up to `threshold` for the payjoin `txid` to confirm, then bump its fee with a child
+    /// spending our output `vout` back to the same script, bringing both to `fee_rate`. Returns the
+    /// txid of the child, or `None` if no bump was needed
+    ///
+    /// Backends that don't track confirmations always get the bump.
+    pub async fn bump_fee(
+        &self,
+        txid: Txid,
+        vout: u32,
+        fee_rate: FeeRate,
+        threshold: Duration,
+    ) -> Result<Option<Txid>, Error> {
+        if let Ok(confirmed) = timeout(threshold, self.wait_for_confirmation(txid, 1)).await {
+            if confirmed? {
+                return Ok(None);
+            }
+        }
+
+        let blockchain = Arc::clone(&self.blockchain);
+        let signer = Arc::clone(&self.signer);
+        let child = run_blocking(move || -> Result<Transaction, Error> {
+            let parent = blockchain.get_tx(&txid)?;
+            let script_pubkey = parent
+                .output
+                .get(vout as usize)
+                .ok_or(Error::InsufficientFunds)?
+                .script_pubkey
+                .clone();
+            let child = child_transaction(
+                &*blockchain,
+                &*signer,
+                &parent,
+                vout,
+                script_pubkey,
+                fee_rate,
+            )?;
+            broadcast_with_retry(&*blockchain, &child)?;
+
+            Ok(child)
         })
+        .await??;
+        info!("Bumped the fee of {} with {}", txid, child.txid());
+
+        Ok(Some(child.txid()))
+    }
+
+    /// Wait for `txid`, usually a completed payjoin, to get `confirmations`, waking up whenever
+    /// the backend reports an update on it. Wrap it in a timeout to give up at some point
+    ///
+    /// Returns `false` right away if the backend doesn't track confirmations.
+    pub async fn wait_for_confirmation(
+        &self,
+        txid: Txid,
+        confirmations: u32,
+    ) -> Result<bool, Error> {
+        wait_for_confirmations(Arc::clone(&self.blockchain), txid, confirmations).await
+    }
+
+    /// Issue a new access token, to be included in a payment URI when `require_token` is set
+    pub fn issue_token(&self) -> String {
+        self.tokens.issue()
+    }
+
+    /// Public `host:port` of the server, once `setup()` has exposed it
+    pub fn endpoint(&self) -> Option<&str> {
+        self.endpoint.as_deref()
+    }
+
+    /// When the payment URIs expire, once `setup()` has started the clock of `invoice_expiry`
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
     }
 
-    fn start_tor(&mut self) -> Result<String, Error> {
-        let rand_string: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
+    /// Stop advertising the payment URI, once it has expired
+    fn invoice_expired(&self) {
+        info!("The invoice expired, no longer accepting payments");
+        self.control.set_invoice(None, self.config.require_token);
+    }
 
-        let mut dir = std::env::temp_dir();
-        dir.push(rand_string);
+    /// Expose the server and return its payment URI, with a fresh access token if `require_token`
+    /// is set
+    pub async fn setup(&mut self) -> Result<String, Error> {
+        // Both are added to the current time, a duration past what it can represent would panic
+        if let Some(max) = self.config.broadcast_delay.max() {
+            if Instant::now().checked_add(max).is_none()
+                || SystemTime::now().checked_add(max).is_none()
+            {
+                return Err(Error::InvalidConfig("broadcast_delay is too long"));
+            }
+        }
+        let expires_at = match self.config.invoice_expiry {
+            Some(expiry) => Some(
+                SystemTime::now()
+                    .checked_add(expiry)
+                    .ok_or(Error::InvalidConfig("invoice_expiry is too long"))?,
+            ),
+            None => None,
+        };
 
-        debug!("Using tempdir: {}", dir.display());
+        if self.endpoint.is_none() {
+            info!("Exposing the server...");
 
-        Tor::new()
-            .flag(TorFlag::DataDirectory(dir.to_str().unwrap().into()))
-            .flag(TorFlag::SocksPort(0))
-            .flag(TorFlag::HiddenServiceDir(
-                dir.join("hs").to_str().unwrap().into(),
-            ))
-            .flag(TorFlag::HiddenServiceVersion(HiddenServiceVersion::V3))
-            .flag(TorFlag::HiddenServicePort(
-                TorAddress::Port(9000),
-                None.into(),
-            ))
-            .start_background();
+            let local_addr = self.local_addr()?;
+            let exposed = timeout(
+                self.config.bootstrap_timeout,
+                self.transport.expose(local_addr, self.config.onion_port),
+            )
+            .await
+            .unwrap_or(Err(Error::Timeout(TimeoutPhase::Bootstrap)))?;
+            self.endpoint = Some(exposed);
+        }
+        if self.expires_at.is_none() {
+            self.expires_at = expires_at;
+        }
 
-        let hostname_file = dir.join("hs/hostname");
-        let mut attempts = 0;
+        let mut uri = self.invoice(&self.our_txout)?;
+        self.control
+            .set_invoice(Some(uri.clone()), self.config.require_token);
+        if self.config.require_token {
+            uri.token = Some(self.issue_token());
+        }
 
-        while attempts < 10 && !hostname_file.exists() {
-            debug!("Waiting for the HS hostname...");
-            std::thread::sleep(Duration::from_secs(1));
+        Ok(uri.to_string())
+    }
 
-            attempts += 1;
+    /// Payment URI for `txout`, without any token. Donation URIs have no amount
+    fn invoice(&self, txout: &TxOut) -> Result<Bip21Uri, Error> {
+        let address = Address::from_script(&txout.script_pubkey, self.config.network)
+            .ok_or(Bip21Error::UnsupportedScript)?;
+        let mut uri = Bip21Uri::new(address);
+        if !self.config.donations {
+            uri.amount = Some(txout.value);
         }
+        uri.endpoint = self.endpoint.clone();
+        uri.exp = self.expires_at.map(|expires_at| {
+            expires_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+
+        Ok(uri)
+    }
 
-        let mut file = File::open(hostname_file)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        contents = contents.trim().into();
+    /// Wait for the payment of `invoice` on the same endpoint, next to the default one and to the
+    /// other invoices. Returns its payment URI, with the identifier of the invoice and a fresh
+    /// access token if `require_token` is set
+    ///
+    /// Meant to be called after `setup()`, so that the URI has the endpoint. Every invoice is
+    /// paid only once, and its UTXO, if any, is contributed by one session at a time.
+    pub fn add_invoice(&self, invoice: Invoice) -> Result<Bip21Uri, Error> {
+        let mut uri = self.invoice(&invoice.txout)?;
+        uri.invoice = Some(self.invoices.insert(invoice));
+        if self.config.require_token {
+            uri.token = Some(self.issue_token());
+        }
 
-        debug!("HS: {}", contents);
-        self.tor_hs = Some(contents.clone());
+        Ok(uri)
+    }
 
-        Ok(contents)
+    /// Stop waiting for the invoice `id`. Returns `false` if it was unknown or already paid
+    pub fn remove_invoice(&self, id: &str) -> bool {
+        self.invoices.remove(id).is_some()
     }
 
-    pub fn setup(&mut self, network: Network) -> Result<String, Error> {
-        if self.tor_hs.is_none() {
-            info!("Starting Tor...");
-            self.start_tor()?;
+    /// Invoices still waiting for their payment
+    pub fn invoices(&self) -> Invoices {
+        self.invoices.clone()
+    }
+
+    /// Sign an `Offer` of one of our UTXOs for the expected amount, so that it can be paid while
+    /// the server is offline. Returns its payment URI, which also has the endpoint once `setup()`
+    /// has been called
+    ///
+    /// The UTXO stays locked until the offer is taken: call `claim_offers()` to find out.
+    pub async fn publish_offer(&mut self) -> Result<Bip21Uri, Error> {
+        let our_txout = self.our_txout.clone();
+        let locks = self.locks.clone();
+        let signer = Arc::clone(&self.signer);
+        let wallet = Arc::clone(&self.wallet);
+        let (offer, guard) = run_blocking(move || -> Result<_, Error> {
+            let (guard, prev_out) = locks
+                .lock_next(|| wallet.next_utxo(our_txout.value, &locks))?
+                .ok_or(ProtocolError::Busy)?;
+            let utxo = guard.utxo();
+            let offer = Offer::new(
+                utxo,
+                prev_out,
+                our_txout.script_pubkey,
+                our_txout.value,
+                &*signer,
+            )?;
+
+            Ok((offer, guard))
+        })
+        .await??;
+
+        let mut uri = self.invoice(&self.our_txout)?;
+        // The amount is fixed by the signature, even for donations
+        uri.amount = Some(offer.amount());
+        uri.offer = Some(offer.to_string());
+        if self.endpoint.is_some() && self.config.require_token {
+            uri.token = Some(self.issue_token());
         }
+        self.offers.push((offer, guard));
 
-        Ok(format!(
-            "bitcoin:{}?amount={}&endpoint={}",
-            Address::from_script(&self.our_txout.script_pubkey, network).unwrap(),
-            self.our_txout.value,
-            self.tor_hs.as_ref().unwrap()
-        ))
+        Ok(uri)
+    }
+
+    /// Account for the offers taken since the last call, returning the txids of their payments
+    ///
+    /// Offers whose UTXO has been spent by something else are dropped. Backends that can't look
+    /// up the spending transactions (see `TxSource::get_spending_tx()`) never see them taken.
+    pub async fn claim_offers(&mut self) -> Result<Vec<Txid>, Error> {
+        let utxos = self
+            .offers
+            .iter()
+            .map(|(offer, _)| offer.utxo())
+            .collect::<Vec<_>>();
+        let blockchain = Arc::clone(&self.blockchain);
+        let spending_txs = run_blocking(move || -> Result<Vec<_>, Error> {
+            utxos
+                .iter()
+                .map(|utxo| Ok(blockchain.get_spending_tx(utxo)?))
+                .collect()
+        })
+        .await??;
+
+        let mut claimed = Vec::new();
+        for ((offer, mut guard), spending_tx) in std::mem::take(&mut self.offers)
+            .into_iter()
+            .zip(spending_txs)
+        {
+            let transaction = match spending_tx {
+                Some(transaction) => transaction,
+                None => {
+                    self.offers.push((offer, guard));
+                    continue;
+                }
+            };
+            guard.spent();
+
+            if transaction.output.contains(offer.txout()) {
+                claimed.push(transaction);
+            } else {
+                warn!(
+                    "UTXO {} of an offer spent by {}",
+                    offer.utxo(),
+                    transaction.txid()
+                );
+            }
+        }
+
+        let mut txids = Vec::new();
+        for transaction in claimed {
+            let txid = transaction.txid();
+            let used_script = transaction
+                .output
+                .iter()
+                .any(|txout| txout.script_pubkey == self.our_txout.script_pubkey);
+            self.payment_completed(&txid, used_script);
+            txids.push(txid);
+        }
+
+        Ok(txids)
     }
 
     pub async fn mainloop(&mut self) -> Result<(), Error> {
-        self.setup(Network::Regtest)?;
+        self.setup().await?;
 
         info!("Server running!");
 
+        let shutdown = self.shutdown.clone();
+        let expired = expiry(self.expires_at);
+        tokio::pin!(expired);
         loop {
-            let (mut stream, _) = self.listener.accept().await?;
+            let (stream, peer) = tokio::select! {
+                accepted = self.listener.accept() => accepted?,
+                _ = &mut expired => {
+                    self.invoice_expired();
+                    break;
+                }
+                _ = shutdown.cancelled() => return self.stop().await,
+            };
+            if !self.accept_peer(peer) {
+                continue;
+            }
             debug!("Accepting connection");
 
+            let mut stream =
+                match accept(&*self.transport, stream, self.config.message_timeout).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("{:?}", e);
+                        continue;
+                    }
+                };
+
             // Handle in the same task on purpose, to avoid conflicts with multiple connections at
             // the same time
-            let state = ServerState::new(
-                self.our_utxo,
-                self.our_txout.clone(),
-                &self.blockchain,
-                &self.signer,
-            );
-            let mut jsonrpc = JsonRpc::new(&mut stream, state, Duration::from_secs(10));
-            match jsonrpc.mainloop().await {
-                Ok(_) => {
+            let state = self.new_session();
+            let mut jsonrpc = JsonRpc::new(&mut stream, state, self.config.message_timeout)
+                .with_session_timeout(self.config.session_timeout)
+                .with_max_message_size(self.config.max_message_size)
+                .with_transcript(self.transcripts.as_ref().map(TranscriptLog::start));
+            let session = self.control.start_session(Some(peer));
+            let result = tokio::select! {
+                result = jsonrpc.mainloop().instrument(info_span!("session", %peer)) => result,
+                _ = session.cancelled() => Err(Error::Cancelled),
+                _ = shutdown.cancelled() => return self.stop().await,
+            };
+            drop(session);
+            match result {
+                Ok(transaction) => {
                     // sleep a little bit to allow the client to read everything from the socket
                     // before closing it
 
-                    std::thread::sleep(Duration::from_secs(1));
+                    delay_for(Duration::from_secs(1)).await;
+                    if !self.session_completed(transaction) {
+                        break;
+                    }
+                }
+                Err(e) => self.session_failed(&e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Negotiate a single payment over a stream that is already connected to the sender, like a
+    /// Unix socket or an in-memory pipe. The listener and the transport are not used
+    pub async fn serve<T: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        mut stream: T,
+    ) -> Result<Txid, Error> {
+        let state = self.new_session();
+        let mut jsonrpc = JsonRpc::new(&mut stream, state, self.config.message_timeout)
+            .with_session_timeout(self.config.session_timeout)
+            .with_max_message_size(self.config.max_message_size)
+            .with_transcript(self.transcripts.as_ref().map(TranscriptLog::start));
+        let session = self.control.start_session(None);
+        let result = tokio::select! {
+            result = jsonrpc.mainloop().instrument(info_span!("session")) => result,
+            _ = session.cancelled() => Err(Error::Cancelled),
+        };
+        drop(session);
+        let transaction = result.inspect_err(|e| self.session_failed(e))?;
+        let txid = transaction.txid();
+        self.session_completed(transaction);
+
+        Ok(txid)
+    }
+
+    /// Like `mainloop()`, but negotiates with up to `max_sessions` senders at the same time
+    ///
+    /// Every connection is handled in its own task. Each session locks the UTXO it picked from the
+    /// wallet once it validates a proof, so that it can't be contributed twice.
+    pub async fn mainloop_concurrent(&mut self, max_sessions: usize) -> Result<(), Error> {
+        self.setup().await?;
+
+        info!(
+            "Server running with up to {} concurrent sessions!",
+            max_sessions
+        );
+
+        let sessions = Arc::new(Semaphore::new(max_sessions));
+        let (completed_sender, mut completed) = mpsc::unbounded_channel();
+        let shutdown = self.shutdown.clone();
+        let expired = expiry(self.expires_at);
+        tokio::pin!(expired);
+
+        loop {
+            let permit = tokio::select! {
+                Some(transaction) = completed.recv() => {
+                    if self.session_completed(transaction) {
+                        continue;
+                    }
+                    break;
+                }
+                permit = Arc::clone(&sessions).acquire_owned() => permit,
+                _ = &mut expired => {
+                    self.invoice_expired();
+                    break;
+                }
+                _ = shutdown.cancelled() => return self.stop().await,
+            };
+            let (stream, peer) = tokio::select! {
+                Some(transaction) = completed.recv() => {
+                    if self.session_completed(transaction) {
+                        continue;
+                    }
+                    break;
+                }
+                accepted = self.listener.accept() => accepted?,
+                _ = &mut expired => {
+                    self.invoice_expired();
                     break;
                 }
-                Err(e) => warn!("{:?}", e),
+                _ = shutdown.cancelled() => return self.stop().await,
+            };
+            if !self.accept_peer(peer) {
+                continue;
             }
+            debug!("Accepting connection");
+
+            let state = self.new_session();
+            let transport = Arc::clone(&self.transport);
+            let metrics = Arc::clone(&self.metrics);
+            let observer = Arc::clone(&self.observer);
+            let completed_sender = completed_sender.clone();
+            let message_timeout = self.config.message_timeout;
+            let session_timeout = self.config.session_timeout;
+            let max_message_size = self.config.max_message_size;
+            let transcript = self.transcripts.as_ref().map(TranscriptLog::start);
+            let session = self.control.start_session(Some(peer));
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+
+                let mut stream = match accept(&*transport, stream, message_timeout).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("{:?}", e);
+                        return;
+                    }
+                };
+                let mut jsonrpc = JsonRpc::new(&mut stream, state, message_timeout)
+                    .with_session_timeout(session_timeout)
+                    .with_max_message_size(max_message_size)
+                    .with_transcript(transcript);
+                let result = tokio::select! {
+                    result = jsonrpc.mainloop().instrument(info_span!("session", %peer)) => result,
+                    _ = session.cancelled() => Err(Error::Cancelled),
+                    _ = shutdown.cancelled() => return,
+                };
+                drop(session);
+                match result {
+                    Ok(transaction) => {
+                        // sleep a little bit to allow the client to read everything from the
+                        // socket before closing it
+                        delay_for(Duration::from_secs(1)).await;
+                        let _ = completed_sender.send(transaction);
+                    }
+                    Err(e) => {
+                        warn!("{:?}", e);
+                        metrics.session_failed(&e);
+                        observer.on_error(&e);
+                    }
+                }
+            });
         }
 
         Ok(())
     }
+
+    /// Release the UTXOs of the BIP78 proposals that haven't been broadcast before their lock
+    /// expired. The ones spent stay locked for good
+    #[cfg(feature = "bip78")]
+    async fn check_proposals(&mut self) -> Result<(), Error> {
+        let utxos = self
+            .proposals
+            .iter()
+            .map(UtxoGuard::utxo)
+            .collect::<Vec<_>>();
+        let blockchain = Arc::clone(&self.blockchain);
+        let unspent = run_blocking(move || -> Result<Vec<_>, Error> {
+            utxos
+                .iter()
+                .map(|utxo| Ok(blockchain.is_unspent(utxo)?))
+                .collect()
+        })
+        .await??;
+
+        for (mut guard, unspent) in std::mem::take(&mut self.proposals).into_iter().zip(unspent) {
+            if !unspent {
+                guard.spent();
+            } else if guard.is_expired() {
+                debug!("Proposal spending {} never broadcast", guard.utxo());
+            } else {
+                self.proposals.push(guard);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "bip78")]
+    pub async fn mainloop_bip78(&mut self) -> Result<(), Error> {
+        use crate::protocol::bip78;
+
+        self.setup().await?;
+
+        info!("Server running in BIP78 mode!");
+
+        let shutdown = self.shutdown.clone();
+        let expired = expiry(self.expires_at);
+        tokio::pin!(expired);
+        loop {
+            let (stream, peer) = tokio::select! {
+                accepted = self.listener.accept() => accepted?,
+                _ = &mut expired => {
+                    self.invoice_expired();
+                    break;
+                }
+                _ = shutdown.cancelled() => return self.stop().await,
+            };
+            if let Err(e) = self.check_proposals().await {
+                warn!("Unable to check the previous proposals: {:?}", e);
+            }
+            if !self.accept_peer(peer) {
+                continue;
+            }
+            debug!("Accepting connection");
+
+            let mut stream =
+                match accept(&*self.transport, stream, self.config.message_timeout).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("{:?}", e);
+                        continue;
+                    }
+                };
+
+            self.metrics.session_started();
+            self.observer.on_event(&SessionEvent::Connected);
+            let original = timeout(
+                self.config.message_timeout,
+                bip78::receive_original(&mut stream),
+            )
+            .await
+            .unwrap_or(Err(Error::Timeout(TimeoutPhase::Message)));
+            let result = match original {
+                // Every proposal reveals one of our UTXOs
+                Ok(_)
+                    if !self
+                        .disclosures
+                        .try_acquire(self.config.max_disclosures_per_hour) =>
+                {
+                    Err(ProtocolError::RateLimited.into())
+                }
+                Ok(original) => {
+                    let our_txout = self.our_txout.clone();
+                    let locks = self.locks.clone();
+                    let blockchain = Arc::clone(&self.blockchain);
+                    let signer = Arc::clone(&self.signer);
+                    let wallet = Arc::clone(&self.wallet);
+
+                    run_blocking(move || -> Result<_, Error> {
+                        let (guard, _) = locks
+                            .lock_next(|| wallet.next_utxo(our_txout.value, &locks))?
+                            .ok_or(ProtocolError::Busy)?;
+                        let our_utxo = guard.utxo();
+
+                        let proposal = bip78::process_original(
+                            original,
+                            our_utxo,
+                            &our_txout,
+                            &*blockchain,
+                            &*signer,
+                        )?;
+
+                        Ok((proposal, guard))
+                    })
+                    .await
+                    .and_then(|result| result)
+                }
+                Err(e) => Err(e),
+            };
+            let response = result.as_ref().map(|(proposal, _)| proposal);
+            if let Err(e) = bip78::respond(&mut stream, response).await {
+                self.session_failed(&e);
+                continue;
+            }
+
+            match result {
+                // The sender is responsible for broadcasting the payjoin transaction, but since all
+                // the inputs are segwit we already know its txid
+                Ok((proposal, guard)) => {
+                    // Locked until the sender broadcasts it, or until the lock expires
+                    self.proposals.push(guard);
+                    let txid = proposal.global.unsigned_tx.txid();
+                    self.metrics.session_completed();
+                    self.observer.on_event(&SessionEvent::Completed { txid });
+                    if !self.payment_completed(&txid, true) {
+                        break;
+                    }
+                }
+                Err(e) => self.session_failed(&e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::common::{ProofPolicy, ProofTransactionError, ScriptType};
+    use crate::protocol::receiver::StateVariant;
+    use crate::{Capabilities, ProtocolError, Version};
+
+    #[tokio::test]
+    async fn test_repeated_proof() {
+        let payjoin = crate::testutil::Payjoin::new(3_000_000).await.unwrap();
+        let offered = || {
+            let mut state = payjoin.server.new_session();
+            state
+                .message(Request::Version {
+                    version: Version::CURRENT.to_string(),
+                    capabilities: Capabilities::empty(),
+                    token: None,
+                    invoice: None,
+                })
+                .unwrap();
+            match state.message(Request::Proof {
+                transaction: payjoin.proof.clone(),
+            }) {
+                Ok(Some(Response::Utxos { utxos, .. })) => utxos,
+                other => panic!("Unexpected response: {:?}", other),
+            }
+        };
+
+        // The UTXOs are unlocked once the first session is dropped
+        assert_eq!(offered(), offered());
+    }
+
+    #[tokio::test]
+    async fn test_distinct_decoys() {
+        // Far more sets wanted by default than the decoys around
+        for &receiver_inputs in &[1, 2] {
+            let mut payjoin = crate::testutil::Payjoin::new(3_000_000).await.unwrap();
+            payjoin.server.set_config(ServerConfig {
+                receiver_inputs,
+                ..Default::default()
+            });
+            let mut state = payjoin.server.new_session();
+            state
+                .message(Request::Version {
+                    version: Version::CURRENT.to_string(),
+                    capabilities: Capabilities::empty(),
+                    token: None,
+                    invoice: None,
+                })
+                .unwrap();
+            let utxos = match state.message(Request::Proof {
+                transaction: payjoin.proof.clone(),
+            }) {
+                Ok(Some(Response::Utxos { utxos, .. })) => utxos,
+                other => panic!("Unexpected response: {:?}", other),
+            };
+
+            let distinct = utxos.iter().collect::<HashSet<_>>();
+            assert_eq!(distinct.len(), utxos.len());
+            // Fewer full sets instead
+            assert_eq!(utxos.len() % receiver_inputs, 0);
+            assert!(utxos.len() < ServerConfig::default().min_decoys * receiver_inputs);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invoices() {
+        let payjoin = crate::testutil::Payjoin::new(3_000_000).await.unwrap();
+        let (_, script) = crate::testutil::p2wpkh_key(3);
+        let utxo = payjoin.blockchain.fund(script.clone(), 50_000_000);
+        let txout = TxOut {
+            script_pubkey: script.clone(),
+            value: 1_000_000,
+        };
+        let uri = payjoin
+            .server
+            .add_invoice(Invoice {
+                txout: txout.clone(),
+                utxo: Some((
+                    utxo,
+                    TxOut {
+                        script_pubkey: script,
+                        value: 50_000_000,
+                    },
+                )),
+            })
+            .unwrap();
+        assert_eq!(uri.amount, Some(1_000_000));
+        let id = uri.invoice.unwrap();
+
+        let version = |invoice: Option<String>| {
+            let mut state = payjoin.server.new_session();
+            state
+                .message(Request::Version {
+                    version: Version::CURRENT.to_string(),
+                    capabilities: Capabilities::empty(),
+                    token: None,
+                    invoice,
+                })
+                .map(|_| state)
+        };
+        assert!(matches!(
+            version(Some("unknown".into())),
+            Err(Error::Protocol(ProtocolError::UnknownInvoice))
+        ));
+
+        let mut state = version(Some(id.clone())).unwrap();
+        match state.message(Request::Proof {
+            transaction: payjoin.proof.clone(),
+        }) {
+            Ok(Some(Response::Utxos { utxos, .. })) => assert!(utxos.contains(&utxo)),
+            other => panic!("Unexpected response: {:?}", other),
+        }
+        // Contributed by one session at a time
+        let mut other = version(Some(id.clone())).unwrap();
+        assert!(matches!(
+            other.message(Request::Proof {
+                transaction: payjoin.proof.clone(),
+            }),
+            Err(Error::Protocol(ProtocolError::Busy))
+        ));
+
+        assert!(payjoin.server.remove_invoice(&id));
+        assert!(payjoin.server.invoices().ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_out_of_order() {
+        let payjoin = crate::testutil::Payjoin::new(3_000_000).await.unwrap();
+        let expected = |result| match result {
+            Err(Error::Protocol(ProtocolError::Expected(expected))) => expected,
+            other => panic!("Unexpected result: {:?}", other),
+        };
+
+        let mut state = payjoin.server.new_session();
+        let proof = Request::Proof {
+            transaction: payjoin.proof.clone(),
+        };
+        assert_eq!(expected(state.message(proof)), "VERSION");
+
+        let mut state = payjoin.server.new_session();
+        state
+            .message(Request::Version {
+                version: Version::CURRENT.to_string(),
+                capabilities: Capabilities::empty(),
+                token: None,
+                invoice: None,
+            })
+            .unwrap();
+        let witnesses = Request::Witnesses {
+            fees: 0,
+            amount: None,
+            change_script: Default::default(),
+            change_position: None,
+            payments: vec![],
+            receiver_input_positions: vec![0],
+            receiver_output_position: 0,
+            receiver_change_position: None,
+            witnesses: vec![],
+        };
+        assert_eq!(expected(state.message(witnesses)), "PROOF");
+    }
+
+    #[tokio::test]
+    async fn test_too_many_witnesses() {
+        let payjoin = crate::testutil::Payjoin::new(3_000_000).await.unwrap();
+        let mut state = payjoin.server.new_session();
+        state
+            .message(Request::Version {
+                version: Version::CURRENT.to_string(),
+                capabilities: Capabilities::empty(),
+                token: None,
+                invoice: None,
+            })
+            .unwrap();
+        let sets = match state.message(Request::Proof {
+            transaction: payjoin.proof.clone(),
+        }) {
+            Ok(Some(Response::Utxos { utxos, .. })) => utxos.len(),
+            other => panic!("Unexpected response: {:?}", other),
+        };
+
+        let result = state.message(Request::Witnesses {
+            fees: 0,
+            amount: None,
+            change_script: Default::default(),
+            change_position: None,
+            payments: vec![],
+            receiver_input_positions: vec![0],
+            receiver_output_position: 0,
+            receiver_change_position: None,
+            witnesses: vec![vec![]; sets + 1],
+        });
+        assert!(matches!(
+            result,
+            Err(Error::Protocol(ProtocolError::TooManyWitnesses))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_proof_policy() {
+        let mut payjoin = crate::testutil::Payjoin::new(3_000_000).await.unwrap();
+        let mut proof_with = |proof_policy: ProofPolicy| {
+            payjoin.server.set_config(ServerConfig {
+                proof_policy,
+                ..Default::default()
+            });
+
+            let mut state = payjoin.server.new_session();
+            state
+                .message(Request::Version {
+                    version: Version::CURRENT.to_string(),
+                    capabilities: Capabilities::empty(),
+                    token: None,
+                    invoice: None,
+                })
+                .unwrap();
+            match state.message(Request::Proof {
+                transaction: payjoin.proof.clone(),
+            }) {
+                Ok(_) => None,
+                Err(Error::Protocol(ProtocolError::InvalidProof(e))) => Some(e),
+                Err(e) => panic!("Unexpected error: {:?}", e),
+            }
+        };
+
+        assert_eq!(proof_with(ProofPolicy::default()), None);
+        assert_eq!(
+            proof_with(ProofPolicy {
+                max_inputs: Some(0),
+                ..Default::default()
+            }),
+            Some(ProofTransactionError::TooManyInputs)
+        );
+        assert_eq!(
+            proof_with(ProofPolicy {
+                min_value_percent: 1_000_000,
+                ..Default::default()
+            }),
+            Some(ProofTransactionError::InsufficientValue)
+        );
+        assert_eq!(
+            proof_with(ProofPolicy {
+                allowed_script_types: vec![ScriptType::P2tr],
+                ..Default::default()
+            }),
+            Some(ProofTransactionError::DisallowedInputType(0))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_access_token() {
+        let mut payjoin = crate::testutil::Payjoin::new(3_000_000).await.unwrap();
+        payjoin.server.set_config(ServerConfig {
+            require_token: true,
+            ..Default::default()
+        });
+        let version = |token: Option<String>| {
+            payjoin.server.new_session().message(Request::Version {
+                version: Version::CURRENT.to_string(),
+                capabilities: Capabilities::empty(),
+                token,
+                invoice: None,
+            })
+        };
+
+        let token = payjoin.server.issue_token();
+        assert!(matches!(
+            version(None),
+            Err(Error::Protocol(ProtocolError::InvalidToken))
+        ));
+        assert!(version(Some(token.clone())).is_ok());
+        // Already spent
+        assert!(matches!(
+            version(Some(token)),
+            Err(Error::Protocol(ProtocolError::InvalidToken))
+        ));
+    }
+
+    #[cfg(feature = "bip78")]
+    #[tokio::test]
+    async fn test_check_proposals() {
+        use crate::blockchain::Broadcaster;
+
+        let mut payjoin = crate::testutil::Payjoin::new(3_000_000).await.unwrap();
+        payjoin.server.set_config(ServerConfig {
+            utxo_lock_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+        let (_, script) = crate::testutil::p2wpkh_key(3);
+        let broadcast = payjoin.blockchain.fund(script.clone(), 100_000);
+        let abandoned = payjoin.blockchain.fund(script.clone(), 100_000);
+        for utxo in &[broadcast, abandoned] {
+            let guard = payjoin.server.locks.try_lock(*utxo).unwrap();
+            payjoin.server.proposals.push(guard);
+        }
+        payjoin
+            .blockchain
+            .broadcast(&Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![bitcoin::TxIn {
+                    previous_output: broadcast,
+                    ..Default::default()
+                }],
+                output: vec![],
+            })
+            .unwrap();
+
+        // The abandoned one is kept until its lock expires
+        payjoin.server.check_proposals().await.unwrap();
+        assert_eq!(payjoin.server.proposals.len(), 1);
+        assert!(payjoin.server.locks.is_locked(&abandoned));
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        payjoin.server.check_proposals().await.unwrap();
+        assert!(payjoin.server.proposals.is_empty());
+        assert!(payjoin.server.locks.is_locked(&broadcast));
+        assert!(!payjoin.server.locks.is_locked(&abandoned));
+    }
+
+    #[tokio::test]
+    async fn test_config_overflow() {
+        let mut payjoin = crate::testutil::Payjoin::new(3_000_000).await.unwrap();
+        for config in [
+            ServerConfig {
+                invoice_expiry: Some(Duration::MAX),
+                ..Default::default()
+            },
+            ServerConfig {
+                broadcast_delay: BroadcastDelay::Random(Duration::MAX),
+                ..Default::default()
+            },
+            ServerConfig {
+                broadcast_delay: BroadcastDelay::NextBlock(Duration::MAX),
+                ..Default::default()
+            },
+        ] {
+            payjoin.server.set_config(config);
+            assert!(matches!(
+                payjoin.server.setup().await,
+                Err(Error::InvalidConfig(_))
+            ));
+            assert!(payjoin.server.expires_at().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invoice_expiry() {
+        let mut payjoin = crate::testutil::Payjoin::new(3_000_000).await.unwrap();
+        payjoin.server.set_config(ServerConfig {
+            invoice_expiry: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        });
+        let uri = Bip21Uri::from_str(&payjoin.server.setup().await.unwrap()).unwrap();
+        assert!(uri.exp.is_some() && payjoin.server.expires_at().is_some());
+        assert!(!uri.is_expired());
+        let version = |server: &Server<_, _, _>| {
+            server.new_session().message(Request::Version {
+                version: Version::CURRENT.to_string(),
+                capabilities: Capabilities::empty(),
+                token: None,
+                invoice: None,
+            })
+        };
+        assert!(version(&payjoin.server).is_ok());
+
+        payjoin.server.expires_at = Some(SystemTime::now() - Duration::from_secs(1));
+        assert!(matches!(
+            version(&payjoin.server),
+            Err(Error::Protocol(ProtocolError::InvoiceExpired))
+        ));
+        assert!(payjoin
+            .server
+            .add_invoice(Invoice {
+                txout: payjoin.fallback.output[1].clone(),
+                utxo: None,
+            })
+            .unwrap()
+            .is_expired());
+        // Stops right away instead of waiting for connections
+        timeout(Duration::from_secs(5), payjoin.server.mainloop())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_session() {
+        let payjoin = crate::testutil::Payjoin::new(3_000_000).await.unwrap();
+
+        let mut state = payjoin.server.new_session();
+        let token = match state.message(Request::Version {
+            version: Version::CURRENT.to_string(),
+            capabilities: Capabilities::empty(),
+            token: None,
+            invoice: None,
+        }) {
+            Ok(Some(Response::Version {
+                session: Some(token),
+                ..
+            })) => token,
+            other => panic!("Unexpected response: {:?}", other),
+        };
+
+        let mut resumed = payjoin.server.new_session();
+        assert!(matches!(
+            resumed.message(Request::Resume { session: token }),
+            Ok(Some(Response::Version { .. }))
+        ));
+        assert!(matches!(resumed.state, StateVariant::ClientVersion { .. }));
+
+        let mut unknown = payjoin.server.new_session();
+        assert!(unknown
+            .message(Request::Resume {
+                session: crate::session::new_token(),
+            })
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proof_nonce() {
+        use std::convert::TryFrom;
+
+        use crate::common::{BoundProof, Created, ProofContext, ProofScheme, ProofTransaction};
+        use crate::demo::SoftwareSigner;
+        use crate::testutil::p2wpkh_key;
+        use crate::transport::Direct;
+
+        let mut payjoin = crate::testutil::Payjoin::new(3_000_000).await.unwrap();
+        payjoin.server.set_transport(Direct);
+        payjoin.server.setup().await.unwrap();
+        let session = || {
+            let mut state = payjoin.server.new_session();
+            match state.message(Request::Version {
+                version: Version::CURRENT.to_string(),
+                capabilities: Capabilities::BOUND_PROOF,
+                token: None,
+                invoice: None,
+            }) {
+                Ok(Some(Response::Version {
+                    nonce: Some(nonce), ..
+                })) => (state, nonce),
+                other => panic!("Unexpected response: {:?}", other),
+            }
+        };
+        let (mut victim, nonce) = session();
+        let (mut relayed, other_nonce) = session();
+        assert_ne!(nonce, other_nonce);
+
+        // Proof of the sender for its own session, relayed to another one
+        let (key, script) = p2wpkh_key(1);
+        let utxo = payjoin.fallback.input[0].previous_output;
+        let signer = SoftwareSigner::new(
+            key,
+            vec![(utxo, (100_000_000, script))].into_iter().collect(),
+        );
+        let proof_output = BoundProof.output(&ProofContext {
+            endpoint: payjoin.server.endpoint().map(String::from),
+            invoice: None,
+            nonce: Some(nonce),
+        });
+        let proof = ProofTransaction::<Created>::try_from((
+            payjoin.fallback.clone(),
+            &signer,
+            &proof_output,
+        ))
+        .unwrap()
+        .into_inner();
+
+        assert!(matches!(
+            relayed.message(Request::Proof {
+                transaction: proof.clone(),
+            }),
+            Err(Error::Protocol(ProtocolError::InvalidProof(
+                ProofTransactionError::InvalidProofOutput
+            )))
+        ));
+        assert!(victim
+            .message(Request::Proof { transaction: proof })
+            .is_ok());
+    }
 }
@@ -0,0 +1,183 @@
+//! Persistence of the server's sessions, so that negotiations can be resumed
+//!
+//! The server hands a token to the sender together with its VERSION response. If the connection
+//! drops, the sender can reconnect and send it back with a RESUME request: the server restores the
+//! session from its `SessionStore` and repeats its last response.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use rand::Rng;
+
+use serde::{Deserialize, Serialize};
+
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::{OutPoint, Transaction, TxOut};
+
+use crate::{Capabilities, Error, Response};
+
+/// Snapshot of a server session, saved after every state transition
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub version: String,
+    pub capabilities: Capabilities,
+    pub our_txout: TxOut,
+    /// Invoice picked by the sender, if any
+    #[serde(default)]
+    pub invoice: Option<String>,
+    /// Challenge sent in the VERSION response, that the proof commits to
+    #[serde(default)]
+    pub nonce: Option<String>,
+    /// Proof sent by the client, validated again when the session is resumed
+    #[serde(
+        default,
+        deserialize_with = "crate::from_hex_option",
+        serialize_with = "crate::to_hex_option"
+    )]
+    pub proof: Option<Transaction>,
+    pub utxos: Vec<OutPoint>,
+    pub our_utxos: Vec<OutPoint>,
+    pub our_utxos_position: usize,
+    /// Output taking back part of the value of our UTXOs, offered along with them
+    #[serde(default)]
+    pub change: Option<TxOut>,
+    /// Repeated to the client when it resumes the session
+    pub last_response: Response,
+}
+
+/// Generate a random session token
+pub fn new_token() -> String {
+    rand::thread_rng().gen::<[u8; 16]>().to_hex()
+}
+
+/// Tokens come from the client, make sure they're safe to use as keys and file names
+pub fn is_valid_token(token: &str) -> bool {
+    token.len() == 32 && token.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+pub trait SessionStore: std::fmt::Debug + Send + Sync {
+    fn save(&self, token: &str, session: &Session) -> Result<(), Error>;
+    fn load(&self, token: &str) -> Result<Option<Session>, Error>;
+    fn remove(&self, token: &str) -> Result<(), Error>;
+}
+
+/// Keep the sessions in memory, only surviving dropped connections
+#[derive(Debug, Default)]
+pub struct MemoryStore(Mutex<HashMap<String, Session>>);
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn save(&self, token: &str, session: &Session) -> Result<(), Error> {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(token.to_string(), session.clone());
+        Ok(())
+    }
+
+    fn load(&self, token: &str) -> Result<Option<Session>, Error> {
+        Ok(self.0.lock().unwrap().get(token).cloned())
+    }
+
+    fn remove(&self, token: &str) -> Result<(), Error> {
+        self.0.lock().unwrap().remove(token);
+        Ok(())
+    }
+}
+
+/// Keep every session in a JSON file named after its token, also surviving restarts
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    /// Store the sessions in the `path` directory, creating it if needed
+    pub fn new<P: Into<PathBuf>>(path: P) -> Result<Self, Error> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+
+        Ok(FileStore { path })
+    }
+
+    fn file(&self, token: &str) -> PathBuf {
+        self.path.join(format!("{}.json", token))
+    }
+}
+
+impl SessionStore for FileStore {
+    fn save(&self, token: &str, session: &Session) -> Result<(), Error> {
+        // Write and rename, so that a crash never leaves a truncated session behind
+        let tmp = self.path.join(format!("{}.tmp", token));
+        fs::write(&tmp, serde_json::to_vec(session)?)?;
+        fs::rename(tmp, self.file(token))?;
+
+        Ok(())
+    }
+
+    fn load(&self, token: &str) -> Result<Option<Session>, Error> {
+        match fs::read(self.file(token)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn remove(&self, token: &str) -> Result<(), Error> {
+        match fs::remove_file(self.file(token)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn test_file_store() {
+        let mut path = env::temp_dir();
+        path.push(format!("libp2ep-sessions-{}", new_token()));
+        let store = FileStore::new(&path).unwrap();
+
+        let token = new_token();
+        assert!(is_valid_token(&token));
+        assert!(!is_valid_token("../../etc/passwd"));
+
+        let session = Session {
+            version: "1.1".into(),
+            capabilities: Capabilities::supported(),
+            our_txout: Default::default(),
+            invoice: None,
+            nonce: None,
+            proof: None,
+            utxos: vec![Default::default()],
+            our_utxos: vec![Default::default()],
+            our_utxos_position: 0,
+            change: None,
+            last_response: Response::Utxos {
+                utxos: vec![Default::default()],
+                receiver_inputs: 1,
+                receiver_change: None,
+            },
+        };
+        store.save(&token, &session).unwrap();
+        let loaded = store.load(&token).unwrap().unwrap();
+        assert_eq!(loaded.utxos, session.utxos);
+
+        store.remove(&token).unwrap();
+        assert!(store.load(&token).unwrap().is_none());
+        fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,79 @@
+//! Cooperative shutdown of servers and clients
+//!
+//! `Server` and `Client` check a `CancellationToken` while they wait for connections and messages.
+//! Cancelling it stops the server loops cleanly, tearing down what the transport exposed, and makes
+//! a negotiation in progress fail with `Error::Cancelled`. Clones share the same state, so a token
+//! can be kept by the embedding application and handed to any number of endpoints.
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+
+        CancellationToken {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    /// Cancel the token and every clone of it. Cancelling more than once has no effect
+    pub fn cancel(&self) {
+        let _ = self.sender.broadcast(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolve once the token is cancelled
+    pub async fn cancelled(&self) {
+        let mut receiver = self.receiver.clone();
+        // The sender lives as long as we do, so the channel can't be closed
+        while let Some(cancelled) = receiver.recv().await {
+            if cancelled {
+                break;
+            }
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        CancellationToken::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancellation_token() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(timeout(Duration::from_millis(50), clone.cancelled())
+            .await
+            .is_err());
+
+        let waiting = tokio::spawn(async move { clone.cancelled().await });
+        token.cancel();
+        waiting.await.unwrap();
+        assert!(token.is_cancelled());
+
+        // Already cancelled
+        token.cancelled().await;
+    }
+}
@@ -1,12 +1,83 @@
 use bitcoin::blockdata::opcodes::all::*;
 use bitcoin::blockdata::script::Builder;
-use bitcoin::{Script, Transaction};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{Script, Transaction, TxOut};
 
 pub trait Signer {
     type Error;
 
     fn sign(&self, transaction: &mut Transaction, inputs: &[usize]) -> Result<(), Self::Error>;
 
+    /// Sign `inputs` of a transaction spending `prev_outs`, in the same order as its inputs
+    ///
+    /// Taproot signatures commit to all the spent outputs, including the ones of the other party,
+    /// so signers of P2TR inputs should override this method. The default implementation ignores
+    /// `prev_outs` and calls `sign()`.
+    fn sign_with_prev_outs(
+        &self,
+        transaction: &mut Transaction,
+        inputs: &[usize],
+        _prev_outs: &[TxOut],
+    ) -> Result<(), Self::Error> {
+        self.sign(transaction, inputs)
+    }
+
+    /// Sign and finalize `inputs` of a PSBT
+    ///
+    /// The default implementation signs the unsigned transaction with `sign_with_prev_outs()`, or
+    /// `sign()` if some witness UTXOs are missing, and moves the resulting witnesses in the PSBT.
+    /// PSBT-native signers (like hardware wallets) can override this method.
+    fn sign_psbt(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        inputs: &[usize],
+    ) -> Result<(), Self::Error> {
+        let mut transaction = psbt.global.unsigned_tx.clone();
+        let prev_outs = psbt
+            .inputs
+            .iter()
+            .map(|input| input.witness_utxo.clone())
+            .collect::<Option<Vec<_>>>();
+        match prev_outs {
+            Some(prev_outs) => self.sign_with_prev_outs(&mut transaction, inputs, &prev_outs)?,
+            None => self.sign(&mut transaction, inputs)?,
+        }
+
+        for index in inputs {
+            let input = &mut transaction.input[*index];
+            let psbt_input = &mut psbt.inputs[*index];
+
+            psbt_input.final_script_witness = Some(std::mem::take(&mut input.witness));
+            if !input.script_sig.is_empty() {
+                psbt_input.final_script_sig = Some(input.script_sig.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sign `inputs` with `SIGHASH_SINGLE | SIGHASH_ANYONECANPAY`, for the offers of the
+    /// non-interactive fallback (see `offer`). Returns `false` if the signer doesn't support it,
+    /// which is the default
+    fn sign_single_anyonecanpay(
+        &self,
+        _transaction: &mut Transaction,
+        _inputs: &[usize],
+    ) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    /// Sign `inputs` with `SIGHASH_ALL | SIGHASH_ANYONECANPAY`, for proofs whose signatures only
+    /// commit to their own input. Returns `false` if the signer doesn't support it, which is the
+    /// default
+    fn sign_all_anyonecanpay(
+        &self,
+        _transaction: &mut Transaction,
+        _inputs: &[usize],
+    ) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
     fn p2wpkh_scriptcode(script: &Script) -> Script {
         assert!(script.is_v0_p2wpkh());
 
@@ -19,4 +90,15 @@ pub trait Signer {
             .push_opcode(OP_CHECKSIG)
             .into_script()
     }
+
+    /// Witness spending a P2WSH multisig, given the signatures in the same order as the keys in
+    /// `witness_script`
+    fn multisig_witness(witness_script: &Script, signatures: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        // OP_CHECKMULTISIG pops one more element than it should
+        let mut witness = vec![vec![]];
+        witness.extend(signatures);
+        witness.push(witness_script.to_bytes());
+
+        witness
+    }
 }
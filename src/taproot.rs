@@ -0,0 +1,310 @@
+//! Key-path spending of v1 P2TR outputs (BIP340/BIP341)
+
+use bitcoin::consensus::serialize;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::{Script, Transaction, TxOut};
+
+use secp256k1_schnorr::schnorrsig::{KeyPair, PublicKey, Signature};
+use secp256k1_schnorr::{Message, Secp256k1, Signing, Verification};
+
+/// Signature hash type committing to all the inputs and outputs, without a trailing byte
+pub const SIGHASH_DEFAULT: u8 = 0x00;
+pub const SIGHASH_ALL: u8 = 0x01;
+
+fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    engine.input(data);
+
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+fn sha256_of<I: IntoIterator<Item = Vec<u8>>>(items: I) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    for item in items {
+        engine.input(&item);
+    }
+
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+pub fn is_p2tr(script: &Script) -> bool {
+    let bytes = script.as_bytes();
+
+    bytes.len() == 34 && bytes[0] == 0x51 && bytes[1] == 0x20
+}
+
+/// Signature hash for the key-path spend of input `index`. `prev_outs` must contain the outputs
+/// spent by all the inputs of `tx`, in order
+pub fn key_spend_sighash(
+    tx: &Transaction,
+    index: usize,
+    prev_outs: &[TxOut],
+    sighash_type: u8,
+) -> [u8; 32] {
+    assert_eq!(tx.input.len(), prev_outs.len());
+    assert!(sighash_type == SIGHASH_DEFAULT || sighash_type == SIGHASH_ALL);
+
+    let mut msg = vec![0x00, sighash_type];
+    msg.extend(&tx.version.to_le_bytes());
+    msg.extend(&tx.lock_time.to_le_bytes());
+    msg.extend(&sha256_of(
+        tx.input
+            .iter()
+            .map(|input| serialize(&input.previous_output)),
+    ));
+    msg.extend(&sha256_of(
+        prev_outs
+            .iter()
+            .map(|prev_out| prev_out.value.to_le_bytes().to_vec()),
+    ));
+    msg.extend(&sha256_of(
+        prev_outs
+            .iter()
+            .map(|prev_out| serialize(&prev_out.script_pubkey)),
+    ));
+    msg.extend(&sha256_of(
+        tx.input
+            .iter()
+            .map(|input| input.sequence.to_le_bytes().to_vec()),
+    ));
+    msg.extend(&sha256_of(tx.output.iter().map(serialize)));
+    // Key-path spend without annex
+    msg.push(0x00);
+    msg.extend(&(index as u32).to_le_bytes());
+
+    tagged_hash("TapSighash", &msg)
+}
+
+/// Tweak `keypair` with an empty script tree, so that it matches the key in the output
+pub fn tweak_keypair<C: Signing + Verification>(secp: &Secp256k1<C>, keypair: &mut KeyPair) {
+    let internal_key = PublicKey::from_keypair(secp, keypair);
+    let tweak = tagged_hash("TapTweak", &internal_key.serialize());
+
+    keypair
+        .tweak_add_assign(secp, &tweak)
+        .expect("the tweak is a valid scalar");
+}
+
+/// Build the witness for the key-path spend of input `index`
+pub fn sign_key_spend<C: Signing + Verification>(
+    secp: &Secp256k1<C>,
+    keypair: &KeyPair,
+    tx: &Transaction,
+    index: usize,
+    prev_outs: &[TxOut],
+) -> Vec<Vec<u8>> {
+    let hash = key_spend_sighash(tx, index, prev_outs, SIGHASH_DEFAULT);
+    // BIP340 derives the nonce of a signature without randomness from 32 zero bytes, while
+    // `schnorrsig_sign_no_aux_rand` skips that step in this version of `secp256k1`
+    let sig =
+        secp.schnorrsig_sign_with_aux_rand(&Message::from_slice(&hash).unwrap(), keypair, &[0; 32]);
+
+    vec![sig[..].to_vec()]
+}
+
+/// Verify the key-path spend of input `index`. Returns `false` for anything other than a valid
+/// signature
+///
+/// `secp256k1` 0.20 only exposes schnorr verification on signing-capable contexts.
+pub fn verify_key_spend<C: Signing>(
+    secp: &Secp256k1<C>,
+    tx: &Transaction,
+    index: usize,
+    prev_outs: &[TxOut],
+) -> bool {
+    let output_key = match PublicKey::from_slice(&prev_outs[index].script_pubkey.as_bytes()[2..]) {
+        Ok(output_key) => output_key,
+        Err(_) => return false,
+    };
+    let (signature, sighash_type) = match tx.input[index].witness.as_slice() {
+        [signature] if signature.len() == 64 => (&signature[..], SIGHASH_DEFAULT),
+        [signature] if signature.len() == 65 && signature[64] == SIGHASH_ALL => {
+            (&signature[..64], SIGHASH_ALL)
+        }
+        _ => return false,
+    };
+    let signature = match Signature::from_slice(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    let hash = key_spend_sighash(tx, index, prev_outs, sighash_type);
+    secp.schnorrsig_verify(
+        &signature,
+        &Message::from_slice(&hash).unwrap(),
+        &output_key,
+    )
+    .is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    use bitcoin::consensus::deserialize;
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::{OutPoint, PrivateKey, TxIn};
+
+    use super::*;
+    use crate::demo::SoftwareSigner;
+    use crate::signer::Signer;
+
+    #[test]
+    fn test_key_spend() {
+        let sk =
+            PrivateKey::from_str("KwDiBf89QgGbjEhKnhXJuH7LrciVrZi3qYjgd9M7rFU73sVHnoWn").unwrap();
+        let signer = SoftwareSigner::new(sk, HashMap::new());
+        let prev_out = TxOut {
+            value: 100_000,
+            script_pubkey: signer.p2tr_script(),
+        };
+        let utxo = OutPoint::default();
+        let signer = SoftwareSigner::new(
+            sk,
+            vec![(utxo, (prev_out.value, prev_out.script_pubkey.clone()))]
+                .into_iter()
+                .collect(),
+        );
+
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: utxo,
+                sequence: 0xFFFF_FFFF,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 90_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        signer.sign(&mut tx, &[0]).unwrap();
+
+        let secp = Secp256k1::new();
+        let prev_outs = vec![prev_out];
+        assert!(is_p2tr(&prev_outs[0].script_pubkey));
+        assert!(verify_key_spend(&secp, &tx, 0, &prev_outs));
+
+        tx.output[0].value -= 1;
+        assert!(!verify_key_spend(&secp, &tx, 0, &prev_outs));
+    }
+
+    /// `keyPathSpending` from the BIP341 wallet test vectors, for the inputs we can spend: the
+    /// tweak without a script tree, and the sighashes and signatures of `SIGHASH_DEFAULT` and
+    /// `SIGHASH_ALL`
+    #[test]
+    fn test_bip341_vectors() {
+        let hex = |s: &str| Vec::<u8>::from_hex(s).unwrap();
+        let tx: Transaction = deserialize(&hex("02000000097de20cbff686da83a54981d2b9bab3586f4ca7e48f57f5b55963115f3b334e9c010000000000000000d7b7cab57b1393ace2d064f4d4a2cb8af6def61273e127517d44759b6dafdd990000000000fffffffff8e1f583384333689228c5d28eac13366be082dc57441760d957275419a418420000000000fffffffff0689180aa63b30cb162a73c6d2a38b7eeda2a83ece74310fda0843ad604853b0100000000feffffffaa5202bdf6d8ccd2ee0f0202afbbb7461d9264a25e5bfd3c5a52ee1239e0ba6c0000000000feffffff956149bdc66faa968eb2be2d2faa29718acbfe3941215893a2a3446d32acd050000000000000000000e664b9773b88c09c32cb70a2a3e4da0ced63b7ba3b22f848531bbb1d5d5f4c94010000000000000000e9aa6b8e6c9de67619e6a3924ae25696bb7b694bb677a632a74ef7eadfd4eabf0000000000ffffffffa778eb6a263dc090464cd125c466b5a99667720b1c110468831d058aa1b82af10100000000ffffffff0200ca9a3b000000001976a91406afd46bcdfd22ef94ac122aa11f241244a37ecc88ac807840cb0000000020ac9a87f5594be208f8532db38cff670c450ed2fea8fcdefcc9a663f78bab962b0065cd1d")).unwrap();
+        let prev_outs = [
+            (
+                "512053a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343",
+                420000000,
+            ),
+            (
+                "5120147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3",
+                462000000,
+            ),
+            (
+                "76a914751e76e8199196d454941c45d1b3a323f1433bd688ac",
+                294000000,
+            ),
+            (
+                "5120e4d810fd50586274face62b8a807eb9719cef49c04177cc6b76a9a4251d5450e",
+                504000000,
+            ),
+            (
+                "512091b64d5324723a985170e4dc5a0f84c041804f2cd12660fa5dec09fc21783605",
+                630000000,
+            ),
+            ("00147dd65592d0ab2fe0d0257d571abf032cd9db93dc", 378000000),
+            (
+                "512075169f4001aa68f15bbed28b218df1d0a62cbbcf1188c6665110c293c907b831",
+                672000000,
+            ),
+            (
+                "5120712447206d7a5238acc7ff53fbe94a3b64539ad291c7cdbc490b7577e4b17df5",
+                546000000,
+            ),
+            (
+                "512077e30a5522dd9f894c3f8b8bd4c4b2cf82ca7da8a3ea6a239655c39c050ab220",
+                588000000,
+            ),
+        ]
+        .iter()
+        .map(|(script_pubkey, value)| TxOut {
+            value: *value,
+            script_pubkey: Script::from(hex(script_pubkey)),
+        })
+        .collect::<Vec<_>>();
+        let secp = Secp256k1::new();
+
+        // Input #0: the tweak of a key without a script tree
+        let mut keypair = KeyPair::from_seckey_slice(
+            &secp,
+            &hex("6b973d88838f27366ed61c9ad6367663045cb456e28335c109e30717ae0c6baa"),
+        )
+        .unwrap();
+        assert_eq!(
+            PublicKey::from_keypair(&secp, &keypair).serialize()[..],
+            hex("d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d")[..]
+        );
+        tweak_keypair(&secp, &mut keypair);
+        assert_eq!(
+            PublicKey::from_keypair(&secp, &keypair).serialize()[..],
+            prev_outs[0].script_pubkey.as_bytes()[2..]
+        );
+
+        // Inputs #3 and #4, signed with `SIGHASH_ALL` and `SIGHASH_DEFAULT`
+        for (index, private_key, merkle_root, sighash_type, sighash, signature) in [
+            (
+                3,
+                "d3c7af07da2d54f7a7735d3d0fc4f0a73164db638b2f2f7c43f711f6d4aa7e64",
+                "c525714a7f49c28aedbbba78c005931a81c234b2f6c99a73e4d06082adc8bf2b",
+                SIGHASH_ALL,
+                "bf013ea93474aa67815b1b6cc441d23b64fa310911d991e713cd34c7f5d46669",
+                "ff45f742a876139946a149ab4d9185574b98dc919d2eb6754f8abaa59d18b025637a3aa043b91817739554f4ed2026cf8022dbd83e351ce1fabc272841d2510a01",
+            ),
+            (
+                4,
+                "f36bb07a11e469ce941d16b63b11b9b9120a84d9d87cff2c84a8d4affb438f4e",
+                "ccbd66c6f7e8fdab47b3a486f59d28262be857f30d4773f2d5ea47f7761ce0e2",
+                SIGHASH_DEFAULT,
+                "4f900a0bae3f1446fd48490c2958b5a023228f01661cda3496a11da502a7f7ef",
+                "b4010dd48a617db09926f729e79c33ae0b4e94b79f04a1ae93ede6315eb3669de185a17d2b0ac9ee09fd4c64b678a0b61a0a86fa888a273c8511be83bfd6810f",
+            ),
+        ] {
+            let hash = key_spend_sighash(&tx, index, &prev_outs, sighash_type);
+            assert_eq!(hash[..], hex(sighash)[..]);
+
+            // These outputs commit to a script tree, which we don't support
+            let mut keypair = KeyPair::from_seckey_slice(&secp, &hex(private_key)).unwrap();
+            let internal_key = PublicKey::from_keypair(&secp, &keypair);
+            let mut data = internal_key.serialize().to_vec();
+            data.extend(hex(merkle_root));
+            keypair
+                .tweak_add_assign(&secp, &tagged_hash("TapTweak", &data))
+                .unwrap();
+            let witness = if sighash_type == SIGHASH_DEFAULT {
+                sign_key_spend(&secp, &keypair, &tx, index, &prev_outs).remove(0)
+            } else {
+                let message = Message::from_slice(&hash).unwrap();
+                let sig = secp.schnorrsig_sign_with_aux_rand(&message, &keypair, &[0; 32]);
+                let mut witness = sig[..].to_vec();
+                witness.push(sighash_type);
+                witness
+            };
+            assert_eq!(witness, hex(signature));
+
+            let mut signed = tx.clone();
+            signed.input[index].witness = vec![witness];
+            assert!(verify_key_spend(&secp, &signed, index, &prev_outs));
+        }
+    }
+}
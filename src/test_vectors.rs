@@ -0,0 +1,384 @@
+//! Canonical JSON of the protocol messages, for other implementations to check against
+//!
+//! `messages.json` has an example of every request, response and error, the way this library
+//! encodes them, and `exchange.json` the messages of a whole payjoin as seen by the sender. Both
+//! are in `test_vectors/` at the root of the repository, so that implementations in other
+//! languages can load them directly: parsing any of the messages and encoding it again must give
+//! back the same JSON.
+//!
+//! `check_transcript()` compares the messages of another session with the exchange: the same
+//! messages in the same order, each in canonical form and answered with the id of its request.
+//! Keys, amounts and transactions differ from one payjoin to the other and are not compared.
+//! `check_state()` runs a `JsonRpcState` against a peer and checks its side of the session the
+//! same way.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::jsonrpc::{JsonRpc, JsonRpcState};
+use crate::testutil::pipe;
+use crate::transcript::{Direction, Entry, Transcript};
+use crate::{Envelope, Error, Message, Request, Response};
+
+pub const MESSAGES: &str = include_str!("../test_vectors/messages.json");
+pub const EXCHANGE: &str = include_str!("../test_vectors/exchange.json");
+
+/// Timeout on every message of `check_state()`
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A message of `messages.json`, with the envelope
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Vector {
+    pub name: String,
+    pub message: Value,
+}
+
+/// A message of `exchange.json`, or of a session being checked against it
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Step {
+    pub direction: Direction,
+    pub message: Value,
+}
+
+impl From<&Entry> for Step {
+    fn from(entry: &Entry) -> Step {
+        Step {
+            direction: entry.direction,
+            message: entry.message.clone(),
+        }
+    }
+}
+
+pub fn messages() -> Vec<Vector> {
+    serde_json::from_str(MESSAGES).expect("Invalid `messages.json`")
+}
+
+/// The messages of a payjoin, as seen by the sender
+pub fn exchange() -> Vec<Step> {
+    serde_json::from_str(EXCHANGE).expect("Invalid `exchange.json`")
+}
+
+#[derive(Debug)]
+pub enum ConformanceError {
+    /// Message #n is not a valid message
+    InvalidMessage(usize),
+    /// Message #n is valid, but isn't encoded the way this library would
+    NotCanonical(usize),
+    /// The session has `found` messages instead of `expected`
+    Length { expected: usize, found: usize },
+    /// Message #n goes the wrong way
+    Direction(usize),
+    /// Message #n is a `found` instead of a `expected`
+    Kind {
+        index: usize,
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// Message #n doesn't have the id of the request it answers
+    Id(usize),
+    /// The session failed before the end of the exchange
+    Session(Error),
+}
+
+impl fmt::Display for ConformanceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConformanceError::InvalidMessage(index) => write!(f, "message #{} is invalid", index),
+            ConformanceError::NotCanonical(index) => {
+                write!(f, "message #{} is not in canonical form", index)
+            }
+            ConformanceError::Length { expected, found } => {
+                write!(f, "expected {} messages, found {}", expected, found)
+            }
+            ConformanceError::Direction(index) => {
+                write!(f, "message #{} goes the wrong way", index)
+            }
+            ConformanceError::Kind {
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "message #{} is a {} instead of a {}",
+                index, found, expected
+            ),
+            ConformanceError::Id(index) => {
+                write!(f, "message #{} doesn't answer the pending request", index)
+            }
+            ConformanceError::Session(_) => write!(f, "session failed"),
+        }
+    }
+}
+
+impl std::error::Error for ConformanceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConformanceError::Session(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// What a message is, regardless of its content
+fn kind(message: &Message) -> &'static str {
+    match message {
+        Message::Request { request } => match request {
+            Request::Version { .. } => "VERSION request",
+            Request::Resume { .. } => "RESUME request",
+            Request::Proof { .. } => "PROOF request",
+            Request::Witnesses { .. } => "WITNESSES request",
+            Request::Psbts { .. } => "PSBTS request",
+            Request::Cosign { .. } => "COSIGN request",
+        },
+        Message::Response { result } => match result {
+            Response::Version { .. } => "VERSION response",
+            Response::Utxos { .. } => "UTXOS response",
+            Response::Txid { .. } => "TXID response",
+        },
+        Message::Error { .. } => "error",
+    }
+}
+
+/// Parse message #`index`, making sure that encoding it again gives the same JSON
+pub fn check_canonical(index: usize, message: &Value) -> Result<Envelope, ConformanceError> {
+    let envelope = serde_json::from_value::<Envelope>(message.clone())
+        .map_err(|_| ConformanceError::InvalidMessage(index))?;
+    match serde_json::to_value(&envelope) {
+        Ok(encoded) if encoded == *message => Ok(envelope),
+        _ => Err(ConformanceError::NotCanonical(index)),
+    }
+}
+
+/// Check that `steps` are the same messages as `expected`, in the same order and directions
+pub fn check_transcript(steps: &[Step], expected: &[Step]) -> Result<(), ConformanceError> {
+    if steps.len() != expected.len() {
+        return Err(ConformanceError::Length {
+            expected: expected.len(),
+            found: steps.len(),
+        });
+    }
+
+    // Id of the last request, and the direction it went
+    let mut pending = None;
+    for (index, (step, expected)) in steps.iter().zip(expected).enumerate() {
+        if step.direction != expected.direction {
+            return Err(ConformanceError::Direction(index));
+        }
+
+        let envelope = check_canonical(index, &step.message)?;
+        let expected_kind = kind(&check_canonical(index, &expected.message)?.message);
+        let found = kind(&envelope.message);
+        if found != expected_kind {
+            return Err(ConformanceError::Kind {
+                index,
+                expected: expected_kind,
+                found,
+            });
+        }
+
+        match envelope.message {
+            Message::Request { .. } => pending = Some((envelope.id, step.direction)),
+            _ => match pending.take() {
+                Some((id, direction)) if direction != step.direction && id == envelope.id => {}
+                _ => return Err(ConformanceError::Id(index)),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Run `state` against `peer` and check the session against `exchange()`, from the side of
+/// `state`. Returns the response of `state` once the session is over
+pub async fn check_state<T, P>(state: T, peer: P) -> Result<T::Response, ConformanceError>
+where
+    T: JsonRpcState<Error = Error> + Send + 'static,
+    T::InMessage: Send + 'static,
+    T::OutMessage: Send + 'static,
+    <T::InMessage as TryFrom<Message>>::Error: fmt::Debug,
+    P: JsonRpcState<Error = Error> + Send + 'static,
+    P::InMessage: Send + 'static,
+    P::OutMessage: Send + 'static,
+    <P::InMessage as TryFrom<Message>>::Error: fmt::Debug,
+{
+    let (mut ours, mut theirs) = pipe().await.map_err(ConformanceError::Session)?;
+    let transcript = Transcript::new();
+
+    let mut jsonrpc =
+        JsonRpc::new(&mut ours, state, TIMEOUT).with_transcript(Some(transcript.clone()));
+    let mut peer = JsonRpc::new(&mut theirs, peer, TIMEOUT);
+    let (result, _) = tokio::join!(jsonrpc.mainloop(), peer.mainloop());
+    let response = result.map_err(ConformanceError::Session)?;
+
+    let steps = transcript
+        .entries()
+        .iter()
+        .map(Step::from)
+        .collect::<Vec<_>>();
+    let mut expected = exchange();
+    // The receiver sees the exchange the other way around
+    if steps.first().map(|step| step.direction) == Some(Direction::Received) {
+        for step in &mut expected {
+            step.direction = match step.direction {
+                Direction::Sent => Direction::Received,
+                Direction::Received => Direction::Sent,
+            };
+        }
+    }
+    check_transcript(&steps, &expected)?;
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    use bitcoin::{Transaction, TxIn, TxOut};
+
+    use super::*;
+    use crate::demo::SoftwareSigner;
+    use crate::protocol::receiver::ServerState;
+    use crate::protocol::sender::ClientState;
+    use crate::testutil::{p2wpkh_key, MockBlockchain};
+    use crate::wallet::SimpleReceiverWallet;
+
+    #[test]
+    fn test_messages() {
+        let vectors = messages();
+        let mut kinds = HashSet::new();
+        for (index, vector) in vectors.iter().enumerate() {
+            let envelope = check_canonical(index, &vector.message).unwrap();
+            kinds.insert(kind(&envelope.message));
+        }
+        // Every kind of message has at least one vector
+        assert_eq!(kinds.len(), 9);
+
+        let names = vectors
+            .iter()
+            .map(|vector| &vector.name)
+            .collect::<HashSet<_>>();
+        assert_eq!(names.len(), vectors.len());
+    }
+
+    #[test]
+    fn test_check_transcript() {
+        let expected = exchange();
+        check_transcript(&expected, &expected).unwrap();
+
+        let mut steps = expected.clone();
+        steps.swap(2, 4);
+        assert!(matches!(
+            check_transcript(&steps, &expected),
+            Err(ConformanceError::Kind { index: 2, .. })
+        ));
+
+        let mut steps = expected.clone();
+        steps[1].message["id"] = "42".into();
+        assert!(matches!(
+            check_transcript(&steps, &expected),
+            Err(ConformanceError::Id(1))
+        ));
+
+        // Unknown fields are ignored, but dropped when encoding the message again
+        let mut steps = expected.clone();
+        steps[0].message["extra"] = true.into();
+        assert!(matches!(
+            check_transcript(&steps, &expected),
+            Err(ConformanceError::NotCanonical(0))
+        ));
+
+        let mut steps = expected.clone();
+        steps[0].message = Value::String(steps[0].message.to_string());
+        assert!(matches!(
+            check_transcript(&steps, &expected),
+            Err(ConformanceError::InvalidMessage(0))
+        ));
+
+        assert!(matches!(
+            check_transcript(&expected[1..], &expected),
+            Err(ConformanceError::Length {
+                expected: 6,
+                found: 5
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_check_state() {
+        const UTXO_VALUE: u64 = 100_000_000;
+        const AMOUNT: u64 = 3_000_000;
+
+        let blockchain = Arc::new(MockBlockchain::new());
+        for seed in 10..20 {
+            blockchain.fund(p2wpkh_key(seed).1, UTXO_VALUE);
+        }
+        let (sender_key, sender_script) = p2wpkh_key(1);
+        let sender_utxo = blockchain.fund(sender_script.clone(), UTXO_VALUE);
+        let (receiver_key, receiver_script) = p2wpkh_key(2);
+        let receiver_utxo = blockchain.fund(receiver_script.clone(), UTXO_VALUE);
+
+        let base_transaction = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: sender_utxo,
+                sequence: 0xFFFF_FFFF,
+                ..Default::default()
+            }],
+            output: vec![
+                TxOut {
+                    script_pubkey: sender_script.clone(),
+                    value: UTXO_VALUE - AMOUNT - 5000,
+                },
+                TxOut {
+                    script_pubkey: receiver_script.clone(),
+                    value: AMOUNT,
+                },
+            ],
+        };
+        let sender_signer = SoftwareSigner::new(
+            sender_key,
+            vec![(sender_utxo, (UTXO_VALUE, sender_script))]
+                .into_iter()
+                .collect(),
+        );
+        let receiver_signer = SoftwareSigner::new(
+            receiver_key,
+            vec![(receiver_utxo, (UTXO_VALUE, receiver_script.clone()))]
+                .into_iter()
+                .collect(),
+        );
+        let wallet = SimpleReceiverWallet::new(
+            vec![(
+                receiver_utxo,
+                TxOut {
+                    script_pubkey: receiver_script.clone(),
+                    value: UTXO_VALUE,
+                },
+            )],
+            vec![receiver_script.clone()],
+        );
+
+        let client = ClientState::new(
+            base_transaction.clone(),
+            1,
+            Arc::clone(&blockchain),
+            Arc::new(sender_signer),
+        );
+        let server = ServerState::new(
+            base_transaction.output[1].clone(),
+            blockchain,
+            Arc::new(receiver_signer),
+            Arc::new(wallet),
+        );
+
+        let result = check_state(client, server).await.unwrap();
+        assert_eq!(result.receiver_input_count, 1);
+    }
+}
@@ -0,0 +1,1505 @@
+//! In-memory blockchain and end-to-end harness, to test the protocol without Electrum or Tor
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use rand::seq::IteratorRandom;
+
+use tokio::net::{TcpListener, TcpStream};
+
+use bitcoin::secp256k1::{Secp256k1, SecretKey};
+use bitcoin::{Address, Network, OutPoint, PrivateKey, Script, Transaction, TxIn, TxOut, Txid};
+
+use crate::blockchain::{Broadcaster, MempoolEntry, TxSource};
+use crate::client::{Client, PaymentResult};
+use crate::common::{Created, LegacyProof, ProofContext, ProofScheme, ProofTransaction};
+use crate::demo::SoftwareSigner;
+use crate::server::{Server, ServerBuilder};
+use crate::signer::Signer;
+use crate::wallet::SimpleReceiverWallet;
+use crate::Error;
+
+#[derive(Debug, Default)]
+struct MockState {
+    transactions: HashMap<Txid, Transaction>,
+    utxos: HashSet<OutPoint>,
+    broadcasts: Vec<Transaction>,
+    funded: u32,
+    height: Option<u32>,
+    /// Blocks mined with `mine()`, and how many there were when each transaction was added
+    blocks: u32,
+    added_at: HashMap<Txid, u32>,
+    /// Transactions moved to the mempool with `set_unconfirmed()`, until the next block
+    mempool: HashMap<Txid, MempoolEntry>,
+}
+
+impl MockState {
+    fn add_tx(&mut self, tx: Transaction) -> Txid {
+        let txid = tx.txid();
+
+        for input in &tx.input {
+            self.utxos.remove(&input.previous_output);
+        }
+        for vout in 0..tx.output.len() {
+            self.utxos.insert(OutPoint {
+                txid,
+                vout: vout as u32,
+            });
+        }
+        self.transactions.insert(txid, tx);
+        self.added_at.insert(txid, self.blocks);
+
+        txid
+    }
+}
+
+/// Blockchain kept in memory, where every transaction is confirmed as soon as it's broadcast
+///
+/// Clones share the same state, so the same chain can be given to both the `Client` and the
+/// `Server`.
+#[derive(Debug, Clone, Default)]
+pub struct MockBlockchain {
+    state: Arc<Mutex<MockState>>,
+    updates: Arc<Condvar>,
+}
+
+impl MockBlockchain {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a transaction, spending its inputs and creating its outputs
+    pub fn add_tx(&self, tx: Transaction) -> Txid {
+        let txid = self.state.lock().unwrap().add_tx(tx);
+        self.updates.notify_all();
+
+        txid
+    }
+
+    /// Move a transaction back to the mempool until the next block, with the `entry` returned by
+    /// `get_mempool_entry()`
+    pub fn set_unconfirmed(&self, txid: Txid, entry: MempoolEntry) {
+        self.state.lock().unwrap().mempool.insert(txid, entry);
+    }
+
+    /// Mine a block, adding a confirmation to every transaction
+    pub fn mine(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.blocks += 1;
+        let blocks = state.blocks;
+        for txid in std::mem::take(&mut state.mempool).keys() {
+            state.added_at.insert(*txid, blocks);
+        }
+        drop(state);
+        self.updates.notify_all();
+    }
+
+    /// Create a new UTXO of `value` satoshis locked by `script_pubkey`
+    pub fn fund(&self, script_pubkey: Script, value: u64) -> OutPoint {
+        let mut state = self.state.lock().unwrap();
+        state.funded += 1;
+
+        // Coinbase-like, with a different input every time so that the txids don't collide
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Default::default(),
+                    vout: state.funded,
+                },
+                sequence: 0xFFFF_FFFF,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                script_pubkey,
+                value,
+            }],
+        };
+
+        OutPoint {
+            txid: state.add_tx(tx),
+            vout: 0,
+        }
+    }
+
+    /// Height returned by `get_height()`, unknown by default
+    pub fn set_height(&self, height: Option<u32>) {
+        self.state.lock().unwrap().height = height;
+    }
+
+    /// Transactions broadcast through the `Blockchain` trait, in order
+    pub fn broadcasts(&self) -> Vec<Transaction> {
+        self.state.lock().unwrap().broadcasts.clone()
+    }
+}
+
+impl TxSource for MockBlockchain {
+    type Error = ();
+
+    fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+        self.state
+            .lock()
+            .unwrap()
+            .transactions
+            .get(txid)
+            .cloned()
+            .ok_or(())
+    }
+
+    fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error> {
+        Ok(self.state.lock().unwrap().utxos.contains(txout))
+    }
+
+    fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+        self.state
+            .lock()
+            .unwrap()
+            .utxos
+            .iter()
+            .choose(&mut rand::thread_rng())
+            .cloned()
+            .ok_or(())
+    }
+
+    fn get_confirmations(&self, txid: &Txid) -> Result<Option<u32>, Self::Error> {
+        let state = self.state.lock().unwrap();
+        if state.mempool.contains_key(txid) {
+            return Ok(Some(0));
+        }
+
+        Ok(state
+            .added_at
+            .get(txid)
+            .map(|added_at| state.blocks - added_at + 1))
+    }
+
+    fn get_height(&self) -> Result<Option<u32>, Self::Error> {
+        Ok(self.state.lock().unwrap().height)
+    }
+
+    fn get_spending_tx(&self, txout: &OutPoint) -> Result<Option<Transaction>, Self::Error> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .transactions
+            .values()
+            .find(|tx| tx.input.iter().any(|input| input.previous_output == *txout))
+            .cloned())
+    }
+
+    fn get_mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Self::Error> {
+        Ok(self.state.lock().unwrap().mempool.get(txid).cloned())
+    }
+
+    /// Any new transaction or block counts as an update
+    fn wait_for_update(
+        &self,
+        _script_pubkey: &Script,
+        timeout: Duration,
+    ) -> Result<bool, Self::Error> {
+        let state = self.state.lock().unwrap();
+        let (_state, result) = self.updates.wait_timeout(state, timeout).unwrap();
+
+        Ok(!result.timed_out())
+    }
+}
+
+impl Broadcaster for MockBlockchain {
+    type Error = ();
+
+    /// Rejects transactions spending unknown or already spent outputs
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        if tx
+            .input
+            .iter()
+            .any(|input| !state.utxos.contains(&input.previous_output))
+        {
+            return Err(());
+        }
+
+        state.broadcasts.push(tx.clone());
+        state.add_tx(tx.clone());
+        self.updates.notify_all();
+
+        Ok(())
+    }
+}
+
+/// Two ends of a local TCP connection
+pub(crate) async fn pipe() -> Result<(TcpStream, TcpStream), Error> {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+
+    Ok((connected?, accepted?.0))
+}
+
+pub(crate) fn p2wpkh_key(seed: u8) -> (PrivateKey, Script) {
+    let secp = Secp256k1::signing_only();
+    let key = PrivateKey {
+        compressed: true,
+        network: Network::Regtest,
+        key: SecretKey::from_slice(&[seed; 32]).unwrap(),
+    };
+    let script_pubkey = Address::p2wpkh(&key.public_key(&secp), Network::Regtest).script_pubkey();
+
+    (key, script_pubkey)
+}
+
+/// Key-path P2TR version of `p2wpkh_key`
+pub(crate) fn p2tr_key(seed: u8) -> (PrivateKey, Script) {
+    let (key, _) = p2wpkh_key(seed);
+    let script_pubkey = SoftwareSigner::new(key, HashMap::new()).p2tr_script();
+
+    (key, script_pubkey)
+}
+
+/// A sender and a receiver with P2WPKH UTXOs on a shared `MockBlockchain`, connected to each other
+///
+/// The sender pays `amount` satoshis to the receiver from a 1 BTC UTXO, and the receiver owns two
+/// 1 BTC UTXOs it can contribute. A few unrelated UTXOs are created too, to be used as decoys.
+pub struct Payjoin {
+    pub blockchain: MockBlockchain,
+    pub client: Client<MockBlockchain, SoftwareSigner>,
+    pub server: Server<MockBlockchain, SoftwareSigner, SimpleReceiverWallet>,
+    /// Proof of the sender's inputs, as the client would send it
+    pub proof: Transaction,
+    /// Payment signed by the sender, without payjoin
+    pub fallback: Transaction,
+
+    server_stream: TcpStream,
+}
+
+impl Payjoin {
+    pub async fn new(amount: u64) -> Result<Self, Error> {
+        Self::with_keys(amount, p2wpkh_key).await
+    }
+
+    /// Same as `new`, with P2TR UTXOs everywhere
+    pub async fn new_taproot(amount: u64) -> Result<Self, Error> {
+        Self::with_keys(amount, p2tr_key).await
+    }
+
+    async fn with_keys(amount: u64, key: fn(u8) -> (PrivateKey, Script)) -> Result<Self, Error> {
+        const UTXO_VALUE: u64 = 100_000_000;
+
+        let blockchain = MockBlockchain::new();
+        for seed in 10..20 {
+            blockchain.fund(key(seed).1, UTXO_VALUE);
+        }
+
+        let (sender_key, sender_script) = key(1);
+        let sender_utxo = blockchain.fund(sender_script.clone(), UTXO_VALUE);
+        let (receiver_key, receiver_script) = key(2);
+        let receiver_utxos = (0..2)
+            .map(|_| {
+                let utxo = blockchain.fund(receiver_script.clone(), UTXO_VALUE);
+                let prev_out = TxOut {
+                    script_pubkey: receiver_script.clone(),
+                    value: UTXO_VALUE,
+                };
+
+                (utxo, prev_out)
+            })
+            .collect::<Vec<_>>();
+
+        let base_transaction = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: sender_utxo,
+                sequence: 0xFFFF_FFFF,
+                ..Default::default()
+            }],
+            output: vec![
+                TxOut {
+                    script_pubkey: sender_script.clone(),
+                    value: UTXO_VALUE - amount - 5000,
+                },
+                TxOut {
+                    script_pubkey: receiver_script.clone(),
+                    value: amount,
+                },
+            ],
+        };
+
+        let sender_signer = SoftwareSigner::new(
+            sender_key,
+            vec![(sender_utxo, (UTXO_VALUE, sender_script))]
+                .into_iter()
+                .collect(),
+        );
+        let proof_output = LegacyProof.output(&ProofContext::default());
+        let proof = ProofTransaction::<Created>::try_from((
+            base_transaction.clone(),
+            &sender_signer,
+            &proof_output,
+        ))?
+        .into_inner();
+        let mut fallback = base_transaction.clone();
+        sender_signer.sign(&mut fallback, &[0])?;
+        let receiver_signer = SoftwareSigner::new(
+            receiver_key,
+            receiver_utxos
+                .iter()
+                .map(|(utxo, prev_out)| (*utxo, (prev_out.value, prev_out.script_pubkey.clone())))
+                .collect(),
+        );
+        // Only used with `ServerConfig::max_contribution`
+        let wallet = SimpleReceiverWallet::new(receiver_utxos, vec![receiver_script])
+            .with_change_script(key(3).1);
+
+        let (client_stream, server_stream) = pipe().await?;
+        let client = Client::from_stream(
+            client_stream,
+            "payjoin.test",
+            blockchain.clone(),
+            sender_signer,
+            base_transaction,
+            1,
+        )?;
+        let server = ServerBuilder::new(blockchain.clone(), receiver_signer, wallet, amount)
+            .network(Network::Regtest)
+            .bind("127.0.0.1:0")
+            .await?;
+
+        Ok(Payjoin {
+            blockchain,
+            client,
+            server,
+            proof,
+            fallback,
+            server_stream,
+        })
+    }
+
+    /// Run both sides to completion, returning the result seen by the client and the txid seen by
+    /// the server
+    pub async fn run(self) -> (Result<PaymentResult, Error>, Result<Txid, Error>) {
+        let Payjoin {
+            mut client,
+            mut server,
+            server_stream,
+            ..
+        } = self;
+
+        tokio::join!(client.start(), server.serve(server_stream))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::util::bip143::SighashComponents;
+
+    use super::*;
+    use crate::analysis::prev_outs;
+    use crate::client::ClientConfig;
+    use crate::common::*;
+    use crate::events::{SessionEvent, SessionObserver};
+    use crate::fees::FeeRate;
+    use crate::metrics::Counters;
+    use crate::offer::{Offer, OfferError};
+    use crate::server::{BroadcastDelay, Invoice, ServerConfig};
+    use crate::transcript::{Direction, Transcript, TranscriptLog};
+    use crate::zeroconf::{Risk, ZeroConfPolicy};
+    use crate::ProtocolError;
+    use crate::{TimeoutPhase, Version};
+
+    #[tokio::test]
+    async fn test_payjoin() {
+        for &use_psbt in &[false, true] {
+            let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+            payjoin.client.use_psbt(use_psbt);
+            let blockchain = payjoin.blockchain.clone();
+
+            let (result, server_txid) = payjoin.run().await;
+            let result = result.unwrap();
+            let txid = result.txid;
+            assert_eq!(txid, server_txid.unwrap());
+            assert_eq!(result.receiver_input_count, 1);
+            assert!(result.fee_paid >= FeeRate::default().fee_wu(result.transaction.get_weight()));
+
+            let broadcasts = blockchain.broadcasts();
+            assert_eq!(broadcasts.len(), 1);
+            assert_eq!(broadcasts[0].txid(), txid);
+            assert_eq!(broadcasts[0].input.len(), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_taproot_payjoin() {
+        for &use_psbt in &[false, true] {
+            let mut payjoin = Payjoin::new_taproot(3_000_000).await.unwrap();
+            payjoin.client.use_psbt(use_psbt);
+            payjoin.server.set_config(ServerConfig {
+                receiver_inputs: 2,
+                ..Default::default()
+            });
+            let blockchain = payjoin.blockchain.clone();
+
+            let (result, server_txid) = payjoin.run().await;
+            let result = result.unwrap();
+            assert_eq!(result.txid, server_txid.unwrap());
+            assert_eq!(result.receiver_input_count, 2);
+
+            let broadcasts = blockchain.broadcasts();
+            assert_eq!(broadcasts.len(), 1);
+            assert_eq!(broadcasts[0].input.len(), 3);
+            // Key-path spends, each committing to all the prevouts
+            let prev_outs = prev_outs(&blockchain, &broadcasts[0]).unwrap();
+            let secp = Secp256k1::new();
+            let comp = SighashComponents::new(&broadcasts[0]);
+            for (index, input) in broadcasts[0].input.iter().enumerate() {
+                assert_eq!(input.witness.len(), 1);
+                assert!(
+                    verify_input(&secp, &comp, &broadcasts[0], index, &prev_outs, false).is_ok()
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invoice() {
+        let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+        let uri = payjoin
+            .server
+            .add_invoice(Invoice {
+                txout: payjoin.fallback.output[1].clone(),
+                utxo: None,
+            })
+            .unwrap();
+        payjoin.client.set_invoice(uri.invoice);
+        let invoices = payjoin.server.invoices();
+
+        let (result, server_txid) = payjoin.run().await;
+        assert_eq!(result.unwrap().txid, server_txid.unwrap());
+        // Paid only once
+        assert!(invoices.ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_bound_proof() {
+        use crate::transport::Direct;
+
+        for &ours in &[true, false] {
+            let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+            payjoin.server.set_transport(Direct);
+            payjoin.server.setup().await.unwrap();
+            // A proof made for another receiver
+            let endpoint = match ours {
+                true => payjoin.server.endpoint().unwrap().to_string(),
+                false => "other.onion:9000".to_string(),
+            };
+            payjoin.client.set_endpoint(Some(endpoint));
+
+            let (result, server_txid) = payjoin.run().await;
+            if ours {
+                assert_eq!(result.unwrap().txid, server_txid.unwrap());
+            } else {
+                assert!(matches!(
+                    result,
+                    Err(Error::PeerError(ProtocolError::InvalidProof(
+                        ProofTransactionError::InvalidProofOutput
+                    )))
+                ));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_donations() {
+        for &(min, accepted) in &[(1_000_000, true), (5_000_000, false)] {
+            let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+            payjoin.server.set_config(ServerConfig {
+                donations: true,
+                network: Network::Regtest,
+                ..Default::default()
+            });
+            let uri = payjoin
+                .server
+                .add_invoice(Invoice {
+                    txout: TxOut {
+                        value: min,
+                        ..payjoin.fallback.output[1].clone()
+                    },
+                    utxo: None,
+                })
+                .unwrap();
+            assert_eq!(uri.amount, None);
+            payjoin.client.set_invoice(uri.invoice);
+
+            let (result, server_txid) = payjoin.run().await;
+            if accepted {
+                assert_eq!(result.unwrap().txid, server_txid.unwrap());
+            } else {
+                assert!(matches!(
+                    result,
+                    Err(Error::PeerError(ProtocolError::AmountTooLow(5_000_000)))
+                ));
+                assert!(matches!(
+                    server_txid,
+                    Err(Error::Protocol(ProtocolError::AmountTooLow(_)))
+                ));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_offer() {
+        let Payjoin {
+            blockchain,
+            mut server,
+            fallback,
+            ..
+        } = Payjoin::new(3_000_000).await.unwrap();
+        let uri = server.publish_offer().await.unwrap();
+        assert_eq!(uri.endpoint, None);
+        let offer = Offer::from_str(&uri.offer.unwrap()).unwrap();
+        assert_eq!(offer.amount(), 3_000_000);
+
+        let (sender_key, sender_script) = p2wpkh_key(1);
+        let sender_utxo = fallback.input[0].previous_output;
+        let sender_signer = SoftwareSigner::new(
+            sender_key,
+            vec![(sender_utxo, (100_000_000, sender_script.clone()))]
+                .into_iter()
+                .collect(),
+        );
+        let transaction = offer
+            .complete(
+                &fallback,
+                1,
+                0,
+                FeeRate::default(),
+                &blockchain,
+                &sender_signer,
+            )
+            .unwrap();
+        assert_eq!(transaction.input.len(), 2);
+        assert!(transaction.output.contains(offer.txout()));
+
+        // The signature of the sender commits to the whole transaction
+        let secp = Secp256k1::new();
+        let comp = SighashComponents::new(&transaction);
+        let index = transaction
+            .input
+            .iter()
+            .position(|input| input.previous_output == sender_utxo)
+            .unwrap();
+        let prev_out = TxOut {
+            script_pubkey: sender_script,
+            value: 100_000_000,
+        };
+        verify_p2wpkh_input(&secp, &comp, &transaction, index, &prev_out, false).unwrap();
+
+        // Nothing to claim before the broadcast
+        assert!(server.claim_offers().await.unwrap().is_empty());
+        blockchain.broadcast(&transaction).unwrap();
+        assert_eq!(
+            server.claim_offers().await.unwrap(),
+            vec![transaction.txid()]
+        );
+        assert_eq!(server.payments(), 1);
+        // Taken only once
+        assert!(matches!(
+            offer.complete(
+                &fallback,
+                1,
+                0,
+                FeeRate::default(),
+                &blockchain,
+                &sender_signer
+            ),
+            Err(Error::Offer(OfferError::InvalidUtxo))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_delay() {
+        let Payjoin {
+            blockchain,
+            mut client,
+            mut server,
+            server_stream,
+            ..
+        } = Payjoin::new(3_000_000).await.unwrap();
+        server.set_config(ServerConfig {
+            broadcast_delay: BroadcastDelay::NextBlock(Duration::from_secs(2)),
+            ..Default::default()
+        });
+        blockchain.set_height(Some(100));
+
+        let (result, server_txid) = tokio::join!(client.start(), server.serve(server_stream));
+        let result = result.unwrap();
+        assert_eq!(result.txid, server_txid.unwrap());
+        assert!(result.will_broadcast_by.is_some());
+        assert!(blockchain.broadcasts().is_empty());
+
+        blockchain.set_height(Some(101));
+        blockchain.mine();
+        for _ in 0..10 {
+            if !blockchain.broadcasts().is_empty() {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        assert_eq!(blockchain.broadcasts()[0].txid(), result.txid);
+        // Already broadcast by the receiver
+        assert!(!client.ensure_broadcast(&result).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_sender_broadcast() {
+        let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+        payjoin.server.set_config(ServerConfig {
+            broadcast_delay: BroadcastDelay::Sender,
+            ..Default::default()
+        });
+        let client_events = Arc::new(Recorder::default());
+        payjoin.client.set_observer(Arc::clone(&client_events));
+        let blockchain = payjoin.blockchain.clone();
+
+        let (result, server_txid) = payjoin.run().await;
+        let result = result.unwrap();
+        assert_eq!(result.txid, server_txid.unwrap());
+        assert!(client_events
+            .events
+            .lock()
+            .unwrap()
+            .contains(&SessionEvent::Broadcasting));
+        // Only once, by the sender
+        let broadcasts = blockchain.broadcasts();
+        assert_eq!(broadcasts.len(), 1);
+        assert_eq!(broadcasts[0].txid(), result.txid);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited() {
+        let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+        payjoin.server.set_config(ServerConfig {
+            max_disclosures_per_hour: Some(0),
+            ..Default::default()
+        });
+        let counters = Arc::new(Counters::new());
+        payjoin.server.set_metrics(Arc::clone(&counters));
+
+        let (result, server_txid) = payjoin.run().await;
+        assert!(matches!(
+            result,
+            Err(Error::PeerError(crate::ProtocolError::RateLimited))
+        ));
+        assert!(server_txid.is_err());
+        assert_eq!(counters.sessions_started(), 1);
+        assert_eq!(counters.sessions_failed(), 1);
+        assert_eq!(counters.validation_failures().get("RateLimited"), Some(&1));
+    }
+
+    /// Keeps every event and error it observes
+    #[derive(Debug, Default)]
+    struct Recorder {
+        events: Mutex<Vec<SessionEvent>>,
+        errors: Mutex<Vec<String>>,
+    }
+
+    impl SessionObserver for Recorder {
+        fn on_event(&self, event: &SessionEvent) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+
+        fn on_error(&self, error: &Error) {
+            self.errors.lock().unwrap().push(error.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_events() {
+        use SessionEvent::*;
+
+        let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+        let client_events = Arc::new(Recorder::default());
+        let server_events = Arc::new(Recorder::default());
+        payjoin.client.set_observer(Arc::clone(&client_events));
+        payjoin.server.set_observer(Arc::clone(&server_events));
+
+        let (result, _) = payjoin.run().await;
+        let txid = result.unwrap().txid;
+        let version = Version::CURRENT.to_string();
+        assert_eq!(
+            *client_events.events.lock().unwrap(),
+            vec![
+                Connected,
+                VersionNegotiated {
+                    version: version.clone()
+                },
+                WaitingForPeer,
+                Signing,
+                WaitingForPeer,
+                VerifyingTransaction,
+                Completed { txid },
+            ]
+        );
+        assert_eq!(
+            *server_events.events.lock().unwrap(),
+            vec![
+                Connected,
+                VersionNegotiated { version },
+                WaitingForPeer,
+                VerifyingProof,
+                WaitingForPeer,
+                Signing,
+                Broadcasting,
+                Completed { txid },
+            ]
+        );
+        assert!(client_events.errors.lock().unwrap().is_empty());
+
+        let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+        payjoin.server.set_config(ServerConfig {
+            max_disclosures_per_hour: Some(0),
+            ..Default::default()
+        });
+        let server_events = Arc::new(Recorder::default());
+        payjoin.server.set_observer(Arc::clone(&server_events));
+
+        let (result, _) = payjoin.run().await;
+        assert!(result.is_err());
+        assert_eq!(server_events.errors.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_zero_conf_policy() {
+        for &replaceable in &[false, true] {
+            let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+            payjoin.server.set_config(ServerConfig {
+                zero_conf_policy: Some(ZeroConfPolicy::default()),
+                ..Default::default()
+            });
+            let server_events = Arc::new(Recorder::default());
+            payjoin.server.set_observer(Arc::clone(&server_events));
+            let parent = payjoin.proof.input[0].previous_output.txid;
+            payjoin.blockchain.set_unconfirmed(
+                parent,
+                MempoolEntry {
+                    fee: 1_000,
+                    vsize: 110,
+                    ancestor_fee: 1_000,
+                    ancestor_vsize: 110,
+                    replaceable,
+                },
+            );
+
+            let (result, _) = payjoin.run().await;
+            let assessment = server_events
+                .events
+                .lock()
+                .unwrap()
+                .iter()
+                .find_map(|event| match event {
+                    SessionEvent::ZeroConfAssessed { assessment } => Some(assessment.clone()),
+                    _ => None,
+                })
+                .unwrap();
+            assert_eq!(assessment.unconfirmed_inputs, 1);
+            assert_eq!(assessment.accepted, !replaceable);
+            if replaceable {
+                assert_eq!(assessment.risks, vec![Risk::Replaceable(parent)]);
+                assert!(matches!(
+                    result,
+                    Err(Error::PeerError(ProtocolError::InvalidProof(
+                        ProofTransactionError::RiskyUnconfirmedInputs
+                    )))
+                ));
+            } else {
+                assert!(result.is_ok());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transcripts() {
+        let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+        let client_transcript = Transcript::new();
+        let server_transcripts = TranscriptLog::new(4);
+        payjoin.client.set_transcript(client_transcript.clone());
+        payjoin.server.set_transcripts(server_transcripts.clone());
+
+        let (result, _) = payjoin.run().await;
+        let txid = result.unwrap().txid;
+
+        let sent = client_transcript.entries();
+        let received = server_transcripts.sessions()[0].entries();
+        assert_eq!(sent.len(), 6);
+        assert_eq!(sent[0].direction, Direction::Sent);
+        assert_eq!(sent[0].message["method"], "VERSION");
+        assert_eq!(sent[5].direction, Direction::Received);
+        assert_eq!(sent[5].message["result"]["txid"], txid.to_string());
+        // Each side received what the other one sent
+        for (sent, received) in sent.iter().zip(received.iter()) {
+            assert_ne!(sent.direction, received.direction);
+            assert_eq!(sent.message, received.message);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_control() {
+        use serde_json::{json, Value};
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        use tokio::net::UnixStream;
+
+        use crate::session::new_token;
+        use crate::transport::Direct;
+
+        async fn call(control: &mut BufReader<UnixStream>, method: &str, params: Value) -> Value {
+            let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+            let mut raw = serde_json::to_vec(&request).unwrap();
+            raw.push(b'\n');
+            control.get_mut().write_all(&raw).await.unwrap();
+
+            let mut line = String::new();
+            control.read_line(&mut line).await.unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+
+        // The client is kept around so that the connection stays open
+        let Payjoin {
+            client: _client,
+            mut server,
+            server_stream,
+            ..
+        } = Payjoin::new(3_000_000).await.unwrap();
+        server.set_transport(Direct);
+        server.setup().await.unwrap();
+
+        let mut path = std::env::temp_dir();
+        path.push(format!("libp2ep-control-{}", new_token()));
+        server.listen_control(&path).unwrap();
+        let mut control = BufReader::new(UnixStream::connect(&path).await.unwrap());
+        let invoice = call(&mut control, "get_invoice", Value::Null).await;
+        assert!(invoice["result"].as_str().unwrap().starts_with("bitcoin:"));
+        let status = call(&mut control, "get_status", Value::Null).await;
+        assert_eq!(status["result"]["payments"], 0);
+        assert_eq!(status["result"]["endpoint"], server.endpoint().unwrap());
+        assert_eq!(
+            call(&mut control, "unknown", Value::Null).await["error"]["code"],
+            -32601
+        );
+        let unknown = call(&mut control, "cancel_session", json!({"id": 42})).await;
+        assert_eq!(unknown["error"]["code"], -32602);
+
+        let utxo = json!({"outpoint": format!("{}:7", Txid::default()), "seconds": 60});
+        assert_eq!(
+            call(&mut control, "lock_utxo", utxo.clone()).await["result"],
+            true
+        );
+        assert_eq!(
+            call(&mut control, "lock_utxo", utxo.clone()).await["error"]["code"],
+            -32000
+        );
+        let locks = call(&mut control, "list_locks", Value::Null).await;
+        assert_eq!(locks["result"][0]["outpoint"], utxo["outpoint"]);
+        assert_eq!(
+            call(&mut control, "unlock_utxo", utxo.clone()).await["result"],
+            true
+        );
+        assert_eq!(
+            call(&mut control, "unlock_utxo", utxo).await["error"]["code"],
+            -32602
+        );
+        let forever = json!({"outpoint": format!("{}:7", Txid::default()), "seconds": u64::MAX});
+        assert_eq!(
+            call(&mut control, "lock_utxo", forever).await["error"]["code"],
+            -32602
+        );
+        let locks = call(&mut control, "list_locks", Value::Null).await;
+        assert_eq!(locks["result"], json!([]));
+
+        // The sender never shows up
+        let cancel = async {
+            let sessions = loop {
+                let sessions =
+                    call(&mut control, "list_sessions", Value::Null).await["result"].clone();
+                if !sessions.as_array().unwrap().is_empty() {
+                    break sessions;
+                }
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            };
+            let cancelled = call(
+                &mut control,
+                "cancel_session",
+                json!({"id": sessions[0]["id"]}),
+            )
+            .await;
+            assert_eq!(cancelled["result"], true);
+        };
+        let (result, _) = tokio::join!(server.serve(server_stream), cancel);
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert_eq!(
+            call(&mut control, "list_sessions", Value::Null).await["result"],
+            json!([])
+        );
+
+        server.cancellation_token().cancel();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fallback() {
+        let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+        payjoin.server.set_config(ServerConfig {
+            max_disclosures_per_hour: Some(0),
+            ..Default::default()
+        });
+        let blockchain = payjoin.blockchain.clone();
+        let fallback = payjoin.fallback.clone();
+
+        let Payjoin {
+            mut client,
+            mut server,
+            server_stream,
+            ..
+        } = payjoin;
+        let (result, _) = tokio::join!(
+            client.start_with_fallback(fallback.clone()),
+            server.serve(server_stream)
+        );
+        let result = result.unwrap();
+        assert_eq!(result.txid, fallback.txid());
+        assert_eq!(result.fee_paid, 5000);
+        assert_eq!(result.receiver_input_count, 0);
+        assert_eq!(blockchain.broadcasts(), vec![fallback]);
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        use std::str::FromStr;
+
+        use crate::bip21::Bip21Uri;
+        use crate::transport::Direct;
+        use crate::wallet::SimpleSenderWallet;
+
+        let Payjoin {
+            mut server,
+            blockchain,
+            ..
+        } = Payjoin::new(3_000_000).await.unwrap();
+        server.set_transport(Direct);
+        let uri = Bip21Uri::from_str(&server.setup().await.unwrap()).unwrap();
+        tokio::spawn(async move { server.mainloop().await });
+
+        let (key, script) = p2wpkh_key(3);
+        let utxos = [
+            (blockchain.fund(script.clone(), 2_000_000), 2_000_000),
+            (blockchain.fund(script.clone(), 5_000_000), 5_000_000),
+        ];
+        let signer = SoftwareSigner::new(
+            key,
+            utxos
+                .iter()
+                .map(|(utxo, value)| (*utxo, (*value, script.clone())))
+                .collect(),
+        );
+        let wallet = SimpleSenderWallet::new(
+            utxos
+                .iter()
+                .map(|(utxo, value)| {
+                    let txout = TxOut {
+                        script_pubkey: script.clone(),
+                        value: *value,
+                    };
+                    (*utxo, txout)
+                })
+                .collect(),
+            script.clone(),
+        );
+
+        assert!(matches!(
+            Client::send(
+                &Direct,
+                &uri,
+                Network::Bitcoin,
+                None,
+                &wallet,
+                blockchain.clone(),
+                SoftwareSigner::new(p2wpkh_key(3).0, Default::default()),
+                &FeeRate::default(),
+            )
+            .await,
+            Err(Error::Bip21(crate::bip21::Bip21Error::WrongNetwork(
+                Network::Regtest
+            )))
+        ));
+        assert!(matches!(
+            Client::send(
+                &Direct,
+                &uri,
+                Network::Regtest,
+                Some(1_000),
+                &wallet,
+                blockchain.clone(),
+                SoftwareSigner::new(p2wpkh_key(3).0, Default::default()),
+                &FeeRate::default(),
+            )
+            .await,
+            Err(Error::Bip21(crate::bip21::Bip21Error::PaymentMismatch))
+        ));
+
+        let result = Client::send(
+            &Direct,
+            &uri,
+            Network::Regtest,
+            None,
+            &wallet,
+            blockchain.clone(),
+            signer,
+            &FeeRate::default(),
+        )
+        .await
+        .unwrap();
+        // The smallest UTXO is enough, plus the receiver's
+        assert_eq!(result.transaction.input.len(), 2);
+        assert!(result
+            .transaction
+            .output
+            .iter()
+            .any(|txout| txout.script_pubkey == uri.address.script_pubkey()));
+        assert_eq!(blockchain.broadcasts()[0].txid(), result.txid);
+    }
+
+    #[tokio::test]
+    async fn test_client_builder() {
+        use crate::client::ClientBuilder;
+
+        let Payjoin {
+            blockchain,
+            fallback,
+            ..
+        } = Payjoin::new(3_000_000).await.unwrap();
+        let mut base_transaction = fallback;
+        base_transaction.input[0].witness.clear();
+        let (key, script) = p2wpkh_key(1);
+        let signer = SoftwareSigner::new(
+            key,
+            vec![(
+                base_transaction.input[0].previous_output,
+                (100_000_000, script),
+            )]
+            .into_iter()
+            .collect(),
+        );
+
+        let (client_stream, _server_stream) = pipe().await.unwrap();
+        let builder = ClientBuilder::new(blockchain, signer, base_transaction)
+            .message_timeout(Duration::from_millis(100));
+        let mut client = builder
+            .receiver_output_index(1)
+            .from_stream(client_stream, "payjoin.test")
+            .unwrap();
+
+        // Nobody answers on the other end
+        assert!(matches!(
+            client.start().await,
+            Err(Error::Timeout(TimeoutPhase::Message))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        use crate::transport::Direct;
+
+        let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+
+        let mut server = payjoin.server;
+        server.set_transport(Direct);
+        let token = server.cancellation_token();
+        let running = tokio::spawn(async move { server.mainloop().await });
+        token.cancel();
+        assert!(running.await.unwrap().is_ok());
+
+        // The server never answers
+        payjoin.client.cancellation_token().cancel();
+        assert!(matches!(
+            payjoin.client.start().await,
+            Err(Error::Cancelled)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_keep_running() {
+        use tokio::io::{copy, split};
+        use tokio::time::timeout;
+
+        use crate::transport::Direct;
+
+        for &(keep_running, max_payments, stops) in &[
+            (false, None, true),
+            (true, Some(1), true),
+            (true, None, false),
+        ] {
+            let Payjoin {
+                mut client,
+                mut server,
+                server_stream,
+                ..
+            } = Payjoin::new(3_000_000).await.unwrap();
+            server.set_transport(Direct);
+            server.set_config(ServerConfig {
+                keep_running,
+                max_payments,
+                ..Default::default()
+            });
+            // The client is already connected, forward it to the listener of the main loop
+            let upstream = TcpStream::connect(server.local_addr().unwrap())
+                .await
+                .unwrap();
+            tokio::spawn(async move {
+                let (mut client_read, mut client_write) = split(server_stream);
+                let (mut server_read, mut server_write) = split(upstream);
+                let _ = tokio::join!(
+                    copy(&mut client_read, &mut server_write),
+                    copy(&mut server_read, &mut client_write)
+                );
+            });
+            let token = server.cancellation_token();
+            let mut running = tokio::spawn(async move {
+                let result = server.mainloop().await;
+                result.map(|_| server.payments())
+            });
+
+            assert!(client.start().await.is_ok());
+            match timeout(Duration::from_secs(3), &mut running).await {
+                Ok(result) => {
+                    assert!(stops);
+                    assert_eq!(result.unwrap().unwrap(), 1);
+                }
+                // Still waiting for the next sender
+                Err(_) => {
+                    assert!(!stops);
+                    token.cancel();
+                    assert_eq!(running.await.unwrap().unwrap(), 1);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_confirmation() {
+        let Payjoin {
+            mut client,
+            mut server,
+            server_stream,
+            blockchain,
+            ..
+        } = Payjoin::new(3_000_000).await.unwrap();
+        let (result, _) = tokio::join!(client.start(), server.serve(server_stream));
+        let txid = result.unwrap().txid;
+
+        assert!(client.wait_for_confirmation(txid, 1).await.unwrap());
+        assert!(!client
+            .wait_for_confirmation(Default::default(), 1)
+            .await
+            .unwrap());
+
+        let miner = tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            blockchain.mine();
+        });
+        assert!(server.wait_for_confirmation(txid, 2).await.unwrap());
+        miner.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rbf() {
+        for &allow_rbf in &[true, false] {
+            let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+            payjoin.client.set_config(ClientConfig {
+                rbf: true,
+                ..Default::default()
+            });
+            payjoin.server.set_config(ServerConfig {
+                proof_policy: ProofPolicy {
+                    allow_rbf,
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
+            let blockchain = payjoin.blockchain.clone();
+
+            let (result, _) = payjoin.run().await;
+            if allow_rbf {
+                let sequences = blockchain.broadcasts()[0]
+                    .input
+                    .iter()
+                    .map(|input| input.sequence)
+                    .collect::<Vec<_>>();
+                assert_eq!(sequences, vec![SEQUENCE_RBF; 2]);
+            } else {
+                assert!(matches!(
+                    result,
+                    Err(Error::PeerError(ProtocolError::InvalidProof(
+                        ProofTransactionError::RbfNotAllowed
+                    )))
+                ));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_anti_fee_sniping() {
+        let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+        payjoin.client.set_config(ClientConfig {
+            anti_fee_sniping: true,
+            ..Default::default()
+        });
+        let blockchain = payjoin.blockchain.clone();
+        blockchain.set_height(Some(1000));
+
+        payjoin.run().await.0.unwrap();
+        let transaction = &blockchain.broadcasts()[0];
+        assert!(transaction.lock_time <= 1000 && transaction.lock_time >= 1000 - MAX_LOCKTIME_AGE);
+        assert!(transaction
+            .input
+            .iter()
+            .all(|input| input.sequence == SEQUENCE_LOCKTIME));
+
+        assert!(is_valid_locktime(0, None));
+        assert!(is_valid_locktime(1001, Some(1000)));
+        assert!(!is_valid_locktime(1002, Some(1000)));
+        assert!(!is_valid_locktime(899, Some(1000)));
+        assert!(!is_valid_locktime(1000, None));
+    }
+
+    #[tokio::test]
+    async fn test_anyonecanpay_proof() {
+        let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+        payjoin.client.set_config(ClientConfig {
+            anyonecanpay_proof: true,
+            ..Default::default()
+        });
+        let blockchain = payjoin.blockchain.clone();
+
+        let (result, server_txid) = payjoin.run().await;
+        assert_eq!(result.unwrap().txid, server_txid.unwrap());
+        // Only the proof can be signed that way
+        assert!(blockchain.broadcasts()[0]
+            .input
+            .iter()
+            .all(|input| input.witness[0].last() == Some(&0x01)));
+    }
+
+    #[tokio::test]
+    async fn test_multiple_receiver_inputs() {
+        for &use_psbt in &[false, true] {
+            let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+            payjoin.client.use_psbt(use_psbt);
+            payjoin.server.set_config(ServerConfig {
+                receiver_inputs: 2,
+                ..Default::default()
+            });
+            let blockchain = payjoin.blockchain.clone();
+
+            let (result, server_txid) = payjoin.run().await;
+            let result = result.unwrap();
+            assert_eq!(result.txid, server_txid.unwrap());
+            assert_eq!(result.receiver_input_count, 2);
+
+            let broadcasts = blockchain.broadcasts();
+            assert_eq!(broadcasts.len(), 1);
+            assert_eq!(broadcasts[0].input.len(), 3);
+            assert_eq!(
+                broadcasts[0].output.iter().map(|o| o.value).max(),
+                Some(2 * 100_000_000 + 3_000_000)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receiver_change() {
+        for &use_psbt in &[false, true] {
+            let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+            payjoin.client.use_psbt(use_psbt);
+            payjoin.server.set_config(ServerConfig {
+                max_contribution: Some(10_000_000),
+                ..Default::default()
+            });
+            let blockchain = payjoin.blockchain.clone();
+
+            let (result, server_txid) = payjoin.run().await;
+            let result = result.unwrap();
+            assert_eq!(result.txid, server_txid.unwrap());
+
+            let broadcasts = blockchain.broadcasts();
+            assert_eq!(broadcasts.len(), 1);
+            assert_eq!(broadcasts[0].output.len(), 3);
+            let value_of = |script: &Script| {
+                broadcasts[0]
+                    .output
+                    .iter()
+                    .find(|txout| &txout.script_pubkey == script)
+                    .map(|txout| txout.value)
+            };
+            assert_eq!(value_of(&p2wpkh_key(2).1), Some(10_000_000 + 3_000_000));
+            assert_eq!(value_of(&p2wpkh_key(3).1), Some(100_000_000 - 10_000_000));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multiparty() {
+        use crate::multiparty::{Chain, DownstreamSession};
+        use crate::protocol::receiver::ServerState;
+
+        const FORWARDED: u64 = 1_000_000;
+
+        for &reachable in &[true, false] {
+            let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+            let blockchain = Arc::new(payjoin.blockchain.clone());
+
+            let (key, script) = p2wpkh_key(4);
+            let utxo = blockchain.fund(script.clone(), 100_000_000);
+            let signer = Arc::new(SoftwareSigner::new(
+                key,
+                vec![(utxo, (100_000_000, script.clone()))]
+                    .into_iter()
+                    .collect(),
+            ));
+            let wallet = Arc::new(SimpleReceiverWallet::new(
+                vec![(
+                    utxo,
+                    TxOut {
+                        script_pubkey: script.clone(),
+                        value: 100_000_000,
+                    },
+                )],
+                vec![script.clone()],
+            ));
+            let txout = TxOut {
+                script_pubkey: script.clone(),
+                value: FORWARDED,
+            };
+            let downstream = {
+                let txout = txout.clone();
+                let blockchain = Arc::clone(&blockchain);
+                move || -> Result<Box<dyn DownstreamSession>, Error> {
+                    if !reachable {
+                        return Err(Error::EOF);
+                    }
+
+                    Ok(Box::new(ServerState::new(
+                        txout.clone(),
+                        Arc::clone(&blockchain),
+                        Arc::clone(&signer),
+                        Arc::clone(&wallet),
+                    )))
+                }
+            };
+            payjoin
+                .server
+                .set_chain(Chain::new(Arc::new(downstream), txout));
+
+            let (result, server_txid) = payjoin.run().await;
+            let result = result.unwrap();
+            assert_eq!(result.txid, server_txid.unwrap());
+
+            let broadcasts = blockchain.broadcasts();
+            assert_eq!(broadcasts.len(), 1);
+            let value_of = |script: &Script| {
+                broadcasts[0]
+                    .output
+                    .iter()
+                    .find(|txout| &txout.script_pubkey == script)
+                    .map(|txout| txout.value)
+            };
+            if reachable {
+                assert_eq!(broadcasts[0].input.len(), 3);
+                assert!(broadcasts[0]
+                    .input
+                    .iter()
+                    .any(|txin| txin.previous_output == utxo));
+                assert_eq!(value_of(&script), Some(100_000_000 + FORWARDED));
+                assert_eq!(
+                    value_of(&p2wpkh_key(2).1),
+                    Some(100_000_000 + 3_000_000 - FORWARDED)
+                );
+            } else {
+                // The payjoin goes on without the second receiver
+                assert_eq!(broadcasts[0].input.len(), 2);
+                assert_eq!(value_of(&script), None);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_uih_policy() {
+        use crate::analysis::UihPolicy;
+
+        // The receiver's 1 BTC UTXOs are larger than the sender's change
+        let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+        payjoin.server.set_config(ServerConfig {
+            uih_policy: UihPolicy::Abort,
+            ..Default::default()
+        });
+        let blockchain = payjoin.blockchain.clone();
+        let (result, server_txid) = payjoin.run().await;
+        assert!(result.is_err());
+        assert!(matches!(
+            server_txid,
+            Err(Error::Protocol(ProtocolError::Detectable))
+        ));
+        assert!(blockchain.broadcasts().is_empty());
+
+        let mut payjoin = Payjoin::new(3_000_000).await.unwrap();
+        payjoin.client.set_config(ClientConfig {
+            uih_policy: UihPolicy::Abort,
+            ..Default::default()
+        });
+        payjoin.server.set_config(ServerConfig {
+            uih_policy: UihPolicy::Ignore,
+            ..Default::default()
+        });
+        let (result, server_txid) = payjoin.run().await;
+        assert!(matches!(
+            result,
+            Err(Error::Protocol(ProtocolError::Detectable))
+        ));
+        assert!(server_txid.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let Payjoin {
+            blockchain,
+            mut client,
+            mut server,
+            server_stream,
+            ..
+        } = Payjoin::new(3_000_000).await.unwrap();
+        client.set_config(ClientConfig {
+            dry_run: true,
+            ..Default::default()
+        });
+        server.set_config(ServerConfig {
+            dry_run: true,
+            broadcast_delay: BroadcastDelay::Sender,
+            ..Default::default()
+        });
+
+        let (result, server_txid) = tokio::join!(client.start(), server.serve(server_stream));
+        let result = result.unwrap();
+        assert_eq!(result.txid, server_txid.unwrap());
+        assert_eq!(result.receiver_input_count, 1);
+        assert_eq!(server.dry_runs().len(), 1);
+        assert_eq!(server.dry_runs()[0], result.transaction);
+        assert!(!client.ensure_broadcast(&result).await.unwrap());
+
+        assert!(blockchain.broadcasts().is_empty());
+        // Our UTXO can be contributed again
+        assert!(server.utxo_locks().locked().is_empty());
+    }
+}
@@ -0,0 +1,127 @@
+//! Record of the messages exchanged during a session
+//!
+//! A `Transcript` keeps every JSON message sent and received by `JsonRpc`, with the time and the
+//! direction, so that a failed or disputed negotiation can be looked at after the fact and users
+//! can audit what was revealed to the peer. Clones share the same entries: the application keeps
+//! one and reads it once the session is over, whatever its outcome.
+//!
+//! Servers run many sessions, so they take a `TranscriptLog` instead, which starts a new
+//! `Transcript` for every session and keeps the most recent ones.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub time: SystemTime,
+    pub direction: Direction,
+    /// The whole line, batches included. Lines that are not valid JSON are kept as a string
+    pub message: Value,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Transcript(Arc<Mutex<Vec<Entry>>>);
+
+impl Transcript {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub(crate) fn record(&self, direction: Direction, message: Value) {
+        self.0.lock().unwrap().push(Entry {
+            time: SystemTime::now(),
+            direction,
+            message,
+        });
+    }
+
+    pub fn entries(&self) -> Vec<Entry> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Dump the entries as a JSON array, with the times in milliseconds since the UNIX epoch
+    pub fn to_json(&self) -> Value {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                let time = entry
+                    .time
+                    .duration_since(UNIX_EPOCH)
+                    .map(|time| time.as_millis() as u64)
+                    .unwrap_or(0);
+                let direction = match entry.direction {
+                    Direction::Sent => "sent",
+                    Direction::Received => "received",
+                };
+
+                json!({"time": time, "direction": direction, "message": entry.message})
+            })
+            .collect()
+    }
+}
+
+/// Transcripts of the last `capacity` sessions of a server
+#[derive(Debug, Clone)]
+pub struct TranscriptLog {
+    transcripts: Arc<Mutex<VecDeque<Transcript>>>,
+    capacity: usize,
+}
+
+impl TranscriptLog {
+    pub fn new(capacity: usize) -> Self {
+        TranscriptLog {
+            transcripts: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Start the transcript of a new session, forgetting the oldest one if the log is full
+    pub(crate) fn start(&self) -> Transcript {
+        let transcript = Transcript::new();
+
+        let mut transcripts = self.transcripts.lock().unwrap();
+        if transcripts.len() >= self.capacity {
+            transcripts.pop_front();
+        }
+        transcripts.push_back(transcript.clone());
+
+        transcript
+    }
+
+    /// Transcripts of the sessions, from the oldest one. The last one may still be running
+    pub fn sessions(&self) -> Vec<Transcript> {
+        self.transcripts.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transcript_log() {
+        let log = TranscriptLog::new(2);
+        for i in 0..3 {
+            log.start().record(Direction::Received, i.into());
+        }
+
+        let sessions = log.sessions();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].entries()[0].message, 1);
+        assert_eq!(sessions[1].to_json()[0]["direction"], "received");
+        assert_eq!(sessions[1].to_json()[0]["message"], 2);
+    }
+}
@@ -0,0 +1,572 @@
+//! Ways for the sender to reach the receiver
+//!
+//! By default both `Client` and `Server` start their own Tor instance through `libtor`. Users
+//! that already run a Tor daemon can use `ExternalTor`, and `Direct` skips Tor entirely. Other
+//! Tor implementations (like arti) can be plugged in by implementing `Transport`, and transports
+//! can be layered on top of each other (see `tls::Tls`).
+
+use std::fs;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex as AsyncMutex;
+
+use tokio_socks::tcp::Socks5Stream;
+
+use rand::{thread_rng, Rng};
+
+use tracing::debug;
+
+use crate::Error;
+
+mod control;
+
+use self::control::{ControlAuth, TorControl};
+
+/// Default port of the onion services, regardless of the port the server is listening on
+pub const ONION_PORT: u16 = 9000;
+
+/// Bidirectional byte stream the protocol can run on
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Open a connection to `addr`, in the `host:port` form
+    async fn connect(&self, addr: &str) -> Result<BoxedStream, Error>;
+    /// Make the server listening on `local_addr` reachable on `port`, or on a random port if it's
+    /// 0, returning its public `host:port`. Transports without public ports of their own ignore
+    /// `port`
+    async fn expose(&self, local_addr: SocketAddr, port: u16) -> Result<String, Error>;
+
+    /// Local SOCKS port used to connect, for transports going through a proxy
+    fn socks_port(&self) -> Option<u16> {
+        None
+    }
+
+    /// Wrap a connection accepted by the server
+    async fn accept(&self, stream: TcpStream) -> Result<BoxedStream, Error> {
+        Ok(Box::new(stream))
+    }
+
+    /// Resolve when the transport is ready to connect and, after `expose()`, once the server can
+    /// be reached by the senders
+    async fn wait_ready(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Tear down what `expose()` set up, like the onion service. Called when the server is shut
+    /// down through its `CancellationToken`
+    async fn shutdown(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Plain TCP connections, without Tor
+#[derive(Debug, Clone, Default)]
+pub struct Direct;
+
+#[async_trait]
+impl Transport for Direct {
+    async fn connect(&self, addr: &str) -> Result<BoxedStream, Error> {
+        Ok(Box::new(TcpStream::connect(addr).await?))
+    }
+
+    async fn expose(&self, local_addr: SocketAddr, _port: u16) -> Result<String, Error> {
+        Ok(local_addr.to_string())
+    }
+}
+
+/// Public port of an onion service, picked at random if `port` is 0. Onion ports are virtual, so
+/// they can't collide with the ports in use on the machine
+fn onion_port(port: u16) -> u16 {
+    match port {
+        0 => thread_rng().gen_range(1024, u16::MAX),
+        port => port,
+    }
+}
+
+/// Tor daemon managed by the user
+///
+/// The onion service for the server is created through the control port, which only supports
+/// password (or no) authentication. Its key is thrown away unless `set_onion_key_file()` is used,
+/// so the server gets a new onion address every time it starts.
+#[derive(Debug, Clone)]
+pub struct ExternalTor {
+    socks_addr: SocketAddr,
+    control_addr: Option<SocketAddr>,
+    control_password: Option<String>,
+    onion_key_file: Option<PathBuf>,
+    /// Control connection of the onion service, which owns it when the key is persistent
+    control: Arc<AsyncMutex<Option<TorControl>>>,
+}
+
+impl ExternalTor {
+    pub fn new(
+        socks_addr: SocketAddr,
+        control_addr: Option<SocketAddr>,
+        control_password: Option<String>,
+    ) -> Self {
+        ExternalTor {
+            socks_addr,
+            control_addr,
+            control_password,
+            onion_key_file: None,
+            control: Arc::new(AsyncMutex::new(None)),
+        }
+    }
+
+    /// Keep the key of the onion service in `path`, to reuse the same onion address across
+    /// restarts. The key is generated and saved the first time
+    pub fn set_onion_key_file<P: Into<PathBuf>>(&mut self, path: P) {
+        self.onion_key_file = Some(path.into());
+    }
+
+    async fn control(&self) -> Result<TorControl, Error> {
+        let control_addr = self
+            .control_addr
+            .ok_or_else(|| Error::Transport("Missing Tor control port".into()))?;
+
+        TorControl::connect(
+            control_addr,
+            &ControlAuth::Password(self.control_password.clone()),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl Transport for ExternalTor {
+    async fn connect(&self, addr: &str) -> Result<BoxedStream, Error> {
+        Ok(Box::new(
+            Socks5Stream::connect(self.socks_addr, addr)
+                .await?
+                .into_inner(),
+        ))
+    }
+
+    fn socks_port(&self) -> Option<u16> {
+        Some(self.socks_addr.port())
+    }
+
+    async fn expose(&self, local_addr: SocketAddr, port: u16) -> Result<String, Error> {
+        let port = onion_port(port);
+
+        // Services with a persistent key are not detached: a stale one left by a previous run
+        // would make the address collide, so it's removed when the connection is closed instead
+        let mut stored = self.control.lock().await;
+        stored.take();
+        let mut control = self.control().await?;
+
+        let saved_key = match &self.onion_key_file {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(key) => Some(key.trim().to_string()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            },
+            None => None,
+        };
+        let command = match (&self.onion_key_file, &saved_key) {
+            (Some(_), Some(key)) => format!("ADD_ONION {} Port={},{}", key, port, local_addr),
+            (Some(_), None) => format!("ADD_ONION NEW:ED25519-V3 Port={},{}", port, local_addr),
+            // Detached, so that the service outlives the control connection
+            (None, _) => format!(
+                "ADD_ONION NEW:ED25519-V3 Flags=Detach,DiscardPK Port={},{}",
+                port, local_addr
+            ),
+        };
+        let reply = control.command(&command).await?;
+
+        if let (Some(path), None) = (&self.onion_key_file, &saved_key) {
+            let key = reply
+                .iter()
+                .find_map(|line| line.strip_prefix("250-PrivateKey="))
+                .ok_or_else(|| Error::Transport("Missing PrivateKey".into()))?;
+            write_private(path, key)?;
+        }
+
+        let service_id = service_id(&reply)?;
+        control.watch_service(service_id);
+        let endpoint = format!("{}.onion:{}", service_id, port);
+        *stored = Some(control);
+
+        Ok(endpoint)
+    }
+
+    /// Wait for the onion service created by `expose()`, or only for the bootstrap if there's
+    /// none. Without a control port there's nothing to wait for
+    async fn wait_ready(&self) -> Result<(), Error> {
+        let mut stored = self.control.lock().await;
+        if stored.is_none() {
+            if self.control_addr.is_none() {
+                return Ok(());
+            }
+            *stored = Some(self.control().await?);
+        }
+
+        stored.as_mut().unwrap().wait_ready().await
+    }
+
+    async fn shutdown(&self) -> Result<(), Error> {
+        match self.control.lock().await.take() {
+            Some(mut control) => control.remove_service().await,
+            None => Ok(()),
+        }
+    }
+}
+
+fn service_id(reply: &[String]) -> Result<&str, Error> {
+    let service_id = reply
+        .iter()
+        .find_map(|line| line.strip_prefix("250-ServiceID="))
+        .ok_or_else(|| Error::Transport("Missing ServiceID".into()))?;
+    debug!("HS: {}.onion", service_id);
+
+    Ok(service_id)
+}
+
+/// Write a secret to `path`, only readable by the current user
+fn write_private(path: &Path, contents: &str) -> Result<(), Error> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)?.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(feature = "libtor")]
+pub use self::libtor_transport::LibTor;
+
+#[cfg(feature = "libtor")]
+mod libtor_transport {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use rand::distributions::Alphanumeric;
+
+    use tokio::time::delay_for;
+
+    use libtor::{HiddenServiceVersion, Tor, TorAddress, TorBool, TorFlag};
+
+    use tracing::{info_span, Instrument};
+
+    use super::*;
+
+    /// Tor instance started in-process through `libtor`, with its data in a fresh tempdir
+    ///
+    /// The onion service gets a new address every time, unless its keys are kept in a
+    /// persistent directory with `set_hidden_service_dir()`.
+    #[derive(Debug)]
+    pub struct LibTor {
+        socks_port: u16,
+        hidden_service_dir: Option<PathBuf>,
+        /// SOCKS port and data directory of the running instance
+        started: Mutex<Option<(u16, PathBuf)>>,
+        /// Control connections of the instances started so far, to follow their bootstrap
+        controls: AsyncMutex<Vec<TorControl>>,
+    }
+
+    impl LibTor {
+        /// Listen for SOCKS connections on `socks_port`, or on a free port if it's 0
+        pub fn new(socks_port: u16) -> Self {
+            LibTor {
+                socks_port,
+                hidden_service_dir: None,
+                started: Mutex::new(None),
+                controls: AsyncMutex::new(Vec::new()),
+            }
+        }
+
+        /// Keep the keys of the onion service in `dir`, to reuse the same onion address across
+        /// restarts. Tor creates the directory if it's missing, and requires it to be only
+        /// accessible by the current user
+        pub fn set_hidden_service_dir<P: Into<PathBuf>>(&mut self, dir: P) {
+            self.hidden_service_dir = Some(dir.into());
+        }
+
+        /// Start the instance used to connect, if it's not running yet
+        fn start_socks(&self) -> Result<(u16, PathBuf), Error> {
+            let mut started = self.started.lock().unwrap();
+            if let Some(started) = &*started {
+                return Ok(started.clone());
+            }
+
+            let socks_port = match self.socks_port {
+                0 => std::net::TcpListener::bind(("127.0.0.1", 0))?
+                    .local_addr()?
+                    .port(),
+                port => port,
+            };
+
+            let _span = info_span!("tor_bootstrap", socks_port).entered();
+            let (mut tor, dir) = Self::tor();
+            tor.flag(TorFlag::SocksPort(socks_port)).start_background();
+            *started = Some((socks_port, dir.clone()));
+
+            Ok((socks_port, dir))
+        }
+
+        /// New instance with its data in a fresh tempdir, and a control port only accessible
+        /// with the cookie written there
+        fn tor() -> (Tor, PathBuf) {
+            let rand_string: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
+
+            let mut dir = std::env::temp_dir();
+            dir.push(rand_string);
+
+            debug!("Using tempdir: {}", dir.display());
+
+            let mut tor = Tor::new();
+            tor.flag(TorFlag::DataDirectory(dir.to_str().unwrap().into()))
+                .flag(TorFlag::ControlPortAuto)
+                .flag(TorFlag::ControlPortWriteToFile(
+                    dir.join("control-port").to_str().unwrap().into(),
+                ))
+                .flag(TorFlag::CookieAuthentication(TorBool::True));
+
+            (tor, dir)
+        }
+
+        /// Connect to the control port of the instance in `dir`. Tor writes the address of the
+        /// port once it starts listening, which only takes a moment
+        async fn control(dir: &Path) -> Result<TorControl, Error> {
+            let port_file = dir.join("control-port");
+            let mut attempts = 0;
+            while !port_file.exists() {
+                if attempts >= 100 {
+                    return Err(Error::Transport("Tor didn't start".into()));
+                }
+                delay_for(Duration::from_millis(100)).await;
+                attempts += 1;
+            }
+
+            // A single `PORT=127.0.0.1:1234` line
+            let contents = fs::read_to_string(port_file)?;
+            let addr = contents
+                .trim()
+                .strip_prefix("PORT=")
+                .and_then(|addr| addr.parse().ok())
+                .ok_or_else(|| Error::Transport(format!("Invalid control port: {}", contents)))?;
+            let cookie = fs::read(dir.join("control_auth_cookie"))?;
+
+            TorControl::connect(addr, &ControlAuth::Cookie(cookie)).await
+        }
+    }
+
+    /// Use a free SOCKS port, so that more than one instance can run on the same machine
+    impl Default for LibTor {
+        fn default() -> Self {
+            LibTor::new(0)
+        }
+    }
+
+    #[async_trait]
+    impl Transport for LibTor {
+        async fn connect(&self, addr: &str) -> Result<BoxedStream, Error> {
+            let (socks_port, _) = self.start_socks()?;
+
+            Ok(Box::new(
+                Socks5Stream::connect(("127.0.0.1", socks_port), addr)
+                    .await?
+                    .into_inner(),
+            ))
+        }
+
+        /// The port picked for the running instance, or the configured one if it hasn't started
+        fn socks_port(&self) -> Option<u16> {
+            let started = self.started.lock().unwrap();
+            Some(started.as_ref().map_or(self.socks_port, |(port, _)| *port))
+        }
+
+        async fn expose(&self, local_addr: SocketAddr, port: u16) -> Result<String, Error> {
+            self.expose_hidden_service(local_addr, onion_port(port))
+                .instrument(info_span!("tor_bootstrap", %local_addr))
+                .await
+        }
+
+        /// Wait for the onion services exposed so far. Without any, start the instance used to
+        /// connect and wait for its bootstrap
+        async fn wait_ready(&self) -> Result<(), Error> {
+            let mut controls = self.controls.lock().await;
+            if controls.is_empty() {
+                let (_, dir) = self.start_socks()?;
+                controls.push(Self::control(&dir).await?);
+            }
+
+            for control in controls.iter_mut() {
+                control.wait_ready().await?;
+            }
+
+            Ok(())
+        }
+
+        /// Stop every instance started so far, taking their onion services down with them
+        async fn shutdown(&self) -> Result<(), Error> {
+            let controls = std::mem::take(&mut *self.controls.lock().await);
+            self.started.lock().unwrap().take();
+
+            for mut control in controls {
+                control.command("SIGNAL SHUTDOWN").await?;
+            }
+
+            Ok(())
+        }
+    }
+
+    impl LibTor {
+        async fn expose_hidden_service(
+            &self,
+            local_addr: SocketAddr,
+            port: u16,
+        ) -> Result<String, Error> {
+            let (mut tor, dir) = Self::tor();
+            let hs_dir = self
+                .hidden_service_dir
+                .clone()
+                .unwrap_or_else(|| dir.join("hs"));
+            tor.flag(TorFlag::SocksPort(0))
+                .flag(TorFlag::HiddenServiceDir(hs_dir.to_str().unwrap().into()))
+                .flag(TorFlag::HiddenServiceVersion(HiddenServiceVersion::V3))
+                .flag(TorFlag::HiddenServicePort(
+                    TorAddress::Port(port),
+                    Some(TorAddress::AddressPort(
+                        local_addr.ip().to_string(),
+                        local_addr.port(),
+                    ))
+                    .into(),
+                ))
+                .start_background();
+
+            // The keys of the service are loaded before the control port is opened, and it can't
+            // publish its descriptor before the bootstrap, so no event is missed
+            let mut control = Self::control(&dir).await?;
+            let contents = fs::read_to_string(hs_dir.join("hostname"))?;
+            let hostname = contents.trim();
+            control.watch_service(hostname.trim_end_matches(".onion"));
+            self.controls.lock().await.push(control);
+
+            debug!("HS: {}", hostname);
+            Ok(format!("{}:{}", hostname, port))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::sync::Mutex;
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Control port of a Tor instance that bootstraps and publishes the descriptor of `abcd.onion`
+    /// when asked for its bootstrap phase, recording the commands it receives
+    async fn fake_control_port(mut listener: TcpListener, commands: Arc<Mutex<Vec<String>>>) {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                    break;
+                }
+                let reply = if line.starts_with("ADD_ONION NEW") {
+                    "250-ServiceID=abcd\r\n250-PrivateKey=ED25519-V3:KEY\r\n250 OK\r\n"
+                } else if line.starts_with("ADD_ONION") {
+                    "250-ServiceID=abcd\r\n250 OK\r\n"
+                } else if line.starts_with("GETINFO status/bootstrap-phase") {
+                    "250-status/bootstrap-phase=NOTICE BOOTSTRAP PROGRESS=50 TAG=loading_descriptors\r\n\
+                     250 OK\r\n\
+                     650 STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\"\r\n\
+                     650 HS_DESC UPLOADED abcd UNKNOWN $AAAA\r\n"
+                } else {
+                    "250 OK\r\n"
+                };
+                stream.get_mut().write_all(reply.as_bytes()).await.unwrap();
+                commands.lock().unwrap().push(line.trim_end().to_string());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_onion_key_file() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let control_addr = listener.local_addr().unwrap();
+        let local_addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn(fake_control_port(listener, Arc::clone(&commands)));
+
+        let mut key_file = env::temp_dir();
+        key_file.push(format!("libp2ep-onion-key-{}", control_addr.port()));
+        let mut tor = ExternalTor::new(local_addr, Some(control_addr), None);
+        tor.set_onion_key_file(&key_file);
+
+        for expected in &[
+            "ADD_ONION NEW:ED25519-V3 Port=9000,127.0.0.1:1234",
+            "ADD_ONION ED25519-V3:KEY Port=9000,127.0.0.1:1234",
+        ] {
+            let endpoint = tor.expose(local_addr, ONION_PORT).await.unwrap();
+            assert_eq!(endpoint, "abcd.onion:9000");
+            assert_eq!(commands.lock().unwrap().last().unwrap(), expected);
+            assert_eq!(fs::read_to_string(&key_file).unwrap(), "ED25519-V3:KEY");
+        }
+
+        fs::remove_file(key_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wait_ready() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let control_addr = listener.local_addr().unwrap();
+        let local_addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn(fake_control_port(listener, Arc::clone(&commands)));
+
+        let tor = ExternalTor::new(local_addr, Some(control_addr), None);
+        tor.expose(local_addr, ONION_PORT).await.unwrap();
+        tor.wait_ready().await.unwrap();
+        // Already ready, without asking again
+        tor.wait_ready().await.unwrap();
+
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec![
+                "AUTHENTICATE",
+                "SETEVENTS STATUS_CLIENT HS_DESC",
+                "ADD_ONION NEW:ED25519-V3 Flags=Detach,DiscardPK Port=9000,127.0.0.1:1234",
+                "GETINFO status/bootstrap-phase",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let control_addr = listener.local_addr().unwrap();
+        let local_addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn(fake_control_port(listener, Arc::clone(&commands)));
+
+        let tor = ExternalTor::new(local_addr, Some(control_addr), None);
+        tor.expose(local_addr, ONION_PORT).await.unwrap();
+        tor.shutdown().await.unwrap();
+        assert_eq!(commands.lock().unwrap().last().unwrap(), "DEL_ONION abcd");
+
+        // Nothing left to remove
+        tor.shutdown().await.unwrap();
+        assert_eq!(commands.lock().unwrap().len(), 4);
+    }
+}
@@ -0,0 +1,150 @@
+//! Client for the control port of a Tor instance
+//!
+//! Used to create onion services and to follow the bootstrap and the publication of the service
+//! descriptors through asynchronous events, instead of polling.
+
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use tracing::{debug, info};
+
+use bitcoin::hashes::hex::ToHex;
+
+use crate::Error;
+
+/// How to authenticate on the control port
+#[derive(Debug, Clone)]
+pub(crate) enum ControlAuth {
+    /// Password, or no authentication at all
+    Password(Option<String>),
+    /// Only used with the instances started by `LibTor`
+    #[cfg_attr(not(feature = "libtor"), allow(dead_code))]
+    Cookie(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub(crate) struct TorControl {
+    reader: BufReader<TcpStream>,
+    /// Onion service whose descriptor we are waiting for
+    service_id: Option<String>,
+    ready: bool,
+}
+
+impl TorControl {
+    /// Connect and authenticate, subscribing to the events needed by `wait_ready()`
+    pub async fn connect(addr: SocketAddr, auth: &ControlAuth) -> Result<Self, Error> {
+        let mut control = TorControl {
+            reader: BufReader::new(TcpStream::connect(addr).await?),
+            service_id: None,
+            ready: false,
+        };
+
+        let authenticate = match auth {
+            ControlAuth::Password(Some(password)) => {
+                format!("AUTHENTICATE \"{}\"", password.replace('"', "\\\""))
+            }
+            ControlAuth::Password(None) => "AUTHENTICATE".into(),
+            ControlAuth::Cookie(cookie) => format!("AUTHENTICATE {}", cookie.to_hex()),
+        };
+        control.command(&authenticate).await?;
+        control.command("SETEVENTS STATUS_CLIENT HS_DESC").await?;
+
+        Ok(control)
+    }
+
+    /// Wait for the descriptor of `service_id` to be published in `wait_ready()`
+    pub fn watch_service(&mut self, service_id: &str) {
+        self.service_id = Some(service_id.to_string());
+        self.ready = false;
+    }
+
+    /// Remove the onion service being watched, if any, and stop following its descriptor
+    pub async fn remove_service(&mut self) -> Result<(), Error> {
+        if let Some(service_id) = self.service_id.take() {
+            self.command(&format!("DEL_ONION {}", service_id)).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Err(Error::EOF);
+        }
+
+        Ok(line.trim_end().to_string())
+    }
+
+    /// Send `command`, returning the lines of the reply. Events received in the meantime are
+    /// handled on the way
+    pub async fn command(&mut self, command: &str) -> Result<Vec<String>, Error> {
+        self.reader.get_mut().write_all(command.as_bytes()).await?;
+        self.reader.get_mut().write_all(b"\r\n").await?;
+
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            if line.starts_with("650") {
+                self.handle_event(&line);
+                continue;
+            }
+            if !line.starts_with("250") {
+                return Err(Error::Transport(line));
+            }
+
+            // The last line of a reply has a space after the status code
+            let last = line.as_bytes().get(3) == Some(&b' ');
+            lines.push(line);
+            if last {
+                break Ok(lines);
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: &str) {
+        let mut words = event.split(' ').skip(1);
+        match (words.next(), words.next(), words.next()) {
+            (Some("STATUS_CLIENT"), Some(_), Some("BOOTSTRAP")) => {
+                if let Some(progress) = words.find_map(|w| w.strip_prefix("PROGRESS=")) {
+                    info!("Tor bootstrap: {}%", progress);
+                    if progress == "100" && self.service_id.is_none() {
+                        self.ready = true;
+                    }
+                }
+            }
+            (Some("HS_DESC"), Some("UPLOADED"), Some(service_id)) => {
+                debug!("Descriptor of {}.onion uploaded", service_id);
+                if self.service_id.as_deref() == Some(service_id) {
+                    self.ready = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Resolve once Tor has finished bootstrapping and, if a service is being watched, once its
+    /// descriptor has been uploaded to at least one directory
+    pub async fn wait_ready(&mut self) -> Result<(), Error> {
+        if self.ready {
+            return Ok(());
+        }
+
+        let phase = self.command("GETINFO status/bootstrap-phase").await?;
+        let bootstrapped = phase.iter().any(|line| line.contains("PROGRESS=100"));
+        if bootstrapped && self.service_id.is_none() {
+            self.ready = true;
+        }
+
+        while !self.ready {
+            let line = self.read_line().await?;
+            if line.starts_with("650") {
+                self.handle_event(&line);
+            }
+        }
+
+        Ok(())
+    }
+}
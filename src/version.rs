@@ -0,0 +1,148 @@
+//! Protocol versions and optional capabilities
+//!
+//! Peers are compatible as long as they share the same major version. The session runs on the
+//! lowest of the two minor versions, with the capabilities supported by both sides.
+
+use std::fmt;
+use std::ops::{BitAnd, BitOr};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ProtocolError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl Version {
+    /// Version spoken by this library
+    pub const CURRENT: Version = Version { major: 1, minor: 1 };
+
+    /// Version to use with a peer speaking `other`, if any
+    pub fn negotiate(&self, other: &Version) -> Option<Version> {
+        if self.major == other.major {
+            Some(*self.min(other))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl FromStr for Version {
+    type Err = ProtocolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ProtocolError::InvalidVersion(s.to_string());
+
+        let mut parts = s.splitn(2, '.');
+        let major = parts.next().and_then(|major| major.parse().ok());
+        let minor = parts.next().and_then(|minor| minor.parse().ok());
+
+        match (major, minor) {
+            (Some(major), Some(minor)) => Ok(Version { major, minor }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Set of optional features, sent as a bitset next to the version
+///
+/// Unknown bits sent by newer peers are dropped when intersecting with our own capabilities.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// The sender's signatures are sent as PSBTs instead of raw witnesses
+    pub const PSBT: Capabilities = Capabilities(1 << 0);
+    /// P2TR key-path inputs are accepted
+    pub const TAPROOT: Capabilities = Capabilities(1 << 1);
+    /// The receiver can contribute more than one UTXO
+    pub const MULTI_UTXO: Capabilities = Capabilities(1 << 2);
+    /// The sender can pay other outputs in the same transaction
+    pub const BATCH: Capabilities = Capabilities(1 << 3);
+    /// The sender can broadcast the final transaction, when the receiver leaves it to it
+    pub const SENDER_BROADCAST: Capabilities = Capabilities(1 << 4);
+    /// The proof can be signed with `SIGHASH_ALL | SIGHASH_ANYONECANPAY`
+    pub const ANYONECANPAY_PROOF: Capabilities = Capabilities(1 << 5);
+    /// The proof output commits to the receiver's endpoint and invoice, see `BoundProof`
+    pub const BOUND_PROOF: Capabilities = Capabilities(1 << 6);
+    /// The receiver can take part of its inputs back in a change output of its own
+    pub const RECEIVER_CHANGE: Capabilities = Capabilities(1 << 7);
+    /// The receiver can be chained by its client into a payjoin with a third party, see
+    /// `multiparty`
+    pub const MULTIPARTY: Capabilities = Capabilities(1 << 8);
+
+    pub fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    /// Capabilities implemented by this library
+    pub fn supported() -> Self {
+        Capabilities::PSBT
+            | Capabilities::TAPROOT
+            | Capabilities::MULTI_UTXO
+            | Capabilities::BATCH
+            | Capabilities::SENDER_BROADCAST
+            | Capabilities::ANYONECANPAY_PROOF
+            | Capabilities::BOUND_PROOF
+            | Capabilities::RECEIVER_CHANGE
+            | Capabilities::MULTIPARTY
+    }
+
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Capabilities) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Capabilities) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Capabilities;
+
+    fn bitand(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let v = |s: &str| Version::from_str(s).unwrap();
+
+        assert_eq!(v("1.1").negotiate(&v("1.0")), Some(v("1.0")));
+        assert_eq!(v("1.1").negotiate(&v("1.7")), Some(v("1.1")));
+        assert_eq!(v("1.1").negotiate(&v("2.0")), None);
+        assert!(Version::from_str("1").is_err());
+        assert!(Version::from_str("1.x").is_err());
+
+        let theirs = Capabilities(Capabilities::PSBT.0 | 1 << 31);
+        assert_eq!(Capabilities::supported() & theirs, Capabilities::PSBT);
+    }
+}
@@ -0,0 +1,169 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bitcoin::{OutPoint, Script, TxOut};
+
+use crate::coin_selection::{UihRange, WeightedUtxo};
+use crate::server::UtxoLocks;
+use crate::ProtocolError;
+
+/// Source of the receiver's contributions to the payjoin transactions
+pub trait ReceiverWallet {
+    type Error;
+
+    /// Pick the UTXO to contribute to a payment of `amount` satoshis, skipping the ones in
+    /// `locks`. Returns it together with the output it spends. Sessions contributing more than
+    /// one UTXO call it again once the previous pick is locked
+    fn next_utxo(&self, amount: u64, locks: &UtxoLocks) -> Result<(OutPoint, TxOut), Self::Error>;
+    /// Like `next_utxo()`, preferring a UTXO in `range` so that the payjoin isn't given away by
+    /// the unnecessary input heuristics. Falls back to `next_utxo()` by default
+    fn next_utxo_within(
+        &self,
+        amount: u64,
+        range: &UihRange,
+        locks: &UtxoLocks,
+    ) -> Result<(OutPoint, TxOut), Self::Error> {
+        let _ = range;
+        self.next_utxo(amount, locks)
+    }
+    /// Script that should receive the next payment. Called once at startup and again after every
+    /// completed payment
+    fn next_script(&self) -> Result<Script, Self::Error>;
+    /// Script receiving what's left of our UTXOs past `ServerConfig::max_contribution`, `None` to
+    /// fold all of them into the payment
+    fn change_script(&self) -> Result<Option<Script>, Self::Error> {
+        Ok(None)
+    }
+}
+
+/// Pick the smallest unlocked UTXO that is at least `amount`, or the largest one if none is. With
+/// a `range`, only the UTXOs in it are considered, unless there are none
+pub(crate) fn select_utxo<I>(
+    utxos: I,
+    amount: u64,
+    range: Option<&UihRange>,
+    locks: &UtxoLocks,
+) -> Option<(OutPoint, TxOut)>
+where
+    I: IntoIterator<Item = (OutPoint, TxOut)>,
+{
+    let mut available = utxos
+        .into_iter()
+        .filter(|(utxo, _)| !locks.is_locked(utxo))
+        .collect::<Vec<_>>();
+    if let Some(range) = range {
+        if available
+            .iter()
+            .any(|(_, prev_out)| range.contains(prev_out.value))
+        {
+            available.retain(|(_, prev_out)| range.contains(prev_out.value));
+        }
+    }
+
+    available
+        .iter()
+        .filter(|(_, prev_out)| prev_out.value >= amount)
+        .min_by_key(|(_, prev_out)| prev_out.value)
+        .or_else(|| available.iter().max_by_key(|(_, prev_out)| prev_out.value))
+        .cloned()
+}
+
+/// Wallet backed by a fixed list of UTXOs and receiving scripts
+///
+/// For every session it contributes the smallest available UTXO that is at least as large as the
+/// payment, falling back to the largest one. Receiving scripts are used in a round-robin fashion.
+#[derive(Debug)]
+pub struct SimpleReceiverWallet {
+    utxos: Vec<(OutPoint, TxOut)>,
+    scripts: Vec<Script>,
+    next_script: AtomicUsize,
+    change_script: Option<Script>,
+}
+
+impl SimpleReceiverWallet {
+    pub fn new(utxos: Vec<(OutPoint, TxOut)>, scripts: Vec<Script>) -> Self {
+        assert!(!scripts.is_empty());
+
+        SimpleReceiverWallet {
+            utxos,
+            scripts,
+            next_script: AtomicUsize::new(0),
+            change_script: None,
+        }
+    }
+
+    /// Take our change to `script`
+    pub fn with_change_script(mut self, script: Script) -> Self {
+        self.change_script = Some(script);
+        self
+    }
+}
+
+impl ReceiverWallet for SimpleReceiverWallet {
+    type Error = ProtocolError;
+
+    fn next_utxo(&self, amount: u64, locks: &UtxoLocks) -> Result<(OutPoint, TxOut), Self::Error> {
+        select_utxo(self.utxos.iter().cloned(), amount, None, locks).ok_or(ProtocolError::Busy)
+    }
+
+    fn next_utxo_within(
+        &self,
+        amount: u64,
+        range: &UihRange,
+        locks: &UtxoLocks,
+    ) -> Result<(OutPoint, TxOut), Self::Error> {
+        select_utxo(self.utxos.iter().cloned(), amount, Some(range), locks)
+            .ok_or(ProtocolError::Busy)
+    }
+
+    fn next_script(&self) -> Result<Script, Self::Error> {
+        let index = self.next_script.fetch_add(1, Ordering::Relaxed);
+
+        Ok(self.scripts[index % self.scripts.len()].clone())
+    }
+
+    fn change_script(&self) -> Result<Option<Script>, Self::Error> {
+        Ok(self.change_script.clone())
+    }
+}
+
+/// Source of the sender's UTXOs and change scripts, used by `Client::send()`
+pub trait SenderWallet {
+    type Error;
+
+    /// UTXOs that can fund a payment
+    fn list_unspent(&self) -> Result<Vec<WeightedUtxo>, Self::Error>;
+    /// Script that should receive the change of the next payment
+    fn change_script(&self) -> Result<Script, Self::Error>;
+}
+
+/// Wallet backed by a fixed list of UTXOs, sending the change to a single script
+#[derive(Debug)]
+pub struct SimpleSenderWallet {
+    utxos: Vec<(OutPoint, TxOut)>,
+    change_script: Script,
+}
+
+impl SimpleSenderWallet {
+    pub fn new(utxos: Vec<(OutPoint, TxOut)>, change_script: Script) -> Self {
+        SimpleSenderWallet {
+            utxos,
+            change_script,
+        }
+    }
+}
+
+impl SenderWallet for SimpleSenderWallet {
+    type Error = ProtocolError;
+
+    fn list_unspent(&self) -> Result<Vec<WeightedUtxo>, Self::Error> {
+        Ok(self
+            .utxos
+            .iter()
+            .map(|(utxo, txout)| WeightedUtxo::new(*utxo, txout.clone()))
+            .collect())
+    }
+
+    fn change_script(&self) -> Result<Script, Self::Error> {
+        Ok(self.change_script.clone())
+    }
+}
@@ -0,0 +1,279 @@
+//! JavaScript bindings of the sender, for browser and Electron wallets
+//!
+//! Browsers can't open raw sockets, so the host connects to the receiver itself, usually through
+//! a WebSocket, sends the line returned by `PayjoinClient::start()` and then hands every line it
+//! receives to `PayjoinClient::receive()`, sending back whatever it returns. The chain and the
+//! keys stay with the host too: `JsBlockchain` and `JsSigner` are plain objects with the methods
+//! below, called synchronously while a line is being handled, so they can only answer from data
+//! the wallet already has.
+//!
+//! ```js
+//! const client = new PayjoinClient(blockchain, signer, baseTransactionHex, 1, true);
+//! socket.onopen = () => socket.send(client.start());
+//! socket.onmessage = (event) => {
+//!     const reply = client.receive(event.data);
+//!     if (reply) socket.send(reply);
+//!     if (client.txid()) socket.close();
+//! };
+//! ```
+
+use std::fmt;
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use wasm_bindgen::prelude::*;
+
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, Transaction, Txid};
+
+use crate::blockchain::{Broadcaster, TxSource};
+use crate::protocol::sender::ClientState;
+use crate::signer::Signer;
+use crate::{Capabilities, Envelope, Error, Message, ProtocolError};
+
+#[wasm_bindgen]
+extern "C" {
+    /// Chain backend of the host
+    pub type JsBlockchain;
+
+    /// Transaction in hex, or `undefined` if it's unknown
+    #[wasm_bindgen(method, js_name = getTx)]
+    fn get_tx(this: &JsBlockchain, txid: &str) -> Option<String>;
+
+    /// Whether `outpoint`, as `txid:vout`, is unspent
+    #[wasm_bindgen(method, js_name = isUnspent)]
+    fn is_unspent(this: &JsBlockchain, outpoint: &str) -> bool;
+
+    /// Relay the transaction, in hex. Returns whether it was accepted
+    #[wasm_bindgen(method)]
+    fn broadcast(this: &JsBlockchain, transaction: &str) -> bool;
+
+    /// Keys of the host's wallet
+    pub type JsSigner;
+
+    /// Sign and finalize `inputs` of the PSBT, in hex, returning the signed PSBT in hex. The
+    /// PSBT only has the unsigned transaction, the wallet adds the UTXOs it spends
+    #[wasm_bindgen(method, js_name = signPsbt)]
+    fn sign_psbt(this: &JsSigner, psbt: &str, inputs: &[u32]) -> Option<String>;
+}
+
+/// `JsBlockchain` where the state machine expects a `Blockchain`
+struct HostBlockchain(JsBlockchain);
+
+/// `JsSigner` where the state machine expects a `Signer`
+struct HostSigner(JsSigner);
+
+// JavaScript objects never leave the thread they were created on, and without the atomics
+// proposal that's the only thread a wasm32 module has
+unsafe impl Send for HostBlockchain {}
+unsafe impl Sync for HostBlockchain {}
+unsafe impl Send for HostSigner {}
+unsafe impl Sync for HostSigner {}
+
+impl fmt::Debug for HostBlockchain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("HostBlockchain")
+    }
+}
+
+impl fmt::Debug for HostSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("HostSigner")
+    }
+}
+
+impl TxSource for HostBlockchain {
+    type Error = Error;
+
+    fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+        let hex = self
+            .0
+            .get_tx(&txid.to_string())
+            .ok_or_else(|| Error::Transport(format!("Unknown transaction {}", txid)))?;
+
+        from_hex(&hex)
+    }
+
+    fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error> {
+        Ok(self.0.is_unspent(&txout.to_string()))
+    }
+
+    /// Only receivers draw random UTXOs
+    fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+        Err(Error::Other)
+    }
+}
+
+impl Broadcaster for HostBlockchain {
+    type Error = Error;
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        if self.0.broadcast(&serialize(tx).to_hex()) {
+            Ok(())
+        } else {
+            Err(Error::Transport("Transaction rejected".into()))
+        }
+    }
+}
+
+impl Signer for HostSigner {
+    type Error = Error;
+
+    fn sign(&self, transaction: &mut Transaction, inputs: &[usize]) -> Result<(), Self::Error> {
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(transaction.clone())
+            .map_err(|_| Error::Other)?;
+        self.sign_psbt(&mut psbt, inputs)?;
+
+        for index in inputs {
+            let input = &mut transaction.input[*index];
+            let psbt_input = &mut psbt.inputs[*index];
+
+            input.witness = psbt_input.final_script_witness.take().unwrap_or_default();
+            input.script_sig = psbt_input.final_script_sig.take().unwrap_or_default();
+        }
+
+        Ok(())
+    }
+
+    fn sign_psbt(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        inputs: &[usize],
+    ) -> Result<(), Self::Error> {
+        let indexes = inputs.iter().map(|index| *index as u32).collect::<Vec<_>>();
+        let signed = self
+            .0
+            .sign_psbt(&serialize(&*psbt).to_hex(), &indexes)
+            .ok_or_else(|| Error::Transport("The wallet didn't sign".into()))?;
+        *psbt = from_hex(&signed)?;
+
+        Ok(())
+    }
+}
+
+fn from_hex<T: bitcoin::consensus::Decodable>(hex: &str) -> Result<T, Error> {
+    let bytes = Vec::<u8>::from_hex(hex).map_err(|_| Error::Other)?;
+    deserialize(&bytes).map_err(|_| Error::Other)
+}
+
+/// `error` and its causes, for the exceptions thrown to JavaScript
+fn to_js(error: Error) -> JsValue {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(&error);
+    while let Some(inner) = source {
+        message = format!("{}: {}", message, inner);
+        source = inner.source();
+    }
+
+    JsValue::from_str(&message)
+}
+
+/// Sender of a payjoin, driven by the host one line at a time
+#[wasm_bindgen]
+pub struct PayjoinClient {
+    state: ClientState<HostBlockchain, HostSigner>,
+    next_id: u64,
+    // Id of the request waiting for a response
+    pending_id: Option<Value>,
+}
+
+#[wasm_bindgen]
+impl PayjoinClient {
+    /// Pay the receiver of output `receiver_output_index` of `base_transaction`, in hex. With
+    /// `use_psbt` the signatures are sent as PSBTs, if the receiver supports them
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        blockchain: JsBlockchain,
+        signer: JsSigner,
+        base_transaction: &str,
+        receiver_output_index: usize,
+        use_psbt: bool,
+    ) -> Result<PayjoinClient, JsValue> {
+        let base_transaction = from_hex(base_transaction).map_err(to_js)?;
+
+        let mut capabilities = Capabilities::TAPROOT
+            | Capabilities::MULTI_UTXO
+            | Capabilities::BATCH
+            | Capabilities::SENDER_BROADCAST;
+        if use_psbt {
+            capabilities.insert(Capabilities::PSBT);
+        }
+        let state = ClientState::new(
+            base_transaction,
+            receiver_output_index,
+            Arc::new(HostBlockchain(blockchain)),
+            Arc::new(HostSigner(signer)),
+        )
+        .with_capabilities(capabilities);
+
+        Ok(PayjoinClient {
+            state,
+            next_id: 1,
+            pending_id: None,
+        })
+    }
+
+    /// First line to send to the receiver
+    pub fn start(&mut self) -> Result<String, JsValue> {
+        let request = self.state.start();
+        self.encode(request.into()).map_err(to_js)
+    }
+
+    /// Handle a line of the receiver, returning the line to send back, if any. Throws when the
+    /// negotiation fails, at which point the host should broadcast its base transaction instead
+    pub fn receive(&mut self, line: &str) -> Result<Option<String>, JsValue> {
+        self.handle(line).map_err(to_js)
+    }
+
+    /// Txid of the payjoin, once the receiver has returned it
+    pub fn txid(&self) -> Option<String> {
+        self.state.result().map(|result| result.txid.to_string())
+    }
+
+    /// Final transaction in hex, once the receiver has returned it
+    pub fn transaction(&self) -> Option<String> {
+        self.state
+            .result()
+            .map(|result| serialize(&result.transaction).to_hex())
+    }
+}
+
+impl PayjoinClient {
+    fn encode(&mut self, message: Message) -> Result<String, Error> {
+        let id = Value::from(self.next_id.to_string());
+        self.next_id += 1;
+        self.pending_id = Some(id.clone());
+
+        Ok(serde_json::to_string(&Envelope::new(id, message))?)
+    }
+
+    fn handle(&mut self, line: &str) -> Result<Option<String>, Error> {
+        let Envelope { id, message, .. } = serde_json::from_str(line.trim())?;
+        let next = match message {
+            // Advisory notifications of the receiver
+            Message::Request { .. } if id.is_none() => return Ok(None),
+            Message::Request { .. } => return Err(ProtocolError::UnexpectedMessage.into()),
+            // The receiver may not have been able to parse our request
+            Message::Error { error } if id.is_none() || id == Some(Value::Null) => {
+                return Err(Error::PeerError(error))
+            }
+            _ if id != self.pending_id => return Err(ProtocolError::InvalidId.into()),
+            Message::Error { error } if error.is_recoverable() => {
+                self.state.recover(&error)?.ok_or(Error::PeerError(error))?
+            }
+            Message::Error { error } => return Err(Error::PeerError(error)),
+            Message::Response { result } => match self.state.step(result)? {
+                Some(request) => request,
+                None => {
+                    self.pending_id = None;
+                    return Ok(None);
+                }
+            },
+        };
+
+        self.encode(next.into()).map(Some)
+    }
+}
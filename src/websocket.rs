@@ -0,0 +1,232 @@
+//! WebSocket framing on top of another transport
+//!
+//! Lets the server sit behind the HTTP reverse proxies and load balancers that only forward
+//! WebSocket connections. Every JSON-RPC message travels in its own text frame, and the stream handed
+//! to the protocol still looks newline-delimited, so nothing above the transport changes.
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+
+use futures_util::{ready, Sink, Stream};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::transport::{BoxedStream, Transport};
+use crate::Error;
+
+const SCHEME: &str = "ws://";
+
+/// Wrap the connections of `inner` in a WebSocket
+///
+/// The endpoints returned by `expose()` start with `ws://`, and `connect()` accepts addresses with or
+/// without the scheme.
+#[derive(Debug, Clone)]
+pub struct WebSocket<T> {
+    inner: T,
+}
+
+impl<T: Transport> WebSocket<T> {
+    pub fn new(inner: T) -> Self {
+        WebSocket { inner }
+    }
+}
+
+fn ws_error(err: WsError) -> Error {
+    Error::Transport(err.to_string())
+}
+
+#[async_trait]
+impl<T: Transport> Transport for WebSocket<T> {
+    async fn connect(&self, addr: &str) -> Result<BoxedStream, Error> {
+        let addr = addr.strip_prefix(SCHEME).unwrap_or(addr);
+        let addr = addr.split('/').next().unwrap_or(addr);
+
+        let stream = self.inner.connect(addr).await?;
+        let (stream, _) = tokio_tungstenite::client_async(format!("{}{}/", SCHEME, addr), stream)
+            .await
+            .map_err(ws_error)?;
+
+        Ok(Box::new(LineStream::new(stream)))
+    }
+
+    fn socks_port(&self) -> Option<u16> {
+        self.inner.socks_port()
+    }
+
+    async fn wait_ready(&self) -> Result<(), Error> {
+        self.inner.wait_ready().await
+    }
+
+    async fn expose(&self, local_addr: SocketAddr, port: u16) -> Result<String, Error> {
+        let endpoint = self.inner.expose(local_addr, port).await?;
+        Ok(format!("{}{}", SCHEME, endpoint))
+    }
+
+    async fn accept(&self, stream: TcpStream) -> Result<BoxedStream, Error> {
+        let stream = self.inner.accept(stream).await?;
+        let stream = tokio_tungstenite::accept_async(stream)
+            .await
+            .map_err(ws_error)?;
+
+        Ok(Box::new(LineStream::new(stream)))
+    }
+}
+
+fn io_error(err: WsError) -> io::Error {
+    match err {
+        WsError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+/// Newline-delimited stream over the messages of a WebSocket
+///
+/// Reads return each message followed by a newline, writes are sent one line per message.
+struct LineStream {
+    inner: WebSocketStream<BoxedStream>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    write_buf: Vec<u8>,
+}
+
+impl LineStream {
+    fn new(inner: WebSocketStream<BoxedStream>) -> Self {
+        LineStream {
+            inner,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            write_buf: Vec::new(),
+        }
+    }
+
+    /// Queue every complete line of `write_buf` in the sink
+    fn poll_send_lines(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(pos) = self.write_buf.iter().position(|b| *b == b'\n') {
+            ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(io_error)?;
+
+            let mut line: Vec<u8> = self.write_buf.drain(..=pos).collect();
+            line.pop();
+            let message = match String::from_utf8(line) {
+                Ok(text) => Message::Text(text),
+                Err(e) => Message::Binary(e.into_bytes()),
+            };
+            Pin::new(&mut self.inner)
+                .start_send(message)
+                .map_err(io_error)?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for LineStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        while this.read_pos == this.read_buf.len() {
+            let data = match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Text(text))) => text.into_bytes(),
+                Some(Ok(Message::Binary(data))) => data,
+                // Pings are answered by tungstenite itself
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(0)),
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+            };
+
+            this.read_buf = data;
+            this.read_buf.push(b'\n');
+            this.read_pos = 0;
+        }
+
+        let len = std::cmp::min(buf.len(), this.read_buf.len() - this.read_pos);
+        buf[..len].copy_from_slice(&this.read_buf[this.read_pos..this.read_pos + len]);
+        this.read_pos += len;
+
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for LineStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // Only accept more data once the previous lines have been handed to the sink
+        ready!(this.poll_send_lines(cx))?;
+        this.write_buf.extend_from_slice(buf);
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_send_lines(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx).map_err(io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_send_lines(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx).map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::transport::Direct;
+
+    #[tokio::test]
+    async fn test_websocket_lines() {
+        let server = WebSocket::new(Direct);
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server_task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = BufReader::new(server.accept(stream).await?);
+
+            let mut line = String::new();
+            stream.read_line(&mut line).await?;
+            stream.get_mut().write_all(line.as_bytes()).await?;
+            stream.get_mut().flush().await?;
+
+            Ok::<_, Error>(line)
+        });
+
+        let client = WebSocket::new(Direct);
+        let mut stream = BufReader::new(client.connect(&format!("ws://{}", addr)).await.unwrap());
+        // Two lines written at once are split in two messages
+        stream
+            .get_mut()
+            .write_all(b"{\"id\":1}\n{\"id\":2}\n")
+            .await
+            .unwrap();
+        stream.get_mut().flush().await.unwrap();
+
+        let mut echo = String::new();
+        stream.read_line(&mut echo).await.unwrap();
+        assert_eq!(server_task.await.unwrap().unwrap(), "{\"id\":1}\n");
+        assert_eq!(echo, "{\"id\":1}\n");
+    }
+}
@@ -0,0 +1,264 @@
+//! Acceptance of proofs spending unconfirmed outputs
+//!
+//! Senders can pay with the outputs of transactions still waiting in the mempool, which might
+//! never confirm: they, or one of their ancestors, could be replaced or pay too little to be
+//! mined, and the sender could already be spending the same outputs elsewhere. With a
+//! `ZeroConfPolicy` the receiver looks up the parents of the proof that are still unconfirmed,
+//! adds up the risks it finds and only goes on with the session below a threshold. The
+//! `Assessment` is reported to the session observer whatever the decision.
+
+use bitcoin::{Transaction, Txid};
+
+use crate::blockchain::{Blockchain, TxSource};
+use crate::fees::FeeRate;
+use crate::Error;
+
+/// Something that could keep a payment from confirming
+#[derive(Debug, Clone, PartialEq)]
+pub enum Risk {
+    /// A transaction in the mempool already spends input `index` of the proof
+    Conflict { index: usize, txid: Txid },
+    /// The unconfirmed parent, or one of its ancestors, signals RBF
+    Replaceable(Txid),
+    /// The unconfirmed parent and its ancestors pay less than the minimum fee rate
+    LowFeeRate { txid: Txid, fee_rate: FeeRate },
+    /// The parent is unconfirmed, but the backend knows nothing about it
+    Unknown(Txid),
+}
+
+impl Risk {
+    /// Contribution to the score of an `Assessment`
+    pub fn weight(&self) -> u8 {
+        match self {
+            Risk::Conflict { .. } => 100,
+            Risk::Replaceable(_) => 60,
+            Risk::LowFeeRate { .. } => 40,
+            Risk::Unknown(_) => 20,
+        }
+    }
+}
+
+/// Outcome of a `ZeroConfPolicy` on a proof
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assessment {
+    /// Inputs of the proof spending unconfirmed outputs
+    pub unconfirmed_inputs: usize,
+    pub risks: Vec<Risk>,
+    /// Sum of the weights of the risks, from 0 to 100
+    pub score: u8,
+    pub accepted: bool,
+}
+
+/// Receiver's requirements on the proofs spending unconfirmed outputs, on top of `ProofPolicy`
+///
+/// Parents are only considered unconfirmed when the backend either has them in its mempool or
+/// knows their confirmations, like with `ProofPolicy::min_confirmations`.
+#[derive(Debug, Clone)]
+pub struct ZeroConfPolicy {
+    /// Highest score accepted
+    pub max_score: u8,
+    /// Fee rate below which an unconfirmed parent, along with its ancestors, is a risk
+    pub min_ancestor_fee_rate: FeeRate,
+    /// Inputs spending unconfirmed outputs accepted in the same proof, regardless of the score
+    pub max_unconfirmed_inputs: Option<usize>,
+}
+
+impl Default for ZeroConfPolicy {
+    fn default() -> Self {
+        ZeroConfPolicy {
+            max_score: 30,
+            min_ancestor_fee_rate: FeeRate::default(),
+            max_unconfirmed_inputs: None,
+        }
+    }
+}
+
+impl ZeroConfPolicy {
+    /// Assess the inputs of `proof`. Meant to run once the proof is validated, so that its
+    /// inputs are known to exist
+    pub fn assess<B>(&self, proof: &Transaction, blockchain: &B) -> Result<Assessment, Error>
+    where
+        B: Blockchain,
+        Error: From<<B as TxSource>::Error>,
+    {
+        let mut risks = vec![];
+        let mut unconfirmed_inputs = 0;
+        let mut parents = vec![];
+
+        for (index, input) in proof.input.iter().enumerate() {
+            if let Some(spending_tx) = blockchain.get_spending_tx(&input.previous_output)? {
+                risks.push(Risk::Conflict {
+                    index,
+                    txid: spending_tx.txid(),
+                });
+            }
+
+            let txid = input.previous_output.txid;
+            let entry = blockchain.get_mempool_entry(&txid)?;
+            let confirmations = match entry {
+                Some(_) => Some(0),
+                None => blockchain.get_confirmations(&txid)?,
+            };
+            if confirmations != Some(0) {
+                continue;
+            }
+
+            unconfirmed_inputs += 1;
+            if parents.contains(&txid) {
+                continue;
+            }
+            parents.push(txid);
+
+            match entry {
+                None => risks.push(Risk::Unknown(txid)),
+                Some(entry) => {
+                    if entry.replaceable {
+                        risks.push(Risk::Replaceable(txid));
+                    }
+                    let fee_rate = FeeRate::from_sat_per_vb(
+                        entry.ancestor_fee as f32 / entry.ancestor_vsize.max(1) as f32,
+                    );
+                    if fee_rate < self.min_ancestor_fee_rate {
+                        risks.push(Risk::LowFeeRate { txid, fee_rate });
+                    }
+                }
+            }
+        }
+
+        let score = risks
+            .iter()
+            .map(|risk| u32::from(risk.weight()))
+            .sum::<u32>()
+            .min(100) as u8;
+        let accepted = score <= self.max_score
+            && self
+                .max_unconfirmed_inputs
+                .is_none_or(|max| unconfirmed_inputs <= max);
+
+        Ok(Assessment {
+            unconfirmed_inputs,
+            risks,
+            score,
+            accepted,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{OutPoint, Script, TxIn, TxOut};
+
+    use super::*;
+    use crate::blockchain::MempoolEntry;
+    use crate::testutil::MockBlockchain;
+
+    fn parent(blockchain: &MockBlockchain, entry: Option<MempoolEntry>) -> OutPoint {
+        let funding = blockchain.fund(Script::new(), 100_000);
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: funding,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 99_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let txid = blockchain.add_tx(tx);
+        if let Some(entry) = entry {
+            blockchain.set_unconfirmed(txid, entry);
+        }
+
+        OutPoint { txid, vout: 0 }
+    }
+
+    fn proof(inputs: &[OutPoint]) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: inputs
+                .iter()
+                .map(|previous_output| TxIn {
+                    previous_output: *previous_output,
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![],
+        }
+    }
+
+    #[test]
+    fn test_assess() {
+        let blockchain = MockBlockchain::new();
+        let entry = MempoolEntry {
+            fee: 1_000,
+            vsize: 110,
+            ancestor_fee: 1_000,
+            ancestor_vsize: 110,
+            replaceable: false,
+        };
+        let confirmed = parent(&blockchain, None);
+        let unconfirmed = parent(&blockchain, Some(entry));
+        let replaceable = parent(
+            &blockchain,
+            Some(MempoolEntry {
+                replaceable: true,
+                ..entry
+            }),
+        );
+        let cheap = parent(
+            &blockchain,
+            Some(MempoolEntry {
+                ancestor_fee: 100,
+                ..entry
+            }),
+        );
+        let policy = ZeroConfPolicy::default();
+
+        let assessment = policy.assess(&proof(&[confirmed]), &blockchain).unwrap();
+        assert_eq!(assessment.unconfirmed_inputs, 0);
+        assert!(assessment.accepted);
+
+        let assessment = policy
+            .assess(&proof(&[confirmed, unconfirmed]), &blockchain)
+            .unwrap();
+        assert_eq!(assessment.unconfirmed_inputs, 1);
+        assert_eq!(assessment.score, 0);
+        assert!(assessment.accepted);
+
+        let assessment = policy.assess(&proof(&[replaceable]), &blockchain).unwrap();
+        assert_eq!(assessment.risks, vec![Risk::Replaceable(replaceable.txid)]);
+        assert!(!assessment.accepted);
+
+        let assessment = policy.assess(&proof(&[cheap]), &blockchain).unwrap();
+        assert!(matches!(
+            assessment.risks.as_slice(),
+            [Risk::LowFeeRate { txid, .. }] if *txid == cheap.txid
+        ));
+        assert!(!assessment.accepted);
+
+        let strict = ZeroConfPolicy {
+            max_unconfirmed_inputs: Some(0),
+            ..Default::default()
+        };
+        assert!(
+            !strict
+                .assess(&proof(&[unconfirmed]), &blockchain)
+                .unwrap()
+                .accepted
+        );
+
+        // The sender already spends the output elsewhere
+        blockchain.add_tx(proof(&[unconfirmed]));
+        let assessment = policy.assess(&proof(&[unconfirmed]), &blockchain).unwrap();
+        assert_eq!(assessment.score, 100);
+        assert!(!assessment.accepted);
+
+        blockchain.mine();
+        let assessment = policy.assess(&proof(&[replaceable]), &blockchain).unwrap();
+        assert_eq!(assessment.unconfirmed_inputs, 0);
+        assert!(assessment.accepted);
+    }
+}
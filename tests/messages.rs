@@ -0,0 +1,447 @@
+//! Property tests of the (de)serialization of the protocol messages
+//!
+//! Every message we can build must survive a round trip through its JSON encoding, and no input a
+//! peer can send, however malformed, may panic while it is being parsed.
+
+use std::convert::TryFrom;
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+use serde_json::{json, Value};
+
+use libp2ep::bitcoin::consensus::serialize;
+use libp2ep::bitcoin::hashes::hex::ToHex;
+use libp2ep::bitcoin::hashes::Hash;
+use libp2ep::bitcoin::util::psbt::PartiallySignedTransaction;
+use libp2ep::bitcoin::*;
+use libp2ep::common::{FinalTransactionError, ProofTransactionError};
+use libp2ep::{Capabilities, Envelope, Message, ProtocolError, Request, Response, WitnessWrapper};
+
+fn capabilities() -> impl Strategy<Value = Capabilities> {
+    vec(
+        prop_oneof![
+            Just(Capabilities::PSBT),
+            Just(Capabilities::TAPROOT),
+            Just(Capabilities::MULTI_UTXO),
+            Just(Capabilities::BATCH),
+        ],
+        0..4,
+    )
+    .prop_map(|flags| flags.into_iter().fold(Capabilities::empty(), |a, b| a | b))
+}
+
+fn script() -> impl Strategy<Value = Script> {
+    vec(any::<u8>(), 0..80).prop_map(Script::from)
+}
+
+fn txid() -> impl Strategy<Value = Txid> {
+    any::<[u8; 32]>().prop_map(|bytes| Txid::from_slice(&bytes).unwrap())
+}
+
+fn outpoint() -> impl Strategy<Value = OutPoint> {
+    (txid(), any::<u32>()).prop_map(|(txid, vout)| OutPoint { txid, vout })
+}
+
+fn txout() -> impl Strategy<Value = TxOut> {
+    (any::<u64>(), script()).prop_map(|(value, script_pubkey)| TxOut {
+        value,
+        script_pubkey,
+    })
+}
+
+fn txin(signed: bool) -> impl Strategy<Value = TxIn> {
+    let (script_sig, witness) = if signed {
+        (script().boxed(), 0..4)
+    } else {
+        (Just(Script::new()).boxed(), 0..1)
+    };
+    (
+        outpoint(),
+        script_sig,
+        any::<u32>(),
+        vec(vec(any::<u8>(), 0..73), witness),
+    )
+        .prop_map(|(previous_output, script_sig, sequence, witness)| TxIn {
+            previous_output,
+            script_sig,
+            sequence,
+            witness,
+        })
+}
+
+fn transaction(signed: bool) -> impl Strategy<Value = Transaction> {
+    // Without inputs the segwit marker makes the encoding ambiguous
+    (
+        any::<u32>(),
+        any::<u32>(),
+        vec(txin(signed), 1..4),
+        vec(txout(), 0..4),
+    )
+        .prop_map(|(version, lock_time, input, output)| Transaction {
+            version,
+            lock_time,
+            input,
+            output,
+        })
+}
+
+fn psbt() -> impl Strategy<Value = PartiallySignedTransaction> {
+    transaction(false).prop_map(|tx| PartiallySignedTransaction::from_unsigned_tx(tx).unwrap())
+}
+
+fn witness_wrapper() -> impl Strategy<Value = WitnessWrapper> {
+    vec(vec(any::<u8>(), 0..73), 0..4).prop_map(|witness| WitnessWrapper::new(&witness))
+}
+
+fn request() -> impl Strategy<Value = Request> {
+    prop_oneof![
+        (
+            ".*",
+            capabilities(),
+            proptest::option::of("[0-9a-f]{32}"),
+            proptest::option::of("[0-9a-f]{32}")
+        )
+            .prop_map(|(version, capabilities, token, invoice)| Request::Version {
+                version,
+                capabilities,
+                token,
+                invoice,
+            }),
+        ".*".prop_map(|session| Request::Resume { session }),
+        transaction(true).prop_map(|transaction| Request::Proof { transaction }),
+        transaction(true).prop_map(|transaction| Request::Cosign { transaction }),
+        (
+            any::<u64>(),
+            proptest::option::of(any::<u64>()),
+            script(),
+            proptest::option::of(any::<usize>()),
+            vec(txout(), 0..3),
+            vec(any::<usize>(), 0..3),
+            any::<usize>(),
+            proptest::option::of(any::<usize>()),
+            vec(vec(witness_wrapper(), 0..3), 0..3),
+        )
+            .prop_map(
+                |(
+                    fees,
+                    amount,
+                    change_script,
+                    change_position,
+                    payments,
+                    receiver_input_positions,
+                    receiver_output_position,
+                    receiver_change_position,
+                    witnesses,
+                )| Request::Witnesses {
+                    fees,
+                    amount,
+                    change_script,
+                    change_position,
+                    payments,
+                    receiver_input_positions,
+                    receiver_output_position,
+                    receiver_change_position,
+                    witnesses,
+                }
+            ),
+        (
+            any::<u64>(),
+            proptest::option::of(any::<u64>()),
+            script(),
+            proptest::option::of(any::<usize>()),
+            vec(txout(), 0..3),
+            vec(any::<usize>(), 0..3),
+            any::<usize>(),
+            proptest::option::of(any::<usize>()),
+            vec(psbt(), 0..3),
+        )
+            .prop_map(
+                |(
+                    fees,
+                    amount,
+                    change_script,
+                    change_position,
+                    payments,
+                    receiver_input_positions,
+                    receiver_output_position,
+                    receiver_change_position,
+                    psbts,
+                )| Request::Psbts {
+                    fees,
+                    amount,
+                    change_script,
+                    change_position,
+                    payments,
+                    receiver_input_positions,
+                    receiver_output_position,
+                    receiver_change_position,
+                    psbts,
+                }
+            ),
+    ]
+}
+
+fn response() -> impl Strategy<Value = Response> {
+    prop_oneof![
+        (
+            ".*",
+            capabilities(),
+            proptest::option::of("[0-9a-f]{32}"),
+            proptest::option::of("[0-9a-f]{32}"),
+        )
+            .prop_map(
+                |(version, capabilities, session, nonce)| Response::Version {
+                    version,
+                    capabilities,
+                    session,
+                    nonce,
+                }
+            ),
+        (
+            vec(outpoint(), 0..8),
+            any::<usize>(),
+            proptest::option::of(txout())
+        )
+            .prop_map(|(utxos, receiver_inputs, receiver_change)| {
+                Response::Utxos {
+                    utxos,
+                    receiver_inputs,
+                    receiver_change,
+                }
+            }),
+        (
+            transaction(true),
+            proptest::option::of(any::<u64>()),
+            any::<bool>()
+        )
+            .prop_map(|(transaction, will_broadcast_by, sender_broadcasts)| {
+                Response::Txid {
+                    txid: transaction.txid(),
+                    transaction,
+                    will_broadcast_by,
+                    sender_broadcasts,
+                }
+            }),
+    ]
+}
+
+fn protocol_error() -> impl Strategy<Value = ProtocolError> {
+    let index = any::<usize>();
+    let proof_error = prop_oneof![
+        Just(ProofTransactionError::InvalidVersion),
+        Just(ProofTransactionError::InvalidLocktime),
+        Just(ProofTransactionError::InvalidProofOutput),
+        index.prop_map(ProofTransactionError::InvalidInputType),
+        index.prop_map(ProofTransactionError::InvalidInputSignature),
+        index.prop_map(ProofTransactionError::MissingUTXO),
+        index.prop_map(ProofTransactionError::InputIsSpent),
+        Just(ProofTransactionError::TooManyInputs),
+        index.prop_map(ProofTransactionError::UnconfirmedInput),
+        Just(ProofTransactionError::InsufficientValue),
+        index.prop_map(ProofTransactionError::DisallowedInputType),
+        Just(ProofTransactionError::RbfNotAllowed),
+        index.prop_map(ProofTransactionError::InvalidSighashType),
+        Just(ProofTransactionError::RiskyUnconfirmedInputs),
+    ];
+    let final_error = prop_oneof![
+        Just(FinalTransactionError::NegativeSenderAmount),
+        Just(FinalTransactionError::InvalidSenderOutputIndex),
+        Just(FinalTransactionError::InvalidReceiverInputSequence),
+        Just(FinalTransactionError::InvalidReceiverInputNonEmptySig),
+        Just(FinalTransactionError::InvalidReceiverInputIndex),
+        Just(FinalTransactionError::InvalidReceiverOutputIndex),
+        Just(FinalTransactionError::InvalidReceiverPayment),
+        Just(FinalTransactionError::InvalidWitness),
+        index.prop_map(FinalTransactionError::InvalidSenderSignature),
+        index.prop_map(FinalTransactionError::InvalidSenderSighashType),
+        Just(FinalTransactionError::PsbtMismatch),
+        index.prop_map(FinalTransactionError::DustOutput),
+        Just(FinalTransactionError::InvalidReceiverChange),
+    ];
+
+    prop_oneof![
+        Just(ProtocolError::UnexpectedMessage),
+        ".*".prop_map(ProtocolError::Expected),
+        ".*".prop_map(ProtocolError::InvalidVersion),
+        proof_error.prop_map(ProtocolError::InvalidProof),
+        final_error.prop_map(ProtocolError::InvalidFinalTransaction),
+        Just(ProtocolError::InvalidUtxo),
+        Just(ProtocolError::MissingData),
+        Just(ProtocolError::Busy),
+        Just(ProtocolError::InvalidId),
+        capabilities().prop_map(ProtocolError::MissingCapability),
+        Just(ProtocolError::InvalidSession),
+        Just(ProtocolError::InvalidToken),
+        Just(ProtocolError::RateLimited),
+        Just(ProtocolError::ProofMismatch),
+        Just(ProtocolError::InvalidFallback),
+        Just(ProtocolError::TxidMismatch),
+        Just(ProtocolError::TransactionMismatch),
+        index.prop_map(ProtocolError::InvalidSignature),
+        Just(ProtocolError::TooManyWitnesses),
+        Just(ProtocolError::UnknownInvoice),
+        any::<u64>().prop_map(ProtocolError::AmountTooLow),
+        Just(ProtocolError::InvoiceExpired),
+        Just(ProtocolError::ChainFailed),
+        capabilities().prop_map(|feature| ProtocolError::Unsupported { feature }),
+        Just(ProtocolError::ReplayedProof),
+        Just(ProtocolError::Detectable),
+        (any::<i64>(), ".*").prop_map(|(code, message)| ProtocolError::Other { code, message }),
+    ]
+}
+
+fn message() -> impl Strategy<Value = Message> {
+    prop_oneof![
+        request().prop_map(Message::from),
+        response().prop_map(Message::from),
+        protocol_error().prop_map(Message::from),
+    ]
+}
+
+/// Any JSON value, nested a few levels deep
+fn json_value() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        any::<u64>().prop_map(Value::from),
+        any::<f64>().prop_map(Value::from),
+        ".*".prop_map(Value::from),
+        "([0-9a-f]{2}){0,64}".prop_map(Value::from),
+    ];
+    leaf.prop_recursive(4, 64, 8, |inner| {
+        prop_oneof![
+            vec(inner.clone(), 0..8).prop_map(Value::from),
+            proptest::collection::hash_map(
+                prop_oneof![
+                    "[a-z_]{1,24}",
+                    Just("transaction".to_string()),
+                    Just("witnesses".to_string()),
+                    Just("psbts".to_string()),
+                    Just("receiver_input_position".to_string()),
+                ],
+                inner,
+                0..8
+            )
+            .prop_map(|map| Value::Object(map.into_iter().collect())),
+        ]
+    })
+}
+
+/// Something that looks like a message, with arbitrary contents
+fn message_like() -> impl Strategy<Value = Value> {
+    let method = prop_oneof![
+        Just("VERSION"),
+        Just("RESUME"),
+        Just("PROOF"),
+        Just("WITNESSES"),
+        Just("PSBTS"),
+    ];
+
+    prop_oneof![
+        (method, json_value()).prop_map(|(method, params)| json!({
+            "jsonrpc": "2.0",
+            "id": "1",
+            "method": method,
+            "params": params,
+        })),
+        json_value().prop_map(|result| json!({ "jsonrpc": "2.0", "id": "1", "result": result })),
+        json_value().prop_map(|error| json!({ "jsonrpc": "2.0", "id": "1", "error": error })),
+        json_value(),
+    ]
+}
+
+/// Flip a byte of the consensus encoding of `data`, or cut it short
+fn corrupt(data: &[u8], position: usize, byte: u8, truncate: bool) -> String {
+    let mut data = data.to_vec();
+    if !data.is_empty() {
+        let position = position % data.len();
+        if truncate {
+            data.truncate(position);
+        } else {
+            data[position] ^= byte;
+        }
+    }
+
+    data.to_hex()
+}
+
+fn parse(line: &str) -> Option<Envelope> {
+    serde_json::from_str(line).ok()
+}
+
+proptest! {
+    #[test]
+    fn test_message_round_trip(message in message()) {
+        let encoded = serde_json::to_value(Envelope::new("1", message)).unwrap();
+        let line = serde_json::to_string(&encoded).unwrap();
+        let decoded = parse(&line).unwrap();
+
+        prop_assert_eq!(&decoded.jsonrpc, "2.0");
+        prop_assert_eq!(&decoded.id, &Some(json!("1")));
+        prop_assert_eq!(serde_json::to_value(decoded).unwrap(), encoded);
+    }
+
+    #[test]
+    fn test_request_response_conversion(message in message()) {
+        let is_request = matches!(message, Message::Request { .. });
+        let is_response = matches!(message, Message::Response { .. });
+
+        prop_assert_eq!(Request::try_from(message.clone()).is_ok(), is_request);
+        prop_assert_eq!(Response::try_from(message).is_ok(), is_response);
+    }
+
+    #[test]
+    fn test_arbitrary_lines(line in ".*") {
+        parse(&line);
+    }
+
+    #[test]
+    fn test_arbitrary_messages(value in message_like()) {
+        parse(&value.to_string());
+    }
+
+    #[test]
+    fn test_corrupted_messages(message in message(), position in any::<usize>(), byte in any::<u8>()) {
+        let mut line = serde_json::to_vec(&Envelope::new("1", message)).unwrap();
+        let position = position % line.len();
+        line[position] = byte;
+
+        parse(&String::from_utf8_lossy(&line));
+    }
+
+    #[test]
+    fn test_witness_wrapper(bytes in vec(any::<u8>(), 0..128), hex in ".*") {
+        let witness = WitnessWrapper::try_from(bytes.to_hex()).unwrap();
+        prop_assert_eq!(witness.as_ref(), &bytes[..]);
+        prop_assert_eq!(String::from(witness), bytes.to_hex());
+
+        let _ = WitnessWrapper::try_from(hex);
+    }
+
+    #[test]
+    fn test_corrupted_payloads(
+        transaction in transaction(true),
+        psbt in psbt(),
+        position in any::<usize>(),
+        byte in 1..=u8::MAX,
+        truncate in any::<bool>(),
+    ) {
+        let transaction = corrupt(&serialize(&transaction), position, byte, truncate);
+        let psbt = corrupt(&serialize(&psbt), position, byte, truncate);
+
+        parse(&json!({"method": "PROOF", "params": {"transaction": transaction}}).to_string());
+        parse(&json!({"result": {"txid": Txid::default(), "transaction": transaction}}).to_string());
+        parse(&json!({
+            "method": "PSBTS",
+            "params": {
+                "fees": 0,
+                "change_script": "",
+                "receiver_input_position": 0,
+                "receiver_output_position": 0,
+                "psbts": [psbt],
+            },
+        }).to_string());
+    }
+}
@@ -0,0 +1,367 @@
+//! End-to-end negotiation against a `bitcoind` node in regtest
+//!
+//! Set `BITCOIND_EXE` to the path of a `bitcoind` binary to start a fresh node, or
+//! `BITCOIND_RPC_URL` and `BITCOIND_RPC_COOKIE` to use a regtest node that is already running with
+//! `-txindex`. The test is skipped when neither is set.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use rand::seq::SliceRandom;
+
+use serde_json::{json, Value};
+
+use tokio::time::timeout;
+
+use bitcoincore_rpc::{Auth, Client as RpcClient, RpcApi};
+
+use libp2ep::bitcoin::consensus::{deserialize, serialize};
+use libp2ep::bitcoin::hashes::hex::{FromHex, ToHex};
+use libp2ep::bitcoin::secp256k1::{Secp256k1, SecretKey};
+use libp2ep::bitcoin::*;
+use libp2ep::client::ClientConfig;
+use libp2ep::demo::SoftwareSigner;
+use libp2ep::transport::Direct;
+use libp2ep::wallet::SimpleReceiverWallet;
+use libp2ep::{Broadcaster, Client, FeeRate, Server, TxSource};
+
+const MINER_WALLET: &str = "libp2ep-miner";
+const UTXO_VALUE: u64 = 100_000_000;
+
+struct Node {
+    url: String,
+    cookie: PathBuf,
+    process: Option<(Child, PathBuf)>,
+}
+
+impl Node {
+    fn from_env() -> Option<Node> {
+        if let Ok(exe) = env::var("BITCOIND_EXE") {
+            return Some(Node::start(&exe));
+        }
+
+        match (
+            env::var("BITCOIND_RPC_URL"),
+            env::var("BITCOIND_RPC_COOKIE"),
+        ) {
+            (Ok(url), Ok(cookie)) => Some(Node {
+                url,
+                cookie: cookie.into(),
+                process: None,
+            }),
+            _ => None,
+        }
+    }
+
+    fn start(exe: &str) -> Node {
+        let free_port = || {
+            TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port()
+        };
+        let (rpc_port, p2p_port) = (free_port(), free_port());
+
+        let mut datadir = env::temp_dir();
+        datadir.push(format!("libp2ep-regtest-{}", rpc_port));
+        fs::create_dir_all(&datadir).unwrap();
+
+        let child = Command::new(exe)
+            .arg("-regtest")
+            .arg("-txindex")
+            .arg("-listen=0")
+            .arg("-fallbackfee=0.0001")
+            .arg(format!("-datadir={}", datadir.display()))
+            .arg(format!("-rpcport={}", rpc_port))
+            .arg(format!("-port={}", p2p_port))
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("Unable to start bitcoind");
+
+        Node {
+            url: format!("http://127.0.0.1:{}", rpc_port),
+            cookie: datadir.join("regtest").join(".cookie"),
+            process: Some((child, datadir)),
+        }
+    }
+
+    fn rpc(&self, wallet: Option<&str>) -> RpcClient {
+        let url = match wallet {
+            Some(wallet) => format!("{}/wallet/{}", self.url, wallet),
+            None => self.url.clone(),
+        };
+
+        // The cookie file only appears once the node is ready to accept connections
+        for _ in 0..60 {
+            if let Ok(client) = RpcClient::new(url.clone(), Auth::CookieFile(self.cookie.clone())) {
+                if client.call::<Value>("getblockcount", &[]).is_ok() {
+                    return client;
+                }
+            }
+            std::thread::sleep(Duration::from_millis(500));
+        }
+
+        panic!("bitcoind is not responding at {}", url);
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        if let Some((child, datadir)) = &mut self.process {
+            let _ = child.kill();
+            let _ = child.wait();
+            let _ = fs::remove_dir_all(datadir);
+        }
+    }
+}
+
+/// `Blockchain` backed by the node, with decoys taken from the miner's wallet
+struct RpcBlockchain(RpcClient);
+
+impl fmt::Debug for RpcBlockchain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RpcBlockchain").finish()
+    }
+}
+
+impl TxSource for RpcBlockchain {
+    type Error = ();
+
+    fn get_tx(&self, txid: &Txid) -> Result<Transaction, Self::Error> {
+        let hex: String = self
+            .0
+            .call("getrawtransaction", &[json!(txid.to_hex())])
+            .map_err(|_| ())?;
+
+        deserialize(&Vec::<u8>::from_hex(&hex).map_err(|_| ())?).map_err(|_| ())
+    }
+
+    fn is_unspent(&self, txout: &OutPoint) -> Result<bool, Self::Error> {
+        let result: Value = self
+            .0
+            .call(
+                "gettxout",
+                &[json!(txout.txid.to_hex()), json!(txout.vout), json!(true)],
+            )
+            .map_err(|_| ())?;
+
+        Ok(!result.is_null())
+    }
+
+    fn get_random_utxo(&self) -> Result<OutPoint, Self::Error> {
+        let unspent: Vec<Value> = self.0.call("listunspent", &[]).map_err(|_| ())?;
+        let utxo = unspent.choose(&mut rand::thread_rng()).ok_or(())?;
+
+        Ok(OutPoint {
+            txid: Txid::from_hex(utxo["txid"].as_str().ok_or(())?).map_err(|_| ())?,
+            vout: utxo["vout"].as_u64().ok_or(())? as u32,
+        })
+    }
+}
+
+impl Broadcaster for RpcBlockchain {
+    type Error = ();
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), Self::Error> {
+        self.0
+            .call::<Value>("sendrawtransaction", &[json!(serialize(tx).to_hex())])
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+}
+
+fn p2wpkh_key(seed: u8) -> (PrivateKey, Address) {
+    let secp = Secp256k1::signing_only();
+    let key = PrivateKey {
+        compressed: true,
+        network: Network::Regtest,
+        key: SecretKey::from_slice(&[seed; 32]).unwrap(),
+    };
+    let address = Address::p2wpkh(&key.public_key(&secp), Network::Regtest);
+
+    (key, address)
+}
+
+fn mine(miner: &RpcClient, blocks: u64) {
+    let address: String = miner.call("getnewaddress", &[]).unwrap();
+    miner
+        .call::<Value>("generatetoaddress", &[json!(blocks), json!(address)])
+        .unwrap();
+}
+
+/// Send `UTXO_VALUE` to `address` from the miner's wallet, returning the new UTXO
+fn fund(miner: &RpcClient, blockchain: &RpcBlockchain, address: &Address) -> OutPoint {
+    let txid: String = miner
+        .call(
+            "sendtoaddress",
+            &[
+                json!(address.to_string()),
+                json!(Amount::from_sat(UTXO_VALUE).as_btc()),
+            ],
+        )
+        .unwrap();
+    let txid = Txid::from_hex(&txid).unwrap();
+    let tx = blockchain.get_tx(&txid).unwrap();
+    let vout = tx
+        .output
+        .iter()
+        .position(|output| output.script_pubkey == address.script_pubkey())
+        .unwrap();
+
+    OutPoint {
+        txid,
+        vout: vout as u32,
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn test_regtest_payjoin() {
+    let node = match Node::from_env() {
+        Some(node) => node,
+        None => {
+            eprintln!("Skipping: neither BITCOIND_EXE nor BITCOIND_RPC_URL is set");
+            return;
+        }
+    };
+
+    let rpc = node.rpc(None);
+    if rpc
+        .call::<Value>("createwallet", &[json!(MINER_WALLET)])
+        .is_err()
+    {
+        let _ = rpc.call::<Value>("loadwallet", &[json!(MINER_WALLET)]);
+    }
+    let miner = node.rpc(Some(MINER_WALLET));
+    let blockchain = RpcBlockchain(node.rpc(Some(MINER_WALLET)));
+    mine(&miner, 101);
+
+    let amount = 3_000_000;
+    let fees = 5000;
+    let fee_rate = FeeRate::from_sat_per_vb(10.0);
+    let (sender_key, sender_address) = p2wpkh_key(1);
+    let (receiver_key, receiver_address) = p2wpkh_key(2);
+    let sender_utxo = fund(&miner, &blockchain, &sender_address);
+    let receiver_utxo = fund(&miner, &blockchain, &receiver_address);
+    mine(&miner, 1);
+
+    let base_transaction = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: sender_utxo,
+            sequence: 0xFFFF_FFFF,
+            ..Default::default()
+        }],
+        output: vec![
+            TxOut {
+                script_pubkey: sender_address.script_pubkey(),
+                value: UTXO_VALUE - amount - fees,
+            },
+            TxOut {
+                script_pubkey: receiver_address.script_pubkey(),
+                value: amount,
+            },
+        ],
+    };
+    let sender_signer = SoftwareSigner::new(
+        sender_key,
+        vec![(sender_utxo, (UTXO_VALUE, sender_address.script_pubkey()))]
+            .into_iter()
+            .collect(),
+    );
+    let receiver_signer = SoftwareSigner::new(
+        receiver_key,
+        vec![(
+            receiver_utxo,
+            (UTXO_VALUE, receiver_address.script_pubkey()),
+        )]
+        .into_iter()
+        .collect(),
+    );
+    let wallet = SimpleReceiverWallet::new(
+        vec![(
+            receiver_utxo,
+            TxOut {
+                script_pubkey: receiver_address.script_pubkey(),
+                value: UTXO_VALUE,
+            },
+        )],
+        vec![receiver_address.script_pubkey()],
+    );
+
+    let mut server = Server::new(
+        "127.0.0.1:0",
+        RpcBlockchain(node.rpc(Some(MINER_WALLET))),
+        receiver_signer,
+        wallet,
+        amount,
+    )
+    .await
+    .unwrap();
+    server.set_transport(Direct);
+    let mut client = Client::new_direct(
+        server.local_addr().unwrap(),
+        RpcBlockchain(node.rpc(Some(MINER_WALLET))),
+        sender_signer,
+        base_transaction,
+        1,
+    )
+    .await
+    .unwrap();
+    client.set_config(ClientConfig {
+        fee_rate,
+        ..Default::default()
+    });
+
+    let (result, served) = tokio::join!(
+        client.start(),
+        timeout(Duration::from_secs(60), server.mainloop())
+    );
+    let result = result.unwrap();
+    let txid = result.txid;
+    served.unwrap().unwrap();
+
+    mine(&miner, 1);
+    let info: Value = miner
+        .call("getrawtransaction", &[json!(txid.to_hex()), json!(true)])
+        .unwrap();
+    assert!(info["confirmations"].as_u64().unwrap_or(0) >= 1);
+
+    let payjoin = blockchain.get_tx(&txid).unwrap();
+    assert!(payjoin
+        .input
+        .iter()
+        .any(|i| i.previous_output == sender_utxo));
+    assert!(payjoin
+        .input
+        .iter()
+        .any(|i| i.previous_output == receiver_utxo));
+
+    let balance = |address: &Address| -> u64 {
+        payjoin
+            .output
+            .iter()
+            .filter(|output| output.script_pubkey == address.script_pubkey())
+            .map(|output| output.value)
+            .sum()
+    };
+    let payjoin_fees = 2 * UTXO_VALUE - payjoin.output.iter().map(|o| o.value).sum::<u64>();
+    assert!(payjoin_fees >= fee_rate.fee_wu(payjoin.get_weight()));
+    assert_eq!(result.fee_paid, payjoin_fees);
+    assert_eq!(balance(&receiver_address), UTXO_VALUE + amount);
+    assert_eq!(balance(&sender_address), UTXO_VALUE - amount - payjoin_fees);
+    for vout in 0..payjoin.output.len() {
+        assert!(blockchain
+            .is_unspent(&OutPoint {
+                txid,
+                vout: vout as u32
+            })
+            .unwrap());
+    }
+}